@@ -0,0 +1,142 @@
+#ifndef TX_ACCT_H
+#define TX_ACCT_H
+
+/* Generated by cbindgen from src/ffi.rs, do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+/**
+ * Transaction types for tx_acct_submit
+ */
+#define TX_ACCT_WITHDRAWAL 0
+
+#define TX_ACCT_DEPOSIT 1
+
+#define TX_ACCT_DISPUTE 2
+
+#define TX_ACCT_RESOLVE 3
+
+#define TX_ACCT_CHARGEBACK 4
+
+#define TX_ACCT_APPROVE 5
+
+#define TX_ACCT_DENY 6
+
+#define TX_ACCT_ESCROW 7
+
+#define TX_ACCT_RELEASE 8
+
+#define TX_ACCT_REFUND 9
+
+/**
+ * The call succeeded
+ */
+#define TX_ACCT_OK 0
+
+/**
+ * The engine refused the transaction, see tx_acct_last_error
+ */
+#define TX_ACCT_REJECTED 1
+
+/**
+ * No account for the client
+ */
+#define TX_ACCT_NOT_FOUND 2
+
+/**
+ * A null pointer or an unknown transaction type was passed
+ */
+#define TX_ACCT_INVALID_ARGUMENT 3
+
+/**
+ * Any other failure, see tx_acct_last_error
+ */
+#define TX_ACCT_ERROR 4
+
+/**
+ * An engine created by tx_acct_engine_new. Not thread safe, callers serialize access to one engine.
+ */
+typedef struct TxAcctEngine TxAcctEngine;
+
+/**
+ * An account's balances in currency subunits
+ */
+typedef struct TxAcctAccount {
+  uint16_t client;
+  uint64_t available;
+  uint64_t held;
+  uint64_t total;
+  bool locked;
+  /**
+   * Added last so the earlier fields keep their offsets
+   */
+  uint64_t escrow;
+} TxAcctAccount;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Creates an empty engine, to be released with tx_acct_engine_free
+ */
+struct TxAcctEngine *tx_acct_engine_new(void);
+
+/**
+ * Releases an engine. Null is ignored.
+ *
+ * # Safety
+ * engine is null or was returned by tx_acct_engine_new and has not been freed.
+ */
+void tx_acct_engine_free(struct TxAcctEngine *engine);
+
+/**
+ * Applies one transaction. tx_type is one of the TX_ACCT_ type constants, amount is ignored for disputes,
+ * resolves and chargebacks. Returns TX_ACCT_OK, TX_ACCT_REJECTED, TX_ACCT_INVALID_ARGUMENT or TX_ACCT_ERROR.
+ *
+ * # Safety
+ * engine was returned by tx_acct_engine_new and has not been freed.
+ */
+int32_t tx_acct_submit(struct TxAcctEngine *engine,
+                       uint32_t tx_type,
+                       uint16_t client,
+                       uint32_t tx,
+                       uint64_t amount);
+
+/**
+ * Copies the client's account into out. Returns TX_ACCT_OK, TX_ACCT_NOT_FOUND or TX_ACCT_INVALID_ARGUMENT.
+ *
+ * # Safety
+ * engine was returned by tx_acct_engine_new and has not been freed, out points to writable memory for one
+ * TxAcctAccount.
+ */
+int32_t tx_acct_get_account(const struct TxAcctEngine *engine,
+                            uint16_t client,
+                            struct TxAcctAccount *out);
+
+/**
+ * Number of accounts in the engine
+ *
+ * # Safety
+ * engine was returned by tx_acct_engine_new and has not been freed.
+ */
+size_t tx_acct_account_count(const struct TxAcctEngine *engine);
+
+/**
+ * Why the last tx_acct_submit on the engine failed, or null if it succeeded. The string belongs to the engine and
+ * stays valid until the next tx_acct_submit or tx_acct_engine_free.
+ *
+ * # Safety
+ * engine was returned by tx_acct_engine_new and has not been freed.
+ */
+const char *tx_acct_last_error(const struct TxAcctEngine *engine);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* TX_ACCT_H */
@@ -0,0 +1,92 @@
+use std::collections::{BTreeMap, HashMap};
+
+use crate::interest::InterestRate;
+use crate::{AccountTransactions, Amount, Error, Result};
+
+// How the engine treats the accounts of one type
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AccountPolicy {
+    // Disputes leave the client's available balance alone, as consumer banking rules would, and the amount is carried
+    // by the system's provisional credit until the dispute is resolved or charged back
+    pub provisional_credit: bool,
+    // Paid at the end of each period on the available funds of unlocked accounts
+    pub interest: Option<InterestRate>,
+}
+
+// Named account types and the clients assigned to each. Clients without a type get the engine's default treatment.
+#[derive(Debug, Clone, Default)]
+pub struct AccountTypes {
+    policies: BTreeMap<String, AccountPolicy>,
+    clients: HashMap<u16, String>,
+}
+
+impl AccountTypes {
+    pub fn new() -> AccountTypes {
+        AccountTypes::default()
+    }
+
+    pub fn add_type(&mut self, name: &str, policy: AccountPolicy) {
+        self.policies.insert(name.to_string(), policy);
+    }
+
+    // A client belongs to at most one type, which must already be added
+    pub fn assign(&mut self, client: u16, name: &str) -> Result<()> {
+        if !self.policies.contains_key(name) {
+            return Err(Error::InvalidArgument(format!("Unknown account type, {}.", name)));
+        }
+        match self.clients.get(&client) {
+            Some(existing) if existing != name => Err(Error::InvalidArgument(format!(
+                "Client, {}, is assigned both account types {} and {}.", client, existing, name))),
+            _ => {
+                self.clients.insert(client, name.to_string());
+                Ok(())
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.policies.is_empty()
+    }
+
+    pub fn type_of(&self, client: u16) -> Option<&str> {
+        self.clients.get(&client).map(String::as_str)
+    }
+
+    pub fn policy(&self, client: u16) -> Option<&AccountPolicy> {
+        self.clients.get(&client).and_then(|name| self.policies.get(name))
+    }
+
+    pub fn provisional_credit(&self, client: u16) -> bool {
+        self.policy(client).is_some_and(|policy| policy.provisional_credit)
+    }
+
+    pub fn clients_of<'a>(&'a self, name: &'a str) -> impl Iterator<Item = u16> + 'a {
+        self.clients.iter().filter(move |(_, type_name)| type_name.as_str() == name).map(|(client, _)| *client)
+    }
+
+    // The types that earn interest, in name order
+    pub fn interest_rates(&self) -> impl Iterator<Item = (&str, &InterestRate)> {
+        self.policies.iter().filter_map(|(name, policy)| policy.interest.as_ref().map(|rate| (name.as_str(), rate)))
+    }
+}
+
+impl AccountTransactions {
+    // Applies from the next message on. Disputes already open keep the treatment they were opened with, and interest
+    // is first paid at the end of the period under way.
+    pub fn set_account_types(&mut self, account_types: AccountTypes) {
+        self.account_types = account_types;
+        self.start_interest();
+    }
+
+    pub fn account_types(&self) -> &AccountTypes {
+        &self.account_types
+    }
+
+    // What the system carries for disputes opened with provisional credit: how many are open and their amount in
+    // subunits
+    pub fn provisional_credit(&self) -> (usize, Amount) {
+        self.open_disputes()
+            .filter(|tx| tx.provisional)
+            .fold((0, 0), |(count, amount), tx| (count + 1, amount + tx.amount))
+    }
+}
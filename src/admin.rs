@@ -0,0 +1,209 @@
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::change::AccountState;
+use crate::dispute_expiry::ExpiredDispute;
+use crate::lifecycle::{self, DisputeEventKind};
+use crate::{format_amount, to_subunit, AccountTransactions, Amount, Error, Result, SignedAmount, TxState};
+
+// A correction made by an operator rather than by a transaction message. Amounts are signed currency subunits.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum AdminAction {
+    // Credits, or with a negative amount debits, the available balance
+    Adjust {
+        client: u16,
+        #[cfg_attr(feature = "amount128", serde(with = "subunits_text"))]
+        amount: SignedAmount,
+    },
+    // Releases a disputed transaction's held amount back to available, as a resolve would
+    CloseDispute { tx: u32 },
+    Unlock { client: u16 },
+    // Completes or refuses a pending withdrawal, as an approve or deny would
+    Approve { tx: u32 },
+    Deny { tx: u32 },
+}
+
+impl AdminAction {
+    // The client whose account the action changes
+    pub fn client(&self, account_txs: &AccountTransactions) -> Option<u16> {
+        match self {
+            AdminAction::Adjust { client, .. } | AdminAction::Unlock { client } => Some(*client),
+            AdminAction::CloseDispute { tx } | AdminAction::Approve { tx } | AdminAction::Deny { tx } => {
+                account_txs.txs_txid.get(tx).map(|tx| tx.client)
+            }
+        }
+    }
+
+    // The transaction the action changes
+    pub fn tx(&self) -> Option<u32> {
+        match self {
+            AdminAction::CloseDispute { tx } | AdminAction::Approve { tx } | AdminAction::Deny { tx } => Some(*tx),
+            AdminAction::Adjust { .. } | AdminAction::Unlock { .. } => None,
+        }
+    }
+}
+
+// serde buffers the fields of an internally tagged enum in a form that cannot hold an i128, so with amount128 the
+// adjustment travels as the decimal string of its subunits
+#[cfg(feature = "amount128")]
+mod subunits_text {
+    use serde::de::Error as _;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use crate::SignedAmount;
+
+    pub fn serialize<S: Serializer>(amount: &SignedAmount, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(amount)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<SignedAmount, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(D::Error::custom)
+    }
+}
+
+// Parses a signed amount in currency units, e.g. "-2.5"
+pub fn signed_subunits(amount: &str) -> Result<SignedAmount> {
+    let (negative, magnitude) = match amount.trim().strip_prefix('-') {
+        Some(magnitude) => (true, magnitude),
+        None => (false, amount.trim()),
+    };
+    let subunits = SignedAmount::try_from(to_subunit(magnitude)?)
+        .map_err(|_| Error::ParseError(format!("'{}' is out of range for an adjustment", amount)))?;
+    Ok(if negative { -subunits } else { subunits })
+}
+
+impl AccountTransactions {
+    pub fn apply_admin(&mut self, action: &AdminAction) -> Result<()> {
+        match action {
+            AdminAction::Adjust { client, amount } => {
+                let acct = self.account_client.get_mut(client)
+                    .ok_or_else(|| Error::NotFound(format!("Client, {}, not found.", client)))?;
+                let magnitude = amount.unsigned_abs() as Amount;
+                let available = match amount.is_negative() {
+                    true => acct.available.checked_sub(magnitude),
+                    false => acct.available.checked_add(magnitude),
+                };
+                acct.available = available.ok_or_else(|| Error::InvalidArgument(format!(
+                    "Adjusting client, {}, by {} would leave its available balance out of range.",
+                    client, *amount as f64 / 1.0e+4_f64)))?;
+                acct.total = acct.available + acct.held + acct.escrow;
+            }
+            AdminAction::CloseDispute { tx } => {
+                let now = self.next_tx_time() - 1;
+                let disputed = self.txs_txid.get_mut(tx)
+                    .ok_or_else(|| Error::NotFound(format!("Transaction, {}, not found.", tx)))?;
+                if !disputed.disputed() {
+                    return Err(Error::InvalidArgument(format!("Transaction, {}, is not disputed.", tx)));
+                }
+                let acct = self.account_client.get_mut(&disputed.client)
+                    .ok_or_else(|| Error::NotFound(format!("Client, {}, not found.", disputed.client)))?;
+                if !disputed.provisional && acct.held < disputed.amount {
+                    return Err(Error::InvalidArgument(format!(
+                        "Client, {}, holds less than the disputed amount of transaction, {}.", disputed.client, tx)));
+                }
+                if !disputed.provisional {
+                    acct.held -= disputed.amount;
+                    acct.available += disputed.amount;
+                    acct.total = acct.available + acct.held + acct.escrow;
+                }
+                disputed.state = TxState::Resolved;
+                disputed.dispute_time = 0;
+                disputed.record_event(DisputeEventKind::Close, now);
+            }
+            AdminAction::Unlock { client } => {
+                let acct = self.account_client.get_mut(client)
+                    .ok_or_else(|| Error::NotFound(format!("Client, {}, not found.", client)))?;
+                if !acct.locked {
+                    return Err(Error::InvalidArgument(format!("Account for client, {}, is not locked.", client)));
+                }
+                acct.locked = false;
+            }
+            AdminAction::Approve { tx } => self.settle_pending(*tx, true)?,
+            AdminAction::Deny { tx } => self.settle_pending(*tx, false)?,
+        }
+        Ok(())
+    }
+}
+
+// One line of the audit log
+#[derive(Debug, Serialize)]
+struct AuditRecord<'a> {
+    at: u64,
+    actor: &'a str,
+    #[serde(flatten)]
+    action: &'a AdminAction,
+    reason: &'a str,
+    client: Option<u16>,
+    old: Option<AccountState>,
+    new: Option<AccountState>,
+    // The dispute lifecycle of the transaction the action changed, as it stands after the action
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<String>,
+}
+
+// Append-only JSON Lines record of every admin action
+pub struct AuditLog {
+    out: BufWriter<File>,
+}
+
+impl AuditLog {
+    pub fn open(path: &str) -> Result<AuditLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)
+            .map_err(|e| Error::StorageError(format!("{}: {}", path, e)))?;
+        Ok(AuditLog { out: BufWriter::new(file) })
+    }
+
+    // Applies the action and logs it. Nothing is logged for an action the engine refuses. Adjustment amounts are
+    // logged as exact decimals of currency units.
+    pub fn apply(&mut self, account_txs: &mut AccountTransactions, action: &AdminAction, reason: &str, actor: &str)
+                 -> Result<()> {
+        if reason.trim().is_empty() {
+            return Err(Error::InvalidArgument(String::from("Admin actions need a reason.")));
+        }
+        let client = action.client(account_txs);
+        let old = client.and_then(|client| account_txs.account_client.get(&client)).map(AccountState::from);
+        account_txs.apply_admin(action)?;
+        let new = client.and_then(|client| account_txs.account_client.get(&client)).map(AccountState::from);
+        let history = action.tx().and_then(|tx| account_txs.txs_txid.get(&tx))
+            .filter(|tx| !tx.history.is_empty())
+            .map(|tx| lifecycle::history_text(&tx.history));
+
+        self.write(AuditRecord { at: now(), actor, action, reason, client, old, new, history })
+    }
+
+    // Logs a dispute the engine resolved for staying open, as a close-dispute by the dispute-expiry actor
+    pub fn record_expired(&mut self, account_txs: &AccountTransactions, expired: &ExpiredDispute) -> Result<()> {
+        let reason = format!("Open for {} rows, since row {}.", expired.expired_time - expired.dispute_time,
+                             expired.dispute_time);
+        self.write(AuditRecord {
+            at: now(),
+            actor: "dispute-expiry",
+            action: &AdminAction::CloseDispute { tx: expired.tx },
+            reason: &reason,
+            client: Some(expired.client),
+            old: Some(AccountState::from(&expired.before)),
+            new: Some(AccountState::from(&expired.after)),
+            history: account_txs.txs_txid.get(&expired.tx).map(|tx| lifecycle::history_text(&tx.history)),
+        })
+    }
+
+    fn write(&mut self, record: AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_value(&record)?;
+        if let AdminAction::Adjust { amount, .. } = record.action {
+            let sign = if *amount < 0 { "-" } else { "" };
+            line["amount"] = serde_json::json!(format!("{}{}", sign, format_amount(amount.unsigned_abs())));
+        }
+        writeln!(self.out, "{}", line)?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
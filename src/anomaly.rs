@@ -0,0 +1,147 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use crate::{AccountTransactions, Amount, TransactionMessage, TransactionType};
+
+// When a transaction is unlike what its client did earlier in the run. Flagged transactions still apply.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnomalyRules {
+    // A deposit or withdrawal this many standard deviations from the mean of the client's earlier ones of its type
+    pub max_z_score: f64,
+    // Earlier deposits or withdrawals the client needs before its amounts are scored
+    pub min_history: u32,
+    // A client opening this many disputes within burst_rows rows
+    pub dispute_burst: u32,
+    pub burst_rows: u32,
+}
+
+impl Default for AnomalyRules {
+    fn default() -> AnomalyRules {
+        AnomalyRules { max_z_score: 3.0, min_history: 5, dispute_burst: 3, burst_rows: 100 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AnomalyKind {
+    Amount,
+    DisputeBurst,
+}
+
+impl fmt::Display for AnomalyKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            AnomalyKind::Amount => "amount",
+            AnomalyKind::DisputeBurst => "dispute-burst",
+        })
+    }
+}
+
+// A flagged transaction. score is the amount's z-score, or the disputes the client opened within the burst rows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Anomaly {
+    pub client: u16,
+    pub tx: u32,
+    pub tx_time: u32,
+    pub tx_type: TransactionType,
+    pub amount: Amount,
+    pub kind: AnomalyKind,
+    pub score: f64,
+}
+
+// Running mean and variance of a client's amounts of one type, by Welford's method
+#[derive(Debug, Clone, Default)]
+struct AmountHistory {
+    count: u32,
+    mean: f64,
+    m2: f64,
+}
+
+impl AmountHistory {
+    fn add(&mut self, amount: f64) {
+        self.count += 1;
+        let delta = amount - self.mean;
+        self.mean += delta / f64::from(self.count);
+        self.m2 += delta * (amount - self.mean);
+    }
+
+    // None while the amounts have no spread, so a client that always moves the same amount is not scored
+    fn z_score(&self, amount: f64) -> Option<f64> {
+        let std_dev = (self.m2 / f64::from(self.count)).sqrt();
+        (std_dev > 0.0).then(|| (amount - self.mean) / std_dev)
+    }
+}
+
+// Watches the messages that apply for ones unlike their client's earlier ones, keeping the flagged ones
+#[derive(Debug, Clone)]
+pub struct AnomalyDetector {
+    rules: AnomalyRules,
+    amounts: HashMap<(u16, TransactionType), AmountHistory>,
+    // tx_times of each client's disputes within the last burst_rows rows
+    disputes: HashMap<u16, VecDeque<u32>>,
+    anomalies: Vec<Anomaly>,
+}
+
+impl AnomalyDetector {
+    pub fn rules(&self) -> &AnomalyRules {
+        &self.rules
+    }
+
+    // Transactions flagged so far, in the order they applied
+    pub fn anomalies(&self) -> &[Anomaly] {
+        &self.anomalies
+    }
+
+    fn check(&mut self, msg: &TransactionMessage, amount: Amount) {
+        let rules = self.rules;
+        let (kind, score) = match msg.tx_type {
+            TransactionType::DEPOSIT | TransactionType::WITHDRAWAL => {
+                let history = self.amounts.entry((msg.client, msg.tx_type.clone())).or_default();
+                let score = if history.count >= rules.min_history {
+                    history.z_score(amount as f64).filter(|z| z.abs() >= rules.max_z_score)
+                } else {
+                    None
+                };
+                history.add(amount as f64);
+                (AnomalyKind::Amount, score)
+            }
+            TransactionType::DISPUTE => {
+                let recent = self.disputes.entry(msg.client).or_default();
+                while recent.front().is_some_and(|&time| msg.tx_time.saturating_sub(time) >= rules.burst_rows) {
+                    recent.pop_front();
+                }
+                recent.push_back(msg.tx_time);
+                let burst = recent.len() as u32;
+                (AnomalyKind::DisputeBurst, (burst >= rules.dispute_burst).then_some(f64::from(burst)))
+            }
+            _ => return,
+        };
+        if let Some(score) = score {
+            self.anomalies.push(Anomaly { client: msg.client, tx: msg.tx, tx_time: msg.tx_time,
+                                          tx_type: msg.tx_type.clone(), amount, kind, score });
+        }
+    }
+}
+
+impl AccountTransactions {
+    // Messages applied from here on are checked against the rules. None stops checking and drops the flags so far.
+    pub fn set_anomaly_rules(&mut self, rules: Option<AnomalyRules>) {
+        self.anomalies = rules.map(|rules| AnomalyDetector {
+            rules,
+            amounts: HashMap::new(),
+            disputes: HashMap::new(),
+            anomalies: Vec::new(),
+        });
+    }
+
+    pub fn anomaly_detector(&self) -> Option<&AnomalyDetector> {
+        self.anomalies.as_ref()
+    }
+
+    // Called for each message that applied. A dispute's amount is that of the transaction it names.
+    pub(crate) fn detect_anomalies(&mut self, transaction_msg: &TransactionMessage) {
+        if let Some(detector) = self.anomalies.as_mut() {
+            let amount = self.txs_txid.get(&transaction_msg.tx).map_or(transaction_msg.amount, |tx| tx.amount);
+            detector.check(transaction_msg, amount);
+        }
+    }
+}
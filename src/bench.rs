@@ -0,0 +1,116 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use serde_json::json;
+
+use crate::{read_file, AccountTransactions, ReadOptions, Result};
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static ALLOCATED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+// The system allocator, counting what it hands out. Counts stay at 0 unless a binary installs it as its
+// #[global_allocator], as the command line tool does when built with the bench feature.
+pub struct CountingAlloc;
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(layout.size() as u64, Ordering::Relaxed);
+        System.alloc_zeroed(layout)
+    }
+
+    // A reallocation counts as an allocation of the new size
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED_BYTES.fetch_add(new_size as u64, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+// Allocations and bytes allocated since the process started
+fn allocated() -> (u64, u64) {
+    (ALLOCATIONS.load(Ordering::Relaxed), ALLOCATED_BYTES.load(Ordering::Relaxed))
+}
+
+// The process's peak resident set, from /proc, so None off Linux
+fn peak_rss_bytes() -> Option<u64> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmHWM:"))?;
+    let kib: u64 = line.trim_start_matches("VmHWM:").trim().trim_end_matches("kB").trim().parse().ok()?;
+    Some(kib * 1024)
+}
+
+// One benchmark run over a file. Allocations count from the start of the read to its end, peak RSS is the whole
+// process's.
+#[derive(Debug, Clone, Default)]
+pub struct BenchReport {
+    pub file: String,
+    pub bytes: u64,
+    pub rows: u32,
+    pub applied: u32,
+    pub rejected: u32,
+    pub malformed: u32,
+    pub elapsed: Duration,
+    pub allocations: u64,
+    pub allocated_bytes: u64,
+    pub peak_rss_bytes: Option<u64>,
+}
+
+impl BenchReport {
+    pub fn rows_per_second(&self) -> f64 {
+        f64::from(self.rows) / self.elapsed.as_secs_f64().max(0.000_001)
+    }
+
+    // What tx_acct bench prints, one JSON object with the crate version so runs can be compared across releases
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "file": self.file,
+            "bytes": self.bytes,
+            "rows": self.rows,
+            "applied": self.applied,
+            "rejected": self.rejected,
+            "malformed": self.malformed,
+            "elapsed_secs": self.elapsed.as_secs_f64(),
+            "rows_per_second": self.rows_per_second(),
+            "allocations": self.allocations,
+            "allocated_bytes": self.allocated_bytes,
+            "peak_rss_bytes": self.peak_rss_bytes,
+        })
+    }
+}
+
+// Reads the file into the engine as read_options say and measures the read. The caller configures the engine, so
+// the read is timed with the policies it would run with.
+pub fn bench_file(filename: &str, account_txs: &mut AccountTransactions, read_options: &ReadOptions)
+                  -> Result<BenchReport> {
+    let (allocations, allocated_bytes) = allocated();
+    let start = Instant::now();
+    let report = read_file(filename, account_txs, read_options)?;
+    let elapsed = start.elapsed();
+    let (allocations_after, allocated_bytes_after) = allocated();
+    Ok(BenchReport {
+        file: filename.to_string(),
+        bytes: report.bytes,
+        rows: report.rows,
+        applied: report.applied,
+        rejected: report.rejected,
+        malformed: report.malformed,
+        elapsed,
+        allocations: allocations_after - allocations,
+        allocated_bytes: allocated_bytes_after - allocated_bytes,
+        peak_rss_bytes: peak_rss_bytes(),
+    })
+}
@@ -0,0 +1,38 @@
+use std::collections::BTreeMap;
+
+use crate::{AccountTransactions, Amount, TransactionMessage, TransactionType};
+
+// What one client deposited and withdrew under one category, amounts in subunits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CategoryTotals {
+    pub deposits: u64,
+    pub deposited: Amount,
+    pub withdrawals: u64,
+    pub withdrawn: Amount,
+}
+
+impl AccountTransactions {
+    // By (client, category), for the deposits and withdrawals applied since the engine started that named a category
+    pub fn categories(&self) -> &BTreeMap<(u16, String), CategoryTotals> {
+        &self.categories
+    }
+
+    // Called for each message that applied. A withdrawal waiting for approval counts once it was accepted.
+    pub(crate) fn count_category(&mut self, transaction_msg: &TransactionMessage) {
+        let category = match (&transaction_msg.tx_type, &transaction_msg.category) {
+            (TransactionType::DEPOSIT | TransactionType::WITHDRAWAL, Some(category)) => category,
+            _ => return,
+        };
+        let totals = self.categories.entry((transaction_msg.client, category.clone())).or_default();
+        match transaction_msg.tx_type {
+            TransactionType::DEPOSIT => {
+                totals.deposits += 1;
+                totals.deposited += transaction_msg.amount;
+            }
+            _ => {
+                totals.withdrawals += 1;
+                totals.withdrawn += transaction_msg.amount;
+            }
+        }
+    }
+}
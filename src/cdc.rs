@@ -0,0 +1,45 @@
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+
+use crate::change::AccountChange;
+use crate::observer::EngineObserver;
+use crate::Result;
+
+// Changelog of account changes as JSON Lines, appended to across runs so it reads as one ordered history
+pub struct CdcLog {
+    out: BufWriter<File>,
+    changes: u32,
+}
+
+impl CdcLog {
+    pub fn open(path: &str) -> Result<CdcLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(CdcLog { out: BufWriter::new(file), changes: 0 })
+    }
+
+    pub fn record(&mut self, change: &AccountChange) -> Result<()> {
+        serde_json::to_writer(&mut self.out, change)?;
+        self.out.write_all(b"\n")?;
+        self.changes += 1;
+        Ok(())
+    }
+
+    pub fn changes(&self) -> u32 {
+        self.changes
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+impl EngineObserver for CdcLog {
+    fn name(&self) -> &str {
+        "cdc"
+    }
+
+    fn account_changed(&mut self, change: &AccountChange) -> Result<()> {
+        self.record(change)
+    }
+}
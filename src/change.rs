@@ -0,0 +1,65 @@
+use std::collections::BTreeMap;
+
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::{format_amount, Account, TransactionMessage};
+
+// Balances and lock status of an account at one point, as exact decimals of currency units
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AccountState {
+    pub available: String,
+    pub held: String,
+    pub escrow: String,
+    pub total: String,
+    pub locked: bool,
+}
+
+impl From<&Account> for AccountState {
+    fn from(acct: &Account) -> AccountState {
+        AccountState {
+            available: format_amount(acct.available),
+            held: format_amount(acct.held),
+            escrow: format_amount(acct.escrow),
+            total: format_amount(acct.total),
+            locked: acct.locked,
+        }
+    }
+}
+
+// An account whose balances or lock status were changed by one transaction message. old is absent when the
+// message created the account.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize))]
+pub struct AccountChange {
+    pub client: u16,
+    pub tx: u32,
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub tx_type: String,
+    pub tx_time: u32,
+    pub old: Option<AccountState>,
+    pub new: AccountState,
+    // Notes validators attached to the message
+    #[cfg_attr(feature = "serde", serde(skip_serializing_if = "BTreeMap::is_empty"))]
+    pub annotations: BTreeMap<String, String>,
+}
+
+impl AccountChange {
+    // Compares the account as it was before msg was handled with how it is after, whether or not msg was applied
+    pub fn between(before: Option<&Account>, after: Option<&Account>, msg: &TransactionMessage) -> Option<AccountChange> {
+        let after = after?;
+        if before == Some(after) {
+            return None;
+        }
+        Some(AccountChange {
+            client: msg.client,
+            tx: msg.tx,
+            tx_type: msg.tx_type.to_string(),
+            tx_time: msg.tx_time,
+            old: before.map(AccountState::from),
+            new: AccountState::from(after),
+            annotations: BTreeMap::new(),
+        })
+    }
+}
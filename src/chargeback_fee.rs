@@ -0,0 +1,106 @@
+use std::fmt;
+
+use crate::lifecycle::DisputeEventKind;
+use crate::{AccountTransactions, Amount, Error, Result};
+
+// Who pays the fee assessed when a chargeback completes
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FeePayer {
+    // The client whose transaction was charged back. What their available funds cannot cover goes to the loss account.
+    #[default]
+    Client,
+    // The system's loss account, leaving the client's account alone
+    Loss,
+}
+
+impl fmt::Display for FeePayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FeePayer::Client => "client",
+            FeePayer::Loss => "loss",
+        })
+    }
+}
+
+impl std::str::FromStr for FeePayer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<FeePayer> {
+        match s {
+            "client" => Ok(FeePayer::Client),
+            "loss" => Ok(FeePayer::Loss),
+            _ => Err(Error::ParseError(format!("'{}' is not a fee payer, expected client or loss", s))),
+        }
+    }
+}
+
+// The fee in subunits posted for each chargeback, and who pays it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChargebackFee {
+    pub amount: Amount,
+    pub payer: FeePayer,
+}
+
+// The fee posted for one chargeback, at the tx_time of the chargeback row
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssessedFee {
+    pub client: u16,
+    pub tx: u32,
+    pub tx_time: u32,
+    // Taken from the client's available funds
+    pub client_paid: Amount,
+    // Absorbed by the loss account
+    pub loss: Amount,
+}
+
+impl AccountTransactions {
+    // Chargebacks completing from here on post the fee. None posts no fees; fees already posted stay.
+    pub fn set_chargeback_fee(&mut self, fee: Option<ChargebackFee>) {
+        self.chargeback_fee = fee;
+    }
+
+    pub fn chargeback_fee(&self) -> Option<&ChargebackFee> {
+        self.chargeback_fee.as_ref()
+    }
+
+    // Fees posted on the retained transactions, in the order of their chargebacks. A pruned transaction takes its fee
+    // with it.
+    pub fn assessed_fees(&self) -> Vec<AssessedFee> {
+        let mut fees: Vec<AssessedFee> = self.iter_transactions()
+            .filter(|tx| tx.fee + tx.fee_loss > 0)
+            .map(|tx| AssessedFee { client: tx.client, tx: tx.tx, tx_time: tx.chargeback_time, client_paid: tx.fee,
+                                    loss: tx.fee_loss })
+            .collect();
+        fees.sort_by_key(|fee| (fee.tx_time, fee.tx));
+        fees
+    }
+
+    // What the loss account has absorbed in subunits, over the retained transactions
+    pub fn loss_account(&self) -> Amount {
+        self.iter_transactions().map(|tx| tx.fee_loss).sum()
+    }
+
+    // Called once the chargeback of tx has applied
+    pub(crate) fn assess_chargeback_fee(&mut self, tx: u32, tx_time: u32) {
+        let fee = match self.chargeback_fee {
+            Some(fee) if fee.amount > 0 => fee,
+            _ => return,
+        };
+        let charged_back = match self.txs_txid.get_mut(&tx) {
+            Some(charged_back) => charged_back,
+            None => return,
+        };
+        let client_paid = match (fee.payer, self.account_client.get_mut(&charged_back.client)) {
+            (FeePayer::Client, Some(acct)) => {
+                let paid = fee.amount.min(acct.available);
+                acct.available -= paid;
+                acct.total = acct.available + acct.held + acct.escrow;
+                paid
+            }
+            _ => 0,
+        };
+        charged_back.fee = client_paid;
+        charged_back.fee_loss = fee.amount - client_paid;
+        charged_back.record_event(DisputeEventKind::Fee, tx_time);
+    }
+}
@@ -0,0 +1,448 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::{Error, Result};
+
+// The command line's commands and options, as main parses them, for shell completions and for checking arguments
+// before anything is loaded. An option added to main belongs here too.
+pub struct Command {
+    pub name: &'static str,
+    pub help: &'static str,
+    pub options: &'static [Opt],
+    pub args: Args,
+}
+
+pub struct Opt {
+    pub name: &'static str,
+    pub value: Value,
+    pub help: &'static str,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Value {
+    Flag,
+    File,
+    Dir,
+    Choice(&'static [&'static str]),
+    Text,
+}
+
+// What a command takes besides its options
+#[derive(Clone, Copy, PartialEq)]
+pub enum Args {
+    None,
+    Files,
+    // The first word is one of these
+    Choice(&'static [&'static str]),
+}
+
+const fn opt(name: &'static str, value: Value, help: &'static str) -> Opt {
+    Opt { name, value, help }
+}
+
+// Accepted before or after any command
+pub const GLOBAL: &[Opt] = &[
+    opt("--config", Value::File, "configuration file"),
+    opt("--profile", Value::Text, "profile in the configuration file"),
+];
+
+const STATE: Opt = opt("--state", Value::Dir, "state directory");
+const STORE: Opt = opt("--store", Value::Text, "state store, e.g. dir:state or snapshot:state.zst");
+const RETAIN_AGE: Opt = opt("--retain-age", Value::Text, "prune transactions older than this many rows");
+const RETAIN_COUNT: Opt = opt("--retain-count", Value::Text, "keep at most this many transactions");
+
+pub const SHELLS: &[&str] = &["bash", "zsh", "fish"];
+pub const ADMIN_COMMANDS: &[&str] = &["erase-client", "prune", "compact", "merge-clients", "adjust", "close-dispute",
+                                      "unlock", "approve", "deny"];
+
+// Reading input files, when no command is named
+pub const RUN: Command = Command {
+    name: "tx_acct",
+    help: "process input files",
+    options: &[
+        STATE,
+        STORE,
+        RETAIN_AGE,
+        RETAIN_COUNT,
+        opt("--output-partitions", Value::Text, "split the accounts across this many files"),
+        opt("--output-dir", Value::Dir, "directory for output partitions and reports"),
+        opt("--output-format", Value::Choice(&["csv", "json"]), "format of the accounts written"),
+        opt("--changed-only", Value::Flag, "only write accounts this run changed"),
+        opt("--dry-run", Value::Flag, "report what would change without saving"),
+        opt("--quiet", Value::Flag, "no progress bar"),
+        opt("--dashboard", Value::Flag, "full-screen view of the run"),
+        opt("--error-log", Value::File, "JSON Lines log of rows not applied"),
+        opt("--quarantine", Value::File, "copy of malformed rows"),
+        opt("--dead-letter", Value::File, "copy of rejected rows"),
+        opt("--cdc", Value::File, "JSON Lines log of account changes"),
+        opt("--filter", Value::Text, "only apply rows matching an expression"),
+        opt("--dedup", Value::Flag, "skip exact repeats of earlier rows"),
+        opt("--output", Value::File, "write the accounts here instead of STDOUT"),
+        opt("--summary", Value::File, "JSON summary of the run"),
+        opt("--decrypt-key", Value::File, "age identity or PGP secret key for encrypted input"),
+        opt("--only-clients", Value::File, "only apply and write the clients listed in a file"),
+        opt("--only-locked", Value::Flag, "only write locked accounts"),
+        opt("--min-total", Value::Text, "only write accounts with at least this total"),
+        opt("--output-clients", Value::File, "only write the clients listed in a file"),
+        opt("--withdrawal-limits", Value::File, "CSV of per-client withdrawal limits"),
+        opt("--limit-breaches", Value::File, "CSV of withdrawals refused over a limit"),
+        opt("--approval-threshold", Value::Text, "hold withdrawals above this amount until approved"),
+        opt("--dispute-expiry", Value::Text, "resolve disputes still open after this many rows"),
+        opt("--max-open-disputes", Value::Text, "reject disputes from clients with this many open"),
+        opt("--chargeback-fee", Value::Text, "post this fee for each chargeback that completes"),
+        opt("--chargeback-fee-to", Value::Text, "who pays the chargeback fee, client or loss"),
+        opt("--recover-deficits", Value::Flag, "sweep deposits against deficits left by provisional chargebacks"),
+        opt("--netting", Value::Flag, "settle transfers as one net movement per pair of clients"),
+        opt("--settlement-report", Value::File, "CSV of the net settlements between clients"),
+        opt("--counterparty-report", Value::File, "CSV of volume, dispute and chargeback rates by counterparty"),
+        opt("--category-report", Value::File, "CSV of each client's deposits and withdrawals by category"),
+        opt("--anomalies", Value::File, "CSV of transactions unlike their client's earlier ones"),
+        opt("--anomaly-z-score", Value::Text, "flag amounts this many standard deviations from the client's mean"),
+        opt("--anomaly-history", Value::Text, "earlier amounts a client needs before its amounts are scored"),
+        opt("--dispute-burst", Value::Text, "flag a client opening this many disputes within the burst rows"),
+        opt("--dispute-burst-rows", Value::Text, "rows a dispute burst is counted over"),
+        opt("--report", Value::Text, "write the named report to <name>.csv in the output directory"),
+        opt("--stale-dispute-age", Value::Text, "flag open disputes this many rows old in the dispute reports"),
+    ],
+    args: Args::Files,
+};
+
+pub const SERVE: Command = Command {
+    name: "serve",
+    help: "serve the HTTP API",
+    options: &[
+        opt("--listen", Value::Text, "HTTP address"),
+        opt("--replication-listen", Value::Text, "address standbys connect to"),
+        opt("--standby-of", Value::Text, "replication address of the primary"),
+        opt("--unix-socket", Value::File, "socket for line-protocol clients"),
+        opt("--tcp-listen", Value::Text, "address for line-protocol clients"),
+        opt("--no-auth", Value::Flag, "serve without API keys"),
+        STATE,
+        STORE,
+    ],
+    args: Args::None,
+};
+
+pub const CONSUME: Command = Command {
+    name: "consume",
+    help: "apply messages from Kafka",
+    options: &[STORE],
+    args: Args::None,
+};
+
+pub const REPL: Command = Command {
+    name: "repl",
+    help: "interactive session",
+    options: &[STATE, STORE],
+    args: Args::None,
+};
+
+pub const DIFF: Command = Command {
+    name: "diff",
+    help: "compare two account outputs",
+    options: &[],
+    args: Args::Files,
+};
+
+pub const BENCH: Command = Command {
+    name: "bench",
+    help: "time a dry run of an input file",
+    options: RUN.options,
+    args: Args::Files,
+};
+
+pub const STATEMENT: Command = Command {
+    name: "statement",
+    help: "export a client's transaction history",
+    options: &[
+        opt("--client", Value::Text, "client id"),
+        opt("--output", Value::File, "write the statement here instead of STDOUT"),
+        STATE,
+        STORE,
+    ],
+    args: Args::None,
+};
+
+pub const VERIFY_SIGNATURE: Command = Command {
+    name: "verify-signature",
+    help: "check a signed output file",
+    options: &[
+        opt("--public-key", Value::Text, "hex public key the file was signed with"),
+        opt("--signature", Value::File, "signature file, <file>.sig by default"),
+    ],
+    args: Args::Files,
+};
+
+pub const GENERATE: Command = Command {
+    name: "generate",
+    help: "write test input",
+    options: &[
+        opt("--rows", Value::Text, "rows to write"),
+        opt("--clients", Value::Text, "distinct clients"),
+        opt("--seed", Value::Text, "random seed"),
+        opt("--malformed-rate", Value::Text, "fraction of malformed rows"),
+        opt("--duplicate-rate", Value::Text, "fraction of duplicate tx ids"),
+        opt("--cross-client-rate", Value::Text, "fraction of disputes naming another client"),
+        opt("--out-of-range-rate", Value::Text, "fraction of out-of-range values"),
+    ],
+    args: Args::None,
+};
+
+pub const ADMIN: Command = Command {
+    name: "admin",
+    help: "maintain the stored state",
+    options: &[
+        opt("--client", Value::Text, "client id"),
+        opt("--tx", Value::Text, "transaction id"),
+        opt("--amount", Value::Text, "signed amount"),
+        opt("--reason", Value::Text, "reason for the audit log"),
+        opt("--from", Value::Text, "client merged away"),
+        opt("--into", Value::Text, "client merged into"),
+        opt("--keep-messages", Value::Text, "messages compaction keeps"),
+        STATE,
+        STORE,
+        RETAIN_AGE,
+        RETAIN_COUNT,
+    ],
+    args: Args::Choice(ADMIN_COMMANDS),
+};
+
+pub const COMPLETIONS: Command = Command {
+    name: "completions",
+    help: "print a shell completion script",
+    options: &[],
+    args: Args::Choice(SHELLS),
+};
+
+pub const COMMANDS: &[Command] = &[SERVE, CONSUME, REPL, DIFF, BENCH, STATEMENT, VERIFY_SIGNATURE, GENERATE, ADMIN,
+                                   COMPLETIONS];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for Shell {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Shell> {
+        match s {
+            "bash" => Ok(Shell::Bash),
+            "zsh" => Ok(Shell::Zsh),
+            "fish" => Ok(Shell::Fish),
+            _ => Err(Error::InvalidArgument(format!("Unknown shell, {}. Expected bash, zsh or fish.", s))),
+        }
+    }
+}
+
+// The error for an option the command does not take, naming the closest one it does when there is one
+pub fn unknown_option(command: &Command, name: &str) -> Error {
+    let closest = command.options.iter().chain(GLOBAL)
+        .map(|opt| (edit_distance(name, opt.name), opt.name))
+        .filter(|(distance, _)| *distance <= 2)
+        .min();
+    match closest {
+        Some((_, known)) => Error::InvalidArgument(format!("Unknown option, {}. Did you mean {}?", name, known)),
+        None if command.name == RUN.name => Error::InvalidArgument(format!("Unknown option, {}.", name)),
+        None => Error::InvalidArgument(format!("Unknown option for {}, {}.", command.name, name)),
+    }
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1).min(row[j] + 1).min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+// An input file must be a readable file. - is STDIN, which can only be read once.
+pub fn check_inputs(inputs: &[String]) -> Result<()> {
+    if inputs.iter().filter(|input| *input == "-").count() > 1 {
+        return Err(Error::InvalidArgument(String::from("STDIN, -, can only be read once.")));
+    }
+    for input in inputs.iter().filter(|input| *input != "-") {
+        match fs::metadata(input) {
+            Ok(metadata) if metadata.is_dir() => return Err(Error::InvalidArgument(format!(
+                "{} is a directory, not an input file.", input))),
+            Ok(_) => {}
+            Err(e) => return Err(Error::InvalidArgument(format!("Cannot read input file, {}. {}", input, e))),
+        }
+    }
+    Ok(())
+}
+
+// A directory the run writes in, created if missing
+pub fn check_dir(option: &str, path: &str) -> Result<()> {
+    let dir = Path::new(path);
+    if dir.exists() && !dir.is_dir() {
+        return Err(Error::InvalidArgument(format!("{} {} is a file, not a directory.", option, path)));
+    }
+    Ok(())
+}
+
+// A file the run writes, whose directory must already exist
+pub fn check_output_file(option: &str, path: &str) -> Result<()> {
+    let file = Path::new(path);
+    if file.is_dir() {
+        return Err(Error::InvalidArgument(format!("{} {} is a directory, not a file.", option, path)));
+    }
+    match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() && !dir.is_dir() => Err(Error::InvalidArgument(format!(
+            "{} {} is in {}, which is not a directory.", option, path, dir.display()))),
+        _ => Ok(()),
+    }
+}
+
+pub fn completions(shell: Shell) -> String {
+    match shell {
+        Shell::Bash => bash(),
+        Shell::Zsh => zsh(),
+        Shell::Fish => fish(),
+    }
+}
+
+fn all_options() -> impl Iterator<Item = &'static Opt> {
+    GLOBAL.iter().chain(RUN.options).chain(COMMANDS.iter().flat_map(|command| command.options))
+}
+
+fn names(options: &[Opt]) -> String {
+    options.iter().chain(GLOBAL).map(|opt| opt.name).collect::<Vec<&str>>().join(" ")
+}
+
+// The options taking a value of each kind, as bash case patterns
+fn value_patterns(kind: fn(&Value) -> bool) -> String {
+    let mut names: Vec<&str> = all_options().filter(|opt| kind(&opt.value)).map(|opt| opt.name).collect();
+    names.sort_unstable();
+    names.dedup();
+    names.join("|")
+}
+
+fn bash() -> String {
+    let mut script = String::from("_tx_acct() {\n");
+    script.push_str("    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n");
+    script.push_str("    local command=\"\" i\n");
+    let commands: Vec<&str> = COMMANDS.iter().map(|command| command.name).collect();
+    let _ = writeln!(script, "    for ((i = 1; i < COMP_CWORD; i++)); do\n        case \"${{COMP_WORDS[i]}}\" in\n            \
+                              {}) command=\"${{COMP_WORDS[i]}}\"; break ;;\n        esac\n    done", commands.join("|"));
+    script.push_str("    case \"$prev\" in\n");
+    let _ = writeln!(script, "        {}) COMPREPLY=($(compgen -f -- \"$cur\")); return ;;",
+                     value_patterns(|value| *value == Value::File));
+    let _ = writeln!(script, "        {}) COMPREPLY=($(compgen -d -- \"$cur\")); return ;;",
+                     value_patterns(|value| *value == Value::Dir));
+    for opt in all_options() {
+        if let Value::Choice(choices) = opt.value {
+            let _ = writeln!(script, "        {}) COMPREPLY=($(compgen -W \"{}\" -- \"$cur\")); return ;;", opt.name,
+                             choices.join(" "));
+        }
+    }
+    let _ = writeln!(script, "        {}) return ;;", value_patterns(|value| *value == Value::Text));
+    script.push_str("    esac\n    local options words files=\"\"\n    case \"$command\" in\n");
+    for command in COMMANDS {
+        let (words, files) = match command.args {
+            Args::None => (String::new(), ""),
+            Args::Files => (String::new(), "1"),
+            Args::Choice(choices) => (choices.join(" "), ""),
+        };
+        let _ = writeln!(script, "        {}) options=\"{}\" words=\"{}\" files=\"{}\" ;;", command.name,
+                         names(command.options), words, files);
+    }
+    let _ = writeln!(script, "        *) options=\"{}\" words=\"{}\" files=1 ;;", names(RUN.options), commands.join(" "));
+    script.push_str("    esac\n");
+    script.push_str("    if [[ \"$cur\" == -* ]]; then\n        COMPREPLY=($(compgen -W \"$options\" -- \"$cur\"))\n");
+    script.push_str("    else\n        COMPREPLY=($(compgen -W \"$words\" -- \"$cur\"))\n");
+    script.push_str("        [[ -n \"$files\" ]] && COMPREPLY+=($(compgen -f -- \"$cur\"))\n    fi\n}\n");
+    script.push_str("complete -o filenames -F _tx_acct tx_acct\n");
+    script
+}
+
+fn zsh_spec(opt: &Opt) -> String {
+    let action = match opt.value {
+        Value::Flag => String::new(),
+        Value::File => String::from(":file:_files"),
+        Value::Dir => String::from(":directory:_files -/"),
+        Value::Choice(choices) => format!(":value:({})", choices.join(" ")),
+        Value::Text => String::from(":value: "),
+    };
+    format!("'{}[{}]{}'", opt.name, opt.help, action)
+}
+
+fn zsh_arguments(command: &Command) -> String {
+    let mut specs: Vec<String> = command.options.iter().chain(GLOBAL).map(zsh_spec).collect();
+    match command.args {
+        Args::None => {}
+        Args::Files => specs.push(String::from("'*:file:_files'")),
+        Args::Choice(choices) => specs.push(format!("'1:{} command:({})'", command.name, choices.join(" "))),
+    }
+    format!("_arguments {}", specs.join(" "))
+}
+
+fn zsh() -> String {
+    let mut script = String::from("#compdef tx_acct\n\n_tx_acct() {\n    local -a commands\n    commands=(\n");
+    for command in COMMANDS {
+        let _ = writeln!(script, "        '{}:{}'", command.name, command.help);
+    }
+    script.push_str("    )\n    case $words[2] in\n");
+    for command in COMMANDS {
+        let _ = writeln!(script, "        {})\n            shift words; (( CURRENT-- ))\n            {} ;;", command.name,
+                         zsh_arguments(command));
+    }
+    let _ = writeln!(script, "        *)\n            (( CURRENT == 2 )) && _describe command commands\n            {} ;;",
+                     zsh_arguments(&RUN));
+    script.push_str("    esac\n}\n\n_tx_acct \"$@\"\n");
+    script
+}
+
+fn fish_option(script: &mut String, condition: &str, opt: &Opt) {
+    let value = match opt.value {
+        Value::Flag => String::new(),
+        Value::File => String::from(" -r -F"),
+        Value::Dir => String::from(" -x -a '(__fish_complete_directories)'"),
+        Value::Choice(choices) => format!(" -x -a '{}'", choices.join(" ")),
+        Value::Text => String::from(" -x"),
+    };
+    let _ = writeln!(script, "complete -c tx_acct -n '{}' -l {}{} -d '{}'", condition,
+                     opt.name.trim_start_matches("--"), value, opt.help);
+}
+
+fn fish() -> String {
+    let mut script = String::from("complete -c tx_acct -f\n");
+    let commands: Vec<&str> = COMMANDS.iter().map(|command| command.name).collect();
+    let no_command = format!("not __fish_seen_subcommand_from {}", commands.join(" "));
+    for command in COMMANDS {
+        let _ = writeln!(script, "complete -c tx_acct -n '{}' -a {} -d '{}'", no_command, command.name, command.help);
+    }
+    let _ = writeln!(script, "complete -c tx_acct -n '{}' -F", no_command);
+    for opt in GLOBAL {
+        fish_option(&mut script, "true", opt);
+    }
+    for opt in RUN.options {
+        fish_option(&mut script, &no_command, opt);
+    }
+    for command in COMMANDS {
+        let condition = format!("__fish_seen_subcommand_from {}", command.name);
+        match command.args {
+            Args::None => {}
+            Args::Files => { let _ = writeln!(script, "complete -c tx_acct -n '{}' -F", condition); }
+            Args::Choice(choices) => {
+                let _ = writeln!(script, "complete -c tx_acct -n '{}; and not __fish_seen_subcommand_from {}' -a '{}'",
+                                 condition, choices.join(" "), choices.join(" "));
+            }
+        }
+        for opt in command.options {
+            fish_option(&mut script, &condition, opt);
+        }
+    }
+    script
+}
@@ -0,0 +1,645 @@
+use std::fs;
+use std::time::Duration;
+
+use serde::Deserialize;
+use toml::{Table, Value};
+
+use crate::webhook::WebhookEvent;
+use crate::{Error, Result};
+
+// Settings read from the TOML file given with --config. Every section is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub run: RunConfig,
+    pub log: LogConfig,
+    pub limits: LimitsConfig,
+    pub ingest: IngestConfig,
+    pub idempotency: IdempotencyConfig,
+    pub dedup: DedupConfig,
+    pub signing: Option<SigningConfig>,
+    pub encryption: Option<EncryptionConfig>,
+    pub kafka: Option<KafkaConfig>,
+    pub kafka_input: Option<KafkaInputConfig>,
+    #[serde(rename = "webhook")]
+    pub webhooks: Vec<WebhookConfig>,
+    pub redis: Option<RedisConfig>,
+    #[serde(rename = "schedule")]
+    pub schedules: Vec<ScheduleConfig>,
+    pub admin: AdminConfig,
+    #[serde(rename = "api_key")]
+    pub api_keys: Vec<ApiKeyConfig>,
+    pub tls: Option<TlsConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub serve: ServeConfig,
+    pub tracing: Option<TracingConfig>,
+    pub statsd: Option<StatsdConfig>,
+    #[serde(rename = "plugin")]
+    pub plugins: Vec<PluginConfig>,
+    pub script: Option<ScriptConfig>,
+    pub rules: Option<RulesConfig>,
+    #[serde(rename = "account_type")]
+    pub account_types: Vec<AccountTypeConfig>,
+    #[serde(rename = "fee_schedule")]
+    pub fee_schedules: Vec<FeeScheduleConfig>,
+}
+
+// Defaults for the command line's flags, so a profile can bundle where state goes and how accounts are written. A flag
+// given on the command line wins.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunConfig {
+    // As --store, for every command that takes one
+    pub store: Option<String>,
+    pub output_format: Option<String>,
+    pub output_dir: Option<String>,
+    pub output_partitions: Option<u32>,
+    // As --withdrawal-limits
+    pub withdrawal_limits: Option<String>,
+    // As --approval-threshold, an amount in currency units
+    pub approval_threshold: Option<String>,
+    // As --dispute-expiry
+    pub dispute_expiry: Option<u32>,
+    // As --max-open-disputes
+    pub max_open_disputes: Option<u32>,
+    // As --stale-dispute-age
+    pub stale_dispute_age: Option<u32>,
+    // As --netting
+    pub netting: bool,
+    // As --chargeback-fee, an amount in currency units
+    pub chargeback_fee: Option<String>,
+    // As --chargeback-fee-to
+    pub chargeback_fee_to: Option<String>,
+    // As --recover-deficits
+    pub recover_deficits: bool,
+    // How amounts with more than four decimal places are read, for every command: truncate, half-up, half-even or
+    // reject-inexact
+    pub rounding: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogDestination {
+    Stderr,
+    File,
+    Syslog,
+    Journald,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    pub destination: LogDestination,
+    // Log file for the file destination, rotated to path.1 .. path.<keep> once it reaches max_bytes
+    pub path: Option<String>,
+    pub max_bytes: u64,
+    pub keep: u32,
+    pub syslog_socket: String,
+    pub journald_socket: String,
+}
+
+impl Default for LogConfig {
+    fn default() -> LogConfig {
+        LogConfig {
+            destination: LogDestination::Stderr,
+            path: None,
+            max_bytes: 10 * 1024 * 1024,
+            keep: 5,
+            syslog_socket: String::from("/dev/log"),
+            journald_socket: String::from("/run/systemd/journal/socket"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitAction {
+    // Fail the read with Error::LimitExceeded
+    Abort,
+    // Stop reading at the row that would cross the limit and keep what was applied so far
+    Stop,
+}
+
+// Caps on how much one input file may grow the engine. Unset limits are not enforced.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LimitsConfig {
+    pub max_rows: Option<u32>,
+    pub max_clients: Option<usize>,
+    pub max_transactions: Option<usize>,
+    // Compared against AccountTransactions::estimated_bytes, not the real heap usage
+    pub max_memory_bytes: Option<u64>,
+    pub on_limit: LimitAction,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> LimitsConfig {
+        LimitsConfig {
+            max_rows: None,
+            max_clients: None,
+            max_transactions: None,
+            max_memory_bytes: None,
+            on_limit: LimitAction::Abort,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IngestConfig {
+    // Parse rows on a separate thread, holding at most this many ahead of the engine. Unset parses inline.
+    pub queue_depth: Option<usize>,
+    // Average rows applied per second. Unset or 0 reads as fast as possible.
+    pub rows_per_second: Option<f64>,
+}
+
+// Rows with an idempotency_key column are always checked against the keys already handled. With tx_keys, rows without
+// one are checked under their type and tx id, so the same file submitted twice is applied once.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IdempotencyConfig {
+    pub tx_keys: bool,
+}
+
+// Rows exactly repeating an earlier row are skipped, see Dedup. --dedup turns it on for one run.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DedupConfig {
+    pub enabled: bool,
+    // Also skip repeats of rows handled by earlier runs against the same store
+    pub across_runs: bool,
+}
+
+// Output files are signed with this key, see Signer. Needs the signing feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SigningConfig {
+    pub key_file: String,
+}
+
+// State is encrypted at rest with this key, see encryption::init. Needs the encryption feature.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EncryptionConfig {
+    // File holding the 32-byte key as 64 hex digits
+    pub key_file: Option<String>,
+    // Environment variable holding the key
+    pub key_env: Option<String>,
+    // Command printing the key
+    pub key_command: Vec<String>,
+    // Reads state written before a key was configured, so it is encrypted by its next save. Off, plaintext state
+    // fails to load, as it could have been put there by anyone with write access and no key.
+    pub migrate_plaintext: bool,
+}
+
+// Account changes are published through a Kafka REST Proxy, e.g. rest_url = "http://localhost:8082"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaConfig {
+    pub rest_url: String,
+    pub topic: String,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+// Transactions consumed by the consume command from a topic through the same REST Proxy. Each record value is
+// a JSON object with the input file's columns.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaInputConfig {
+    pub rest_url: String,
+    pub topic: String,
+    #[serde(default = "default_group")]
+    pub group: String,
+    // Wait between polls that return no records
+    #[serde(default = "default_poll_interval_ms")]
+    pub poll_interval_ms: u64,
+}
+
+fn default_group() -> String {
+    String::from("tx_acct")
+}
+
+fn default_poll_interval_ms() -> u64 {
+    1000
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+// One [[webhook]] table. Each event is posted as JSON to url, retried with doubling backoff on failure.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WebhookConfig {
+    pub url: String,
+    #[serde(default = "WebhookEvent::all")]
+    pub events: Vec<WebhookEvent>,
+    #[serde(default = "default_retries")]
+    pub retries: u32,
+    #[serde(default = "default_backoff_ms")]
+    pub backoff_ms: u64,
+}
+
+// Current balances are mirrored into the hash <key_prefix><client> on a Redis server
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RedisConfig {
+    pub addr: String,
+    #[serde(default = "default_key_prefix")]
+    pub key_prefix: String,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub database: u32,
+    #[serde(default = "default_batch_size")]
+    pub batch_size: usize,
+}
+
+// One [[schedule]] table, run daily by serve. Files matching input that no earlier run processed are read into
+// the schedule's store in name order, and the resulting accounts are written to output.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub name: String,
+    // e.g. "/data/incoming/*.csv". * and ? match within the file name only.
+    pub input: String,
+    // Time of day as HH:MM, in UTC
+    pub at: String,
+    pub store: Option<String>,
+    pub output: Option<String>,
+    // Holds <name>.lock while a run is in progress and the run history, <name>.history.jsonl
+    #[serde(default = "default_history_dir")]
+    pub history_dir: String,
+}
+
+// Manual adjustments made with the admin commands or serve's /admin endpoints. Every one is appended to audit_log.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // Bearer token the /admin endpoints require. Unset disables them.
+    pub token: Option<String>,
+    pub audit_log: String,
+}
+
+impl Default for AdminConfig {
+    fn default() -> AdminConfig {
+        AdminConfig { token: None, audit_log: String::from("audit.jsonl") }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    // POST /transactions and the TCP line protocol
+    Submit,
+    // GET /accounts, GET /ws and POST /graphql
+    Query,
+    // The /admin endpoints
+    Admin,
+    // Following this instance from a standby, over the replication listener
+    Replicate,
+}
+
+// One [[api_key]] table. Once any is configured, serve's HTTP endpoints and TCP line protocol need a key.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApiKeyConfig {
+    // Identifies the key in logs and as the actor in the audit log
+    pub name: String,
+    pub key: String,
+    pub permissions: Vec<Permission>,
+}
+
+// Serve HTTPS with the PEM certificate chain in cert and its private key in key. Needs the tls feature.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+// Token bucket applied by serve to each API key, or each peer address without keys, on POST /transactions and the
+// TCP line protocol. A request or line takes one token.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub requests_per_second: f64,
+    // Tokens a bucket holds when full. Defaults to one second's worth.
+    pub burst: Option<u32>,
+}
+
+impl RateLimitConfig {
+    pub fn burst(&self) -> u32 {
+        self.burst.unwrap_or(self.requests_per_second.ceil() as u32).max(1)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ServeAuth {
+    // Every request needs one of the [[api_key]] tables, and serve refuses to start without one
+    ApiKeys,
+    // Anyone who can reach serve may submit and query, as --no-auth
+    None,
+}
+
+// Limits serve puts on every connection it accepts
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServeConfig {
+    pub auth: ServeAuth,
+    // Larger HTTP request bodies are answered with 413 before any of the body is read
+    pub max_body_bytes: usize,
+    // How long a read or write may block before the connection is dropped, for HTTP, the line protocols and standbys
+    pub timeout_secs: u64,
+    // Most connections served at once, across HTTP and the line protocols. HTTP connections past it are answered
+    // with 503 and line connections are closed. 0 is no limit.
+    pub max_connections: usize,
+    // Key a standby presents to its primary, for a key with the replicate permission there
+    pub replication_key: Option<String>,
+}
+
+impl Default for ServeConfig {
+    fn default() -> ServeConfig {
+        ServeConfig {
+            auth: ServeAuth::ApiKeys,
+            max_body_bytes: 16 * 1024 * 1024,
+            timeout_secs: 30,
+            max_connections: 1024,
+            replication_key: None,
+        }
+    }
+}
+
+impl ServeConfig {
+    // None for timeout_secs = 0, which lets a connection block forever
+    pub fn timeout(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.timeout_secs)).filter(|timeout| !timeout.is_zero())
+    }
+}
+
+// Spans are sent as OTLP/HTTP JSON to <endpoint>/v1/traces, e.g. endpoint = "http://localhost:4318"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TracingConfig {
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    // One in this many transaction messages gets its own span
+    #[serde(default = "default_sample_every")]
+    pub transaction_sample_every: u32,
+    #[serde(default = "default_span_batch_size")]
+    pub batch_size: usize,
+}
+
+// Counters and timers sent over UDP, e.g. addr = "127.0.0.1:8125". With dogstatsd, type and reason are sent as
+// tags, otherwise they become part of the metric name.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StatsdConfig {
+    pub addr: String,
+    #[serde(default = "default_statsd_prefix")]
+    pub prefix: String,
+    #[serde(default)]
+    pub dogstatsd: bool,
+    // Sent with every metric when dogstatsd is set, e.g. ["env:prod"]
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "default_statsd_flush_interval_ms")]
+    pub flush_interval_ms: u64,
+}
+
+fn default_statsd_prefix() -> String {
+    String::from("tx_acct.")
+}
+
+fn default_statsd_flush_interval_ms() -> u64 {
+    1000
+}
+
+// A WASM module that checks every message before it is applied
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    // .wasm, or .wat text
+    pub path: String,
+    // Instructions one check may run before it is stopped, so a plugin stuck in a loop fails instead of hanging
+    #[serde(default = "default_plugin_fuel")]
+    pub fuel: u64,
+}
+
+fn default_plugin_fuel() -> u64 {
+    10_000_000
+}
+
+// A policies file evaluated against every message, see README "Policy Rules"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RulesConfig {
+    pub path: String,
+}
+
+// A kind of account and the clients that have it, see README "Account Types"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountTypeConfig {
+    pub name: String,
+    // Client list file, one client id per line
+    pub clients: String,
+    // Disputes leave the available balance alone, the amount carried as provisional credit
+    #[serde(default)]
+    pub provisional_credit: bool,
+    // Paid on available funds at the end of every interest_period_rows rows, a percentage like 0.25
+    #[serde(default)]
+    pub interest_percent: Option<String>,
+    #[serde(default)]
+    pub interest_period_rows: Option<u32>,
+}
+
+// The fees charged on one type of transaction, see README "Fee Schedules"
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeScheduleConfig {
+    // deposit or withdraw
+    #[serde(rename = "type")]
+    pub tx_type: String,
+    // Rows a period lasts, tier usage starting afresh each period. Without it the period is the whole run.
+    #[serde(default)]
+    pub period_rows: Option<u32>,
+    #[serde(default, rename = "tier")]
+    pub tiers: Vec<FeeTierConfig>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FeeTierConfig {
+    // Transactions of the type the client makes in the period before the tier applies
+    pub after: u32,
+    // Amounts in currency units, and percent a number like 1.5
+    pub flat: Option<String>,
+    pub percent: Option<String>,
+    // The percentage is of the amount above this
+    pub above: Option<String>,
+}
+
+// A Rhai script defining any of on_transaction, on_reject and on_account_update
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScriptConfig {
+    pub path: String,
+    // Operations one hook call may run before it is stopped
+    #[serde(default = "default_script_max_operations")]
+    pub max_operations: u64,
+}
+
+fn default_script_max_operations() -> u64 {
+    1_000_000
+}
+
+fn default_service_name() -> String {
+    String::from("tx_acct")
+}
+
+fn default_sample_every() -> u32 {
+    100
+}
+
+fn default_span_batch_size() -> usize {
+    512
+}
+
+fn default_history_dir() -> String {
+    String::from("schedules")
+}
+
+fn default_key_prefix() -> String {
+    String::from("tx_acct:account:")
+}
+
+fn default_retries() -> u32 {
+    3
+}
+
+fn default_backoff_ms() -> u64 {
+    500
+}
+
+// Settings can also be given in the environment, named by section and key, e.g. TX_ACCT_LIMITS__MAX_ROWS=1000000
+// for max_rows in [limits]. A double underscore separates the levels, and a number picks a table in a list, as in
+// TX_ACCT_WEBHOOK__0__URL. They override the file.
+pub const ENV_PREFIX: &str = "TX_ACCT_";
+// Not settings, but the --config file, --profile and the --store spec used when the flag is not given
+pub const ENV_CONFIG: &str = "TX_ACCT_CONFIG";
+pub const ENV_PROFILE: &str = "TX_ACCT_PROFILE";
+pub const ENV_STORE: &str = "TX_ACCT_STORE";
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config> {
+        Config::load_with_env(Some(path), None, Vec::new())
+    }
+
+    // The file, if any, with the named profile applied over it and then the TX_ACCT_ variables among vars
+    pub fn load_with_env<I>(path: Option<&str>, profile: Option<&str>, vars: I) -> Result<Config>
+        where I: IntoIterator<Item = (String, String)> {
+        let mut table: Table = match path {
+            Some(path) => {
+                let text = fs::read_to_string(path)
+                    .map_err(|e| Error::ConfigError(format!("{}: {}", path, e)))?;
+                toml::from_str(&text).map_err(|e| Error::ConfigError(format!("{}: {}", path, e)))?
+            }
+            None => Table::new(),
+        };
+        let profiles = match table.remove("profile") {
+            Some(Value::Table(profiles)) => profiles,
+            Some(_) => return Err(Error::ConfigError(String::from("profile must be a table of named profiles."))),
+            None => Table::new(),
+        };
+        if let Some(name) = profile {
+            match profiles.get(name) {
+                Some(Value::Table(overrides)) => merge(&mut table, overrides.clone()),
+                Some(_) => return Err(Error::ConfigError(format!("Profile {} must be a table.", name))),
+                None if profiles.is_empty() => return Err(Error::ConfigError(match path {
+                    Some(path) => format!("No profile named {}. {} defines no profiles.", name, path),
+                    None => format!("No profile named {}, and no config file to define it.", name),
+                })),
+                None => return Err(Error::ConfigError(format!("No profile named {}. Expected one of {}.", name,
+                    profiles.keys().map(String::as_str).collect::<Vec<&str>>().join(", ")))),
+            }
+        }
+        let mut vars: Vec<(String, String)> = vars.into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX) && ![ENV_CONFIG, ENV_PROFILE, ENV_STORE].contains(&name.as_str()))
+            .collect();
+        // In name order, indexes compared as numbers, so list entries are added in ascending order
+        vars.sort_by_cached_key(|(name, _)| name.split("__")
+            .map(|segment| segment.parse::<usize>().map_or_else(|_| segment.to_string(), |n| format!("{:020}", n)))
+            .collect::<Vec<String>>());
+        let mut root = Value::Table(table);
+        for (name, value) in &vars {
+            *setting(&mut root, name)? = env_value(value);
+        }
+        let mut source = match (path, vars.is_empty()) {
+            (Some(path), true) => path.to_string(),
+            (Some(path), false) => format!("{} with {} variables", path, ENV_PREFIX),
+            (None, _) => format!("{} variables", ENV_PREFIX),
+        };
+        if let Some(name) = profile {
+            source = format!("{}, profile {}", source, name);
+        }
+        root.try_into().map_err(|e| Error::ConfigError(format!("{}: {}", source, e)))
+    }
+}
+
+// Tables are merged key by key, anything else, lists of tables included, is replaced
+fn merge(base: &mut Table, overrides: Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(Value::Table(base)), Value::Table(overrides)) => merge(base, overrides),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+// Where the variable's value goes, creating the tables and list entries leading to it
+fn setting<'a>(root: &'a mut Value, name: &str) -> Result<&'a mut Value> {
+    let path: Vec<String> = name[ENV_PREFIX.len()..].split("__").map(str::to_lowercase).collect();
+    if path.len() < 2 || path.iter().any(String::is_empty) {
+        return Err(Error::ConfigError(format!(
+            "{} is not a setting. Settings are named {}<SECTION>__<KEY>, e.g. {}LIMITS__MAX_ROWS.",
+            name, ENV_PREFIX, ENV_PREFIX)));
+    }
+    let mut current = root;
+    for segment in &path {
+        current = match segment.parse::<usize>() {
+            Ok(index) => {
+                // A table created on the way here becomes the list the index points into
+                if matches!(current, Value::Table(table) if table.is_empty()) {
+                    *current = Value::Array(Vec::new());
+                }
+                let list = current.as_array_mut().ok_or_else(|| not_a(name, "list"))?;
+                if index == list.len() {
+                    list.push(Value::Table(Table::new()));
+                }
+                list.get_mut(index).ok_or_else(|| Error::ConfigError(format!(
+                    "{} skips an index. List entries are numbered from 0 without gaps.", name)))?
+            }
+            Err(_) => current.as_table_mut().ok_or_else(|| not_a(name, "table"))?
+                .entry(segment.clone())
+                .or_insert_with(|| Value::Table(Table::new())),
+        };
+    }
+    Ok(current)
+}
+
+fn not_a(name: &str, kind: &str) -> Error {
+    Error::ConfigError(format!("{} goes through a setting that is not a {}.", name, kind))
+}
+
+// Read as a TOML value, so numbers, booleans and lists keep their types, falling back to a plain string. A string
+// that looks like a number is quoted, e.g. TX_ACCT_REDIS__PASSWORD='"123456"'.
+fn env_value(value: &str) -> Value {
+    toml::from_str::<Table>(&format!("value = {}", value)).ok()
+        .and_then(|mut table| table.remove("value"))
+        .unwrap_or_else(|| Value::String(value.to_string()))
+}
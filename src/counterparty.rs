@@ -0,0 +1,68 @@
+use std::collections::BTreeMap;
+
+use crate::{AccountTransactions, Amount, TransactionMessage, TransactionType};
+
+// What the transactions naming one counterparty came to, so merchants drawing disputes and chargebacks stand out
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CounterpartyStats {
+    // Deposits, withdrawals, escrows and transfers applied, and their amounts in subunits
+    pub transactions: u64,
+    pub volume: Amount,
+    // Disputes and chargebacks applied to its transactions, those of earlier runs included
+    pub disputes: u64,
+    pub chargebacks: u64,
+}
+
+impl CounterpartyStats {
+    // Per transaction, so a counterparty with transactions from earlier runs disputed in this one can exceed 1
+    pub fn dispute_rate(&self) -> f64 {
+        rate(self.disputes, self.transactions)
+    }
+
+    pub fn chargeback_rate(&self) -> f64 {
+        rate(self.chargebacks, self.transactions)
+    }
+}
+
+fn rate(count: u64, transactions: u64) -> f64 {
+    match transactions {
+        0 => 0.0,
+        transactions => count as f64 / transactions as f64,
+    }
+}
+
+impl AccountTransactions {
+    // By counterparty name, for the messages applied since the engine started
+    pub fn counterparties(&self) -> &BTreeMap<String, CounterpartyStats> {
+        &self.counterparties
+    }
+
+    // Called for each message that applied. Disputes and chargebacks count against the counterparty of the
+    // transaction they name.
+    pub(crate) fn count_counterparty(&mut self, transaction_msg: &TransactionMessage) {
+        let counterparty = match transaction_msg.tx_type {
+            TransactionType::DEPOSIT | TransactionType::WITHDRAWAL | TransactionType::ESCROW
+            | TransactionType::TRANSFER => transaction_msg.counterparty.as_deref(),
+            TransactionType::DISPUTE | TransactionType::CHARGEBACK => {
+                self.txs_txid.get(&transaction_msg.tx).and_then(|tx| tx.counterparty.as_deref())
+            }
+            _ => None,
+        };
+        let counterparty = match counterparty {
+            Some(counterparty) => counterparty,
+            None => return,
+        };
+        if !self.counterparties.contains_key(counterparty) {
+            self.counterparties.insert(counterparty.to_string(), CounterpartyStats::default());
+        }
+        let stats = self.counterparties.get_mut(counterparty).expect("Inserted above.");
+        match transaction_msg.tx_type {
+            TransactionType::DISPUTE => stats.disputes += 1,
+            TransactionType::CHARGEBACK => stats.chargebacks += 1,
+            _ => {
+                stats.transactions += 1;
+                stats.volume += transaction_msg.amount;
+            }
+        }
+    }
+}
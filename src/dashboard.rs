@@ -0,0 +1,312 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, IsTerminal, Stderr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use ratatui::backend::CrosstermBackend;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::widgets::{Block, List, Paragraph, Row, Sparkline, Table};
+use ratatui::{Frame, Terminal};
+
+use crate::cancel::CancelToken;
+use crate::change::{AccountChange, AccountState};
+use crate::observer::EngineObserver;
+use crate::{to_subunit, Amount, Error, Rejection, Result, TransactionMessage};
+
+const REDRAW: Duration = Duration::from_millis(200);
+// Accounts in each top table, and events kept in each recent list
+const TOP: usize = 10;
+const RECENT: usize = 10;
+// Seconds of throughput in the sparkline
+const HISTORY: usize = 120;
+
+type Screen = Terminal<CrosstermBackend<Stderr>>;
+
+// What the engine has done so far, written by the observer and read by the drawing thread
+#[derive(Default)]
+struct Stats {
+    messages: u64,
+    rejected: u64,
+    last_time: Option<u32>,
+    accounts: HashMap<u16, AccountState>,
+    rejections: VecDeque<String>,
+    locks: VecDeque<String>,
+}
+
+impl Stats {
+    // A message can both change an account and be rejected, so messages are counted by their time
+    fn count(&mut self, tx_time: u32) {
+        if self.last_time != Some(tx_time) {
+            self.messages += 1;
+            self.last_time = Some(tx_time);
+        }
+    }
+}
+
+// A full-screen view of a run on STDERR, leaving STDOUT for the accounts: throughput, the accounts with the most
+// funds and the most held, and the latest rejections and locks. It is fed by the observer start returns, which goes
+// to the engine. q, Esc or Ctrl-C cancels the run.
+pub struct Dashboard {
+    done: Arc<AtomicBool>,
+    drawing: JoinHandle<Result<()>>,
+}
+
+impl Dashboard {
+    pub fn start(title: &str, cancel: CancelToken) -> Result<(Dashboard, DashboardObserver)> {
+        if !io::stderr().is_terminal() {
+            return Err(Error::InvalidArgument(String::from("The dashboard draws on STDERR, which is not a terminal.")));
+        }
+        enable_raw_mode()?;
+        let screen = execute!(io::stderr(), EnterAlternateScreen)
+            .and_then(|_| Terminal::new(CrosstermBackend::new(io::stderr())));
+        let screen = match screen {
+            Ok(screen) => screen,
+            Err(e) => {
+                restore();
+                return Err(e.into());
+            }
+        };
+        let stats = Arc::new(Mutex::new(Stats::default()));
+        let done = Arc::new(AtomicBool::new(false));
+        let title = title.to_string();
+        let drawing = {
+            let (stats, done) = (Arc::clone(&stats), Arc::clone(&done));
+            thread::spawn(move || draw_until_done(screen, &title, &stats, &done, &cancel))
+        };
+        let observer = DashboardObserver { stats };
+        Ok((Dashboard { done, drawing }, observer))
+    }
+
+    // Called once the run is over. The final numbers stay up until a key is pressed, unless the run was cancelled
+    // from the dashboard, then the terminal is given back.
+    pub fn finish(self) -> Result<()> {
+        self.done.store(true, Ordering::SeqCst);
+        let result = self.drawing.join()
+            .unwrap_or_else(|_| Err(Error::InvalidArgument(String::from("The dashboard stopped drawing."))));
+        restore();
+        result
+    }
+}
+
+fn restore() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stderr(), LeaveAlternateScreen);
+}
+
+fn draw_until_done(mut screen: Screen, title: &str, stats: &Mutex<Stats>, done: &AtomicBool,
+                   cancel: &CancelToken) -> Result<()> {
+    let start = Instant::now();
+    let mut throughput = Throughput::new(start);
+    loop {
+        let finished = done.load(Ordering::SeqCst);
+        let view = View::from(&lock(stats));
+        throughput.sample(view.messages);
+        let status = match (finished, cancel.is_cancelled()) {
+            (true, true) => return Ok(()),
+            (true, false) => "Finished. Press any key to exit.",
+            (false, true) => "Stopping...",
+            (false, false) => "q to stop",
+        };
+        screen.draw(|frame| view.draw(frame, title, status, start.elapsed(), &throughput))?;
+        if finished {
+            wait_for_key()?;
+            return Ok(());
+        }
+        if event::poll(REDRAW)? {
+            if let Event::Key(key) = event::read()? {
+                let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+                if key.kind == KeyEventKind::Press && (ctrl_c || matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)) {
+                    cancel.cancel();
+                }
+            }
+        }
+    }
+}
+
+fn wait_for_key() -> Result<()> {
+    loop {
+        if let Event::Key(key) = event::read()? {
+            if key.kind == KeyEventKind::Press {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn lock(stats: &Mutex<Stats>) -> MutexGuard<'_, Stats> {
+    stats.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+// Messages handled in each of the last HISTORY whole seconds
+struct Throughput {
+    start: Instant,
+    seconds: VecDeque<u64>,
+    second_start: Instant,
+    messages_at_second: u64,
+    messages: u64,
+}
+
+impl Throughput {
+    fn new(start: Instant) -> Throughput {
+        Throughput { start, seconds: VecDeque::new(), second_start: start, messages_at_second: 0, messages: 0 }
+    }
+
+    fn sample(&mut self, messages: u64) {
+        self.messages = messages;
+        if self.second_start.elapsed() >= Duration::from_secs(1) {
+            if self.seconds.len() == HISTORY {
+                self.seconds.pop_front();
+            }
+            self.seconds.push_back(messages - self.messages_at_second);
+            self.second_start = Instant::now();
+            self.messages_at_second = messages;
+        }
+    }
+
+    fn last_second(&self) -> u64 {
+        self.seconds.back().copied().unwrap_or(0)
+    }
+
+    fn average(&self) -> f64 {
+        self.messages as f64 / self.start.elapsed().as_secs_f64().max(0.001)
+    }
+}
+
+// A copy of what is drawn, taken so the engine is not kept waiting on the lock while the screen is drawn
+struct View {
+    messages: u64,
+    rejected: u64,
+    accounts: usize,
+    locked: usize,
+    by_total: Vec<(u16, AccountState)>,
+    by_held: Vec<(u16, AccountState)>,
+    rejections: Vec<String>,
+    locks: Vec<String>,
+}
+
+impl View {
+    fn from(stats: &Stats) -> View {
+        // The states hold exact decimals, read back into subunits to rank them
+        let top = |key: fn(&AccountState) -> &str| {
+            let subunits = |state: &AccountState| -> Amount { to_subunit(key(state)).unwrap_or(0) };
+            let mut accounts: Vec<(u16, AccountState)> = stats.accounts.iter()
+                .filter(|(_, state)| subunits(state) > 0)
+                .map(|(client, state)| (*client, state.clone()))
+                .collect();
+            accounts.sort_by(|a, b| subunits(&b.1).cmp(&subunits(&a.1)).then(a.0.cmp(&b.0)));
+            accounts.truncate(TOP);
+            accounts
+        };
+        View {
+            messages: stats.messages,
+            rejected: stats.rejected,
+            accounts: stats.accounts.len(),
+            locked: stats.accounts.values().filter(|state| state.locked).count(),
+            by_total: top(|state| &state.total),
+            by_held: top(|state| &state.held),
+            rejections: stats.rejections.iter().rev().cloned().collect(),
+            locks: stats.locks.iter().rev().cloned().collect(),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame, title: &str, status: &str, elapsed: Duration, throughput: &Throughput) {
+        let [summary, sparkline, tops, events] = Layout::vertical([
+            Constraint::Length(4),
+            Constraint::Length(6),
+            Constraint::Length(TOP as u16 + 3),
+            Constraint::Min(RECENT as u16 / 2),
+        ]).areas(frame.area());
+
+        let summary_text = format!(
+            "{} messages: {} applied, {} rejected. {} accounts, {} locked.\n{} rows/s in the last second, {:.0} rows/s \
+             on average, {:.1?} elapsed. {}",
+            self.messages, self.messages - self.rejected, self.rejected, self.accounts, self.locked,
+            throughput.last_second(), throughput.average(), elapsed, status);
+        frame.render_widget(Paragraph::new(summary_text).block(Block::bordered().title(format!(" tx_acct: {} ", title))),
+                            summary);
+
+        let seconds: Vec<u64> = throughput.seconds.iter().copied().collect();
+        frame.render_widget(Sparkline::default()
+                                .block(Block::bordered().title(" Rows per second "))
+                                .data(&seconds), sparkline);
+
+        let [by_total, by_held] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(tops);
+        frame.render_widget(account_table(" Top accounts by total ", &self.by_total), by_total);
+        frame.render_widget(account_table(" Top accounts by held ", &self.by_held), by_held);
+
+        let [rejections, locks] = Layout::horizontal([Constraint::Percentage(50); 2]).areas(events);
+        event_list(frame, " Recent rejections ", &self.rejections, rejections);
+        event_list(frame, " Lock events ", &self.locks, locks);
+    }
+}
+
+fn account_table<'a>(title: &'a str, accounts: &[(u16, AccountState)]) -> Table<'a> {
+    let rows = accounts.iter().map(|(client, state)| Row::new(vec![
+        client.to_string(),
+        state.available.clone(),
+        state.held.clone(),
+        state.total.clone(),
+        if state.locked { String::from("locked") } else { String::new() },
+    ]));
+    Table::new(rows, [Constraint::Length(6), Constraint::Fill(1), Constraint::Fill(1), Constraint::Fill(1),
+                      Constraint::Length(6)])
+        .header(Row::new(vec!["client", "available", "held", "total", ""]))
+        .block(Block::bordered().title(title))
+}
+
+fn event_list(frame: &mut Frame, title: &str, events: &[String], area: Rect) {
+    frame.render_widget(List::new(events.iter().map(String::as_str)).block(Block::bordered().title(title)), area);
+}
+
+// Goes to the engine, reporting to the Dashboard it was started with
+pub struct DashboardObserver {
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl DashboardObserver {
+    fn record(&self, change: &AccountChange, locked: bool) {
+        let mut stats = lock(&self.stats);
+        stats.count(change.tx_time);
+        stats.accounts.insert(change.client, change.new.clone());
+        if locked {
+            push_recent(&mut stats.locks, format!("Client {} locked by {} of tx {}, {} total.", change.client,
+                                                  change.tx_type, change.tx, change.new.total));
+        }
+    }
+}
+
+fn push_recent(events: &mut VecDeque<String>, event: String) {
+    if events.len() == RECENT {
+        events.pop_front();
+    }
+    events.push_back(event);
+}
+
+impl EngineObserver for DashboardObserver {
+    fn name(&self) -> &str {
+        "dashboard"
+    }
+
+    fn account_changed(&mut self, change: &AccountChange) -> Result<()> {
+        self.record(change, false);
+        Ok(())
+    }
+
+    fn account_locked(&mut self, change: &AccountChange) -> Result<()> {
+        self.record(change, true);
+        Ok(())
+    }
+
+    fn tx_rejected(&mut self, msg: &TransactionMessage, reason: Rejection, message: &str) -> Result<()> {
+        let mut stats = lock(&self.stats);
+        stats.count(msg.tx_time);
+        stats.rejected += 1;
+        push_recent(&mut stats.rejections, format!("{}: {}", reason.code(), message));
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use std::fs::File;
+
+use serde::Serialize;
+
+use crate::logging::utc_timestamp;
+use crate::{format_amount, Rejection, Result, TransactionMessage, TransactionType};
+
+// A message the engine refused, in the input's own columns followed by why and when it failed. The reader ignores
+// the extra columns, so a dead-letter file can be fed back in once the cause is dealt with.
+#[derive(Debug, Serialize)]
+struct DeadLetterRecord<'a> {
+    #[serde(rename = "type")]
+    tx_type: String,
+    client: u16,
+    tx: u32,
+    amount: Option<String>,
+    code: &'a str,
+    message: &'a str,
+    row: u32,
+    failed_at: String,
+}
+
+pub struct DeadLetter {
+    wtr: csv::Writer<File>,
+    messages: u32,
+}
+
+impl DeadLetter {
+    pub fn create(path: &str) -> Result<DeadLetter> {
+        Ok(DeadLetter { wtr: csv::Writer::from_path(path)?, messages: 0 })
+    }
+
+    pub fn record(&mut self, msg: &TransactionMessage, reason: Rejection, message: &str, row: u32) -> Result<()> {
+        let carries_amount = matches!(msg.tx_type, TransactionType::DEPOSIT | TransactionType::WITHDRAWAL);
+        self.wtr.serialize(DeadLetterRecord {
+            tx_type: msg.tx_type.to_string(),
+            client: msg.client,
+            tx: msg.tx,
+            amount: if carries_amount { Some(format_amount(msg.amount)) } else { None },
+            code: reason.code(),
+            message,
+            row,
+            failed_at: utc_timestamp(),
+        })?;
+        self.messages += 1;
+        Ok(())
+    }
+
+    pub fn messages(&self) -> u32 {
+        self.messages
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.wtr.flush()?;
+        Ok(())
+    }
+}
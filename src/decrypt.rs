@@ -0,0 +1,189 @@
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process::{self, Child, ChildStdout, Command, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+
+use crate::{Error, Result};
+
+// Numbers the temporary GnuPG homes of one process
+static HOMES: AtomicUsize = AtomicUsize::new(0);
+
+// How an encrypted input file was encrypted, told apart by how it starts
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Age,
+    Pgp,
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Format::Age => "age",
+            Format::Pgp => "PGP",
+        })
+    }
+}
+
+impl Format {
+    fn tool(self) -> &'static str {
+        match self {
+            Format::Age => "age",
+            Format::Pgp => "gpg",
+        }
+    }
+}
+
+// None for a file that is not encrypted. Binary OpenPGP is recognised by its first packet being an encrypted session
+// key, so a CSV starting with a byte order mark is not mistaken for it.
+pub fn detect(path: &str) -> Result<Option<Format>> {
+    let mut start = Vec::with_capacity(64);
+    File::open(path)?.take(64).read_to_end(&mut start)?;
+    if start.starts_with(b"age-encryption.org/v1\n") || start.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----") {
+        return Ok(Some(Format::Age));
+    }
+    let pgp_binary = matches!(start.first(), Some(0x84..=0x87 | 0x8c..=0x8f | 0xc1 | 0xc3));
+    if pgp_binary || start.starts_with(b"-----BEGIN PGP MESSAGE-----") {
+        return Ok(Some(Format::Pgp));
+    }
+    Ok(None)
+}
+
+// An encrypted input file's plaintext, never written out decrypted. age authenticates each chunk before printing it,
+// so its output is streamed and a failed decryption surfaces as a read error once age exits. gpg only checks the whole
+// message at its end, so PGP input is decrypted into memory and refused before any row is read unless gpg succeeded.
+pub struct Decrypted {
+    format: Format,
+    child: Child,
+    plaintext: Plaintext,
+    // The tool's messages, read as it runs so a full pipe cannot stall it
+    stderr: Option<JoinHandle<String>>,
+    // GnuPG home holding only the imported key, removed when the input is dropped
+    home: Option<PathBuf>,
+}
+
+enum Plaintext {
+    Streamed(ChildStdout),
+    Verified(Cursor<Vec<u8>>),
+}
+
+// key_file is an age identity file, or an OpenPGP secret key without a passphrase
+pub fn open(path: &str, format: Format, key_file: &str) -> Result<Decrypted> {
+    let (mut command, home) = match format {
+        Format::Age => {
+            let mut command = Command::new("age");
+            command.args(["--decrypt", "--identity", key_file, "--", path]);
+            (command, None)
+        }
+        Format::Pgp => {
+            let home = gnupg_home(key_file)?;
+            let mut command = gpg(&home);
+            command.args(["--decrypt", "--", path]);
+            (command, Some(home))
+        }
+    };
+    let spawned = command.stdin(Stdio::null()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn();
+    let mut child = match spawned {
+        Ok(child) => child,
+        Err(e) => {
+            if let Some(home) = &home {
+                remove_home(home);
+            }
+            return Err(tool_error(format, e));
+        }
+    };
+    let stdout = child.stdout.take().expect("stdout was piped.");
+    let mut stderr = child.stderr.take().expect("stderr was piped.");
+    let stderr = thread::spawn(move || {
+        let mut text = String::new();
+        let _ = stderr.read_to_string(&mut text);
+        text
+    });
+    let mut decrypted = Decrypted { format, child, plaintext: Plaintext::Streamed(stdout), stderr: Some(stderr), home };
+    if format == Format::Pgp {
+        let mut plaintext = Vec::new();
+        if let Plaintext::Streamed(stdout) = &mut decrypted.plaintext {
+            stdout.read_to_end(&mut plaintext)?;
+        }
+        decrypted.finish()?;
+        decrypted.plaintext = Plaintext::Verified(Cursor::new(plaintext));
+    }
+    Ok(decrypted)
+}
+
+// gpg needs a keyring, so the key is imported into a fresh home rather than the user's own
+fn gnupg_home(key_file: &str) -> Result<PathBuf> {
+    let number = HOMES.fetch_add(1, Ordering::Relaxed);
+    let home = env::temp_dir().join(format!("tx_acct-gnupg-{}-{}", process::id(), number));
+    let mut builder = fs::DirBuilder::new();
+    #[cfg(unix)]
+    std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+    builder.create(&home)?;
+    let imported = gpg(&home).args(["--import", "--", key_file]).stdin(Stdio::null()).output();
+    let failure = match imported {
+        Ok(output) if output.status.success() => return Ok(home),
+        Ok(output) => Error::InvalidArgument(format!("gpg could not import the key {}, {}. {}", key_file, output.status,
+                                                     String::from_utf8_lossy(&output.stderr).trim())),
+        Err(e) => tool_error(Format::Pgp, e),
+    };
+    remove_home(&home);
+    Err(failure)
+}
+
+fn gpg(home: &Path) -> Command {
+    let mut command = Command::new("gpg");
+    command.arg("--batch").arg("--quiet").arg("--homedir").arg(home);
+    command
+}
+
+fn remove_home(home: &Path) {
+    // gpg leaves an agent running for the home
+    let _ = Command::new("gpgconf").arg("--homedir").arg(home).args(["--kill", "gpg-agent"]).output();
+    let _ = fs::remove_dir_all(home);
+}
+
+fn tool_error(format: Format, e: io::Error) -> Error {
+    Error::InvalidArgument(format!("Decrypting {} input needs the {} command. {}", format, format.tool(), e))
+}
+
+impl Decrypted {
+    fn finish(&mut self) -> io::Result<()> {
+        let status = self.child.wait()?;
+        let stderr = self.stderr.take().map(|reader| reader.join().unwrap_or_default()).unwrap_or_default();
+        if status.success() {
+            return Ok(());
+        }
+        Err(io::Error::other(format!("{} could not decrypt the input, {}. {}", self.format.tool(), status,
+                                     stderr.trim())))
+    }
+}
+
+impl Read for Decrypted {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let stdout = match &mut self.plaintext {
+            Plaintext::Streamed(stdout) => stdout,
+            Plaintext::Verified(plaintext) => return plaintext.read(buf),
+        };
+        let read = stdout.read(buf)?;
+        if read == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+        Ok(read)
+    }
+}
+
+// A read cut short, by an error or interruption, leaves the tool to be stopped
+impl Drop for Decrypted {
+    fn drop(&mut self) {
+        if let Ok(None) = self.child.try_wait() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+        if let Some(home) = &self.home {
+            remove_home(home);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::HashSet;
+
+use crate::config::DedupConfig;
+use crate::idempotency::IdempotencyKeys;
+use crate::TransactionMessage;
+
+// Skips rows that exactly repeat an earlier one, compared by a hash of the parsed row, so 1.5 and 1.5000 are the
+// same amount. Within one run the hashes are kept here. With across_runs they are kept as idempotency keys instead,
+// saved with the state, so a row delivered again in a later run is caught too.
+pub(crate) struct Dedup {
+    across_runs: bool,
+    seen: HashSet<u64>,
+}
+
+impl Dedup {
+    pub(crate) fn new(config: &DedupConfig) -> Option<Dedup> {
+        match config.enabled {
+            true => Some(Dedup { across_runs: config.across_runs, seen: HashSet::new() }),
+            false => None,
+        }
+    }
+
+    // Whether the message repeats a row already seen. A row seen for the first time is remembered.
+    pub(crate) fn repeated(&mut self, msg: &TransactionMessage, keys: &mut IdempotencyKeys) -> bool {
+        let hash = row_hash(msg);
+        if !self.across_runs {
+            return !self.seen.insert(hash);
+        }
+        let key = format!("row:{:016x}", hash);
+        if keys.contains(&key) {
+            return true;
+        }
+        keys.insert(key, msg.tx_time);
+        false
+    }
+}
+
+// 64-bit FNV-1a of the row's fields, stable across releases and platforms since hashes saved by one run are
+// compared by the next
+fn row_hash(msg: &TransactionMessage) -> u64 {
+    let row = format!("{},{},{},{}", msg.tx_type, msg.client, msg.tx, msg.amount);
+    row.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3))
+}
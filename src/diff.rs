@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+use serde::Deserialize;
+
+use crate::{Error, Result};
+
+// One row of an accounts output file, with amounts kept as written
+#[derive(Debug, Deserialize)]
+struct OutputRecord {
+    client: u16,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DiffSummary {
+    pub added: u32,
+    pub removed: u32,
+    pub changed: u32,
+}
+
+impl DiffSummary {
+    pub fn is_empty(&self) -> bool {
+        self.added == 0 && self.removed == 0 && self.changed == 0
+    }
+
+    // What the diff command exits with, 0 when the outputs agree and 1 when they differ, like diff(1)
+    pub fn exit_code(&self) -> i32 {
+        if self.is_empty() { 0 } else { 1 }
+    }
+}
+
+fn read_output(filename: &str) -> Result<BTreeMap<u16, OutputRecord>> {
+    let mut rdr = csv::Reader::from_path(filename)?;
+    let mut records = BTreeMap::new();
+    for result in rdr.deserialize() {
+        let record: OutputRecord = result?;
+        records.insert(record.client, record);
+    }
+    Ok(records)
+}
+
+// Amounts are compared numerically, so "1" and "1.0000" are the same balance
+fn same_amount(a: &str, b: &str) -> Result<bool> {
+    let parse = |amount: &str| amount.trim().parse::<f64>()
+        .map_err(|_| Error::ParseError(format!("'{}' is not a valid amount", amount)));
+    let a = parse(a)?;
+    let b = parse(b)?;
+    Ok((a * 1.0e+4_f64).round() == (b * 1.0e+4_f64).round())
+}
+
+// Writes one CSV row per added client, removed client or changed field, ordered by client
+pub fn diff_outputs(a_file: &str, b_file: &str) -> Result<DiffSummary> {
+    diff_outputs_to(a_file, b_file, io::stdout())
+}
+
+fn diff_outputs_to<W: Write>(a_file: &str, b_file: &str, out: W) -> Result<DiffSummary> {
+    let a = read_output(a_file)?;
+    let b = read_output(b_file)?;
+    let mut summary = DiffSummary::default();
+
+    let mut wtr = csv::Writer::from_writer(out);
+    wtr.write_record(["change", "client", "field", "a", "b"])?;
+    for (client, a_rec) in a.iter() {
+        let client_id = client.to_string();
+        match b.get(client) {
+            None => {
+                wtr.write_record(["removed", &client_id, "", "", ""])?;
+                summary.removed += 1;
+            }
+            Some(b_rec) => {
+                let mut changed = false;
+                for (field, a_val, b_val) in [("available", &a_rec.available, &b_rec.available),
+                                              ("held", &a_rec.held, &b_rec.held),
+                                              ("total", &a_rec.total, &b_rec.total)] {
+                    if !same_amount(a_val, b_val)? {
+                        wtr.write_record(["changed", &client_id, field, a_val, b_val])?;
+                        changed = true;
+                    }
+                }
+                if a_rec.locked != b_rec.locked {
+                    wtr.write_record(["changed", &client_id, "locked",
+                                      &a_rec.locked.to_string(), &b_rec.locked.to_string()])?;
+                    changed = true;
+                }
+                if changed {
+                    summary.changed += 1;
+                }
+            }
+        }
+    }
+    for client in b.keys().filter(|client| !a.contains_key(client)) {
+        wtr.write_record(["added", &client.to_string(), "", "", ""])?;
+        summary.added += 1;
+    }
+    wtr.flush()?;
+
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    const HEADER: &str = "client,available,held,total,locked\n";
+
+    // Diffs the two files' rows and returns the summary with the CSV written
+    fn diff(name: &str, a_rows: &str, b_rows: &str) -> (DiffSummary, String) {
+        let (a, b) = (temp_path(&format!("{}-a.csv", name)), temp_path(&format!("{}-b.csv", name)));
+        std::fs::write(&a, format!("{}{}", HEADER, a_rows)).unwrap();
+        std::fs::write(&b, format!("{}{}", HEADER, b_rows)).unwrap();
+        let mut out = Vec::new();
+        let summary = diff_outputs_to(a.to_str().unwrap(), b.to_str().unwrap(), &mut out).unwrap();
+        let _ = std::fs::remove_file(a);
+        let _ = std::fs::remove_file(b);
+        (summary, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn reports_added_removed_and_changed_clients() {
+        let (summary, out) = diff("diff-changes", "1,1.0,0,1.0,false\n2,2.0,0,2.0,false\n3,3.0,0,3.0,false\n",
+                                  "1,1.0,0,1.0,false\n2,1.5,0.5,2.0,true\n4,4.0,0,4.0,false\n");
+        assert_eq!((summary.added, summary.removed, summary.changed), (1, 1, 1));
+        assert_eq!(out, "change,client,field,a,b\n\
+                         changed,2,available,2.0,1.5\n\
+                         changed,2,held,0,0.5\n\
+                         changed,2,locked,false,true\n\
+                         removed,3,,,\n\
+                         added,4,,,\n");
+        assert_eq!(summary.exit_code(), 1);
+    }
+
+    #[test]
+    fn identical_outputs_differ_in_nothing() {
+        let (summary, out) = diff("diff-same", "1,1.5,0,1.5,false\n2,0,2,2,true\n",
+                                  "1,1.5000,0.0000,1.5000,false\n2,0.0,2.0,2.0,true\n");
+        assert!(summary.is_empty());
+        assert_eq!(out, "change,client,field,a,b\n");
+        assert_eq!(summary.exit_code(), 0);
+    }
+}
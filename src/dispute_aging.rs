@@ -0,0 +1,43 @@
+use crate::AccountTransactions;
+
+// Disputes resolved since the engine started, by a resolve or by expiry, and how many rows they stayed open. Disputes
+// from state written before disputes recorded when they were opened are left out, their ages being unknown.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Resolutions {
+    pub resolved: u64,
+    pub rows: u64,
+}
+
+impl Resolutions {
+    pub fn mean_rows(&self) -> Option<f64> {
+        match self.resolved {
+            0 => None,
+            resolved => Some(self.rows as f64 / resolved as f64),
+        }
+    }
+
+    pub(crate) fn record(&mut self, dispute_time: u32, now: u32) {
+        if dispute_time != 0 {
+            self.resolved += 1;
+            self.rows += u64::from(now.saturating_sub(dispute_time));
+        }
+    }
+}
+
+impl AccountTransactions {
+    pub fn resolutions(&self) -> &Resolutions {
+        &self.resolutions
+    }
+
+    // Rows since each open dispute opened, counting from the last row handled, youngest first. Disputes whose dispute
+    // row was not recorded are left out.
+    pub fn open_dispute_ages(&self) -> Vec<u32> {
+        let now = self.next_tx_time() - 1;
+        let mut ages: Vec<u32> = self.open_disputes()
+            .filter(|tx| tx.dispute_time != 0)
+            .map(|tx| now.saturating_sub(tx.dispute_time))
+            .collect();
+        ages.sort_unstable();
+        ages
+    }
+}
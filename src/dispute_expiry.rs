@@ -0,0 +1,172 @@
+use std::collections::VecDeque;
+
+use crate::lifecycle::DisputeEventKind;
+use crate::{Account, AccountTransactions, Amount, TxState};
+#[cfg(feature = "io")]
+use crate::TransactionMessage;
+
+// A dispute resolved by the engine for staying open too long, with the account before and after
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExpiredDispute {
+    pub tx: u32,
+    pub client: u16,
+    pub amount: Amount,
+    // tx_time of the dispute, and of the row after which it expired
+    pub dispute_time: u32,
+    pub expired_time: u32,
+    pub before: Account,
+    pub after: Account,
+}
+
+// Resolves disputes still open max_rows rows after they were opened. Rows are counted by tx_time, so rejected and
+// malformed rows count too, and the count carries on across runs with the state. Input rows carry no timestamps, so
+// rows stand in for elapsed time.
+#[derive(Debug, Clone)]
+pub struct DisputeExpiry {
+    max_rows: u32,
+    // (dispute_time, tx) of disputes in the order they were opened. Entries resolved or charged back since are
+    // skipped when they come up.
+    open: VecDeque<(u32, u32)>,
+    expired: Vec<ExpiredDispute>,
+}
+
+impl DisputeExpiry {
+    pub fn max_rows(&self) -> u32 {
+        self.max_rows
+    }
+
+    // Disputes expired so far, in the order they expired
+    pub fn expired(&self) -> &[ExpiredDispute] {
+        &self.expired
+    }
+}
+
+impl AccountTransactions {
+    // Disputes open from here on, and those already open, expire max_rows rows after they were opened. A dispute from
+    // state written before disputes recorded when they were opened counts from now.
+    pub fn set_dispute_expiry(&mut self, max_rows: Option<u32>) {
+        let max_rows = match max_rows {
+            Some(max_rows) => max_rows,
+            None => {
+                self.dispute_expiry = None;
+                return;
+            }
+        };
+        let now = self.next_tx_time() - 1;
+        let mut open: Vec<(u32, u32)> = self.txs_txid.values_mut()
+            .filter(|tx| tx.disputed())
+            .map(|tx| {
+                if tx.dispute_time == 0 {
+                    tx.dispute_time = now;
+                }
+                (tx.dispute_time, tx.tx)
+            })
+            .collect();
+        open.sort_unstable();
+        self.dispute_expiry = Some(DisputeExpiry { max_rows, open: open.into(), expired: Vec::new() });
+    }
+
+    pub fn dispute_expiry(&self) -> Option<&DisputeExpiry> {
+        self.dispute_expiry.as_ref()
+    }
+
+    // Disputes that expired after msg's row, for stores and audit logs that write as each message is handled
+    #[cfg(feature = "io")]
+    pub(crate) fn expired_by<'a>(&'a self, msg: &'a TransactionMessage) -> impl Iterator<Item = &'a ExpiredDispute> + 'a {
+        let expired = self.dispute_expiry.as_ref().map_or(&[][..], |expiry| &expiry.expired[..]);
+        expired.iter().rev().take_while(move |expired| expired.expired_time == msg.tx_time)
+    }
+
+    pub(crate) fn track_dispute(&mut self, dispute_time: u32, tx: u32) {
+        if let Some(expiry) = self.dispute_expiry.as_mut() {
+            expiry.open.push_back((dispute_time, tx));
+        }
+    }
+
+    // Called after each row, with its tx_time. The held funds go back to available as a resolve would.
+    pub(crate) fn expire_disputes(&mut self, now: u32) {
+        let expiry = match self.dispute_expiry.as_mut() {
+            Some(expiry) => expiry,
+            None => return,
+        };
+        while let Some(&(dispute_time, tx)) = expiry.open.front() {
+            if now.saturating_sub(dispute_time) < expiry.max_rows {
+                break;
+            }
+            expiry.open.pop_front();
+            // Resolved, charged back, pruned or disputed again since
+            let disputed = match self.txs_txid.get_mut(&tx) {
+                Some(disputed) if disputed.disputed() && disputed.dispute_time == dispute_time => disputed,
+                _ => continue,
+            };
+            let acct = match self.account_client.get_mut(&disputed.client) {
+                Some(acct) if disputed.provisional || acct.held >= disputed.amount => acct,
+                _ => continue,
+            };
+            let before = acct.clone();
+            if !disputed.provisional {
+                acct.held -= disputed.amount;
+                acct.available += disputed.amount;
+                acct.total = acct.available + acct.held + acct.escrow;
+            }
+            self.resolutions.record(dispute_time, now);
+            disputed.state = TxState::Resolved;
+            disputed.dispute_time = 0;
+            disputed.record_event(DisputeEventKind::Expire, now);
+            self.touched.insert(disputed.client);
+            expiry.expired.push(ExpiredDispute {
+                tx,
+                client: disputed.client,
+                amount: disputed.amount,
+                dispute_time,
+                expired_time: now,
+                before,
+                after: acct.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{apply, message, units};
+    use crate::{AccountTransactions, TransactionType, TxState};
+
+    #[test]
+    fn expires_disputes_after_max_rows_and_lists_them_by_row() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_dispute_expiry(Some(2));
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DISPUTE, 1, 1, 0)).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(1))).unwrap();
+        assert_eq!(account_txs.get_account(1).unwrap().held, units(10));
+
+        let mut row = message(TransactionType::DEPOSIT, 2, 3, units(1));
+        row.tx_time = account_txs.next_tx_time();
+        account_txs.handle_tx_message(&row).unwrap();
+        #[cfg(feature = "io")]
+        assert_eq!(account_txs.expired_by(&row).map(|expired| expired.tx).collect::<Vec<u32>>(), vec![1]);
+        assert_eq!(account_txs.touched_accounts().collect::<Vec<_>>(), vec![1, 2]);
+        let acct = account_txs.get_account(1).unwrap();
+        assert_eq!((acct.available, acct.held), (units(10), 0));
+        assert_eq!(account_txs.get_transaction(1).unwrap().state, TxState::Resolved);
+
+        let mut later = message(TransactionType::DEPOSIT, 2, 4, units(1));
+        later.tx_time = account_txs.next_tx_time();
+        account_txs.handle_tx_message(&later).unwrap();
+        #[cfg(feature = "io")]
+        assert_eq!(account_txs.expired_by(&later).count(), 0);
+        assert_eq!(account_txs.dispute_expiry().unwrap().expired().len(), 1);
+    }
+
+    #[test]
+    fn leaves_resolved_disputes_alone() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_dispute_expiry(Some(2));
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DISPUTE, 1, 1, 0)).unwrap();
+        apply(&mut account_txs, message(TransactionType::RESOLVE, 1, 1, 0)).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(1))).unwrap();
+        assert!(account_txs.dispute_expiry().unwrap().expired().is_empty());
+    }
+}
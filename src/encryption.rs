@@ -0,0 +1,215 @@
+#[cfg(feature = "encryption")]
+use std::sync::OnceLock;
+
+#[cfg(feature = "encryption")]
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+#[cfg(feature = "encryption")]
+use ring::digest::{digest, SHA256};
+#[cfg(feature = "encryption")]
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::config::EncryptionConfig;
+use crate::{Error, Result};
+
+// Sealed data is MAGIC, a 12-byte nonce, then the AES-256-GCM ciphertext and its tag. Data without MAGIC is plaintext
+// written before a key was configured, read with a key only if migrate_plaintext opts in.
+const MAGIC: &[u8] = b"TXENC\x01";
+
+#[cfg(feature = "encryption")]
+static CIPHER: OnceLock<Cipher> = OnceLock::new();
+
+#[cfg(feature = "encryption")]
+struct Cipher {
+    key: LessSafeKey,
+    // SHA-256 of the key, to tell a second init with another key from a repeat of the first
+    fingerprint: Vec<u8>,
+    rng: SystemRandom,
+    migrate_plaintext: bool,
+}
+
+#[cfg(feature = "encryption")]
+impl Cipher {
+    fn new(key: &[u8; 32], migrate_plaintext: bool) -> Result<Cipher> {
+        let unbound = UnboundKey::new(&AES_256_GCM, key).map_err(|e| Error::ConfigError(e.to_string()))?;
+        Ok(Cipher {
+            key: LessSafeKey::new(unbound),
+            fingerprint: digest(&SHA256, key).as_ref().to_vec(),
+            rng: SystemRandom::new(),
+            migrate_plaintext,
+        })
+    }
+
+    fn seal(&self, mut data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        self.rng.fill(&mut nonce).map_err(|_| Error::StorageError(String::from("No random nonce for encryption")))?;
+        self.key.seal_in_place_append_tag(Nonce::assume_unique_for_key(nonce), Aad::from(aad), &mut data)
+            .map_err(|_| Error::StorageError(String::from("Encryption failed")))?;
+        let mut sealed = Vec::with_capacity(MAGIC.len() + NONCE_LEN + data.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&data);
+        Ok(sealed)
+    }
+
+    fn open(&self, data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>> {
+        if !data.starts_with(MAGIC) {
+            return match self.migrate_plaintext {
+                true => Ok(data),
+                false => Err(Error::StorageError(String::from(
+                    "Not encrypted, and [encryption] does not set migrate_plaintext"))),
+            };
+        }
+        let sealed = &data[MAGIC.len()..];
+        if sealed.len() < NONCE_LEN {
+            return Err(Error::StorageError(String::from("Encrypted data is truncated")));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::try_assume_unique_for_key(nonce).expect("The nonce was split at NONCE_LEN.");
+        let mut in_out = ciphertext.to_vec();
+        let plain_len = self.key.open_in_place(nonce, Aad::from(aad), &mut in_out)
+            .map_err(|_| Error::StorageError(String::from("Decryption failed, wrong key or damaged data")))?
+            .len();
+        in_out.truncate(plain_len);
+        Ok(in_out)
+    }
+}
+
+// Encrypts state directories, snapshots and sled values written from here on with the configured key. Calling it
+// again is allowed only with the same key and settings, so data is never sealed with one key and opened with another.
+#[cfg(feature = "encryption")]
+pub fn init(config: &EncryptionConfig) -> Result<()> {
+    let (source, text) = read_key(config)?;
+    let key: [u8; 32] = crate::merkle::from_hex(text.trim())
+        .ok_or_else(|| Error::ConfigError(format!("Encryption key from {} is not 64 hex digits.", source)))?;
+    let cipher = Cipher::new(&key, config.migrate_plaintext)
+        .map_err(|e| Error::ConfigError(format!("Encryption key from {} is not usable. {}", source, e)))?;
+    install(&CIPHER, cipher)
+        .map_err(|e| Error::ConfigError(format!("Encryption key from {} cannot be used. {}", source, e)))
+}
+
+#[cfg(feature = "encryption")]
+fn install(lock: &OnceLock<Cipher>, cipher: Cipher) -> Result<()> {
+    let fingerprint = cipher.fingerprint.clone();
+    let migrate_plaintext = cipher.migrate_plaintext;
+    let installed = lock.get_or_init(|| cipher);
+    if installed.fingerprint != fingerprint || installed.migrate_plaintext != migrate_plaintext {
+        return Err(Error::ConfigError(String::from(
+            "Encryption was already set up with a different key or migrate_plaintext.")));
+    }
+    Ok(())
+}
+
+// Where the key came from, for messages, and its hex digits. key_command is for key services: the command is run
+// without a shell and prints the key, e.g. ["vault", "kv", "get", "-field=key", "secret/tx_acct"].
+#[cfg(feature = "encryption")]
+fn read_key(config: &EncryptionConfig) -> Result<(String, String)> {
+    match (&config.key_file, &config.key_env, &config.key_command[..]) {
+        (Some(file), None, []) => {
+            let source = format!("file {}", file);
+            let text = std::fs::read_to_string(file)
+                .map_err(|e| Error::ConfigError(format!("Cannot read encryption key {}. {}", file, e)))?;
+            Ok((source, text))
+        }
+        (None, Some(var), []) => {
+            let text = std::env::var(var)
+                .map_err(|e| Error::ConfigError(format!("Cannot read encryption key from ${}. {}", var, e)))?;
+            Ok((format!("${}", var), text))
+        }
+        (None, None, [program, args @ ..]) => {
+            let output = std::process::Command::new(program).args(args).output()
+                .map_err(|e| Error::ConfigError(format!("Cannot run encryption key command {}. {}", program, e)))?;
+            if !output.status.success() {
+                return Err(Error::ConfigError(format!("Encryption key command {} failed, {}. {}", program,
+                                                      output.status, String::from_utf8_lossy(&output.stderr).trim())));
+            }
+            let text = String::from_utf8(output.stdout)
+                .map_err(|_| Error::ConfigError(format!("Encryption key command {} printed non UTF-8.", program)))?;
+            Ok((format!("command {}", program), text))
+        }
+        _ => Err(Error::ConfigError(String::from(
+            "[encryption] needs exactly one of key_file, key_env and key_command."))),
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+pub fn init(_config: &EncryptionConfig) -> Result<()> {
+    Err(Error::ConfigError(String::from("This build has no encryption. Rebuild with --features encryption.")))
+}
+
+pub fn enabled() -> bool {
+    #[cfg(feature = "encryption")]
+    return CIPHER.get().is_some();
+    #[cfg(not(feature = "encryption"))]
+    false
+}
+
+// aad is bound to the ciphertext without being stored in it, so data moved to another file or key fails to open
+#[cfg(feature = "encryption")]
+pub(crate) fn seal(data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>> {
+    match CIPHER.get() {
+        Some(cipher) => cipher.seal(data, aad),
+        None => Ok(data),
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn seal(data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>> {
+    Ok(data)
+}
+
+#[cfg(feature = "encryption")]
+pub(crate) fn open(data: Vec<u8>, aad: &[u8]) -> Result<Vec<u8>> {
+    match CIPHER.get() {
+        Some(cipher) => cipher.open(data, aad),
+        None if data.starts_with(MAGIC) => Err(Error::StorageError(String::from(
+            "Encrypted, and no [encryption] key is configured"))),
+        None => Ok(data),
+    }
+}
+
+#[cfg(not(feature = "encryption"))]
+pub(crate) fn open(data: Vec<u8>, _aad: &[u8]) -> Result<Vec<u8>> {
+    match data.starts_with(MAGIC) {
+        true => Err(Error::StorageError(String::from(
+            "Encrypted. This build has no encryption. Rebuild with --features encryption."))),
+        false => Ok(data),
+    }
+}
+
+#[cfg(all(test, feature = "encryption"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opens_what_it_sealed() {
+        let cipher = Cipher::new(&[7; 32], false).unwrap();
+        let sealed = cipher.seal(b"client,available".to_vec(), b"accounts.csv").unwrap();
+        assert!(sealed.starts_with(MAGIC));
+        assert_eq!(cipher.open(sealed.clone(), b"accounts.csv").unwrap(), b"client,available");
+        // Bound to where it was written
+        assert!(cipher.open(sealed, b"txs.csv").is_err());
+    }
+
+    #[test]
+    fn refuses_the_wrong_key() {
+        let sealed = Cipher::new(&[7; 32], false).unwrap().seal(b"state".to_vec(), b"snapshot").unwrap();
+        assert!(matches!(Cipher::new(&[8; 32], false).unwrap().open(sealed, b"snapshot"), Err(Error::StorageError(_))));
+    }
+
+    #[test]
+    fn refuses_tampered_ciphertext() {
+        let cipher = Cipher::new(&[7; 32], false).unwrap();
+        let mut sealed = cipher.seal(b"state".to_vec(), b"snapshot").unwrap();
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(matches!(cipher.open(sealed, b"snapshot"), Err(Error::StorageError(_))));
+    }
+
+    #[test]
+    fn refuses_a_second_key() {
+        let lock = OnceLock::new();
+        install(&lock, Cipher::new(&[7; 32], false).unwrap()).unwrap();
+        install(&lock, Cipher::new(&[7; 32], false).unwrap()).unwrap();
+        assert!(matches!(install(&lock, Cipher::new(&[8; 32], false).unwrap()), Err(Error::ConfigError(_))));
+    }
+}
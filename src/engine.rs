@@ -0,0 +1,186 @@
+use crate::hooks::{HookConfig, Validator};
+use crate::observer::EngineObserver;
+use crate::pipeline::{Middleware, Next, Stage};
+use crate::source::{CsvSource, MessageSource, TransactionSource};
+use crate::state::{open_store, Store};
+use crate::{read_source, AccountTransactions, Amount, Error, PrunedRange, ReadOptions, ReadReport, Result, Retention,
+            TransactionMessage};
+
+// Amounts are held in ten-thousandths, so at most four decimal places are kept
+const MAX_DECIMALS: u32 = 4;
+
+// Configures an Engine through one typed API instead of the command line's flags, e.g.
+//   EngineBuilder::new().precision(2).store("dir:state").retention(Retention::Count(1000)).build()?
+pub struct EngineBuilder {
+    decimals: u32,
+    hooks: HookConfig,
+    validators: Vec<Box<dyn Validator>>,
+    middlewares: Vec<Box<dyn Middleware>>,
+    observers: Vec<Box<dyn EngineObserver>>,
+    store_spec: Option<String>,
+    store: Option<Box<dyn Store>>,
+    retention: Option<Retention>,
+}
+
+impl Default for EngineBuilder {
+    fn default() -> EngineBuilder {
+        EngineBuilder::new()
+    }
+}
+
+impl EngineBuilder {
+    pub fn new() -> EngineBuilder {
+        EngineBuilder {
+            decimals: MAX_DECIMALS,
+            hooks: HookConfig::default(),
+            validators: Vec::new(),
+            middlewares: Vec::new(),
+            observers: Vec::new(),
+            store_spec: None,
+            store: None,
+            retention: None,
+        }
+    }
+
+    // Decimal places amounts are kept to, finer digits are dropped as messages enter the engine. 4, the most the
+    // engine holds, by default.
+    pub fn precision(mut self, decimals: u32) -> EngineBuilder {
+        self.decimals = decimals;
+        self
+    }
+
+    // The rules file, plugins and script to load, as configured for the command line
+    pub fn hooks(mut self, hooks: HookConfig) -> EngineBuilder {
+        self.hooks = hooks;
+        self
+    }
+
+    // Runs after the configured hooks
+    pub fn validator(mut self, validator: Box<dyn Validator>) -> EngineBuilder {
+        self.validators.push(validator);
+        self
+    }
+
+    pub fn middleware(mut self, middleware: Box<dyn Middleware>) -> EngineBuilder {
+        self.middlewares.push(middleware);
+        self
+    }
+
+    pub fn observer(mut self, observer: Box<dyn EngineObserver>) -> EngineBuilder {
+        self.observers.push(observer);
+        self
+    }
+
+    // A store as --store names it, such as dir:state or snapshot:state.zst, opened and loaded by build
+    pub fn store(mut self, spec: &str) -> EngineBuilder {
+        self.store_spec = Some(spec.to_string());
+        self
+    }
+
+    // A store already opened, loaded by build
+    pub fn store_backend(mut self, store: Box<dyn Store>) -> EngineBuilder {
+        self.store = Some(store);
+        self
+    }
+
+    // Applied by Engine::finish, before the state is saved
+    pub fn retention(mut self, retention: Retention) -> EngineBuilder {
+        self.retention = Some(retention);
+        self
+    }
+
+    pub fn build(self) -> Result<Engine> {
+        if self.decimals > MAX_DECIMALS {
+            return Err(Error::InvalidArgument(format!(
+                "Precision of {} decimal places is more than the {} the engine keeps.", self.decimals, MAX_DECIMALS)));
+        }
+        let mut account_txs = AccountTransactions::with_validators(self.hooks.load()?);
+        if self.decimals < MAX_DECIMALS {
+            account_txs.add_middleware(Box::new(Precision { step: Amount::pow(10, MAX_DECIMALS - self.decimals) }));
+        }
+        for validator in self.validators {
+            account_txs.add_validator(validator);
+        }
+        for middleware in self.middlewares {
+            account_txs.add_middleware(middleware);
+        }
+        for observer in self.observers {
+            account_txs.add_observer(observer);
+        }
+        let mut store = match self.store_spec {
+            Some(spec) => Some(open_store(&spec)?),
+            None => self.store,
+        };
+        if let Some(store) = store.as_mut() {
+            store.load(&mut account_txs)?;
+        }
+        Ok(Engine { account_txs, store, retention: self.retention })
+    }
+}
+
+// An engine with its store and retention policy, as built by EngineBuilder
+pub struct Engine {
+    account_txs: AccountTransactions,
+    store: Option<Box<dyn Store>>,
+    retention: Option<Retention>,
+}
+
+impl Engine {
+    pub fn accounts(&self) -> &AccountTransactions {
+        &self.account_txs
+    }
+
+    pub fn accounts_mut(&mut self) -> &mut AccountTransactions {
+        &mut self.account_txs
+    }
+
+    pub fn read_file(&mut self, filename: &str, read_options: &ReadOptions) -> Result<ReadReport> {
+        self.read_source(&mut CsvSource::open(filename)?, read_options)
+    }
+
+    pub fn read_source(&mut self, source: &mut dyn TransactionSource, read_options: &ReadOptions) -> Result<ReadReport> {
+        let store = self.store.as_mut().map(|store| store.as_mut() as &mut dyn Store);
+        read_source(source, &mut self.account_txs, read_options, store)
+    }
+
+    pub fn process<I>(&mut self, messages: I) -> Result<ReadReport>
+        where I: IntoIterator<Item = TransactionMessage>, I::IntoIter: Send {
+        self.read_source(&mut MessageSource::new(messages.into_iter()), &ReadOptions::default())
+    }
+
+    // Ends a run: prunes by the retention policy, then saves the state to the store
+    pub fn finish(&mut self) -> Result<Option<PrunedRange>> {
+        let account_txs = &mut self.account_txs;
+        let pruned = self.retention.as_ref().and_then(|retention| account_txs.prune(retention));
+        if let Some(store) = self.store.as_mut() {
+            store.save(&self.account_txs)?;
+        }
+        Ok(pruned)
+    }
+
+    pub fn into_inner(self) -> AccountTransactions {
+        self.account_txs
+    }
+}
+
+// Rounds amounts down to the configured precision, as the parser does digits past the fourth
+struct Precision {
+    step: Amount,
+}
+
+impl Middleware for Precision {
+    fn name(&self) -> &str {
+        "precision"
+    }
+
+    fn stage(&self) -> Stage {
+        Stage::Normalize
+    }
+
+    fn handle(&mut self, msg: &TransactionMessage, next: &mut Next<'_>) -> Result<()> {
+        if msg.amount.is_multiple_of(self.step) {
+            return next.run(msg);
+        }
+        next.run(&TransactionMessage { amount: msg.amount - msg.amount % self.step, ..msg.clone() })
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt;
+use std::io;
+
+use thiserror::Error;
+
+// Why the engine refused to apply a transaction. The codes are stable and appear in the error log
+// and run summaries, so new reasons are only ever added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Rejection {
+    InsufficientFunds,
+    InsufficientHeld,
+    UnknownClient,
+    UnknownTx,
+    ClientMismatch,
+    LockedAccount,
+    DuplicateTx,
+    AlreadyDisputed,
+    NotDisputed,
+    // A validator, such as a WASM plugin, refused the message
+    Vetoed,
+    // A withdrawal over the client's limit, see WithdrawalLimits
+    WithdrawalLimit,
+    CumulativeLimit,
+    // An approve or deny for a transaction that is not a pending withdrawal
+    NotPending,
+    // A dispute of a withdrawal still waiting for approval
+    PendingApproval,
+    // A dispute from a client already at the most open disputes allowed
+    TooManyDisputes,
+    // A release or refund for a transaction that is not an escrow still holding its funds
+    NotInEscrow,
+    // A dispute of an escrow or transfer, which disputes do not apply to
+    NotDisputable,
+    // A transfer to the client making it
+    SelfTransfer,
+    // A dispute, resolve or chargeback of a transaction already charged back
+    ChargedBack,
+    // A fee or interest accrual that would not fit in an amount
+    AmountOverflow,
+}
+
+impl Rejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Rejection::InsufficientFunds => "insufficient_funds",
+            Rejection::InsufficientHeld => "insufficient_held",
+            Rejection::UnknownClient => "unknown_client",
+            Rejection::UnknownTx => "unknown_tx",
+            Rejection::ClientMismatch => "client_mismatch",
+            Rejection::LockedAccount => "locked_account",
+            Rejection::DuplicateTx => "duplicate_tx",
+            Rejection::AlreadyDisputed => "already_disputed",
+            Rejection::NotDisputed => "not_disputed",
+            Rejection::Vetoed => "vetoed",
+            Rejection::WithdrawalLimit => "withdrawal_limit",
+            Rejection::CumulativeLimit => "cumulative_withdrawal_limit",
+            Rejection::NotPending => "not_pending",
+            Rejection::PendingApproval => "pending_approval",
+            Rejection::TooManyDisputes => "too_many_disputes",
+            Rejection::NotInEscrow => "not_in_escrow",
+            Rejection::NotDisputable => "not_disputable",
+            Rejection::SelfTransfer => "self_transfer",
+            Rejection::ChargedBack => "charged_back",
+            Rejection::AmountOverflow => "amount_overflow",
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.code())
+    }
+}
+
+// Every failure the crate reports, grouped by category so callers can match on the kind of failure
+// rather than on message text.
+#[derive(Debug, Error)]
+pub enum Error {
+    // An input row, amount or transaction type that could not be parsed
+    #[error("{0}")]
+    ParseError(String),
+    // A well-formed transaction the engine refused to apply
+    #[error("{message}")]
+    PolicyViolation { reason: Rejection, message: String },
+    // Reading or writing the persistent state directory failed
+    #[error("{0}")]
+    StorageError(String),
+    // A client or transaction named by an operation does not exist
+    #[error("{0}")]
+    NotFound(String),
+    // Command line arguments or operation parameters that cannot be used
+    #[error("{0}")]
+    InvalidArgument(String),
+    // A configuration file that cannot be read or does not match the expected settings
+    #[error("{0}")]
+    ConfigError(String),
+    // A configured resource limit would be exceeded by the input
+    #[error("{0}")]
+    LimitExceeded(String),
+    // Delivering events to an external system failed
+    #[error("{0}")]
+    SinkError(String),
+    // A validator could not be loaded, failed while checking a message or answered with something unreadable
+    #[error("{0}")]
+    PluginError(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[cfg(feature = "io")]
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+    #[cfg(feature = "io")]
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
@@ -0,0 +1,38 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use serde::Serialize;
+
+use crate::Result;
+
+// One rejected or unparseable input record. client and tx are absent when the row could not be parsed.
+#[derive(Debug, Serialize)]
+pub struct ErrorRecord<'a> {
+    pub code: &'a str,
+    pub message: &'a str,
+    pub row: u32,
+    pub client: Option<u16>,
+    pub tx: Option<u32>,
+}
+
+// JSON Lines file with one ErrorRecord per line
+pub struct ErrorLog {
+    out: BufWriter<File>,
+}
+
+impl ErrorLog {
+    pub fn create(path: &str) -> Result<ErrorLog> {
+        Ok(ErrorLog { out: BufWriter::new(File::create(path)?) })
+    }
+
+    pub fn record(&mut self, record: &ErrorRecord) -> Result<()> {
+        serde_json::to_writer(&mut self.out, record)?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use std::collections::{BTreeMap, HashMap};
+
+#[cfg(feature = "io")]
+use serde::{Deserialize, Serialize};
+
+use crate::error::Rejection;
+use crate::{rejected, rounding, AccountTransactions, Amount, Error, Result, TransactionMessage, TransactionType};
+
+// One tier of a fee schedule, applying once the client has made `after` transactions of the type in the period
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeTier {
+    pub after: u32,
+    // Per transaction, in subunits
+    pub flat: Amount,
+    // Parts per million of the amount above `above`, so a percentage read like an amount, 1.5 being 15000
+    pub rate_ppm: u64,
+    pub above: Amount,
+}
+
+impl FeeTier {
+    // Rounded down to the subunit
+    pub fn fee(&self, amount: Amount) -> Result<Amount> {
+        self.fee_and_remainder(amount).map(|(fee, _)| fee)
+    }
+
+    // The fee, and what rounding it down dropped in millionths of a subunit. Refused as amount_overflow when it would
+    // not fit in an amount.
+    pub fn fee_and_remainder(&self, amount: Amount) -> Result<(Amount, u64)> {
+        let (percent, dropped) = rounding::apply_rate(amount.saturating_sub(self.above), self.rate_ppm)?;
+        let fee = self.flat.checked_add(percent).ok_or_else(|| rejected(Rejection::AmountOverflow, format!(
+            "A fee of {} plus {} subunits is out of range for an amount.", self.flat, percent)))?;
+        Ok((fee, dropped))
+    }
+}
+
+// The tiers for one type of transaction, and how many rows a period lasts. Without period_rows the period is
+// everything since the state was created.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FeeSchedule {
+    tiers: Vec<FeeTier>,
+    pub period_rows: Option<u32>,
+}
+
+impl FeeSchedule {
+    pub fn new(period_rows: Option<u32>) -> FeeSchedule {
+        FeeSchedule { tiers: Vec::new(), period_rows }
+    }
+
+    // Tiers are kept in order of `after`, a later one with the same `after` replacing the earlier
+    pub fn add_tier(&mut self, tier: FeeTier) {
+        self.tiers.retain(|existing| existing.after != tier.after);
+        let at = self.tiers.partition_point(|existing| existing.after < tier.after);
+        self.tiers.insert(at, tier);
+    }
+
+    pub fn tiers(&self) -> &[FeeTier] {
+        &self.tiers
+    }
+
+    // The tier for a client's next transaction after `used` in the period, none before the first tier
+    pub fn tier(&self, used: u32) -> Option<&FeeTier> {
+        self.tiers.iter().rev().find(|tier| tier.after <= used)
+    }
+
+    // Periods are numbered from 0, tx_times 1 to period_rows making the first
+    pub fn period(&self, tx_time: u32) -> u32 {
+        self.period_rows.map_or(0, |rows| tx_time.saturating_sub(1) / rows.max(1))
+    }
+}
+
+// What one client's transactions of one type came to in one period
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeeUsage {
+    pub count: u32,
+    pub fees: Amount,
+}
+
+// One (client, type, period) of tier usage, as the state files, snapshots and stores keep it
+#[cfg(feature = "io")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct FeeUsageRecord {
+    pub(crate) client: u16,
+    pub(crate) tx_type: TransactionType,
+    pub(crate) period: u32,
+    pub(crate) count: u32,
+    pub(crate) fees: Amount,
+}
+
+// Fee schedules for deposits and withdrawals, and the tier usage of each client, carried across runs with the state
+#[derive(Debug, Clone, Default)]
+pub struct FeeSchedules {
+    schedules: HashMap<TransactionType, FeeSchedule>,
+    usage: BTreeMap<(u16, TransactionType, u32), FeeUsage>,
+}
+
+impl FeeSchedules {
+    pub fn new() -> FeeSchedules {
+        FeeSchedules::default()
+    }
+
+    pub fn set(&mut self, tx_type: TransactionType, schedule: FeeSchedule) -> Result<()> {
+        if !matches!(tx_type, TransactionType::DEPOSIT | TransactionType::WITHDRAWAL) {
+            return Err(Error::InvalidArgument(format!("Fee schedules are for deposits and withdrawals, not {}.",
+                                                      tx_type)));
+        }
+        if schedule.period_rows == Some(0) {
+            return Err(Error::InvalidArgument(String::from("A fee schedule's period_rows must be at least 1.")));
+        }
+        self.schedules.insert(tx_type, schedule);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.schedules.is_empty()
+    }
+
+    pub fn schedule(&self, tx_type: &TransactionType) -> Option<&FeeSchedule> {
+        self.schedules.get(tx_type)
+    }
+
+    // By (client, type, period), for the transactions applied under a schedule
+    pub fn usage(&self) -> &BTreeMap<(u16, TransactionType, u32), FeeUsage> {
+        &self.usage
+    }
+
+    // The fee the message would pay, given what its client already used in the period, and what rounding it down
+    // dropped in millionths of a subunit
+    pub(crate) fn quote(&self, msg: &TransactionMessage) -> Result<(Amount, u64)> {
+        let schedule = match self.schedules.get(&msg.tx_type) {
+            Some(schedule) => schedule,
+            None => return Ok((0, 0)),
+        };
+        let key = (msg.client, msg.tx_type.clone(), schedule.period(msg.tx_time));
+        let used = self.usage.get(&key).map_or(0, |usage| usage.count);
+        schedule.tier(used).map_or(Ok((0, 0)), |tier| tier.fee_and_remainder(msg.amount))
+    }
+
+    // Counts a message that applied, with the fee it paid
+    pub(crate) fn record(&mut self, msg: &TransactionMessage, fee: Amount) {
+        if let Some(schedule) = self.schedules.get(&msg.tx_type) {
+            let usage = self.usage.entry((msg.client, msg.tx_type.clone(), schedule.period(msg.tx_time))).or_default();
+            usage.count += 1;
+            usage.fees += fee;
+        }
+    }
+
+    // Keeps what earlier schedules counted, so schedules set after the state was loaded go on from it
+    pub(crate) fn carry_over(&mut self, earlier: FeeSchedules) {
+        for (key, usage) in earlier.usage {
+            self.usage.entry(key).or_insert(usage);
+        }
+    }
+
+    // Client from's usage counts towards client into's tiers from here on, period by period
+    pub(crate) fn merge_client(&mut self, from: u16, into: u16) {
+        let moved: Vec<(TransactionType, u32)> = self.usage.keys()
+            .filter(|(client, _, _)| *client == from)
+            .map(|(_, tx_type, period)| (tx_type.clone(), *period))
+            .collect();
+        for (tx_type, period) in moved {
+            let usage = self.usage.remove(&(from, tx_type.clone(), period)).expect("Usage listed above.");
+            let total = self.usage.entry((into, tx_type, period)).or_default();
+            total.count += usage.count;
+            total.fees += usage.fees;
+        }
+    }
+
+    // The usage the message counted towards, once it applied under a schedule
+    #[cfg(any(feature = "sled", feature = "postgres"))]
+    pub(crate) fn record_of(&self, msg: &TransactionMessage) -> Option<FeeUsageRecord> {
+        let period = self.schedules.get(&msg.tx_type)?.period(msg.tx_time);
+        let usage = self.usage.get(&(msg.client, msg.tx_type.clone(), period))?;
+        Some(FeeUsageRecord { client: msg.client, tx_type: msg.tx_type.clone(), period, count: usage.count,
+                              fees: usage.fees })
+    }
+
+    // In (client, type, period) order
+    #[cfg(feature = "io")]
+    pub(crate) fn records(&self) -> Vec<FeeUsageRecord> {
+        self.usage.iter()
+            .map(|((client, tx_type, period), usage)| FeeUsageRecord {
+                client: *client,
+                tx_type: tx_type.clone(),
+                period: *period,
+                count: usage.count,
+                fees: usage.fees,
+            })
+            .collect()
+    }
+
+    #[cfg(feature = "io")]
+    pub(crate) fn restore(&mut self, records: Vec<FeeUsageRecord>) {
+        self.usage.extend(records.into_iter().map(|record| {
+            ((record.client, record.tx_type, record.period), FeeUsage { count: record.count, fees: record.fees })
+        }));
+    }
+}
+
+impl AccountTransactions {
+    // Deposits and withdrawals from here on pay the schedules' fees, their tiers going on from the usage the state
+    // already counted
+    pub fn set_fee_schedules(&mut self, mut schedules: FeeSchedules) {
+        schedules.carry_over(std::mem::take(&mut self.fee_schedules));
+        self.fee_schedules = schedules;
+    }
+
+    pub fn fee_schedules(&self) -> &FeeSchedules {
+        &self.fee_schedules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{apply, message, units};
+
+    // Free for the first two deposits of a period, then 1 flat plus 1% above 100
+    fn deposit_schedules(period_rows: Option<u32>) -> FeeSchedules {
+        let mut schedule = FeeSchedule::new(period_rows);
+        schedule.add_tier(FeeTier { after: 2, flat: units(1), rate_ppm: 10_000, above: units(100) });
+        let mut schedules = FeeSchedules::new();
+        schedules.set(TransactionType::DEPOSIT, schedule).unwrap();
+        schedules
+    }
+
+    #[test]
+    fn tier_fee_is_flat_plus_rate_above_rounded_down() {
+        let tier = FeeTier { after: 0, flat: units(1), rate_ppm: 15_000, above: units(100) };
+        assert_eq!(tier.fee(units(50)).unwrap(), units(1));
+        assert_eq!(tier.fee(units(300)).unwrap(), units(4));
+        assert_eq!(tier.fee_and_remainder(units(100) + 1).unwrap(), (units(1), 15_000));
+    }
+
+    #[test]
+    fn refuses_fees_out_of_range() {
+        let tier = FeeTier { after: 0, flat: Amount::MAX, rate_ppm: 10_000, above: 0 };
+        assert!(tier.fee(0).is_ok());
+        assert!(matches!(tier.fee(units(100)),
+                         Err(Error::PolicyViolation { reason: Rejection::AmountOverflow, .. })));
+    }
+
+    #[test]
+    fn tiers_replace_by_after_and_start_at_the_first() {
+        let mut schedule = FeeSchedule::new(None);
+        schedule.add_tier(FeeTier { after: 5, flat: 2, ..FeeTier::default() });
+        schedule.add_tier(FeeTier { after: 1, flat: 1, ..FeeTier::default() });
+        schedule.add_tier(FeeTier { after: 5, flat: 3, ..FeeTier::default() });
+        assert_eq!(schedule.tiers().len(), 2);
+        assert!(schedule.tier(0).is_none());
+        assert_eq!(schedule.tier(4).map(|tier| tier.flat), Some(1));
+        assert_eq!(schedule.tier(9).map(|tier| tier.flat), Some(3));
+    }
+
+    #[test]
+    fn rejects_schedules_for_other_types_and_empty_periods() {
+        let mut schedules = FeeSchedules::new();
+        assert!(schedules.set(TransactionType::DISPUTE, FeeSchedule::new(None)).is_err());
+        assert!(schedules.set(TransactionType::DEPOSIT, FeeSchedule::new(Some(0))).is_err());
+        assert!(schedules.is_empty());
+    }
+
+    #[test]
+    fn charges_once_the_tier_is_reached_and_counts_per_period() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_fee_schedules(deposit_schedules(Some(4)));
+        for tx in 1..=3 {
+            apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, tx, units(200))).unwrap();
+        }
+        assert_eq!(account_txs.get_account(1).unwrap().available, units(600) - units(2));
+        // Rows 5 on are the next period, free again for two deposits
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 4, units(1))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 5, units(200))).unwrap();
+        assert_eq!(account_txs.get_account(1).unwrap().available, units(800) - units(2));
+        let usage = account_txs.fee_schedules().usage();
+        assert_eq!(usage[&(1, TransactionType::DEPOSIT, 0)], FeeUsage { count: 3, fees: units(2) });
+        assert_eq!(usage[&(1, TransactionType::DEPOSIT, 1)], FeeUsage { count: 1, fees: 0 });
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn schedules_set_after_a_restore_keep_its_usage() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_fee_schedules(deposit_schedules(None));
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(200))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 2, units(200))).unwrap();
+        let records = account_txs.fee_schedules().records();
+
+        let mut restored = AccountTransactions::new();
+        restored.fee_schedules.restore(records);
+        restored.set_fee_schedules(deposit_schedules(None));
+        apply(&mut restored, message(TransactionType::DEPOSIT, 1, 3, units(200))).unwrap();
+        assert_eq!(restored.get_account(1).unwrap().available, units(198));
+    }
+}
@@ -0,0 +1,180 @@
+// C interface to the engine, for embedding it in a C or C++ system. include/tx_acct.h is generated from this file
+// by cbindgen, so the comments here are written for C callers. Amounts are currency subunits, ten-thousandths of a
+// unit, as the engine holds them. Built with amount128 the engine's amounts are wider than these, and balances past
+// u64::MAX read as u64::MAX.
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::ptr;
+
+use crate::{Account, AccountTransactions, Amount, Error, TransactionMessage, TransactionType};
+
+/// Transaction types for tx_acct_submit
+pub const TX_ACCT_WITHDRAWAL: u32 = 0;
+pub const TX_ACCT_DEPOSIT: u32 = 1;
+pub const TX_ACCT_DISPUTE: u32 = 2;
+pub const TX_ACCT_RESOLVE: u32 = 3;
+pub const TX_ACCT_CHARGEBACK: u32 = 4;
+pub const TX_ACCT_APPROVE: u32 = 5;
+pub const TX_ACCT_DENY: u32 = 6;
+pub const TX_ACCT_ESCROW: u32 = 7;
+pub const TX_ACCT_RELEASE: u32 = 8;
+pub const TX_ACCT_REFUND: u32 = 9;
+
+/// The call succeeded
+pub const TX_ACCT_OK: i32 = 0;
+/// The engine refused the transaction, see tx_acct_last_error
+pub const TX_ACCT_REJECTED: i32 = 1;
+/// No account for the client
+pub const TX_ACCT_NOT_FOUND: i32 = 2;
+/// A null pointer or an unknown transaction type was passed
+pub const TX_ACCT_INVALID_ARGUMENT: i32 = 3;
+/// Any other failure, see tx_acct_last_error
+pub const TX_ACCT_ERROR: i32 = 4;
+
+/// An engine created by tx_acct_engine_new. Not thread safe, callers serialize access to one engine.
+pub struct TxAcctEngine {
+    account_txs: AccountTransactions,
+    next_time: u32,
+    last_error: Option<CString>,
+}
+
+/// An account's balances in currency subunits
+#[repr(C)]
+pub struct TxAcctAccount {
+    pub client: u16,
+    pub available: u64,
+    pub held: u64,
+    pub total: u64,
+    pub locked: bool,
+    /// Added last so the earlier fields keep their offsets
+    pub escrow: u64,
+}
+
+impl From<&Account> for TxAcctAccount {
+    fn from(account: &Account) -> TxAcctAccount {
+        TxAcctAccount {
+            client: account.client,
+            available: narrow(account.available),
+            held: narrow(account.held),
+            total: narrow(account.total),
+            locked: account.locked,
+            escrow: narrow(account.escrow),
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "amount128"), allow(clippy::useless_conversion))]
+fn narrow(amount: Amount) -> u64 {
+    u64::try_from(amount).unwrap_or(u64::MAX)
+}
+
+/// Creates an empty engine, to be released with tx_acct_engine_free
+#[no_mangle]
+pub extern "C" fn tx_acct_engine_new() -> *mut TxAcctEngine {
+    Box::into_raw(Box::new(TxAcctEngine { account_txs: AccountTransactions::new(), next_time: 1, last_error: None }))
+}
+
+/// Releases an engine. Null is ignored.
+///
+/// # Safety
+/// engine is null or was returned by tx_acct_engine_new and has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn tx_acct_engine_free(engine: *mut TxAcctEngine) {
+    if !engine.is_null() {
+        drop(Box::from_raw(engine));
+    }
+}
+
+/// Applies one transaction. tx_type is one of the TX_ACCT_ type constants, amount is ignored for disputes,
+/// resolves and chargebacks. Returns TX_ACCT_OK, TX_ACCT_REJECTED, TX_ACCT_INVALID_ARGUMENT or TX_ACCT_ERROR.
+///
+/// # Safety
+/// engine was returned by tx_acct_engine_new and has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn tx_acct_submit(engine: *mut TxAcctEngine, tx_type: u32, client: u16, tx: u32,
+                                        amount: u64) -> i32 {
+    let engine = match engine.as_mut() {
+        Some(engine) => engine,
+        None => return TX_ACCT_INVALID_ARGUMENT,
+    };
+    let tx_type = match tx_type {
+        TX_ACCT_WITHDRAWAL => TransactionType::WITHDRAWAL,
+        TX_ACCT_DEPOSIT => TransactionType::DEPOSIT,
+        TX_ACCT_DISPUTE => TransactionType::DISPUTE,
+        TX_ACCT_RESOLVE => TransactionType::RESOLVE,
+        TX_ACCT_CHARGEBACK => TransactionType::CHARGEBACK,
+        TX_ACCT_APPROVE => TransactionType::APPROVE,
+        TX_ACCT_DENY => TransactionType::DENY,
+        TX_ACCT_ESCROW => TransactionType::ESCROW,
+        TX_ACCT_RELEASE => TransactionType::RELEASE,
+        TX_ACCT_REFUND => TransactionType::REFUND,
+        _ => {
+            engine.set_error(format!("Unknown transaction type, {}.", tx_type));
+            return TX_ACCT_INVALID_ARGUMENT;
+        }
+    };
+    let msg = TransactionMessage { tx_time: engine.next_time, tx_type, client, tx, amount: Amount::from(amount),
+                                   to_client: None, counterparty: None, memo: None, category: None };
+    engine.next_time += 1;
+    match engine.account_txs.handle_tx_message(&msg) {
+        Ok(()) => {
+            engine.last_error = None;
+            TX_ACCT_OK
+        }
+        Err(e) => {
+            let status = if matches!(e, Error::PolicyViolation { .. }) { TX_ACCT_REJECTED } else { TX_ACCT_ERROR };
+            engine.set_error(e.to_string());
+            status
+        }
+    }
+}
+
+/// Copies the client's account into out. Returns TX_ACCT_OK, TX_ACCT_NOT_FOUND or TX_ACCT_INVALID_ARGUMENT.
+///
+/// # Safety
+/// engine was returned by tx_acct_engine_new and has not been freed, out points to writable memory for one
+/// TxAcctAccount.
+#[no_mangle]
+pub unsafe extern "C" fn tx_acct_get_account(engine: *const TxAcctEngine, client: u16, out: *mut TxAcctAccount) -> i32 {
+    let engine = match engine.as_ref() {
+        Some(engine) if !out.is_null() => engine,
+        _ => return TX_ACCT_INVALID_ARGUMENT,
+    };
+    match engine.account_txs.get_account(client) {
+        Some(account) => {
+            out.write(TxAcctAccount::from(account));
+            TX_ACCT_OK
+        }
+        None => TX_ACCT_NOT_FOUND,
+    }
+}
+
+/// Number of accounts in the engine
+///
+/// # Safety
+/// engine was returned by tx_acct_engine_new and has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn tx_acct_account_count(engine: *const TxAcctEngine) -> usize {
+    engine.as_ref().map_or(0, |engine| engine.account_txs.account_count())
+}
+
+/// Why the last tx_acct_submit on the engine failed, or null if it succeeded. The string belongs to the engine and
+/// stays valid until the next tx_acct_submit or tx_acct_engine_free.
+///
+/// # Safety
+/// engine was returned by tx_acct_engine_new and has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn tx_acct_last_error(engine: *const TxAcctEngine) -> *const c_char {
+    engine.as_ref()
+        .and_then(|engine| engine.last_error.as_ref())
+        .map_or(ptr::null(), |message| message.as_ptr())
+}
+
+impl TxAcctEngine {
+    fn set_error(&mut self, message: String) {
+        // Engine messages never hold a NUL, but one would make CString::new fail
+        self.last_error = Some(CString::new(message.replace('\0', " ")).unwrap_or_default());
+    }
+}
@@ -0,0 +1,369 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use crate::{to_subunit, Amount, Error, Result, TransactionMessage, TransactionType};
+
+// A row filter such as `client == 42 || amount > 100.0`. Fields are client, tx, amount (in currency units) and type,
+// compared with == != < <= > >= and combined with &&, || and !, with parentheses for grouping. Types are written as
+// in the input, quoted or bare: `type == withdraw`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Filter {
+    Compare(Field, Op, Value),
+    Not(Box<Filter>),
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Client,
+    Tx,
+    Amount,
+    Type,
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", match self {
+            Field::Client => "client",
+            Field::Tx => "tx",
+            Field::Amount => "amount",
+            Field::Type => "type",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+// Amounts are held as currency subunits, like the messages they are compared with
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Number(Amount),
+    Type(TransactionType),
+}
+
+impl Filter {
+    pub fn matches(&self, msg: &TransactionMessage) -> bool {
+        match self {
+            Filter::Compare(field, op, value) => compare(*field, *op, value, msg),
+            Filter::Not(inner) => !inner.matches(msg),
+            Filter::And(left, right) => left.matches(msg) && right.matches(msg),
+            Filter::Or(left, right) => left.matches(msg) || right.matches(msg),
+        }
+    }
+}
+
+// Client ids read from a file, one per line, with blank lines and # comments skipped
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClientList {
+    clients: HashSet<u16>,
+}
+
+impl ClientList {
+    pub fn read(path: &str) -> Result<ClientList> {
+        let text = fs::read_to_string(path)
+            .map_err(|e| Error::InvalidArgument(format!("Cannot read client list {}. {}", path, e)))?;
+        let mut clients = HashSet::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let client = line.parse().map_err(|_| Error::InvalidArgument(format!(
+                "Line {} of client list {} is not a client id, {}.", number + 1, path, line)))?;
+            clients.insert(client);
+        }
+        Ok(ClientList { clients })
+    }
+
+    pub fn contains(&self, client: u16) -> bool {
+        self.clients.contains(&client)
+    }
+
+    pub fn len(&self) -> usize {
+        self.clients.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.clients.iter().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+}
+
+fn compare(field: Field, op: Op, value: &Value, msg: &TransactionMessage) -> bool {
+    let ordering = match (field, value) {
+        (Field::Client, Value::Number(n)) => Amount::from(msg.client).cmp(n),
+        (Field::Tx, Value::Number(n)) => Amount::from(msg.tx).cmp(n),
+        (Field::Amount, Value::Number(n)) => msg.amount.cmp(n),
+        (Field::Type, Value::Type(tx_type)) => return match op {
+            Op::Eq => msg.tx_type == *tx_type,
+            _ => msg.tx_type != *tx_type,
+        },
+        // Mismatched comparisons are refused when the filter is parsed
+        _ => return false,
+    };
+    match op {
+        Op::Eq => ordering.is_eq(),
+        Op::Ne => ordering.is_ne(),
+        Op::Lt => ordering.is_lt(),
+        Op::Le => ordering.is_le(),
+        Op::Gt => ordering.is_gt(),
+        Op::Ge => ordering.is_ge(),
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(text: &str) -> Result<Filter> {
+        let mut parser = Parser { tokens: tokenize(text)?, pos: 0 };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(filter),
+            Some(token) => Err(invalid(format!("unexpected {}", token))),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Number(String),
+    Text(String),
+    Op(Op),
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Word(word) | Token::Number(word) => write!(f, "'{}'", word),
+            Token::Text(text) => write!(f, "\"{}\"", text),
+            Token::Op(op) => write!(f, "'{}'", match op {
+                Op::Eq => "==", Op::Ne => "!=", Op::Lt => "<", Op::Le => "<=", Op::Gt => ">", Op::Ge => ">=",
+            }),
+            Token::And => write!(f, "'&&'"),
+            Token::Or => write!(f, "'||'"),
+            Token::Not => write!(f, "'!'"),
+            Token::Open => write!(f, "'('"),
+            Token::Close => write!(f, "')'"),
+        }
+    }
+}
+
+fn invalid(reason: String) -> Error {
+    Error::ParseError(format!("Bad filter, {}.", reason))
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let (token, len) = match (c, next) {
+            _ if c.is_whitespace() => { i += 1; continue }
+            ('=', Some('=')) => (Token::Op(Op::Eq), 2),
+            ('!', Some('=')) => (Token::Op(Op::Ne), 2),
+            ('<', Some('=')) => (Token::Op(Op::Le), 2),
+            ('>', Some('=')) => (Token::Op(Op::Ge), 2),
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('<', _) => (Token::Op(Op::Lt), 1),
+            ('>', _) => (Token::Op(Op::Gt), 1),
+            ('!', _) => (Token::Not, 1),
+            ('(', _) => (Token::Open, 1),
+            (')', _) => (Token::Close, 1),
+            ('"', _) | ('\'', _) => {
+                let end = chars[i + 1..].iter().position(|&q| q == c)
+                    .ok_or_else(|| invalid(format!("unterminated string at {}", i)))?;
+                (Token::Text(chars[i + 1..i + 1 + end].iter().collect()), end + 2)
+            }
+            _ if c.is_ascii_digit() || c == '.' => {
+                let len = chars[i..].iter().take_while(|d| d.is_ascii_digit() || **d == '.').count();
+                (Token::Number(chars[i..i + len].iter().collect()), len)
+            }
+            _ if c.is_alphabetic() || c == '_' => {
+                let len = chars[i..].iter().take_while(|w| w.is_alphanumeric() || **w == '_').count();
+                (Token::Word(chars[i..i + len].iter().collect()), len)
+            }
+            _ => return Err(invalid(format!("unexpected '{}' at {}", c, i))),
+        };
+        tokens.push(token);
+        i += len;
+    }
+    Ok(tokens)
+}
+
+// Recursive descent, || binding loosest, then &&, then !
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn or(&mut self) -> Result<Filter> {
+        let mut filter = self.and()?;
+        while self.eat(&Token::Or) {
+            filter = Filter::Or(Box::new(filter), Box::new(self.and()?));
+        }
+        Ok(filter)
+    }
+
+    fn and(&mut self) -> Result<Filter> {
+        let mut filter = self.unary()?;
+        while self.eat(&Token::And) {
+            filter = Filter::And(Box::new(filter), Box::new(self.unary()?));
+        }
+        Ok(filter)
+    }
+
+    fn unary(&mut self) -> Result<Filter> {
+        if self.eat(&Token::Not) {
+            return Ok(Filter::Not(Box::new(self.unary()?)));
+        }
+        if self.eat(&Token::Open) {
+            let filter = self.or()?;
+            if !self.eat(&Token::Close) {
+                return Err(invalid(String::from("missing ')'")));
+            }
+            return Ok(filter);
+        }
+        self.comparison()
+    }
+
+    fn comparison(&mut self) -> Result<Filter> {
+        let field = match self.next() {
+            Some(Token::Word(word)) => match word.as_str() {
+                "client" => Field::Client,
+                "tx" => Field::Tx,
+                "amount" => Field::Amount,
+                "type" => Field::Type,
+                _ => return Err(invalid(format!("unknown field '{}', expected client, tx, amount or type", word))),
+            },
+            Some(token) => return Err(invalid(format!("expected a field, found {}", token))),
+            None => return Err(invalid(String::from("expected a field, found the end"))),
+        };
+        let op = match self.next() {
+            Some(Token::Op(op)) => op,
+            Some(token) => return Err(invalid(format!("expected a comparison after {}, found {}", field, token))),
+            None => return Err(invalid(format!("expected a comparison after {}", field))),
+        };
+        let value = match (field, self.next()) {
+            (Field::Type, Some(Token::Word(name))) | (Field::Type, Some(Token::Text(name))) => {
+                if !matches!(op, Op::Eq | Op::Ne) {
+                    return Err(invalid(String::from("type can only be compared with == or !=")));
+                }
+                Value::Type(TransactionType::from_str(&name).map_err(|e| invalid(e.to_string()))?)
+            }
+            (Field::Amount, Some(Token::Number(number))) => Value::Number(to_subunit(&number).map_err(|e| invalid(e.to_string()))?),
+            (Field::Client, Some(Token::Number(number))) | (Field::Tx, Some(Token::Number(number))) => {
+                Value::Number(number.parse().map_err(|_| invalid(format!("'{}' is not a whole number", number)))?)
+            }
+            (_, Some(token)) => return Err(invalid(format!("{} cannot be compared with {}", token, field))),
+            (_, None) => return Err(invalid(format!("expected a value to compare {} with", field))),
+        };
+        Ok(Filter::Compare(field, op, value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{message, units};
+
+    fn filter(text: &str) -> Filter {
+        text.parse().unwrap()
+    }
+
+    fn compare(field: Field, op: Op, value: Value) -> Box<Filter> {
+        Box::new(Filter::Compare(field, op, value))
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or_and_not_tightest() {
+        assert_eq!(filter("client == 1 || client == 2 && tx > 5"),
+                   Filter::Or(compare(Field::Client, Op::Eq, Value::Number(1)),
+                              Box::new(Filter::And(compare(Field::Client, Op::Eq, Value::Number(2)),
+                                                   compare(Field::Tx, Op::Gt, Value::Number(5))))));
+        assert_eq!(filter("!client == 1 && tx > 5"),
+                   Filter::And(Box::new(Filter::Not(compare(Field::Client, Op::Eq, Value::Number(1)))),
+                               compare(Field::Tx, Op::Gt, Value::Number(5))));
+        let grouped = filter("(client == 1 || client == 2) && tx > 5");
+        assert!(!grouped.matches(&message(TransactionType::DEPOSIT, 1, 3, 0)));
+        assert!(filter("client == 1 || client == 2 && tx > 5").matches(&message(TransactionType::DEPOSIT, 1, 3, 0)));
+    }
+
+    #[test]
+    fn compares_each_field() {
+        let msg = message(TransactionType::WITHDRAWAL, 42, 7, units(100));
+        for (text, expected) in [("client == 42", true), ("client != 42", false), ("client < 43", true),
+                                 ("client <= 41", false), ("client > 41", true), ("client >= 43", false),
+                                 ("tx == 7", true), ("tx >= 8", false), ("amount > 99.9999", true),
+                                 ("amount == 100", true), ("amount < 100.0", false), ("amount <= 100", true),
+                                 ("type == withdraw", true), ("type != 'withdraw'", false),
+                                 ("type == \"deposit\"", false)] {
+            assert_eq!(filter(text).matches(&msg), expected, "{}", text);
+        }
+    }
+
+    #[test]
+    fn refuses_bad_expressions() {
+        for text in ["", "client", "client ==", "client = 1", "account == 1", "client == deposit", "type < deposit",
+                     "type == bogus", "amount == 1.2.3", "tx == 1.5", "(client == 1", "client == 1)",
+                     "client == 1 &&", "type == 'deposit", "client == 1 # 2"] {
+            assert!(matches!(text.parse::<Filter>(), Err(Error::ParseError(_))), "{}", text);
+        }
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn filtered_rows_never_reach_the_engine() {
+        use crate::source::MessageSource;
+        use crate::{read_source, AccountTransactions, ReadOptions};
+
+        let messages = vec![message(TransactionType::DEPOSIT, 1, 1, units(10)),
+                            message(TransactionType::DEPOSIT, 2, 2, units(5)),
+                            message(TransactionType::WITHDRAWAL, 1, 3, units(20))];
+        let read_options = ReadOptions { filter: Some(filter("client == 1 && type == deposit")), ..ReadOptions::default() };
+        let mut account_txs = AccountTransactions::new();
+
+        let report = read_source(&mut MessageSource::new(messages.into_iter()), &mut account_txs, &read_options, None)
+            .unwrap();
+        assert_eq!((report.applied, report.rejected, report.filtered), (1, 0, 2));
+        assert_eq!(account_txs.account_client[&1].available, units(10));
+        assert!(!account_txs.account_client.contains_key(&2));
+    }
+}
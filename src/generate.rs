@@ -0,0 +1,246 @@
+use std::io;
+use std::io::Write;
+
+use crate::Result;
+
+// Rates are probabilities per generated row, between 0.0 and 1.0
+#[derive(Debug)]
+pub struct GeneratorConfig {
+    pub rows: u32,
+    pub clients: u16,
+    pub seed: u64,
+    pub malformed_rate: f64,
+    pub duplicate_rate: f64,
+    pub cross_client_rate: f64,
+    pub out_of_range_rate: f64,
+}
+
+impl GeneratorConfig {
+    pub fn new() -> GeneratorConfig {
+        GeneratorConfig {
+            rows: 1000,
+            clients: 100,
+            seed: 1,
+            malformed_rate: 0.0,
+            duplicate_rate: 0.0,
+            cross_client_rate: 0.0,
+            out_of_range_rate: 0.0,
+        }
+    }
+}
+
+impl Default for GeneratorConfig {
+    fn default() -> GeneratorConfig {
+        GeneratorConfig::new()
+    }
+}
+
+// What was injected, so a run over the generated file can be checked against it
+#[derive(Debug, Default)]
+pub struct GeneratorReport {
+    pub rows: u32,
+    pub malformed: u32,
+    pub duplicates: u32,
+    pub cross_client: u32,
+    pub out_of_range: u32,
+}
+
+// xorshift64*, enough to make reproducible files from a seed without pulling in a rand crate
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(seed.max(1))
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64 <= rate
+    }
+}
+
+const MALFORMED_ROWS: [&str; 5] = [
+    "deposit,1,2",
+    "transfer,1,4000000000,1.0",
+    "deposit,abc,4000000001,1.0",
+    "withdraw,1,4000000002,1.0.0",
+    "deposit,1,4000000003,1.0,extra",
+];
+
+const OUT_OF_RANGE_ROWS: [&str; 4] = [
+    "deposit,70000,4000000010,1.0",
+    "deposit,1,4294967296,1.0",
+    "deposit,1,4000000011,-5.0",
+    "deposit,1,4000000012,100000000000000000000.0",
+];
+
+// A deposit later rows can dispute, resolve or charge back
+#[derive(Clone, Copy)]
+struct Deposit {
+    tx: u32,
+    client: u16,
+    amount: u64,
+}
+
+// Rows other than the injected ones apply without rejection: withdrawals and disputes stay within the client's
+// available funds, resolves and chargebacks name disputed deposits, locked clients get no more deposits or
+// withdrawals, and a chargeback never locks the last unlocked client.
+pub fn generate<W: Write>(config: &GeneratorConfig, out: &mut W) -> Result<GeneratorReport> {
+    let mut rng = Rng::new(config.seed);
+    let mut report = GeneratorReport::default();
+    let mut next_tx: u32 = 1;
+    let clients = config.clients.max(1) as u64;
+    let mut available = vec![0u64; clients as usize];
+    let mut locked = vec![false; clients as usize];
+    let mut unlocked = clients as usize;
+    let mut open: Vec<Deposit> = Vec::new();
+    let mut disputed: Vec<Deposit> = Vec::new();
+
+    writeln!(out, "type,client,tx,amount")?;
+    for _ in 0..config.rows {
+        report.rows += 1;
+        if rng.chance(config.malformed_rate) {
+            writeln!(out, "{}", MALFORMED_ROWS[rng.below(MALFORMED_ROWS.len() as u64) as usize])?;
+            report.malformed += 1;
+            continue;
+        }
+        if rng.chance(config.out_of_range_rate) {
+            writeln!(out, "{}", OUT_OF_RANGE_ROWS[rng.below(OUT_OF_RANGE_ROWS.len() as u64) as usize])?;
+            report.out_of_range += 1;
+            continue;
+        }
+
+        let roll = rng.below(100);
+        if roll >= 80 {
+            let named = if roll < 90 { &mut open } else { &mut disputed };
+            if !named.is_empty() {
+                let i = rng.below(named.len() as u64) as usize;
+                let deposit = named[i];
+                let owner = deposit.client as usize - 1;
+                let tx_type = match roll {
+                    80..=89 => "dispute",
+                    90..=94 => "resolve",
+                    _ => "chargeback",
+                };
+                // A dispute holds the deposit's amount, which the client may since have spent, and the last
+                // unlocked client is never locked
+                let applies = match tx_type {
+                    "dispute" => !locked[owner] && available[owner] >= deposit.amount,
+                    "resolve" => true,
+                    _ => locked[owner] || unlocked > 1,
+                };
+                if applies {
+                    let client = if rng.chance(config.cross_client_rate) {
+                        report.cross_client += 1;
+                        if clients > 1 {
+                            ((deposit.client as u64 + rng.below(clients - 1)) % clients + 1) as u16
+                        } else {
+                            deposit.client.wrapping_add(1)
+                        }
+                    } else {
+                        named.swap_remove(i);
+                        match tx_type {
+                            "dispute" => { available[owner] -= deposit.amount; disputed.push(deposit) }
+                            "resolve" => { available[owner] += deposit.amount; open.push(deposit) }
+                            _ => {
+                                unlocked -= usize::from(!locked[owner]);
+                                locked[owner] = true;
+                            }
+                        }
+                        deposit.client
+                    };
+                    writeln!(out, "{},{},{},", tx_type, client, deposit.tx)?;
+                    continue;
+                }
+            }
+        }
+
+        // Deposits and withdrawals, and the rows that found no deposit to name
+        let start = rng.below(clients);
+        let owner = (0..clients).map(|i| ((start + i) % clients) as usize).find(|&i| !locked[i])
+            .expect("A client is always left unlocked.");
+        let client = owner as u16 + 1;
+        if (50..80).contains(&roll) && available[owner] > 0 {
+            let amount = 1 + rng.below(available[owner].min(100_000_000));
+            available[owner] -= amount;
+            writeln!(out, "withdraw,{},{},{}.{:04}", client, next_tx, amount / 10_000, amount % 10_000)?;
+            next_tx += 1;
+            continue;
+        }
+        let amount = 1 + rng.below(100_000_000);
+        let tx = if next_tx > 1 && rng.chance(config.duplicate_rate) {
+            report.duplicates += 1;
+            1 + rng.below(next_tx as u64 - 1) as u32
+        } else {
+            available[owner] += amount;
+            open.push(Deposit { tx: next_tx, client, amount });
+            next_tx += 1;
+            next_tx - 1
+        };
+        writeln!(out, "deposit,{},{},{}.{:04}", client, tx, amount / 10_000, amount % 10_000)?;
+    }
+    out.flush()?;
+
+    Ok(report)
+}
+
+pub fn generate_stdout(config: &GeneratorConfig) -> Result<GeneratorReport> {
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    generate(config, &mut out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::CsvSource;
+    use crate::{read_source, AccountTransactions, ReadOptions};
+
+    fn generated(config: &GeneratorConfig) -> Vec<u8> {
+        let mut out = Vec::new();
+        generate(config, &mut out).unwrap();
+        out
+    }
+
+    #[test]
+    fn same_seed_same_rows() {
+        let config = GeneratorConfig { rows: 500, clients: 10, seed: 42, malformed_rate: 0.05, duplicate_rate: 0.05,
+                                       cross_client_rate: 0.05, out_of_range_rate: 0.05 };
+        assert_eq!(generated(&config), generated(&config));
+        assert_ne!(generated(&config), generated(&GeneratorConfig { seed: 43, ..config }));
+    }
+
+    #[test]
+    fn rows_apply_without_rejections() {
+        for seed in 1..=3 {
+            let config = GeneratorConfig { rows: 2000, clients: 20, seed, ..GeneratorConfig::new() };
+            let (mut source, generator) = CsvSource::generated(&config).unwrap();
+            let mut account_txs = AccountTransactions::new();
+            let report = read_source(&mut source, &mut account_txs, &ReadOptions::default(), None).unwrap();
+            assert_eq!((report.rows, report.applied, report.rejected, report.malformed), (generator.rows, 2000, 0, 0),
+                       "seed {}: {}", seed, report.rejection_counts());
+        }
+    }
+
+    #[test]
+    fn injected_rows_are_caught() {
+        let config = GeneratorConfig { rows: 2000, clients: 20, malformed_rate: 0.02, duplicate_rate: 0.02,
+                                       cross_client_rate: 0.02, ..GeneratorConfig::new() };
+        let (mut source, generator) = CsvSource::generated(&config).unwrap();
+        let mut account_txs = AccountTransactions::new();
+        let read_options = ReadOptions { lenient: true, ..ReadOptions::default() };
+        let report = read_source(&mut source, &mut account_txs, &read_options, None).unwrap();
+        assert_eq!(report.malformed, generator.malformed);
+        assert_eq!(report.rejected, generator.duplicates + generator.cross_client);
+    }
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeMap;
+
+#[cfg(feature = "io")]
+use crate::config::{Config, PluginConfig, RulesConfig, ScriptConfig};
+use crate::pipeline::{Middleware, Next};
+#[cfg(feature = "io")]
+use crate::rules::RulesEngine;
+use crate::{rejected, Account, Error, Rejection, Result, TransactionMessage};
+
+// What a validator decided about one message
+#[derive(Debug, Clone, PartialEq)]
+pub enum Verdict {
+    Accept,
+    // Accepts the message, attaching notes that go out with its account change to the change sinks
+    Annotate(BTreeMap<String, String>),
+    // Rejects the message as vetoed, for this reason
+    Veto(String),
+}
+
+// A business rule checked against each message before the engine applies it. account is the client's account
+// before the message, None for a client not seen yet. An Err stops processing like a storage failure would, so
+// rules that cannot decide should accept or veto rather than fail.
+pub trait Validator: Send {
+    fn name(&self) -> &str;
+    fn check(&mut self, msg: &TransactionMessage, account: Option<&Account>) -> Result<Verdict>;
+
+    // After the engine, or a validator, refused the message
+    fn rejected(&mut self, _msg: &TransactionMessage, _reason: Rejection, _account: Option<&Account>) -> Result<()> {
+        Ok(())
+    }
+
+    // After the message changed an account. before is None when the message created it.
+    fn updated(&mut self, _msg: &TransactionMessage, _before: Option<&Account>, _after: &Account) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Runs a validator in the policy stage of the pipeline, telling it afterwards how the message turned out
+pub(crate) struct ValidatorMiddleware(pub(crate) Box<dyn Validator>);
+
+impl Middleware for ValidatorMiddleware {
+    fn name(&self) -> &str {
+        self.0.name()
+    }
+
+    fn handle(&mut self, msg: &TransactionMessage, next: &mut Next<'_>) -> Result<()> {
+        let before = next.account(msg.client).cloned();
+        let result = match self.0.check(msg, before.as_ref())? {
+            Verdict::Accept => next.run(msg),
+            Verdict::Annotate(notes) => {
+                for (key, value) in notes {
+                    next.annotate(key, value);
+                }
+                next.run(msg)
+            }
+            Verdict::Veto(reason) => Err(rejected(Rejection::Vetoed,
+                                                  format!("{} vetoed {} transaction, {}, from client, {}. {}",
+                                                          self.0.name(), msg.tx_type, msg.tx, msg.client, reason))),
+        };
+        let after = next.account(msg.client);
+        if let Err(Error::PolicyViolation { reason, .. }) = &result {
+            self.0.rejected(msg, *reason, after)?;
+        }
+        if let Some(after) = after.filter(|after| before.as_ref() != Some(*after)) {
+            self.0.updated(msg, before.as_ref(), after)?;
+        }
+        result
+    }
+}
+
+// The validators configured for an engine. Each engine needs its own instances, so this is kept to load them
+// again, as the scheduler does for every run.
+#[cfg(feature = "io")]
+#[derive(Debug, Clone, Default)]
+pub struct HookConfig {
+    pub rules: Option<RulesConfig>,
+    pub plugins: Vec<PluginConfig>,
+    pub script: Option<ScriptConfig>,
+}
+
+#[cfg(feature = "io")]
+impl HookConfig {
+    pub fn new(config: &Config) -> HookConfig {
+        HookConfig { rules: config.rules.clone(), plugins: config.plugins.clone(), script: config.script.clone() }
+    }
+
+    // The rules file, plugins in the order listed, then the script
+    pub fn load(&self) -> Result<Vec<Box<dyn Validator>>> {
+        let mut validators: Vec<Box<dyn Validator>> = Vec::new();
+        if let Some(rules) = &self.rules {
+            validators.push(Box::new(RulesEngine::load(rules)?));
+        }
+        for plugin in &self.plugins {
+            validators.push(load_plugin(plugin)?);
+        }
+        if let Some(script) = &self.script {
+            validators.push(load_script(script)?);
+        }
+        Ok(validators)
+    }
+}
+
+#[cfg(feature = "plugins")]
+fn load_plugin(config: &PluginConfig) -> Result<Box<dyn Validator>> {
+    Ok(Box::new(crate::plugin::WasmPlugin::load(config)?))
+}
+
+#[cfg(all(feature = "io", not(feature = "plugins")))]
+fn load_plugin(config: &PluginConfig) -> Result<Box<dyn Validator>> {
+    Err(crate::Error::PluginError(format!(
+        "This build has no WASM plugin support for {}. Rebuild with --features plugins.", config.path)))
+}
+
+#[cfg(feature = "scripting")]
+fn load_script(config: &ScriptConfig) -> Result<Box<dyn Validator>> {
+    Ok(Box::new(crate::script::ScriptHooks::load(config)?))
+}
+
+#[cfg(all(feature = "io", not(feature = "scripting")))]
+fn load_script(config: &ScriptConfig) -> Result<Box<dyn Validator>> {
+    Err(crate::Error::PluginError(format!(
+        "This build has no Rhai scripting support for {}. Rebuild with --features scripting.", config.path)))
+}
@@ -0,0 +1,221 @@
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::{Error, Result};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// The request line and headers together, so a client cannot make serve buffer an endless line
+const MAX_HEAD_BYTES: u64 = 64 * 1024;
+
+// Plain http:// URLs only, split into the address to connect to, the Host header and the request path
+#[derive(Debug, Clone)]
+pub struct Url {
+    pub addr: String,
+    pub host: String,
+    pub path: String,
+}
+
+impl Url {
+    pub fn parse(url: &str) -> Result<Url> {
+        let rest = url.strip_prefix("http://")
+            .ok_or_else(|| Error::InvalidArgument(format!("Only http:// URLs are supported, got {}.", url)))?;
+        let (host, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        if host.is_empty() {
+            return Err(Error::InvalidArgument(format!("URL, {}, has no host.", url)));
+        }
+        let addr = if host.contains(':') { host.to_string() } else { format!("{}:80", host) };
+        Ok(Url { addr, host: host.to_string(), path: path.to_string() })
+    }
+
+    // Appends a path segment, keeping a single slash between it and the base path
+    pub fn join(&self, segment: &str) -> Url {
+        Url { path: format!("{}/{}", self.path.trim_end_matches('/'), segment), ..self.clone() }
+    }
+}
+
+// Sends one request on a fresh connection and returns the response status code
+pub fn post(url: &Url, content_type: &str, body: &[u8]) -> Result<u16> {
+    Ok(send("POST", url, &[("Content-Type", content_type)], body)?.status)
+}
+
+#[derive(Debug)]
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+// Sends one request on a fresh connection and reads the whole response, decoding a chunked body
+pub fn send(method: &str, url: &Url, headers: &[(&str, &str)], body: &[u8]) -> Result<Response> {
+    let mut stream = TcpStream::connect(&url.addr)?;
+    stream.set_read_timeout(Some(TIMEOUT))?;
+    stream.set_write_timeout(Some(TIMEOUT))?;
+    write!(stream, "{} {} HTTP/1.1\r\nHost: {}\r\n", method, url.path, url.host)?;
+    for (name, value) in headers {
+        write!(stream, "{}: {}\r\n", name, value)?;
+    }
+    write!(stream, "Content-Length: {}\r\nConnection: close\r\n\r\n", body.len())?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut rdr = BufReader::new(stream);
+    let mut status_line = String::new();
+    rdr.read_line(&mut status_line)?;
+    let status = status_line.split_whitespace().nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| Error::SinkError(format!("Unexpected HTTP response from {}: {}", url.addr, status_line.trim())))?;
+
+    let (mut length, mut chunked) = (None, false);
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if rdr.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let (name, value) = (name.trim().to_ascii_lowercase(), value.trim());
+            if name == "content-length" {
+                length = value.parse::<usize>().ok();
+            } else if name == "transfer-encoding" && value.eq_ignore_ascii_case("chunked") {
+                chunked = true;
+            }
+        }
+    }
+
+    let mut response = Response { status, body: Vec::new() };
+    if chunked {
+        loop {
+            line.clear();
+            rdr.read_line(&mut line)?;
+            let size = usize::from_str_radix(line.trim().split(';').next().unwrap_or(""), 16)
+                .map_err(|_| Error::SinkError(format!("Bad chunk size from {}: {}", url.addr, line.trim())))?;
+            if size == 0 {
+                break;
+            }
+            let start = response.body.len();
+            response.body.resize(start + size, 0);
+            rdr.read_exact(&mut response.body[start..])?;
+            line.clear();
+            rdr.read_line(&mut line)?;
+        }
+    } else if let Some(length) = length {
+        response.body.resize(length, 0);
+        rdr.read_exact(&mut response.body)?;
+    } else {
+        rdr.read_to_end(&mut response.body)?;
+    }
+    Ok(response)
+}
+
+// A request read by the server. Header names are lowercased.
+#[derive(Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+}
+
+// A body longer than max_body is refused with Error::LimitExceeded before any of it is read
+pub fn read_request<R: BufRead>(rdr: &mut R, max_body: usize) -> Result<Request> {
+    let mut head = rdr.by_ref().take(MAX_HEAD_BYTES);
+    let mut line = String::new();
+    head.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (method, path) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Err(Error::ParseError(format!("Malformed HTTP request line: {}", line.trim()))),
+    };
+
+    let mut headers = Vec::new();
+    loop {
+        line.clear();
+        if head.read_line(&mut line)? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_ascii_lowercase(), value.trim().to_string()));
+        }
+    }
+    if head.limit() == 0 {
+        return Err(Error::LimitExceeded(format!("Request headers exceed {} bytes.", MAX_HEAD_BYTES)));
+    }
+
+    let mut request = Request { method, path, headers, body: Vec::new() };
+    let length: usize = match request.header("content-length") {
+        Some(length) => length.parse().map_err(|_| Error::ParseError(format!("Bad Content-Length: {}", length)))?,
+        None => 0,
+    };
+    if length > max_body {
+        return Err(Error::LimitExceeded(format!("Request body of {} bytes exceeds the {} byte limit.", length, max_body)));
+    }
+    request.body.resize(length, 0);
+    rdr.read_exact(&mut request.body)?;
+    Ok(request)
+}
+
+pub fn write_response<W: Write>(out: &mut W, status: u16, content_type: &str, body: &[u8]) -> Result<()> {
+    write!(out, "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+           status, reason_phrase(status), content_type, body.len())?;
+    out.write_all(body)?;
+    out.flush()?;
+    Ok(())
+}
+
+fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        101 => "Switching Protocols",
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        503 => "Service Unavailable",
+        _ => "Internal Server Error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_a_body_within_the_limit() {
+        let raw = b"POST /transactions HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello";
+        let request = read_request(&mut &raw[..], 5).unwrap();
+        assert_eq!((request.method.as_str(), request.path.as_str()), ("POST", "/transactions"));
+        assert_eq!(request.header("content-length"), Some("5"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn refuses_a_body_over_the_limit_before_reading_it() {
+        let raw = b"POST /transactions HTTP/1.1\r\nContent-Length: 18446744073709551615\r\n\r\n";
+        assert!(matches!(read_request(&mut &raw[..], 1024), Err(Error::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn refuses_endless_headers() {
+        let mut raw = b"GET /accounts HTTP/1.1\r\nX-Padding: ".to_vec();
+        raw.resize(MAX_HEAD_BYTES as usize * 2, b'a');
+        assert!(matches!(read_request(&mut &raw[..], 1024), Err(Error::LimitExceeded(_))));
+    }
+
+    #[test]
+    fn refuses_a_malformed_request_line() {
+        assert!(matches!(read_request(&mut &b"\r\n\r\n"[..], 1024), Err(Error::ParseError(_))));
+    }
+}
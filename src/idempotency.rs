@@ -0,0 +1,77 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::IdempotencyConfig;
+use crate::TransactionMessage;
+
+// Keys of the messages already handled, kept with the state so a row submitted again, in this run or a later one, is
+// skipped instead of applied twice. A key is kept whether its message was applied or rejected.
+#[derive(Debug, Default)]
+pub struct IdempotencyKeys {
+    // Each key with the tx_time of the message first handled under it
+    keys: HashMap<String, u32>,
+    // The keys added for the message handled last, for stores that write each message as it is handled
+    #[cfg(any(feature = "postgres", feature = "sled"))]
+    added: Vec<String>,
+    #[cfg(any(feature = "postgres", feature = "sled"))]
+    added_time: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct IdempotencyRecord {
+    pub(crate) key: String,
+    pub(crate) tx_time: u32,
+}
+
+impl IdempotencyKeys {
+    pub fn contains(&self, key: &str) -> bool {
+        self.keys.contains_key(key)
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub(crate) fn insert(&mut self, key: String, tx_time: u32) {
+        #[cfg(any(feature = "postgres", feature = "sled"))]
+        {
+            if self.added_time != tx_time {
+                self.added.clear();
+                self.added_time = tx_time;
+            }
+            self.added.push(key.clone());
+        }
+        self.keys.insert(key, tx_time);
+    }
+
+    // The keys the message at tx_time was handled under, when it is the message handled last
+    #[cfg(any(feature = "postgres", feature = "sled"))]
+    pub(crate) fn added_with(&self, tx_time: u32) -> &[String] {
+        if self.added_time == tx_time { &self.added } else { &[] }
+    }
+
+    // In the order the keys were added
+    pub(crate) fn records(&self) -> Vec<IdempotencyRecord> {
+        let mut records: Vec<IdempotencyRecord> = self.keys.iter()
+            .map(|(key, tx_time)| IdempotencyRecord { key: key.clone(), tx_time: *tx_time })
+            .collect();
+        records.sort_by_key(|record| record.tx_time);
+        records
+    }
+
+    pub(crate) fn restore(&mut self, records: Vec<IdempotencyRecord>) {
+        self.keys.extend(records.into_iter().map(|record| (record.key, record.tx_time)));
+    }
+}
+
+// The key a row is checked under: its idempotency_key column, or with tx_keys its type and tx id. None for a row
+// that is not checked. An empty column counts as no key.
+pub(crate) fn key_for(column: Option<String>, msg: &TransactionMessage, config: &IdempotencyConfig) -> Option<String> {
+    column.filter(|key| !key.is_empty())
+        .or_else(|| if config.tx_keys { Some(format!("{}:{}", msg.tx_type, msg.tx)) } else { None })
+}
@@ -0,0 +1,187 @@
+use std::collections::HashMap;
+
+use crate::error::Rejection;
+use crate::{rejected, rounding, AccountTransactions, Amount, Result};
+
+// What an account type earns each period on its available funds. Input rows carry no timestamps, so periods are
+// counted in rows, as dispute expiry counts age, and there is no day count to apply.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterestRate {
+    // Parts per million of available per period, so a percentage read like an amount, 0.25 being 2500
+    pub rate_ppm: u64,
+    pub period_rows: u32,
+}
+
+impl InterestRate {
+    // Periods are numbered from 0, tx_times 1 to period_rows making the first
+    pub fn period(&self, tx_time: u32) -> u32 {
+        tx_time.saturating_sub(1) / self.period_rows.max(1)
+    }
+
+    // Rounded down to the subunit
+    pub fn accrual(&self, available: Amount) -> Result<Amount> {
+        rounding::apply_rate(available, self.rate_ppm).map(|(accrual, _)| accrual)
+    }
+}
+
+// Interest credited to one account at the end of a period
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Accrual {
+    pub client: u16,
+    pub account_type: String,
+    pub period: u32,
+    pub amount: Amount,
+}
+
+// The last period each account type was paid for, and what was paid since the engine started
+#[derive(Debug, Clone, Default)]
+pub(crate) struct InterestLedger {
+    paid_through: HashMap<String, u32>,
+    accruals: Vec<Accrual>,
+}
+
+impl AccountTransactions {
+    // Accruals posted since the engine started, in the order they were posted
+    pub fn accruals(&self) -> &[Accrual] {
+        &self.interest.accruals
+    }
+
+    // Periods under way when the account types were set are paid at their end, earlier ones never
+    pub(crate) fn start_interest(&mut self) {
+        let now = self.next_tx_time() - 1;
+        self.interest.paid_through = self.account_types.interest_rates()
+            .map(|(name, rate)| (name.to_string(), rate.period(now)))
+            .collect();
+    }
+
+    // Called before each message, posting interest for the periods that ended before its row on available funds as
+    // they stand at the boundary. Locked accounts earn nothing. An accrual out of range for an amount is not posted,
+    // the others are, and the message is refused as amount_overflow naming the first such account.
+    pub(crate) fn accrue_interest(&mut self, tx_time: u32) -> Result<()> {
+        if self.interest.paid_through.is_empty() {
+            return Ok(());
+        }
+        let due: Vec<(String, InterestRate, u32)> = self.account_types.interest_rates()
+            .filter_map(|(name, rate)| {
+                let paid_through = self.interest.paid_through.get(name).copied()?;
+                (rate.period(tx_time) > paid_through).then(|| (name.to_string(), *rate, paid_through))
+            })
+            .collect();
+        let mut overflow = None;
+        for (name, rate, paid_through) in due {
+            let mut clients: Vec<u16> = self.account_types.clients_of(&name).collect();
+            clients.sort_unstable();
+            for ended in paid_through..rate.period(tx_time) {
+                for client in &clients {
+                    let acct = match self.account_client.get_mut(client) {
+                        Some(acct) if !acct.locked => acct,
+                        _ => continue,
+                    };
+                    // The total has to fit too
+                    let accrued = rounding::apply_rate(acct.available, rate.rate_ppm).ok().and_then(|(amount, dropped)| {
+                        let available = acct.available.checked_add(amount)?;
+                        available.checked_add(acct.held)?.checked_add(acct.escrow)?;
+                        Some((amount, dropped, available))
+                    });
+                    let (amount, dropped, available) = match accrued {
+                        Some(accrued) => accrued,
+                        None => {
+                            overflow.get_or_insert((*client, name.clone(), ended));
+                            continue;
+                        }
+                    };
+                    self.rounding.interest.add(dropped);
+                    if amount == 0 {
+                        continue;
+                    }
+                    acct.available = available;
+                    acct.total = acct.available + acct.held + acct.escrow;
+                    self.touched.insert(*client);
+                    self.interest.accruals.push(Accrual { client: *client, account_type: name.clone(), period: ended,
+                                                          amount });
+                }
+            }
+            self.interest.paid_through.insert(name, rate.period(tx_time));
+        }
+        match overflow {
+            Some((client, name, period)) => Err(rejected(Rejection::AmountOverflow, format!(
+                "Interest for client, {}, of account type {} for period {} is out of range for an amount and was not \
+                 posted. Ignored transaction at row {}.", client, name, period, tx_time))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::account_types::{AccountPolicy, AccountTypes};
+    use crate::testing::{apply, message, units};
+    use crate::{Error, TransactionType};
+
+    fn savings(clients: &[u16]) -> AccountTransactions {
+        let mut account_txs = AccountTransactions::new();
+        let mut account_types = AccountTypes::new();
+        let interest = Some(InterestRate { rate_ppm: 10_000, period_rows: 2 });
+        account_types.add_type("savings", AccountPolicy { provisional_credit: false, interest });
+        for client in clients {
+            account_types.assign(*client, "savings").unwrap();
+        }
+        account_txs.set_account_types(account_types);
+        account_txs
+    }
+
+    #[test]
+    fn period_rounds_rows_into_periods() {
+        let rate = InterestRate { rate_ppm: 1, period_rows: 3 };
+        assert_eq!(rate.period(1), 0);
+        assert_eq!(rate.period(3), 0);
+        assert_eq!(rate.period(4), 1);
+        assert_eq!(InterestRate { rate_ppm: 1, period_rows: 0 }.period(5), 4);
+    }
+
+    #[test]
+    fn accrues_at_period_end_and_touches_credited_accounts() {
+        let mut account_txs = savings(&[1, 2]);
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(100))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(50))).unwrap();
+        assert!(account_txs.accruals().is_empty());
+
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 3, 3, units(10))).unwrap();
+        let paid: Vec<(u16, Amount)> = account_txs.accruals().iter().map(|accrual| (accrual.client, accrual.amount))
+            .collect();
+        assert_eq!(paid, vec![(1, units(1)), (2, 5_000)]);
+        assert_eq!(account_txs.get_account(1).unwrap().available, units(101));
+        assert_eq!(account_txs.touched_accounts().collect::<Vec<_>>(), vec![1, 2, 3]);
+
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 3, 4, units(10))).unwrap();
+        assert_eq!(account_txs.touched_accounts().collect::<Vec<_>>(), vec![3]);
+    }
+
+    #[test]
+    fn refuses_the_row_when_an_accrual_overflows_and_pays_the_rest() {
+        let mut account_txs = savings(&[1, 2]);
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, Amount::MAX - 1)).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(100))).unwrap();
+        let refused = apply(&mut account_txs, message(TransactionType::DEPOSIT, 3, 3, units(1)));
+        assert!(matches!(refused, Err(Error::PolicyViolation { reason: Rejection::AmountOverflow, .. })));
+        assert!(account_txs.get_account(3).is_none());
+        assert_eq!(account_txs.get_account(1).unwrap().available, Amount::MAX - 1);
+        assert_eq!(account_txs.get_account(2).unwrap().available, units(101));
+
+        // The period was paid, so the next row goes through
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 3, 4, units(1))).unwrap();
+        assert_eq!(account_txs.accruals().len(), 1);
+    }
+
+    #[test]
+    fn skips_accruals_rounding_to_nothing() {
+        let mut account_txs = savings(&[1]);
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, 99)).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(1))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 3, units(1))).unwrap();
+        assert!(account_txs.accruals().is_empty());
+        assert_eq!(account_txs.get_account(1).unwrap().available, 99);
+        assert_eq!(account_txs.touched_accounts().collect::<Vec<_>>(), vec![2]);
+    }
+}
@@ -0,0 +1,39 @@
+use std::sync::OnceLock;
+
+use crate::CancelToken;
+
+static TOKEN: OnceLock<CancelToken> = OnceLock::new();
+
+#[cfg(unix)]
+mod sys {
+    use std::os::raw::c_int;
+
+    pub const SIGINT: c_int = 2;
+    pub const SIGTERM: c_int = 15;
+
+    extern "C" {
+        pub fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+        fn _exit(status: c_int) -> !;
+    }
+
+    // A second signal means the caller is done waiting for the current row and the state save to finish
+    pub extern "C" fn handle(_signum: c_int) {
+        if let Some(token) = super::TOKEN.get() {
+            if token.swap_cancel() {
+                unsafe { _exit(130) }
+            }
+        }
+    }
+}
+
+// Replaces the default SIGINT and SIGTERM handling, which kills the process wherever it is, with cancelling the
+// returned token. Only the binary installs this, so embedding programs keep their own handling.
+pub fn install() -> CancelToken {
+    let token = TOKEN.get_or_init(CancelToken::new).clone();
+    #[cfg(unix)]
+    unsafe {
+        sys::signal(sys::SIGINT, sys::handle);
+        sys::signal(sys::SIGTERM, sys::handle);
+    }
+    token
+}
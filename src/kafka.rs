@@ -0,0 +1,324 @@
+use std::collections::VecDeque;
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::change::AccountChange;
+use crate::config::{KafkaConfig, KafkaInputConfig};
+use crate::http::{self, Url};
+use crate::observer::EngineObserver;
+use crate::read::{input_record_to_transaction, TxInputRecord};
+use crate::rounding::RoundingMode;
+use crate::source::{SourceRow, TransactionSource};
+use crate::state::Store;
+use crate::{read_source, AccountTransactions, CancelToken, Error, ReadOptions, ReadReport, Result};
+
+const CONTENT_TYPE: &str = "application/vnd.kafka.json.v2+json";
+const V2_CONTENT_TYPE: &str = "application/vnd.kafka.v2+json";
+
+// Publishes account changes to a Kafka topic through a Kafka REST Proxy, keyed by client so that every change
+// to one account lands on the same partition in order
+pub struct KafkaSink {
+    url: Url,
+    batch_size: usize,
+    pending: Vec<serde_json::Value>,
+    published: u32,
+}
+
+#[derive(Serialize)]
+struct ProduceRequest<'a> {
+    records: &'a [serde_json::Value],
+}
+
+impl KafkaSink {
+    pub fn new(config: &KafkaConfig) -> Result<KafkaSink> {
+        let url = Url::parse(&config.rest_url)?.join("topics").join(&config.topic);
+        Ok(KafkaSink { url, batch_size: config.batch_size.max(1), pending: Vec::new(), published: 0 })
+    }
+
+    pub fn publish(&mut self, change: &AccountChange) -> Result<()> {
+        self.pending.push(json!({ "key": change.client.to_string(), "value": change }));
+        if self.pending.len() >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn published(&self) -> u32 {
+        self.published
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let body = serde_json::to_vec(&ProduceRequest { records: &self.pending })?;
+        let status = http::post(&self.url, CONTENT_TYPE, &body)?;
+        if !(200..300).contains(&status) {
+            return Err(Error::SinkError(format!("Kafka REST Proxy at {} answered {} for {} records.",
+                                                self.url.addr, status, self.pending.len())));
+        }
+        self.published += self.pending.len() as u32;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+impl EngineObserver for KafkaSink {
+    fn name(&self) -> &str {
+        "kafka"
+    }
+
+    fn account_changed(&mut self, change: &AccountChange) -> Result<()> {
+        self.publish(change)
+    }
+}
+
+#[derive(Deserialize)]
+struct ConsumerInstance {
+    base_uri: String,
+}
+
+#[derive(Deserialize)]
+struct PartitionInfo {
+    partition: u32,
+}
+
+#[derive(Deserialize)]
+struct ConsumedRecord {
+    topic: String,
+    partition: u32,
+    offset: u64,
+    value: serde_json::Value,
+}
+
+// A REST Proxy consumer instance assigned every partition of the input topic. Offsets are never committed to
+// Kafka. The consumer seeks to the offsets saved with the engine state instead, so the state decides where
+// reading resumes. Each record's row carries the offset after it, and the last row of each poll a checkpoint.
+// Rows end once cancel is set.
+struct KafkaSource {
+    instance: Url,
+    topic: String,
+    poll_interval: Duration,
+    cancel: Option<CancelToken>,
+    polled: VecDeque<ConsumedRecord>,
+    rounding: RoundingMode,
+}
+
+impl KafkaSource {
+    fn open(config: &KafkaInputConfig, account_txs: &AccountTransactions, cancel: Option<CancelToken>)
+            -> Result<KafkaSource> {
+        let rest_url = Url::parse(&config.rest_url)?;
+        let create = json!({ "format": "json", "auto.offset.reset": "earliest", "auto.commit.enable": "false" });
+        let body = call("POST", &rest_url.join("consumers").join(&config.group), Some(&create))?;
+        let instance: ConsumerInstance = serde_json::from_slice(&body)?;
+        let source = KafkaSource {
+            instance: Url::parse(&instance.base_uri)?,
+            topic: config.topic.clone(),
+            poll_interval: Duration::from_millis(config.poll_interval_ms),
+            cancel,
+            polled: VecDeque::new(),
+            rounding: RoundingMode::default(),
+        };
+
+        let body = call("GET", &rest_url.join("topics").join(&config.topic).join("partitions"), None)?;
+        let partitions: Vec<PartitionInfo> = serde_json::from_slice(&body)?;
+        let assigned: Vec<serde_json::Value> = partitions.iter()
+            .map(|info| json!({ "topic": config.topic, "partition": info.partition }))
+            .collect();
+        call("POST", &source.instance.join("assignments"), Some(&json!({ "partitions": assigned })))?;
+
+        let positions: Vec<serde_json::Value> = partitions.iter()
+            .filter_map(|info| account_txs.source_offsets.get(&(config.topic.clone(), info.partition))
+                .map(|offset| json!({ "topic": config.topic, "partition": info.partition, "offset": offset })))
+            .collect();
+        if !positions.is_empty() {
+            call("POST", &source.instance.join("positions"), Some(&json!({ "offsets": positions })))?;
+        }
+        Ok(source)
+    }
+
+    fn poll(&self) -> Result<Vec<ConsumedRecord>> {
+        let response = http::send("GET", &self.instance.join("records"), &[("Accept", CONTENT_TYPE)], &[])?;
+        if !(200..300).contains(&response.status) {
+            return Err(Error::SinkError(format!("Kafka REST Proxy at {} answered {} polling {}.",
+                                                self.instance.addr, response.status, self.topic)));
+        }
+        Ok(serde_json::from_slice(&response.body)?)
+    }
+
+    fn row(&self, record: ConsumedRecord) -> SourceRow {
+        let at = format!("{} partition {} offset {}", record.topic, record.partition, record.offset);
+        let parsed = serde_json::from_value::<TxInputRecord>(record.value)
+            .map_err(|e| Error::ParseError(format!("Record at {}. {}", at, e)))
+            .and_then(|input| input_record_to_transaction(&input, 0, self.rounding)
+                .map(|msg| (msg, input.idempotency_key)));
+        let (parsed, idempotency_key) = match parsed {
+            Ok((msg, key)) => (Ok(msg), key),
+            Err(e) => (Err(e), None),
+        };
+        SourceRow {
+            parsed,
+            idempotency_key,
+            start: 0,
+            end: 0,
+            resume: Some(((record.topic, record.partition), record.offset + 1)),
+            checkpoint: self.polled.is_empty(),
+        }
+    }
+}
+
+impl TransactionSource for KafkaSource {
+    fn describe(&self) -> String {
+        format!("Kafka topic {}", self.topic)
+    }
+
+    fn next_row(&mut self) -> Option<SourceRow> {
+        loop {
+            if let Some(record) = self.polled.pop_front() {
+                return Some(self.row(record));
+            }
+            if self.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+                return None;
+            }
+            match self.poll() {
+                Ok(records) if records.is_empty() => thread::sleep(self.poll_interval),
+                Ok(records) => self.polled.extend(records),
+                Err(e) => return Some(SourceRow { parsed: Err(e), idempotency_key: None, start: 0, end: 0, resume: None,
+                                                  checkpoint: false }),
+            }
+        }
+    }
+
+    fn set_rounding(&mut self, rounding: RoundingMode) {
+        self.rounding = rounding;
+    }
+}
+
+impl Drop for KafkaSource {
+    fn drop(&mut self) {
+        if let Err(e) = call("DELETE", &self.instance, None) {
+            diag!("Failed to delete Kafka consumer instance {}. {}", self.instance.path, e);
+        }
+    }
+}
+
+fn call(method: &str, url: &Url, body: Option<&serde_json::Value>) -> Result<Vec<u8>> {
+    let body = body.map(serde_json::to_vec).transpose()?.unwrap_or_default();
+    let response = http::send(method, url, &[("Content-Type", V2_CONTENT_TYPE), ("Accept", V2_CONTENT_TYPE)], &body)?;
+    if !(200..300).contains(&response.status) {
+        return Err(Error::SinkError(format!("Kafka REST Proxy at {} answered {} for {} {}. {}", url.addr,
+                                            response.status, method, url.path, String::from_utf8_lossy(&response.body))));
+    }
+    Ok(response.body)
+}
+
+// Applies transactions from the Kafka input topic until read_options.cancel is set. After each poll, the new
+// state and the offsets after the records it applied are saved in one write, so a restart resumes exactly where
+// the saved state left off, neither dropping records nor applying any twice. The store must save atomically. A
+// malformed record is skipped for good, its offset saved like any other.
+pub fn consume(config: &KafkaInputConfig, account_txs: &mut AccountTransactions, store: &mut dyn Store,
+               read_options: &ReadOptions) -> Result<ReadReport> {
+    check_store(store)?;
+    let mut source = KafkaSource::open(config, account_txs, read_options.cancel.clone())?;
+    diag!("Consuming {} from {}.", config.topic, config.rest_url);
+    let read_options = ReadOptions { lenient: true, ..read_options.clone() };
+    let mut report = read_source(&mut source, account_txs, &read_options, Some(store))?;
+    // Only a limit stops the consumer short of being cancelled
+    report.cancelled |= report.stopped.is_none();
+    Ok(report)
+}
+
+// Refuses a store that cannot save offsets with the state, for consume to check before loading any state
+pub fn check_store(store: &dyn Store) -> Result<()> {
+    if !store.saves_atomically() {
+        return Err(Error::InvalidArgument(format!(
+            "{} cannot save state and offsets together. Use --store snapshot:<file> to consume from Kafka.",
+            store.describe())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::BufReader;
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::snapshot::SnapshotStore;
+    use crate::state::StateStore;
+    use crate::testing::temp_path;
+
+    // Answers as a REST Proxy would, handing out the records on the first poll and cancelling the consumer on the
+    // next. Returns the proxy's URL.
+    fn stub_proxy(records: serde_json::Value, cancel: CancelToken) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let base_uri = format!("{}/consumers/tx_acct/instances/test", url);
+        thread::spawn(move || {
+            let mut records = Some(records);
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let request = http::read_request(&mut BufReader::new(&stream), 1 << 20).unwrap();
+                let body = match (request.method.as_str(), request.path.as_str()) {
+                    ("POST", "/consumers/tx_acct") => json!({ "base_uri": base_uri }),
+                    ("GET", "/topics/transactions/partitions") => json!([{ "partition": 0 }]),
+                    ("GET", path) if path.ends_with("/records") => records.take().unwrap_or_else(|| {
+                        cancel.cancel();
+                        json!([])
+                    }),
+                    _ => json!({}),
+                };
+                http::write_response(&mut stream, 200, V2_CONTENT_TYPE, body.to_string().as_bytes()).unwrap();
+                if request.method == "DELETE" {
+                    break;
+                }
+            }
+        });
+        url
+    }
+
+    fn input(rest_url: String) -> KafkaInputConfig {
+        KafkaInputConfig { rest_url, topic: String::from("transactions"), group: String::from("tx_acct"), poll_interval_ms: 0 }
+    }
+
+    #[test]
+    fn saves_state_and_offsets_after_each_poll() {
+        let cancel = CancelToken::new();
+        let record = |offset: u64, value: serde_json::Value| {
+            json!({ "topic": "transactions", "partition": 0, "offset": offset, "value": value })
+        };
+        let records = json!([
+            record(7, json!({ "type": "deposit", "client": 1, "tx": 1, "amount": "2.5" })),
+            record(8, json!({ "type": "deposit", "client": 1 })),
+            record(9, json!({ "type": "withdraw", "client": 1, "tx": 2, "amount": "1.0" })),
+        ]);
+        let config = input(stub_proxy(records, cancel.clone()));
+        let path = temp_path("kafka-snapshot");
+        let mut store = SnapshotStore::new(&path);
+        let mut account_txs = AccountTransactions::new();
+        let read_options = ReadOptions { cancel: Some(cancel), ..ReadOptions::default() };
+
+        let report = consume(&config, &mut account_txs, &mut store, &read_options).unwrap();
+        assert_eq!((report.rows, report.applied, report.malformed), (3, 2, 1));
+        assert!(report.cancelled);
+        let mut saved = AccountTransactions::new();
+        store.load(&mut saved).unwrap();
+        assert_eq!(saved.account_client[&1].available, 15_000);
+        // The malformed record is not read again either
+        assert_eq!(saved.source_offsets[&(String::from("transactions"), 0)], 10);
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn refuses_a_store_that_saves_piecemeal_before_connecting() {
+        let mut store = StateStore::new(temp_path("kafka-dir"));
+        let read_options = ReadOptions { cancel: Some(CancelToken::new()), ..ReadOptions::default() };
+        // Nothing listens on the discard port, so reaching the proxy would fail differently
+        let result = consume(&input(String::from("http://127.0.0.1:9")), &mut AccountTransactions::new(), &mut store,
+                             &read_options);
+        assert!(matches!(result, Err(Error::InvalidArgument(_))));
+    }
+}
@@ -0,0 +1,499 @@
+use std::convert::TryInto;
+
+use sled::transaction::{ConflictableTransactionError, TransactionError, Transactional};
+use sled::{Db, Tree};
+
+use crate::admin::AdminAction;
+use crate::encryption;
+use crate::fee_schedule::FeeUsageRecord;
+use crate::idempotency::IdempotencyRecord;
+use crate::lifecycle;
+use crate::rounding::RemainderRecord;
+use crate::state::Store;
+use crate::withdrawal_limits::WithdrawnRecord;
+use crate::{Account, AccountTransactions, Error, Result, TransactionMessage, TransactionType, Tx, TxState};
+
+// Embedded store on sled, for single-node deployments without a database server. Keys are big-endian ids so
+// each tree iterates in id order. Values are fixed-width big-endian records, encrypted when a key is configured.
+pub struct SledStore {
+    db: Db,
+    path: String,
+    accounts: Tree,
+    transactions: Tree,
+    messages: Tree,
+    meta: Tree,
+    // Idempotency key bytes to the big-endian tx_time of the message handled under it
+    idempotency_keys: Tree,
+    // Big-endian client to what it has withdrawn towards its cumulative limit
+    withdrawn: Tree,
+    // Big-endian client, type code and period to the count and fees of its transactions under a fee schedule
+    fee_usage: Tree,
+    // Each remainder of the rounding account by kind, to how many amounts were inexact and the millionths dropped
+    rounding: Tree,
+}
+
+const LAST_TX_TIME: &[u8] = b"last_tx_time";
+
+impl SledStore {
+    pub fn open(path: &str) -> Result<SledStore> {
+        let db = sled::open(path).map_err(|e| sled_error("open", e))?;
+        let tree = |name: &str| db.open_tree(name).map_err(|e| sled_error("open", e));
+        Ok(SledStore {
+            accounts: tree("accounts")?,
+            transactions: tree("transactions")?,
+            messages: tree("messages")?,
+            meta: tree("meta")?,
+            idempotency_keys: tree("idempotency_keys")?,
+            withdrawn: tree("withdrawn")?,
+            fee_usage: tree("fee_usage")?,
+            rounding: tree("rounding")?,
+            path: path.to_string(),
+            db,
+        })
+    }
+}
+
+impl Store for SledStore {
+    fn load(&mut self, account_txs: &mut AccountTransactions) -> Result<()> {
+        for entry in self.accounts.iter() {
+            let (key, value) = entry.map_err(|e| sled_error("load", e))?;
+            let acct = decode_account(u16::from_be_bytes(fixed(&key)?), &open("accounts", &key, &value)?)?;
+            account_txs.account_client.insert(acct.client, acct);
+        }
+        for entry in self.transactions.iter() {
+            let (key, value) = entry.map_err(|e| sled_error("load", e))?;
+            let tx = decode_tx(u32::from_be_bytes(fixed(&key)?), &open("transactions", &key, &value)?)?;
+            account_txs.txs_txid.insert(tx.tx, tx);
+        }
+        for entry in self.messages.iter() {
+            let (key, value) = entry.map_err(|e| sled_error("load", e))?;
+            let msg = decode_message(u32::from_be_bytes(fixed(&key)?), &open("messages", &key, &value)?)?;
+            account_txs.tx_msgs_time.insert(msg.tx_time, msg);
+        }
+        let mut keys = Vec::new();
+        for entry in self.idempotency_keys.iter() {
+            let (key, value) = entry.map_err(|e| sled_error("load", e))?;
+            let tx_time = u32::from_be_bytes(fixed(&open("idempotency_keys", &key, &value)?)?);
+            let key = String::from_utf8(key.to_vec())
+                .map_err(|_| Error::StorageError(String::from("sled idempotency key is not UTF-8.")))?;
+            keys.push(IdempotencyRecord { key, tx_time });
+        }
+        account_txs.idempotency_keys.restore(keys);
+        let mut withdrawn = Vec::new();
+        for entry in self.withdrawn.iter() {
+            let (key, value) = entry.map_err(|e| sled_error("load", e))?;
+            let amount = u64::from_be_bytes(fixed(&open("withdrawn", &key, &value)?)?);
+            withdrawn.push(WithdrawnRecord { client: u16::from_be_bytes(fixed(&key)?), amount });
+        }
+        account_txs.withdrawal_limits.restore(withdrawn);
+        let mut fee_usage = Vec::new();
+        for entry in self.fee_usage.iter() {
+            let (key, value) = entry.map_err(|e| sled_error("load", e))?;
+            fee_usage.push(decode_fee_usage(&key, &open("fee_usage", &key, &value)?)?);
+        }
+        account_txs.fee_schedules.restore(fee_usage);
+        let mut rounding = Vec::new();
+        for entry in self.rounding.iter() {
+            let (key, value) = entry.map_err(|e| sled_error("load", e))?;
+            let value: [u8; 24] = fixed(&open("rounding", &key, &value)?)?;
+            let kind = String::from_utf8(key.to_vec())
+                .map_err(|_| Error::StorageError(String::from("sled rounding remainder is not UTF-8.")))?;
+            rounding.push(RemainderRecord { kind, inexact: u64::from_be_bytes(fixed(&value[..8])?),
+                                            subunits: u64::from_be_bytes(fixed(&value[8..16])?),
+                                            carry_ppm: u64::from_be_bytes(fixed(&value[16..])?) });
+        }
+        account_txs.rounding.restore(rounding)?;
+        // Message times already used, even by messages since pruned, must not be handed out again
+        if let Some(value) = self.meta.get(LAST_TX_TIME).map_err(|e| sled_error("load", e))? {
+            let last_tx_time = u32::from_be_bytes(fixed(&value)?);
+            account_txs.compacted_through = account_txs.compacted_through.max(last_tx_time);
+        }
+        Ok(())
+    }
+
+    // The accounts, transaction, message and idempotency key a message touched are written in one sled transaction
+    fn record(&mut self, account_txs: &AccountTransactions, msg: &TransactionMessage) -> Result<()> {
+        let tx = account_txs.txs_txid.get(&msg.tx).filter(|tx| tx.client == msg.client);
+        // Sealed before the transaction, which sled may run more than once
+        let tx_time = msg.tx_time.to_be_bytes();
+        let message = seal("messages", &tx_time, encode_message(msg))?;
+        let added = account_txs.idempotency_keys.added_with(msg.tx_time).iter()
+            .map(|key| seal("idempotency_keys", key.as_bytes(), tx_time.to_vec()).map(|value| (key, value)))
+            .collect::<Result<Vec<_>>>()?;
+        // A transfer touches the account it pays too, and interest or a dispute expiry may credit others
+        let accts = account_txs.touched_accounts()
+            .filter_map(|client| account_txs.account_client.get(&client))
+            .map(|acct| seal("accounts", &acct.client.to_be_bytes(), encode_account(acct)).map(|value| (acct.client, value)))
+            .collect::<Result<Vec<_>>>()?;
+        // A deposit may also sweep from charged back transactions in recovery, and any row may expire disputes
+        let expired = account_txs.expired_by(msg).filter_map(|expired| account_txs.txs_txid.get(&expired.tx));
+        let txs = tx.into_iter().chain(account_txs.swept_by(msg)).chain(expired)
+            .map(|tx| seal("transactions", &tx.tx.to_be_bytes(), encode_tx(tx)).map(|value| (tx.tx, value)))
+            .collect::<Result<Vec<_>>>()?;
+        // A withdrawal counts towards its client's cumulative limit
+        let withdrawn = match account_txs.withdrawal_limits.withdrawn(msg.client) {
+            amount if amount > 0 && msg.tx_type == TransactionType::WITHDRAWAL =>
+                Some(seal("withdrawn", &msg.client.to_be_bytes(), amount.to_be_bytes().to_vec())?),
+            _ => None,
+        };
+        let fee_usage = account_txs.fee_schedules.record_of(msg)
+            .map(|record| {
+                let (key, value) = encode_fee_usage(&record);
+                seal("fee_usage", &key, value).map(|value| (key, value))
+            })
+            .transpose()?;
+        // Fees and interest may have dropped a remainder
+        let remainders = account_txs.rounding.records().into_iter()
+            .map(|record| {
+                let value = [record.inexact.to_be_bytes(), record.subunits.to_be_bytes(), record.carry_ppm.to_be_bytes()]
+                    .concat();
+                seal("rounding", record.kind.as_bytes(), value).map(|value| (record.kind, value))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let trees = (&self.accounts, &self.transactions, &self.messages, &self.meta, &self.idempotency_keys,
+                     &self.withdrawn, &self.fee_usage, &self.rounding);
+        let result: std::result::Result<(), TransactionError<()>> = trees.transaction(|(accounts, transactions, messages, meta, keys, limits, usage, rounding)| {
+            messages.insert(&msg.tx_time.to_be_bytes(), message.as_slice())?;
+            for (key, value) in &added {
+                keys.insert(key.as_bytes(), value.as_slice())?;
+            }
+            let last = meta.get(LAST_TX_TIME)?.and_then(|value| fixed(&value).ok()).map_or(0, u32::from_be_bytes);
+            meta.insert(LAST_TX_TIME, &last.max(msg.tx_time).to_be_bytes())?;
+            for (client, value) in &accts {
+                accounts.insert(&client.to_be_bytes(), value.as_slice())?;
+            }
+            for (tx, value) in &txs {
+                transactions.insert(&tx.to_be_bytes(), value.as_slice())?;
+            }
+            if let Some(value) = &withdrawn {
+                limits.insert(&msg.client.to_be_bytes(), value.as_slice())?;
+            }
+            if let Some((key, value)) = &fee_usage {
+                usage.insert(key.as_slice(), value.as_slice())?;
+            }
+            for (kind, value) in &remainders {
+                rounding.insert(kind.as_bytes(), value.as_slice())?;
+            }
+            Ok::<(), ConflictableTransactionError<()>>(())
+        });
+        result.map_err(|e| Error::StorageError(format!("sled record failed. {:?}", e)))
+    }
+
+    fn record_admin(&mut self, account_txs: &AccountTransactions, action: &AdminAction) -> Result<()> {
+        if let Some(acct) = action.client(account_txs).and_then(|client| account_txs.account_client.get(&client)) {
+            let value = seal("accounts", &acct.client.to_be_bytes(), encode_account(acct))?;
+            self.accounts.insert(acct.client.to_be_bytes(), value).map_err(|e| sled_error("record", e))?;
+        }
+        if let Some(tx) = action.tx().and_then(|tx| account_txs.txs_txid.get(&tx)) {
+            let value = seal("transactions", &tx.tx.to_be_bytes(), encode_tx(tx))?;
+            self.transactions.insert(tx.tx.to_be_bytes(), value).map_err(|e| sled_error("record", e))?;
+        }
+        Ok(())
+    }
+
+    // Every message was written as it was handled, so only entries dropped from memory since, by pruning or
+    // erasure, are left to remove before flushing to disk
+    fn save(&mut self, account_txs: &AccountTransactions) -> Result<()> {
+        remove_missing(&self.accounts, |key| Ok(account_txs.account_client.contains_key(&u16::from_be_bytes(fixed(key)?))))?;
+        remove_missing(&self.transactions, |key| Ok(account_txs.txs_txid.contains_key(&u32::from_be_bytes(fixed(key)?))))?;
+        remove_missing(&self.messages, |key| Ok(account_txs.tx_msgs_time.contains_key(&u32::from_be_bytes(fixed(key)?))))?;
+        self.db.flush().map_err(|e| sled_error("flush", e))?;
+        Ok(())
+    }
+
+    fn describe(&self) -> String {
+        format!("sled:{}", self.path)
+    }
+}
+
+fn remove_missing<F: Fn(&[u8]) -> Result<bool>>(tree: &Tree, keep: F) -> Result<()> {
+    for key in tree.iter().keys() {
+        let key = key.map_err(|e| sled_error("save", e))?;
+        if !keep(&key)? {
+            tree.remove(key).map_err(|e| sled_error("save", e))?;
+        }
+    }
+    Ok(())
+}
+
+// A value is sealed with its tree and key as associated data, so it cannot be moved to another entry. Keys and the
+// meta tree stay plaintext.
+fn seal(tree: &str, key: &[u8], value: Vec<u8>) -> Result<Vec<u8>> {
+    encryption::seal(value, &[tree.as_bytes(), b"/", key].concat())
+        .map_err(|e| Error::StorageError(format!("sled {}: {}", tree, e)))
+}
+
+fn open(tree: &str, key: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+    encryption::open(value.to_vec(), &[tree.as_bytes(), b"/", key].concat())
+        .map_err(|e| Error::StorageError(format!("sled {}: {}", tree, e)))
+}
+
+// Entries written before accounts had escrow are 25 bytes, without the last eight
+fn encode_account(acct: &Account) -> Vec<u8> {
+    let mut value = Vec::with_capacity(33);
+    value.extend_from_slice(&acct.available.to_be_bytes());
+    value.extend_from_slice(&acct.held.to_be_bytes());
+    value.extend_from_slice(&acct.total.to_be_bytes());
+    value.push(acct.locked as u8);
+    value.extend_from_slice(&acct.escrow.to_be_bytes());
+    value
+}
+
+fn decode_account(client: u16, value: &[u8]) -> Result<Account> {
+    let (value, escrow) = match value.len() {
+        25 => (fixed_ref::<25>(value)?, 0),
+        _ => {
+            let value: &[u8; 33] = fixed_ref(value)?;
+            (fixed_ref::<25>(&value[..25])?, u64::from_be_bytes(fixed(&value[25..])?))
+        }
+    };
+    Ok(Account {
+        client,
+        available: u64::from_be_bytes(fixed(&value[0..8])?),
+        held: u64::from_be_bytes(fixed(&value[8..16])?),
+        escrow,
+        total: u64::from_be_bytes(fixed(&value[16..24])?),
+        locked: value[24] != 0,
+    })
+}
+
+// Byte 15 holds the disputed flag in bit 0, the pending flag in bit 1, in bit 2 whether the chargeback's tx_time
+// follows, in bit 3 the resolved flag, in bit 4 the provisional flag, in bit 5 whether the chargeback fee follows, in
+// bit 6 whether the recovery follows and in bit 7 whether the service fee follows, and bytes 16 to 19 the dispute's
+// tx_time. A charged back transaction is one with a chargeback time. Then come the chargeback's tx_time when bit 2 is
+// set, the fee the client paid and the fee the loss account absorbed when bit 5 is set, the deficit outstanding and
+// recovered when bit 6 is set, the service fee when bit 7 is set, and the texts.
+// Entries written before dispute times were kept are 16 bytes.
+fn encode_tx(tx: &Tx) -> Vec<u8> {
+    let mut value = Vec::with_capacity(24);
+    let charged_back = tx.chargeback_time != 0;
+    let fee = tx.fee + tx.fee_loss > 0;
+    let recovery = tx.recovery + tx.recovered > 0;
+    value.extend_from_slice(&tx.tx_time.to_be_bytes());
+    value.push(type_code(&tx.tx_type));
+    value.extend_from_slice(&tx.client.to_be_bytes());
+    value.extend_from_slice(&tx.amount.to_be_bytes());
+    let resolved = tx.state == TxState::Resolved;
+    value.push(tx.disputed() as u8 | (tx.pending as u8) << 1 | (charged_back as u8) << 2 | (resolved as u8) << 3
+               | (tx.provisional as u8) << 4 | (fee as u8) << 5
+               | (recovery as u8) << 6 | ((tx.service_fee > 0) as u8) << 7);
+    value.extend_from_slice(&tx.dispute_time.to_be_bytes());
+    if charged_back {
+        value.extend_from_slice(&tx.chargeback_time.to_be_bytes());
+    }
+    if fee {
+        value.extend_from_slice(&tx.fee.to_be_bytes());
+        value.extend_from_slice(&tx.fee_loss.to_be_bytes());
+    }
+    if recovery {
+        value.extend_from_slice(&tx.recovery.to_be_bytes());
+        value.extend_from_slice(&tx.recovered.to_be_bytes());
+    }
+    if tx.service_fee > 0 {
+        value.extend_from_slice(&tx.service_fee.to_be_bytes());
+    }
+    let history = Some(lifecycle::history_text(&tx.history)).filter(|history| !history.is_empty());
+    encode_texts(&mut value, &[tx.counterparty.as_deref(), tx.memo.as_deref(), tx.category.as_deref(),
+                               history.as_deref()]);
+    value
+}
+
+fn decode_tx(tx: u32, value: &[u8]) -> Result<Tx> {
+    let (value, dispute_time, rest) = match value.len() {
+        16 => (fixed_ref::<16>(value)?, 0, &value[16..]),
+        len if len < 20 => return Err(Error::StorageError(format!("sled entry is {} bytes, expected 20.", len))),
+        _ => (fixed_ref::<16>(&value[..16])?, u32::from_be_bytes(fixed(&value[16..20])?), &value[20..]),
+    };
+    let (chargeback_time, rest) = match value[15] & 4 != 0 {
+        true if rest.len() >= 4 => (u32::from_be_bytes(fixed(&rest[..4])?), &rest[4..]),
+        true => return Err(Error::StorageError(String::from("sled entry has a truncated chargeback time."))),
+        false => (0, rest),
+    };
+    let (fee, fee_loss, rest) = match value[15] & 32 != 0 {
+        true if rest.len() >= 16 => (u64::from_be_bytes(fixed(&rest[..8])?), u64::from_be_bytes(fixed(&rest[8..16])?),
+                                     &rest[16..]),
+        true => return Err(Error::StorageError(String::from("sled entry has a truncated chargeback fee."))),
+        false => (0, 0, rest),
+    };
+    let (recovery, recovered, rest) = match value[15] & 64 != 0 {
+        true if rest.len() >= 16 => (u64::from_be_bytes(fixed(&rest[..8])?), u64::from_be_bytes(fixed(&rest[8..16])?),
+                                     &rest[16..]),
+        true => return Err(Error::StorageError(String::from("sled entry has a truncated recovery."))),
+        false => (0, 0, rest),
+    };
+    let (service_fee, rest) = match value[15] & 128 != 0 {
+        true if rest.len() >= 8 => (u64::from_be_bytes(fixed(&rest[..8])?), &rest[8..]),
+        true => return Err(Error::StorageError(String::from("sled entry has a truncated service fee."))),
+        false => (0, rest),
+    };
+    let [counterparty, memo, category, history] = decode_texts(rest)?;
+    let state = match value[15] {
+        flags if flags & 1 != 0 => TxState::Disputed,
+        _ if chargeback_time != 0 => TxState::ChargedBack,
+        flags if flags & 8 != 0 => TxState::Resolved,
+        _ => TxState::Posted,
+    };
+    Ok(Tx {
+        tx,
+        tx_time: u32::from_be_bytes(fixed(&value[0..4])?),
+        tx_type: type_from_code(value[4])?,
+        client: u16::from_be_bytes(fixed(&value[5..7])?),
+        amount: u64::from_be_bytes(fixed(&value[7..15])?),
+        state,
+        dispute_time,
+        chargeback_time,
+        pending: value[15] & 2 != 0,
+        provisional: value[15] & 16 != 0,
+        fee,
+        fee_loss,
+        recovery,
+        recovered,
+        service_fee,
+        counterparty,
+        memo,
+        category,
+        history: history.as_deref().map(lifecycle::parse_history).transpose()?.unwrap_or_default(),
+    })
+}
+
+// A transfer's to_client follows the 15 bytes of every message, then the texts
+fn encode_message(msg: &TransactionMessage) -> Vec<u8> {
+    let mut value = Vec::with_capacity(17);
+    value.push(type_code(&msg.tx_type));
+    value.extend_from_slice(&msg.client.to_be_bytes());
+    value.extend_from_slice(&msg.tx.to_be_bytes());
+    value.extend_from_slice(&msg.amount.to_be_bytes());
+    if let Some(to_client) = msg.to_client {
+        value.extend_from_slice(&to_client.to_be_bytes());
+    }
+    encode_texts(&mut value, &[msg.counterparty.as_deref(), msg.memo.as_deref(), msg.category.as_deref()]);
+    value
+}
+
+fn decode_message(tx_time: u32, value: &[u8]) -> Result<TransactionMessage> {
+    if value.len() < 15 {
+        return Err(Error::StorageError(format!("sled entry is {} bytes, expected 15.", value.len())));
+    }
+    let (value, rest) = value.split_at(15);
+    let value: &[u8; 15] = fixed_ref(value)?;
+    let tx_type = type_from_code(value[0])?;
+    let (to_client, rest) = match tx_type {
+        TransactionType::TRANSFER if rest.len() >= 2 => (Some(u16::from_be_bytes(fixed(&rest[..2])?)), &rest[2..]),
+        _ => (None, rest),
+    };
+    let [counterparty, memo, category] = decode_texts(rest)?;
+    Ok(TransactionMessage {
+        tx_time,
+        tx_type,
+        client: u16::from_be_bytes(fixed(&value[1..3])?),
+        tx: u32::from_be_bytes(fixed(&value[3..7])?),
+        amount: u64::from_be_bytes(fixed(&value[7..15])?),
+        to_client,
+        counterparty,
+        memo,
+        category,
+    })
+}
+
+// Optional texts after a record's fixed bytes, each a two-byte length then UTF-8 with an empty one for None. Those
+// after the last present one are left out, so records without texts keep their old length.
+fn encode_texts(value: &mut Vec<u8>, texts: &[Option<&str>]) {
+    let present = texts.iter().rposition(Option::is_some).map_or(0, |last| last + 1);
+    for text in &texts[..present] {
+        let text = text.unwrap_or("");
+        // Cut to what the length holds, on a character boundary
+        let mut len = text.len().min(usize::from(u16::MAX));
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        value.extend_from_slice(&(len as u16).to_be_bytes());
+        value.extend_from_slice(&text.as_bytes()[..len]);
+    }
+}
+
+fn decode_texts<const N: usize>(mut value: &[u8]) -> Result<[Option<String>; N]> {
+    let mut texts: [Option<String>; N] = std::array::from_fn(|_| None);
+    for text in texts.iter_mut() {
+        if value.is_empty() {
+            break;
+        }
+        let len = usize::from(u16::from_be_bytes(fixed(value.get(..2).unwrap_or(value))?));
+        let bytes = value.get(2..2 + len)
+            .ok_or_else(|| Error::StorageError(String::from("sled entry has a truncated text.")))?;
+        let decoded = String::from_utf8(bytes.to_vec())
+            .map_err(|_| Error::StorageError(String::from("sled entry has a text that is not UTF-8.")))?;
+        *text = Some(decoded).filter(|text| !text.is_empty());
+        value = &value[2 + len..];
+    }
+    Ok(texts)
+}
+
+// Keyed by client, type code and period so a client's usage iterates together, the value being count then fees
+fn encode_fee_usage(record: &FeeUsageRecord) -> (Vec<u8>, Vec<u8>) {
+    let mut key = Vec::with_capacity(7);
+    key.extend_from_slice(&record.client.to_be_bytes());
+    key.push(type_code(&record.tx_type));
+    key.extend_from_slice(&record.period.to_be_bytes());
+    let mut value = Vec::with_capacity(12);
+    value.extend_from_slice(&record.count.to_be_bytes());
+    value.extend_from_slice(&record.fees.to_be_bytes());
+    (key, value)
+}
+
+fn decode_fee_usage(key: &[u8], value: &[u8]) -> Result<FeeUsageRecord> {
+    let key: &[u8; 7] = fixed_ref(key)?;
+    let value: &[u8; 12] = fixed_ref(value)?;
+    Ok(FeeUsageRecord {
+        client: u16::from_be_bytes(fixed(&key[0..2])?),
+        tx_type: type_from_code(key[2])?,
+        period: u32::from_be_bytes(fixed(&key[3..7])?),
+        count: u32::from_be_bytes(fixed(&value[0..4])?),
+        fees: u64::from_be_bytes(fixed(&value[4..12])?),
+    })
+}
+
+// Stored codes, never renumber
+fn type_code(tx_type: &TransactionType) -> u8 {
+    match tx_type {
+        TransactionType::WITHDRAWAL => 0,
+        TransactionType::DEPOSIT => 1,
+        TransactionType::DISPUTE => 2,
+        TransactionType::RESOLVE => 3,
+        TransactionType::CHARGEBACK => 4,
+        TransactionType::APPROVE => 5,
+        TransactionType::DENY => 6,
+        TransactionType::ESCROW => 7,
+        TransactionType::RELEASE => 8,
+        TransactionType::REFUND => 9,
+        TransactionType::TRANSFER => 10,
+    }
+}
+
+fn type_from_code(code: u8) -> Result<TransactionType> {
+    match code {
+        0 => Ok(TransactionType::WITHDRAWAL),
+        1 => Ok(TransactionType::DEPOSIT),
+        2 => Ok(TransactionType::DISPUTE),
+        3 => Ok(TransactionType::RESOLVE),
+        4 => Ok(TransactionType::CHARGEBACK),
+        5 => Ok(TransactionType::APPROVE),
+        6 => Ok(TransactionType::DENY),
+        7 => Ok(TransactionType::ESCROW),
+        8 => Ok(TransactionType::RELEASE),
+        9 => Ok(TransactionType::REFUND),
+        10 => Ok(TransactionType::TRANSFER),
+        _ => Err(Error::StorageError(format!("sled entry has unknown transaction type code {}.", code))),
+    }
+}
+
+fn fixed<const N: usize>(bytes: &[u8]) -> Result<[u8; N]> {
+    bytes.try_into().map_err(|_| Error::StorageError(format!("sled entry is {} bytes, expected {}.", bytes.len(), N)))
+}
+
+fn fixed_ref<const N: usize>(bytes: &[u8]) -> Result<&[u8; N]> {
+    bytes.try_into().map_err(|_| Error::StorageError(format!("sled entry is {} bytes, expected {}.", bytes.len(), N)))
+}
+
+fn sled_error(action: &str, e: sled::Error) -> Error {
+    Error::StorageError(format!("sled {} failed. {}", action, e))
+}
@@ -0,0 +1,139 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::TransactionType;
+
+// Bucket i counts durations under 2^i nanoseconds, the last bucket everything longer (about 9 minutes and up)
+const BUCKETS: usize = 40;
+
+// Log-scale histogram of durations. Percentiles are reported as the upper bound of their bucket, so they are
+// within a factor of two, which is enough to tell a microsecond engine step from a millisecond storage write.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    total_nanos: u128,
+    max_nanos: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, elapsed: Duration) {
+        let nanos = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        if self.buckets.is_empty() {
+            self.buckets = vec![0; BUCKETS];
+        }
+        let bucket = (u64::BITS - nanos.leading_zeros()) as usize;
+        self.buckets[bucket.min(BUCKETS - 1)] += 1;
+        self.count += 1;
+        self.total_nanos += u128::from(nanos);
+        self.max_nanos = self.max_nanos.max(nanos);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_nanos(u64::try_from(self.total_nanos).unwrap_or(u64::MAX))
+    }
+
+    pub fn mean(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::ZERO;
+        }
+        Duration::from_nanos((self.total_nanos / u128::from(self.count)) as u64)
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_nanos(self.max_nanos)
+    }
+
+    // Upper bound of the bucket holding the p-th percentile, p between 0 and 1
+    pub fn percentile(&self, p: f64) -> Duration {
+        let rank = ((self.count as f64 * p).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let bound = if bucket == 0 { 0 } else { 1u64 << bucket };
+                return Duration::from_nanos(bound.min(self.max_nanos));
+            }
+        }
+        self.max()
+    }
+}
+
+// "n=1027 mean=1.2µs p50=2.0µs p99=8.2µs max=31.5µs"
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "n={} mean={:.1?} p50={:.1?} p99={:.1?} max={:.1?}",
+               self.count, self.mean(), self.percentile(0.5), self.percentile(0.99), self.max())
+    }
+}
+
+// Where the time handling each message went
+#[derive(Debug, Clone, Default)]
+pub struct Latencies {
+    // The whole of handling one message, by its type
+    pub by_type: BTreeMap<TransactionType, Histogram>,
+    // The engine applying the message, including dispute lookups
+    pub engine: Histogram,
+    // Per-message store writes, only recorded for stores that write as messages are handled
+    pub store: Histogram,
+    // Fanning the account change out to the change sinks
+    pub sinks: Histogram,
+}
+
+impl Latencies {
+    pub fn is_empty(&self) -> bool {
+        self.by_type.is_empty()
+    }
+
+    // Starts timing one message
+    pub fn start(&mut self, tx_type: &TransactionType) -> Stopwatch<'_> {
+        let now = Instant::now();
+        Stopwatch { latencies: self, tx_type: tx_type.clone(), start: now, lap: now }
+    }
+}
+
+// Times the phases of handling one message. Call the phase methods as each phase ends, the total is recorded
+// against the message type on drop.
+pub struct Stopwatch<'a> {
+    latencies: &'a mut Latencies,
+    tx_type: TransactionType,
+    start: Instant,
+    lap: Instant,
+}
+
+impl Stopwatch<'_> {
+    pub fn engine(&mut self) {
+        let elapsed = self.lap();
+        self.latencies.engine.record(elapsed);
+    }
+
+    pub fn store(&mut self) {
+        let elapsed = self.lap();
+        self.latencies.store.record(elapsed);
+    }
+
+    pub fn sinks(&mut self) {
+        let elapsed = self.lap();
+        self.latencies.sinks.record(elapsed);
+    }
+
+    fn lap(&mut self) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.lap);
+        self.lap = now;
+        elapsed
+    }
+}
+
+impl Drop for Stopwatch<'_> {
+    fn drop(&mut self) {
+        let elapsed = self.start.elapsed();
+        self.latencies.by_type.entry(self.tx_type.clone()).or_default().record(elapsed);
+    }
+}
@@ -0,0 +1,1498 @@
+use std::convert::TryFrom;
+use std::fmt;
+use std::mem;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+// The core, the types and balance logic along with the pipeline, observers and C interface, needs only std and
+// thiserror. Everything that reads or writes something, including the command line, is behind the io feature.
+#[cfg(feature = "io")]
+#[macro_use]
+pub mod logging;
+
+#[cfg(all(feature = "amount128", any(feature = "sled", feature = "postgres")))]
+compile_error!("The sled and postgres stores write 64-bit amounts, so they cannot be built with amount128.");
+
+pub mod account_types;
+#[cfg(feature = "io")]
+pub mod admin;
+pub mod anomaly;
+#[cfg(feature = "bench")]
+pub mod bench;
+pub mod cancel;
+pub mod category;
+pub mod chargeback_fee;
+#[cfg(feature = "io")]
+pub mod cdc;
+pub mod change;
+pub mod counterparty;
+#[cfg(feature = "cli")]
+pub mod cli;
+#[cfg(feature = "io")]
+pub mod config;
+#[cfg(feature = "tui")]
+pub mod dashboard;
+#[cfg(feature = "io")]
+pub mod dead_letter;
+#[cfg(feature = "io")]
+pub mod decrypt;
+#[cfg(feature = "io")]
+mod dedup;
+#[cfg(feature = "io")]
+pub mod diff;
+pub mod dispute_aging;
+pub mod dispute_expiry;
+#[cfg(feature = "io")]
+pub mod encryption;
+#[cfg(feature = "io")]
+pub mod engine;
+pub mod error;
+#[cfg(feature = "io")]
+pub mod error_log;
+pub mod fee_schedule;
+pub mod ffi;
+pub mod filter;
+#[cfg(feature = "io")]
+pub mod generate;
+pub mod hooks;
+pub mod interest;
+#[cfg(feature = "io")]
+pub mod http;
+#[cfg(feature = "io")]
+pub mod idempotency;
+#[cfg(feature = "io")]
+pub mod interrupt;
+#[cfg(feature = "io")]
+pub mod kafka;
+#[cfg(feature = "io")]
+pub mod latency;
+pub mod lifecycle;
+#[cfg(feature = "sled")]
+pub mod kv;
+#[cfg(feature = "io")]
+pub mod merkle;
+pub mod netting;
+#[cfg(feature = "io")]
+pub mod otel;
+pub mod observer;
+#[cfg(feature = "io")]
+pub mod output;
+pub mod pipeline;
+#[cfg(feature = "plugins")]
+pub mod plugin;
+#[cfg(feature = "postgres")]
+pub mod pg;
+#[cfg(feature = "io")]
+pub mod progress;
+#[cfg(feature = "io")]
+pub mod quarantine;
+#[cfg(feature = "io")]
+mod read;
+pub mod recovery;
+#[cfg(feature = "io")]
+pub mod redis;
+#[cfg(feature = "io")]
+pub mod repl;
+#[cfg(feature = "io")]
+pub mod report;
+#[cfg(feature = "io")]
+mod replication;
+#[cfg(feature = "io")]
+pub mod rules;
+pub mod rounding;
+#[cfg(feature = "io")]
+pub mod scheduler;
+#[cfg(feature = "scripting")]
+pub mod script;
+#[cfg(feature = "io")]
+pub mod server;
+#[cfg(feature = "signing")]
+pub mod signing;
+#[cfg(feature = "io")]
+mod sinks;
+#[cfg(feature = "io")]
+pub mod snapshot;
+#[cfg(feature = "io")]
+pub mod source;
+#[cfg(feature = "io")]
+pub mod state;
+#[cfg(feature = "io")]
+pub mod statsd;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "io")]
+pub mod throttle;
+pub mod volume;
+#[cfg(all(target_arch = "wasm32", feature = "io"))]
+pub mod wasm;
+#[cfg(feature = "io")]
+pub mod webhook;
+pub mod withdrawal_limits;
+#[cfg(feature = "io")]
+pub mod websocket;
+
+pub use cancel::CancelToken;
+pub use error::{Error, Rejection, Result};
+#[cfg(feature = "io")]
+pub use read::{read_file, read_file_with_store, read_source, ReadOptions, ReadReport};
+
+use account_types::AccountTypes;
+use anomaly::AnomalyDetector;
+use chargeback_fee::ChargebackFee;
+use fee_schedule::FeeSchedules;
+use interest::InterestLedger;
+use rounding::{RoundingAccount, RoundingMode};
+use change::AccountChange;
+use category::CategoryTotals;
+use counterparty::CounterpartyStats;
+use dispute_aging::Resolutions;
+use dispute_expiry::DisputeExpiry;
+use netting::Netting;
+use volume::TypeVolume;
+#[cfg(feature = "io")]
+use idempotency::IdempotencyKeys;
+#[cfg(feature = "io")]
+use merkle::MerkleTree;
+use hooks::{Validator, ValidatorMiddleware};
+use lifecycle::{DisputeEvent, DisputeEventKind};
+use observer::{EngineObserver, Observers};
+use pipeline::{Middleware, Next, Pipeline};
+use withdrawal_limits::WithdrawalLimits;
+
+// Amounts in currency subunits. The amount128 feature widens them, and the balance arithmetic on them, for asset
+// classes whose balances outgrow u64.
+#[cfg(not(feature = "amount128"))]
+pub type Amount = u64;
+#[cfg(feature = "amount128")]
+pub type Amount = u128;
+// An amount with a sign, for adjustments and settlements
+#[cfg(not(feature = "amount128"))]
+pub type SignedAmount = i64;
+#[cfg(feature = "amount128")]
+pub type SignedAmount = i128;
+
+// Sets the policies an application configured on an engine once its state is loaded, for the engines serve's
+// standbys and schedules build for themselves
+pub type Configure = std::sync::Arc<dyn Fn(&mut AccountTransactions) + Send + Sync>;
+
+// Expect amount to be currency subunit, fraction of main unit like cents for USD.
+// Serialized with amounts as whole subunits, so they round-trip exactly, and tx_type as it is written in the input.
+#[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct TransactionMessage {
+  pub tx_time: u32,
+  #[cfg_attr(feature = "serde", serde(rename = "type"))]
+  pub tx_type: TransactionType,
+  pub client: u16,
+  pub tx: u32,
+  pub amount: Amount,
+  // The client a transfer pays, None for every other type
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub to_client: Option<u16>,
+  // The merchant or other party on the far side of the transaction, as given in the input
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub counterparty: Option<String>,
+  // Free text describing the transaction, passed through untouched for statements and support
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub memo: Option<String>,
+  // A budgeting category or tag, e.g. groceries
+  #[cfg_attr(feature = "serde", serde(default))]
+  pub category: Option<String>,
+}
+
+// Limit tx_type to either WITHDRAWAL or DEPOSIT
+#[derive(Debug,Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Tx {
+    pub tx: u32,
+    // Absent from state files written before transactions recorded it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tx_time: u32,
+    #[cfg_attr(feature = "serde", serde(rename = "type"))]
+    pub tx_type: TransactionType,
+    pub client: u16,
+    pub amount: Amount,
+    // Read from the disputed flag of state written before transactions recorded their state
+    #[cfg_attr(feature = "serde", serde(alias = "disputed", default))]
+    pub state: TxState,
+    // The tx_time of its open dispute, 0 when it is not disputed or the state predates recording it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub dispute_time: u32,
+    // The tx_time of the chargeback that reversed it, 0 when it was not charged back or the state predates recording it
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub chargeback_time: u32,
+    // A withdrawal above the approval threshold whose funds are held until it is approved or denied, or an escrow
+    // not yet released or refunded
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub pending: bool,
+    // Whether its last dispute was opened with provisional credit, leaving the client's available balance alone
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub provisional: bool,
+    // The fee posted when it was charged back, in subunits: what the client paid and what the loss account absorbed
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fee: Amount,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub fee_loss: Amount,
+    // What its chargeback left the client owing, outstanding and swept from deposits so far, in subunits
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub recovery: Amount,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub recovered: Amount,
+    // What the fee schedule charged on it, in subunits
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub service_fee: Amount,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub counterparty: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub memo: Option<String>,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub category: Option<String>,
+    // Every dispute, resolve and chargeback applied to it, in order, empty for state written before they were kept
+    #[cfg_attr(feature = "serde", serde(default, with = "lifecycle::history_serde"))]
+    pub history: Vec<DisputeEvent>,
+}
+
+impl Tx {
+    // Disputed and not yet resolved or charged back, its amount held on the account
+    pub fn disputed(&self) -> bool {
+        self.state == TxState::Disputed
+    }
+
+    pub(crate) fn record_event(&mut self, kind: DisputeEventKind, tx_time: u32) {
+        self.history.push(DisputeEvent { kind, tx_time });
+    }
+}
+
+// Where a transaction stands in the dispute process. A dispute moves it from Posted or Resolved to Disputed, a resolve
+// back out to Resolved, and a chargeback to ChargedBack, which is final.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TxState {
+    #[default]
+    Posted,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+impl TxState {
+    // The state a dispute, resolve or chargeback leaves a transaction in this state in, None when it may not apply
+    pub fn after(self, event: &TransactionType) -> Option<TxState> {
+        match (self, event) {
+            (TxState::Posted | TxState::Resolved, TransactionType::DISPUTE) => Some(TxState::Disputed),
+            (TxState::Disputed, TransactionType::RESOLVE) => Some(TxState::Resolved),
+            (TxState::Disputed, TransactionType::CHARGEBACK) => Some(TxState::ChargedBack),
+            _ => None,
+        }
+    }
+}
+
+impl std::str::FromStr for TxState {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "posted" => Ok(TxState::Posted),
+            "disputed" => Ok(TxState::Disputed),
+            "resolved" => Ok(TxState::Resolved),
+            "charged_back" => Ok(TxState::ChargedBack),
+            _ => Err(Error::ParseError(format!("'{}' is not a valid transaction state", s))),
+        }
+    }
+}
+
+impl fmt::Display for TxState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TxState::Posted => "posted",
+            TxState::Disputed => "disputed",
+            TxState::Resolved => "resolved",
+            TxState::ChargedBack => "charged_back",
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for TxState {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+// Also accepts the disputed flag that stood in for the state before, true as Disputed and false as Posted
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for TxState {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        struct StateVisitor;
+
+        impl serde::de::Visitor<'_> for StateVisitor {
+            type Value = TxState;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a transaction state or a disputed flag")
+            }
+
+            fn visit_bool<E: serde::de::Error>(self, disputed: bool) -> std::result::Result<TxState, E> {
+                Ok(if disputed { TxState::Disputed } else { TxState::Posted })
+            }
+
+            fn visit_str<E: serde::de::Error>(self, state: &str) -> std::result::Result<TxState, E> {
+                state.parse().map_err(E::custom)
+            }
+        }
+
+        deserializer.deserialize_any(StateVisitor)
+    }
+}
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug,Clone,PartialEq,Eq,PartialOrd,Ord,Hash)]
+pub enum TransactionType {
+  WITHDRAWAL,
+  DEPOSIT,
+  DISPUTE,
+  RESOLVE,
+  CHARGEBACK,
+  // Complete or refuse a pending withdrawal
+  APPROVE,
+  DENY,
+  // Receive funds into escrow, then pay them into available or return them to the payer
+  ESCROW,
+  RELEASE,
+  REFUND,
+  // Pay another client, from available to available
+  TRANSFER,
+}
+
+// Expect available, held, escrow, total to be currency subunit, fraction of main unit
+#[derive(Debug,Clone,PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct Account {
+    pub client: u16,
+    pub available: Amount,
+    pub held: Amount,
+    // Received into escrow and not yet released or refunded, counted in total but apart from dispute holds
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub escrow: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+impl std::str::FromStr for TransactionType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "withdraw" => Ok(TransactionType::WITHDRAWAL),
+            "deposit" => Ok(TransactionType::DEPOSIT),
+            "dispute" => Ok(TransactionType::DISPUTE),
+            "resolve" => Ok(TransactionType::RESOLVE),
+            "chargeback" => Ok(TransactionType::CHARGEBACK),
+            "approve" => Ok(TransactionType::APPROVE),
+            "deny" => Ok(TransactionType::DENY),
+            "escrow" => Ok(TransactionType::ESCROW),
+            "release" => Ok(TransactionType::RELEASE),
+            "refund" => Ok(TransactionType::REFUND),
+            "transfer" => Ok(TransactionType::TRANSFER),
+            _ => Err(Error::ParseError(format!("'{}' is not a valid TransactionType", s))),
+        }
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TransactionType::WITHDRAWAL => "withdraw",
+            TransactionType::DEPOSIT => "deposit",
+            TransactionType::DISPUTE => "dispute",
+            TransactionType::RESOLVE => "resolve",
+            TransactionType::CHARGEBACK => "chargeback",
+            TransactionType::APPROVE => "approve",
+            TransactionType::DENY => "deny",
+            TransactionType::ESCROW => "escrow",
+            TransactionType::RELEASE => "release",
+            TransactionType::REFUND => "refund",
+            TransactionType::TRANSFER => "transfer",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl Serialize for TransactionType {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for TransactionType {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+// Everything removed from the books when a client is erased
+#[cfg(feature = "io")]
+#[derive(Debug)]
+struct ErasedClient {
+    account: Account,
+    transactions: u32,
+    messages: u32,
+}
+
+// How long transactions stay eligible for dispute, measured in input rows or in retained transactions.
+// Disputed transactions are always retained so they can still be resolved or charged back.
+#[derive(Debug,Clone)]
+pub enum Retention {
+    Age(u32),
+    Count(usize),
+}
+
+// Transactions and their messages dropped by a single pruning pass
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PrunedRange {
+    pub from_time: u32,
+    pub to_time: u32,
+    pub transactions: u32,
+    pub messages: u32,
+    pub pruned_at: u64,
+}
+
+// Outcome of folding one client's account into another
+#[derive(Debug)]
+pub struct MergedClients {
+    pub from: u16,
+    pub into: u16,
+    pub transactions: u32,
+    pub messages: u32,
+    pub lock_conflict: bool,
+}
+
+#[derive(Debug)]
+pub struct AccountTransactions {
+    txs_txid: HashMap<u32, Tx>,
+    account_client: HashMap<u16, Account>,
+    tx_msgs_time: HashMap<u32, TransactionMessage>,
+    pruned_ranges: Vec<PrunedRange>,
+    compacted_through: u32,
+    // Next offset to consume from each (topic, partition) a Kafka input has been read from
+    #[cfg(feature = "io")]
+    source_offsets: BTreeMap<(String, u32), u64>,
+    #[cfg(feature = "io")]
+    idempotency_keys: IdempotencyKeys,
+    // Every transaction applied to these books, carried across runs by snapshots
+    #[cfg(feature = "io")]
+    merkle: MerkleTree,
+    withdrawal_limits: WithdrawalLimits,
+    // Withdrawals of more than this many subunits wait for approval
+    approval_threshold: Option<Amount>,
+    dispute_expiry: Option<DisputeExpiry>,
+    // Disputes resolved since the engine started, for how long they stayed open
+    resolutions: Resolutions,
+    // Most disputes a client may have open at once
+    max_open_disputes: Option<u32>,
+    // What each client's account type changes about how its disputes apply
+    account_types: AccountTypes,
+    chargeback_fee: Option<ChargebackFee>,
+    // Clients with a deficit outstanding, None while deficit recovery is off
+    recovering: Option<HashSet<u16>>,
+    fee_schedules: FeeSchedules,
+    interest: InterestLedger,
+    // What fees and interest dropped past the subunit, carried across runs with the state
+    rounding: RoundingAccount,
+    // How amounts in input rows with more than four decimal places become subunits
+    rounding_mode: RoundingMode,
+    // Obligations from transfers, when they are netted rather than moved as they apply
+    netting: Option<Netting>,
+    // Transactions applied since the engine started, by the counterparty they named
+    counterparties: BTreeMap<String, CounterpartyStats>,
+    // Deposits and withdrawals applied since the engine started, by client and the category they named
+    categories: BTreeMap<(u16, String), CategoryTotals>,
+    // Messages applied since the engine started, by client and type
+    volumes: BTreeMap<(u16, TransactionType), TypeVolume>,
+    // Flags messages unlike their client's earlier ones, when anomaly rules are set
+    anomalies: Option<AnomalyDetector>,
+    pipeline: Pipeline,
+    observers: Observers,
+    // What observers failed with, by observer name, kept for take_observer_errors since the core has no log
+    #[cfg(not(feature = "io"))]
+    observer_errors: Vec<(String, Error)>,
+    // What the middlewares attached to the last message handled
+    annotations: BTreeMap<String, String>,
+    // Accounts the last message handled changed, interest and dispute expiries it set off included
+    touched: BTreeSet<u16>,
+}
+
+impl Default for AccountTransactions {
+    fn default() -> AccountTransactions {
+        AccountTransactions::new()
+    }
+}
+
+impl AccountTransactions {
+    pub fn new() -> AccountTransactions {
+        AccountTransactions {
+            txs_txid: HashMap::new(),
+            tx_msgs_time: HashMap::new(),
+            account_client: HashMap::new(),
+            pruned_ranges: Vec::new(),
+            compacted_through: 0,
+            #[cfg(feature = "io")]
+            source_offsets: BTreeMap::new(),
+            #[cfg(feature = "io")]
+            idempotency_keys: IdempotencyKeys::default(),
+            #[cfg(feature = "io")]
+            merkle: MerkleTree::default(),
+            withdrawal_limits: WithdrawalLimits::default(),
+            approval_threshold: None,
+            dispute_expiry: None,
+            resolutions: Resolutions::default(),
+            max_open_disputes: None,
+            account_types: AccountTypes::default(),
+            chargeback_fee: None,
+            recovering: None,
+            fee_schedules: FeeSchedules::default(),
+            interest: InterestLedger::default(),
+            rounding: RoundingAccount::default(),
+            rounding_mode: RoundingMode::default(),
+            netting: None,
+            counterparties: BTreeMap::new(),
+            categories: BTreeMap::new(),
+            volumes: BTreeMap::new(),
+            anomalies: None,
+            pipeline: Pipeline::default(),
+            observers: Observers::default(),
+            #[cfg(not(feature = "io"))]
+            observer_errors: Vec::new(),
+            annotations: BTreeMap::new(),
+            touched: BTreeSet::new(),
+        }
+    }
+
+    pub fn with_validators(validators: Vec<Box<dyn Validator>>) -> AccountTransactions {
+        let mut account_txs = AccountTransactions::new();
+        for validator in validators {
+            account_txs.add_validator(validator);
+        }
+        account_txs
+    }
+
+    // Checked on every withdrawal from here on, going on from what the state already counted towards cumulative limits
+    pub fn set_withdrawal_limits(&mut self, mut limits: WithdrawalLimits) {
+        limits.carry_over(mem::take(&mut self.withdrawal_limits));
+        self.withdrawal_limits = limits;
+    }
+
+    pub fn withdrawal_limits(&self) -> &WithdrawalLimits {
+        &self.withdrawal_limits
+    }
+
+    // Withdrawals of more than threshold subunits from here on hold their funds until an approve or deny
+    pub fn set_approval_threshold(&mut self, threshold: Option<Amount>) {
+        self.approval_threshold = threshold;
+    }
+
+    // Disputes beyond max from one client are rejected while its others are open
+    pub fn set_max_open_disputes(&mut self, max: Option<u32>) {
+        self.max_open_disputes = max;
+    }
+
+    // Withdrawals waiting for approval, in no particular order
+    pub fn pending_withdrawals(&self) -> impl Iterator<Item = &Tx> {
+        self.txs_txid.values().filter(|tx| tx.pending && tx.tx_type == TransactionType::WITHDRAWAL)
+    }
+
+    // Escrows not yet released or refunded, in no particular order
+    pub fn open_escrows(&self) -> impl Iterator<Item = &Tx> {
+        self.txs_txid.values().filter(|tx| tx.pending && tx.tx_type == TransactionType::ESCROW)
+    }
+
+    // Validators run in the order added, in the policy stage of the pipeline. The first veto rejects the message.
+    pub fn add_validator(&mut self, validator: Box<dyn Validator>) {
+        self.add_middleware(Box::new(ValidatorMiddleware(validator)));
+    }
+
+    // Middlewares run in stage order, then in the order added
+    pub fn add_middleware(&mut self, middleware: Box<dyn Middleware>) {
+        self.pipeline.add(middleware);
+    }
+
+    // Observers are told about every message handled, in the order added
+    pub fn add_observer(&mut self, observer: Box<dyn EngineObserver>) {
+        self.observers.0.push(observer);
+    }
+
+    // The errors observers returned since the last call. Builds with io log them instead.
+    #[cfg(not(feature = "io"))]
+    pub fn take_observer_errors(&mut self) -> Vec<(String, Error)> {
+        mem::take(&mut self.observer_errors)
+    }
+
+    // Notes the middlewares attached to the message handled last
+    pub fn annotations(&self) -> &BTreeMap<String, String> {
+        &self.annotations
+    }
+
+    // Clients whose accounts the message handled last changed, for stores that write only what a message changed
+    pub fn touched_accounts(&self) -> impl Iterator<Item = u16> + '_ {
+        self.touched.iter().copied()
+    }
+
+    pub fn iter_accounts(&self) -> impl Iterator<Item = &Account> {
+        self.account_client.values()
+    }
+
+    pub fn get_account(&self, client: u16) -> Option<&Account> {
+        self.account_client.get(&client)
+    }
+
+    pub fn account_count(&self) -> usize {
+        self.account_client.len()
+    }
+
+    // Deposits and withdrawals still retained, the ones disputes can refer to
+    pub fn get_transaction(&self, tx: u32) -> Option<&Tx> {
+        self.txs_txid.get(&tx)
+    }
+
+    pub fn iter_transactions(&self) -> impl Iterator<Item = &Tx> {
+        self.txs_txid.values()
+    }
+
+    pub fn transaction_count(&self) -> usize {
+        self.txs_txid.len()
+    }
+
+    pub fn client_transactions(&self, client: u16) -> impl Iterator<Item = &Tx> {
+        self.txs_txid.values().filter(move |tx| tx.client == client)
+    }
+
+    // Transactions disputed and not yet resolved or charged back, their amounts held on the account
+    pub fn open_disputes(&self) -> impl Iterator<Item = &Tx> {
+        self.txs_txid.values().filter(|tx| tx.disputed())
+    }
+
+    // Every message handled and still retained, keyed by its tx_time
+    pub fn get_message(&self, tx_time: u32) -> Option<&TransactionMessage> {
+        self.tx_msgs_time.get(&tx_time)
+    }
+
+    pub fn iter_messages(&self) -> impl Iterator<Item = &TransactionMessage> {
+        self.tx_msgs_time.values()
+    }
+
+    // Ranges of messages dropped by retention
+    pub fn pruned_ranges(&self) -> &[PrunedRange] {
+        &self.pruned_ranges
+    }
+
+    // Keys of the rows already handled, so a replay of them is skipped
+    #[cfg(feature = "io")]
+    pub fn idempotency_keys(&self) -> &IdempotencyKeys {
+        &self.idempotency_keys
+    }
+
+    // Merkle tree over the transactions applied, in order, rejected ones left out
+    #[cfg(feature = "io")]
+    pub fn merkle(&self) -> &MerkleTree {
+        &self.merkle
+    }
+
+    // Rough heap footprint of the maps, counting each entry's key and value plus a word of table overhead
+    pub fn estimated_bytes(&self) -> u64 {
+        fn entries<K, V>(map: &HashMap<K, V>) -> u64 {
+            (map.capacity() * (mem::size_of::<K>() + mem::size_of::<V>() + mem::size_of::<usize>())) as u64
+        }
+        entries(&self.txs_txid) + entries(&self.account_client) + entries(&self.tx_msgs_time)
+            + (self.pruned_ranges.capacity() * mem::size_of::<PrunedRange>()) as u64
+    }
+
+    // Next message time, continuing after any messages loaded, pruned or compacted from persistent state
+    fn next_tx_time(&self) -> u32 {
+        let last_msg = self.tx_msgs_time.keys().max().copied().unwrap_or(0);
+        let last_tx = self.txs_txid.values().map(|tx| tx.tx_time).max().unwrap_or(0);
+        let last_pruned = self.pruned_ranges.iter().map(|range| range.to_time).max().unwrap_or(0);
+        last_msg.max(last_tx).max(last_pruned).max(self.compacted_through) + 1
+    }
+
+    // Drops all but the newest keep_messages messages, returning how many were dropped
+    #[cfg(feature = "io")]
+    fn trim_messages(&mut self, keep_messages: usize) -> u32 {
+        let through = self.next_tx_time() - 1;
+        let mut times: Vec<u32> = self.tx_msgs_time.keys().copied().collect();
+        times.sort_unstable();
+        let excess = times.len().saturating_sub(keep_messages);
+        for time in &times[..excess] {
+            self.tx_msgs_time.remove(time);
+        }
+        self.compacted_through = through;
+        excess as u32
+    }
+
+    pub fn prune(&mut self, retention: &Retention) -> Option<PrunedRange> {
+        // Open disputes, withdrawals waiting for approval, open escrows and deficits still being recovered are kept
+        let mut candidates: Vec<&Tx> = self.txs_txid.values()
+            .filter(|tx| !tx.disputed() && !tx.pending && tx.recovery == 0)
+            .collect();
+        match retention {
+            Retention::Age(max_age) => {
+                let now = self.next_tx_time() - 1;
+                candidates.retain(|tx| now - tx.tx_time >= *max_age);
+            }
+            Retention::Count(max_count) => {
+                let excess = self.txs_txid.len().saturating_sub(*max_count);
+                candidates.sort_by_key(|tx| tx.tx_time);
+                candidates.truncate(excess);
+            }
+        }
+        if candidates.is_empty() {
+            return None;
+        }
+
+        let pruned_txs: HashSet<u32> = candidates.iter().map(|tx| tx.tx).collect();
+        let from_time = candidates.iter().map(|tx| tx.tx_time).min()?;
+        let to_time = candidates.iter().map(|tx| tx.tx_time).max()?;
+
+        self.txs_txid.retain(|tx, _| !pruned_txs.contains(tx));
+        let msgs_before = self.tx_msgs_time.len();
+        self.tx_msgs_time.retain(|_, msg| !pruned_txs.contains(&msg.tx));
+
+        let range = PrunedRange {
+            from_time,
+            to_time,
+            transactions: pruned_txs.len() as u32,
+            messages: (msgs_before - self.tx_msgs_time.len()) as u32,
+            pruned_at: SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()),
+        };
+        self.pruned_ranges.push(range.clone());
+        Some(range)
+    }
+
+    // Moves the balances, transactions and messages of client `from` onto client `into`, along with what it has
+    // withdrawn towards its limits, its fee tier usage and any deficit being recovered from it.
+    // The merged account stays locked if either account was locked.
+    pub fn merge_clients(&mut self, from: u16, into: u16) -> Result<MergedClients> {
+        if from == into {
+            return Err(Error::InvalidArgument(format!("Cannot merge client, {}, into itself.", from)));
+        }
+        if !self.account_client.contains_key(&into) {
+            return Err(Error::NotFound(format!("Client, {}, not found.", into)));
+        }
+        let source = self.account_client.remove(&from)
+            .ok_or_else(|| Error::NotFound(format!("Client, {}, not found.", from)))?;
+        let target = self.account_client.get_mut(&into).expect("Target account checked above.");
+
+        let lock_conflict = source.locked != target.locked;
+        target.available += source.available;
+        target.held += source.held;
+        target.escrow += source.escrow;
+        target.total = target.available + target.held + target.escrow;
+        target.locked = target.locked || source.locked;
+
+        let mut transactions = 0;
+        for tx in self.txs_txid.values_mut().filter(|tx| tx.client == from) {
+            tx.client = into;
+            transactions += 1;
+        }
+        let mut messages = 0;
+        for msg in self.tx_msgs_time.values_mut().filter(|msg| msg.client == from) {
+            msg.client = into;
+            messages += 1;
+        }
+        self.withdrawal_limits.merge_client(from, into);
+        self.fee_schedules.merge_client(from, into);
+        self.merge_recovery(from, into);
+
+        Ok(MergedClients { from, into, transactions, messages, lock_conflict })
+    }
+
+    #[cfg(feature = "io")]
+    fn erase_client(&mut self, client: u16) -> Option<ErasedClient> {
+        let account = self.account_client.remove(&client)?;
+
+        let txs_before = self.txs_txid.len();
+        self.txs_txid.retain(|_, tx| tx.client != client);
+        let msgs_before = self.tx_msgs_time.len();
+        self.tx_msgs_time.retain(|_, msg| msg.client != client);
+
+        Some(ErasedClient {
+            account,
+            transactions: (txs_before - self.txs_txid.len()) as u32,
+            messages: (msgs_before - self.tx_msgs_time.len()) as u32,
+        })
+    }
+
+    // Err carries the reason the message was ignored
+    pub fn handle_tx_message(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        if self.observers.0.is_empty() {
+            return self.run_pipeline(transaction_msg);
+        }
+        let before = self.account_client.get(&transaction_msg.client).cloned();
+        let result = self.run_pipeline(transaction_msg);
+        let change = AccountChange::between(before.as_ref(), self.account_client.get(&transaction_msg.client),
+                                            transaction_msg)
+            .map(|mut change| {
+                change.annotations.clone_from(&self.annotations);
+                change
+            });
+        // The message is handled by now, so an observer that fails is reported rather than made the message's result
+        for observer in self.observers.0.iter_mut() {
+            if let Err(e) = observer::dispatch(observer.as_mut(), change.as_ref(), transaction_msg, &result) {
+                #[cfg(feature = "io")]
+                diag!("Observer, {}, failed on transaction, {}. {}", observer.name(), transaction_msg.tx, e);
+                #[cfg(not(feature = "io"))]
+                self.observer_errors.push((observer.name().to_string(), e));
+            }
+        }
+        result
+    }
+
+    fn run_pipeline(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        self.touched.clear();
+        self.touched.extend(std::iter::once(transaction_msg.client).chain(transaction_msg.to_client));
+        let accrued = self.accrue_interest(transaction_msg.tx_time);
+        self.tx_msgs_time.insert(transaction_msg.tx_time, (*transaction_msg).clone());
+        let result = if accrued.is_err() {
+            accrued
+        } else if self.pipeline.0.is_empty() {
+            self.apply_tx(transaction_msg)
+        } else {
+            self.annotations.clear();
+            // Taken out while the chain runs, since each middleware is handed the engine
+            let mut middlewares = mem::take(&mut self.pipeline.0);
+            let result = Next::start(self, &mut middlewares).run(transaction_msg);
+            self.pipeline.0 = middlewares;
+            result
+        };
+        #[cfg(feature = "io")]
+        if result.is_ok() {
+            self.merkle.push(transaction_msg);
+        }
+        if result.is_ok() {
+            self.count_counterparty(transaction_msg);
+            self.count_category(transaction_msg);
+            self.count_volume(transaction_msg);
+            self.detect_anomalies(transaction_msg);
+        }
+        self.expire_disputes(transaction_msg.tx_time);
+        result
+    }
+
+    // The end of the pipeline, keeping the message as the middlewares passed it on
+    fn apply(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        self.tx_msgs_time.insert(transaction_msg.tx_time, transaction_msg.clone());
+        self.apply_tx(transaction_msg)
+    }
+
+    fn apply_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        match transaction_msg.tx_type  {
+            TransactionType::DEPOSIT => self.deposit_tx(transaction_msg),
+            TransactionType::WITHDRAWAL => self.withdrawal_tx(transaction_msg),
+            TransactionType::DISPUTE => self.dispute_tx(transaction_msg),
+            TransactionType::RESOLVE => self.resolve_tx(transaction_msg),
+            TransactionType::CHARGEBACK => self.chargeback_tx(transaction_msg),
+            TransactionType::APPROVE | TransactionType::DENY => self.settle_tx(transaction_msg),
+            TransactionType::ESCROW => self.escrow_tx(transaction_msg),
+            TransactionType::RELEASE | TransactionType::REFUND => self.escrow_settle_tx(transaction_msg),
+            TransactionType::TRANSFER => self.transfer_tx(transaction_msg),
+        }
+    }
+
+    // Deposits, withdrawals, escrows and transfers need an unused transaction id and an account that is not locked
+    fn check_new_tx(&self, transaction_msg: &TransactionMessage) -> Result<()> {
+        if self.txs_txid.contains_key(&transaction_msg.tx) {
+            return Err(rejected(Rejection::DuplicateTx,
+                                format!("Duplicate transaction ID, {}. Ignoring {} from client, {}.",
+                                        transaction_msg.tx, transaction_msg.tx_type, transaction_msg.client)));
+        }
+        // A client in recovery may still pay in, its deposits going to the deficit
+        let recovering = transaction_msg.tx_type == TransactionType::DEPOSIT
+            && self.in_recovery(transaction_msg.client);
+        if self.account_client.get(&transaction_msg.client).is_some_and(|acct| acct.locked) && !recovering {
+            return Err(rejected(Rejection::LockedAccount,
+                                format!("Account for client, {}, is locked. Ignoring {} transaction, {}.",
+                                        transaction_msg.client, transaction_msg.tx_type, transaction_msg.tx)));
+        }
+        Ok(())
+    }
+
+    // Disputes, resolves and chargebacks must name an existing transaction belonging to the same client
+    fn check_referenced_tx(&self, transaction_msg: &TransactionMessage) -> Result<()> {
+        match self.txs_txid.get(&transaction_msg.tx) {
+            None => Err(rejected(Rejection::UnknownTx,
+                                 format!("Failed to location transaction, {}. Ignoring {}.",
+                                         transaction_msg.tx, transaction_msg.tx_type))),
+            Some(tx) if tx.client != transaction_msg.client => Err(rejected(Rejection::ClientMismatch,
+                format!("Transaction, {}, belongs to client, {}, not client, {}. Ignoring {}.",
+                        transaction_msg.tx, tx.client, transaction_msg.client, transaction_msg.tx_type))),
+            Some(_) => Ok(()),
+        }
+    }
+
+    // Creates an empty account for a client that was first seen on a message that could not apply to it
+    fn unknown_client(&mut self, transaction_msg: &TransactionMessage) -> Error {
+        let new_acct = Account {
+            client: transaction_msg.client,
+            available: 0,
+            held: 0,
+            escrow: 0,
+            total: 0,
+            locked: false,
+        };
+        self.account_client.insert(transaction_msg.client, new_acct);
+        rejected(Rejection::UnknownClient,
+                 format!("Ignored {} on non-existent client, {}. New client account created with 0.000 total balance.",
+                         transaction_msg.tx_type, transaction_msg.client))
+    }
+
+    fn deposit_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        self.check_new_tx(transaction_msg)?;
+        let (quoted, dropped) = self.fee_schedules.quote(transaction_msg)?;
+        self.txs_txid.insert(transaction_msg.tx,
+                             Tx {
+                                 tx: transaction_msg.tx,
+                                 tx_time: transaction_msg.tx_time,
+                                 tx_type: transaction_msg.tx_type.clone(),
+                                 client: transaction_msg.client,
+                                 amount: transaction_msg.amount,
+                                 state: TxState::Posted,
+                                 dispute_time: 0,
+                                 chargeback_time: 0,
+                                 pending: false,
+                                 provisional: false,
+                                 fee: 0,
+                                 fee_loss: 0,
+                                 recovery: 0,
+                                 recovered: 0,
+                                 service_fee: 0,
+                                 counterparty: transaction_msg.counterparty.clone(),
+                                 memo: transaction_msg.memo.clone(),
+                                 category: transaction_msg.category.clone(),
+                                 history: Vec::new(),
+                             });
+        // The fee comes out of the deposit, and any deficit is swept from what is left
+        // A fee capped at the deposit drops nothing to rounding, the cap having taken more than the remainder
+        let fee = quoted.min(transaction_msg.amount);
+        let credited = transaction_msg.amount - fee;
+        let credited = credited - self.sweep_deposit(transaction_msg.client, credited, transaction_msg.tx_time);
+        if let Some(acct) = self.account_client.get_mut(&transaction_msg.client) {
+            acct.available += credited;
+            acct.total = acct.available + acct.held + acct.escrow;
+        } else {
+            let new_acct = Account {
+                client: transaction_msg.client,
+                available: credited,
+                held: 0,
+                escrow: 0,
+                total: credited,
+                locked: false,
+            };
+            self.account_client.insert(transaction_msg.client, new_acct);
+        }
+        self.txs_txid.get_mut(&transaction_msg.tx).expect("Transaction inserted above.").service_fee = fee;
+        self.fee_schedules.record(transaction_msg, fee);
+        self.rounding.fees.add(if quoted > fee { 0 } else { dropped });
+        Ok(())
+    }
+
+    fn withdrawal_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        self.check_new_tx(transaction_msg)?;
+        if !self.account_client.contains_key(&transaction_msg.client) {
+            return Err(self.unknown_client(transaction_msg));
+        }
+        // The fee is paid at once, even by a withdrawal waiting for approval
+        let (fee, dropped) = self.fee_schedules.quote(transaction_msg)?;
+        let acct = self.account_client.get_mut(&transaction_msg.client).expect("Account checked above.");
+        if acct.available < transaction_msg.amount.saturating_add(fee) {
+            return Err(rejected(Rejection::InsufficientFunds,
+                                format!("Insufficient funds for withdrawal. Ignored transaction. Client: {}, Transaction ID: {}.",
+                                        transaction_msg.client, transaction_msg.tx)));
+        }
+        self.withdrawal_limits.admit(transaction_msg)?;
+        // Recorded only once it applies, so a refused withdrawal leaves nothing to dispute or approve
+        let pending = self.approval_threshold.is_some_and(|threshold| transaction_msg.amount > threshold);
+        acct.available -= transaction_msg.amount + fee;
+        if pending {
+            acct.held += transaction_msg.amount;
+        }
+        acct.total = acct.available + acct.held + acct.escrow;
+        self.txs_txid.insert(transaction_msg.tx,
+                             Tx {
+                                 tx: transaction_msg.tx,
+                                 tx_time: transaction_msg.tx_time,
+                                 tx_type: transaction_msg.tx_type.clone(),
+                                 client: transaction_msg.client,
+                                 amount: transaction_msg.amount,
+                                 state: TxState::Posted,
+                                 dispute_time: 0,
+                                 chargeback_time: 0,
+                                 pending,
+                                 provisional: false,
+                                 fee: 0,
+                                 fee_loss: 0,
+                                 recovery: 0,
+                                 recovered: 0,
+                                 service_fee: fee,
+                                 counterparty: transaction_msg.counterparty.clone(),
+                                 memo: transaction_msg.memo.clone(),
+                                 category: transaction_msg.category.clone(),
+                                 history: Vec::new(),
+                             });
+        self.fee_schedules.record(transaction_msg, fee);
+        self.rounding.fees.add(dropped);
+        Ok(())
+    }
+
+    fn dispute_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        if !self.account_client.contains_key(&transaction_msg.client) {
+            return Err(self.unknown_client(transaction_msg));
+        }
+        self.check_referenced_tx(transaction_msg)?;
+        let open_disputes = match self.max_open_disputes {
+            Some(_) => self.txs_txid.values().filter(|tx| tx.client == transaction_msg.client && tx.disputed()).count(),
+            None => 0,
+        };
+        let provisional = self.account_types.provisional_credit(transaction_msg.client);
+        let acct = self.account_client.get_mut(&transaction_msg.client).expect("Account checked above.");
+        let tx = self.txs_txid.get_mut(&transaction_msg.tx).expect("Transaction checked above.");
+        if acct.locked {
+            Err(rejected(Rejection::LockedAccount,
+                         format!("Account for client, {}, is locked. Ignoring dispute of transaction, {}.",
+                                 transaction_msg.client, transaction_msg.tx)))
+        } else if matches!(tx.tx_type, TransactionType::ESCROW | TransactionType::TRANSFER) {
+            Err(rejected(Rejection::NotDisputable,
+                         format!("Transaction, {}, is {} {}. Ignoring dispute.", transaction_msg.tx,
+                                 if tx.tx_type == TransactionType::ESCROW { "an" } else { "a" }, tx.tx_type)))
+        } else if tx.pending {
+            Err(rejected(Rejection::PendingApproval,
+                         format!("Transaction, {}, is waiting for approval. Ignoring dispute.", transaction_msg.tx)))
+        } else if let Err(e) = transition(tx, transaction_msg) {
+            Err(e)
+        } else if self.max_open_disputes.is_some_and(|max| open_disputes >= max as usize) {
+            Err(rejected(Rejection::TooManyDisputes,
+                         format!("Client, {}, already has {} open disputes. Ignoring dispute of transaction, {}.",
+                                 transaction_msg.client, open_disputes, transaction_msg.tx)))
+        } else if provisional || acct.available >= tx.amount {
+            if !provisional {
+                acct.held += tx.amount;
+                acct.available -= tx.amount;
+            }
+            tx.provisional = provisional;
+            tx.state = TxState::Disputed;
+            tx.dispute_time = transaction_msg.tx_time;
+            tx.record_event(DisputeEventKind::Dispute, transaction_msg.tx_time);
+            self.track_dispute(transaction_msg.tx_time, transaction_msg.tx);
+            Ok(())
+        } else {
+            Err(rejected(Rejection::InsufficientFunds,
+                         format!("Unable to hold funds for dispute of transaction, {}, from client, {}. Ignoring dispute.",
+                                 transaction_msg.tx, transaction_msg.client)))
+        }
+    }
+
+    // Resolves are accepted on locked accounts so funds held by other disputes can still be released
+    fn resolve_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        if !self.account_client.contains_key(&transaction_msg.client) {
+            return Err(self.unknown_client(transaction_msg));
+        }
+        self.check_referenced_tx(transaction_msg)?;
+        let acct = self.account_client.get_mut(&transaction_msg.client).expect("Account checked above.");
+        let tx = self.txs_txid.get_mut(&transaction_msg.tx).expect("Transaction checked above.");
+        let state = transition(tx, transaction_msg)?;
+        if tx.provisional || tx.amount <= acct.held {
+            if !tx.provisional {
+                acct.held -= tx.amount;
+                acct.available += tx.amount;
+                acct.total = acct.available + acct.held + acct.escrow;
+            }
+            self.resolutions.record(tx.dispute_time, transaction_msg.tx_time);
+            tx.state = state;
+            tx.dispute_time = 0;
+            tx.record_event(DisputeEventKind::Resolve, transaction_msg.tx_time);
+            Ok(())
+        } else {
+            Err(rejected(Rejection::InsufficientHeld,
+                         format!("Unable to resolve held funds for disputed transaction, {}, from client, {}. Ignoring resolve.",
+                                 transaction_msg.tx, transaction_msg.client)))
+        }
+    }
+
+    fn chargeback_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        if !self.account_client.contains_key(&transaction_msg.client) {
+            return Err(self.unknown_client(transaction_msg));
+        }
+        self.check_referenced_tx(transaction_msg)?;
+        let recovering = self.deficit_recovery();
+        let acct = self.account_client.get_mut(&transaction_msg.client).expect("Account checked above.");
+        let tx = self.txs_txid.get_mut(&transaction_msg.tx).expect("Transaction checked above.");
+        let state = transition(tx, transaction_msg)?;
+        if tx.provisional && tx.amount > acct.available && !recovering {
+            return Err(rejected(Rejection::InsufficientFunds,
+                                format!("Unable to reverse provisional credit for transaction, {}, from client, {}. \
+                                         Ignoring chargeback.", transaction_msg.tx, transaction_msg.client)));
+        }
+        // What the client has spent of the provisional credit, left as a deficit to recover
+        let shortfall = if tx.provisional { tx.amount.saturating_sub(acct.available) } else { 0 };
+        if tx.provisional || tx.amount <= acct.held {
+            if tx.provisional {
+                acct.available -= tx.amount - shortfall;
+            } else {
+                acct.held -= tx.amount;
+            }
+            acct.total = acct.available + acct.held + acct.escrow;
+            acct.locked = true;
+            tx.state = state;
+            tx.dispute_time = 0;
+            tx.chargeback_time = transaction_msg.tx_time;
+            tx.record_event(DisputeEventKind::Chargeback, transaction_msg.tx_time);
+            if shortfall > 0 {
+                self.open_recovery(transaction_msg.tx, shortfall);
+            }
+            self.assess_chargeback_fee(transaction_msg.tx, transaction_msg.tx_time);
+            Ok(())
+        } else {
+            Err(rejected(Rejection::InsufficientHeld,
+                         format!("Failed to complete chargeback. Hold less chargeback amount: {}, Disputed: {}, transaction: {}.",
+                                 acct.held as SignedAmount - tx.amount as SignedAmount, tx.disputed(), transaction_msg.tx)))
+        }
+    }
+
+    // Approves or denies a pending withdrawal. Accepted on locked accounts, like resolves, since it only releases
+    // held funds.
+    fn settle_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        if !self.account_client.contains_key(&transaction_msg.client) {
+            return Err(self.unknown_client(transaction_msg));
+        }
+        self.check_referenced_tx(transaction_msg)?;
+        self.settle_pending(transaction_msg.tx, transaction_msg.tx_type == TransactionType::APPROVE)
+    }
+
+    // An approved withdrawal takes its held funds out of the account, a denied one returns them to available
+    pub(crate) fn settle_pending(&mut self, tx: u32, approve: bool) -> Result<()> {
+        let verb = if approve { "approve" } else { "deny" };
+        let pending = self.txs_txid.get_mut(&tx)
+            .ok_or_else(|| rejected(Rejection::UnknownTx,
+                                    format!("Failed to location transaction, {}. Ignoring {}.", tx, verb)))?;
+        let acct = self.account_client.get_mut(&pending.client)
+            .ok_or_else(|| Error::NotFound(format!("Client, {}, not found.", pending.client)))?;
+        if !pending.pending || pending.tx_type != TransactionType::WITHDRAWAL {
+            return Err(rejected(Rejection::NotPending,
+                                format!("Transaction, {}, from client, {}, is not waiting for approval. Ignoring {}.",
+                                        tx, pending.client, verb)));
+        }
+        if acct.held < pending.amount {
+            return Err(rejected(Rejection::InsufficientHeld,
+                                format!("Client, {}, holds less than pending withdrawal, {}. Ignoring {}.",
+                                        pending.client, tx, verb)));
+        }
+        acct.held -= pending.amount;
+        if !approve {
+            acct.available += pending.amount;
+        }
+        acct.total = acct.available + acct.held + acct.escrow;
+        pending.pending = false;
+        Ok(())
+    }
+
+    // Funds received into escrow for a client, kept out of available until a release or refund
+    fn escrow_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        self.check_new_tx(transaction_msg)?;
+        self.txs_txid.insert(transaction_msg.tx,
+                             Tx {
+                                 tx: transaction_msg.tx,
+                                 tx_time: transaction_msg.tx_time,
+                                 tx_type: transaction_msg.tx_type.clone(),
+                                 client: transaction_msg.client,
+                                 amount: transaction_msg.amount,
+                                 state: TxState::Posted,
+                                 dispute_time: 0,
+                                 chargeback_time: 0,
+                                 pending: true,
+                                 provisional: false,
+                                 fee: 0,
+                                 fee_loss: 0,
+                                 recovery: 0,
+                                 recovered: 0,
+                                 service_fee: 0,
+                                 counterparty: transaction_msg.counterparty.clone(),
+                                 memo: transaction_msg.memo.clone(),
+                                 category: transaction_msg.category.clone(),
+                                 history: Vec::new(),
+                             });
+        let acct = self.account_client.entry(transaction_msg.client).or_insert_with(|| Account {
+            client: transaction_msg.client,
+            available: 0,
+            held: 0,
+            escrow: 0,
+            total: 0,
+            locked: false,
+        });
+        acct.escrow += transaction_msg.amount;
+        acct.total = acct.available + acct.held + acct.escrow;
+        Ok(())
+    }
+
+    // A release pays an escrow's funds into available, a refund returns them to the payer. Accepted on locked
+    // accounts, like resolves, since the funds are already on the account.
+    fn escrow_settle_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        if !self.account_client.contains_key(&transaction_msg.client) {
+            return Err(self.unknown_client(transaction_msg));
+        }
+        self.check_referenced_tx(transaction_msg)?;
+        let acct = self.account_client.get_mut(&transaction_msg.client).expect("Account checked above.");
+        let tx = self.txs_txid.get_mut(&transaction_msg.tx).expect("Transaction checked above.");
+        if tx.tx_type != TransactionType::ESCROW || !tx.pending {
+            return Err(rejected(Rejection::NotInEscrow,
+                                format!("Transaction, {}, from client, {}, is not in escrow. Ignoring {}.",
+                                        transaction_msg.tx, transaction_msg.client, transaction_msg.tx_type)));
+        }
+        if acct.escrow < tx.amount {
+            return Err(rejected(Rejection::InsufficientHeld,
+                                format!("Client, {}, has less in escrow than transaction, {}. Ignoring {}.",
+                                        transaction_msg.client, transaction_msg.tx, transaction_msg.tx_type)));
+        }
+        acct.escrow -= tx.amount;
+        if transaction_msg.tx_type == TransactionType::RELEASE {
+            acct.available += tx.amount;
+        }
+        acct.total = acct.available + acct.held + acct.escrow;
+        tx.pending = false;
+        Ok(())
+    }
+}
+
+// The state a dispute, resolve or chargeback moves its transaction to, or why the sequence is refused
+fn transition(tx: &Tx, transaction_msg: &TransactionMessage) -> Result<TxState> {
+    tx.state.after(&transaction_msg.tx_type).ok_or_else(|| match tx.state {
+        TxState::ChargedBack => rejected(Rejection::ChargedBack,
+                                         format!("Transaction, {}, from client, {}, was charged back. Ignoring {}.",
+                                                 transaction_msg.tx, transaction_msg.client, transaction_msg.tx_type)),
+        TxState::Disputed => rejected(Rejection::AlreadyDisputed,
+                                      format!("Transaction, {}, is already disputed. Ignoring dispute.",
+                                              transaction_msg.tx)),
+        TxState::Posted | TxState::Resolved => rejected(Rejection::NotDisputed,
+            format!("Transaction, {}, from client, {}, is not disputed. Ignoring {}.",
+                    transaction_msg.tx, transaction_msg.client, transaction_msg.tx_type)),
+    })
+}
+
+pub(crate) fn rejected(reason: Rejection, message: String) -> Error {
+    Error::PolicyViolation { reason, message }
+}
+
+// Truncates places past the fourth. Input rows round by the engine's mode, see AccountTransactions::set_rounding_mode.
+pub fn to_subunit(amount_unit: &str) -> Result<Amount> {
+    rounding::parse_subunits(amount_unit, RoundingMode::Truncate)
+}
+
+// A percentage read like an amount, in parts per million, 1.5 being 15000
+#[cfg_attr(not(feature = "amount128"), allow(clippy::useless_conversion))]
+pub fn to_ppm(percent: &str) -> Result<u64> {
+    let ppm = to_subunit(percent)?;
+    u64::try_from(ppm).map_err(|_| Error::ParseError(format!("'{}' is out of range for a percentage", percent)))
+}
+
+pub fn to_currency_unit(amount_subunit: Amount) -> f64 {
+    amount_subunit as f64 / 1.0e+4_f64
+}
+
+// The exact decimal of an amount in currency units, as to_currency_unit would print it but without going through f64,
+// which cannot hold every amount once they are wider than 53 bits
+pub fn format_amount(amount_subunit: Amount) -> String {
+    let (whole, fraction) = (amount_subunit / 10_000, amount_subunit % 10_000);
+    match fraction {
+        0 => whole.to_string(),
+        _ => format!("{}.{}", whole, format!("{:04}", fraction).trim_end_matches('0')),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{apply, message, units};
+
+    #[test]
+    fn prune_keeps_withdrawals_waiting_for_approval() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_approval_threshold(Some(units(50)));
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(100))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(60))).unwrap();
+
+        let pruned = account_txs.prune(&Retention::Count(0)).unwrap();
+        assert_eq!(pruned.transactions, 1);
+        assert!(account_txs.get_transaction(1).is_none());
+        assert!(account_txs.get_transaction(2).is_some_and(|tx| tx.pending));
+
+        apply(&mut account_txs, message(TransactionType::APPROVE, 1, 2, 0)).unwrap();
+        let account = account_txs.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (units(40), 0, units(40)));
+    }
+
+    #[test]
+    fn prune_keeps_open_escrows() {
+        let mut account_txs = AccountTransactions::new();
+        apply(&mut account_txs, message(TransactionType::ESCROW, 42, 9001, units(250))).unwrap();
+        apply(&mut account_txs, message(TransactionType::ESCROW, 42, 9002, units(80))).unwrap();
+        apply(&mut account_txs, message(TransactionType::RELEASE, 42, 9002, 0)).unwrap();
+
+        let pruned = account_txs.prune(&Retention::Count(0)).unwrap();
+        assert_eq!(pruned.transactions, 1);
+        assert_eq!(account_txs.open_escrows().map(|tx| tx.tx).collect::<Vec<u32>>(), vec![9001]);
+
+        apply(&mut account_txs, message(TransactionType::RELEASE, 42, 9001, 0)).unwrap();
+        let account = account_txs.get_account(42).unwrap();
+        assert_eq!((account.available, account.escrow, account.total), (units(330), 0, units(330)));
+        // A released escrow can go once nothing else holds it
+        assert_eq!(account_txs.prune(&Retention::Count(0)).map(|pruned| pruned.transactions), Some(1));
+    }
+
+    #[test]
+    fn prune_by_age_keeps_recent_transactions() {
+        let mut account_txs = AccountTransactions::new();
+        for tx in 1..=3 {
+            apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, tx, units(1))).unwrap();
+        }
+        let pruned = account_txs.prune(&Retention::Age(1)).unwrap();
+        assert_eq!((pruned.from_time, pruned.to_time, pruned.transactions), (1, 2, 2));
+        assert!(account_txs.get_transaction(3).is_some());
+        assert!(account_txs.prune(&Retention::Age(1)).is_none());
+    }
+
+    #[test]
+    fn merge_moves_limit_usage_fee_usage_and_recoveries() {
+        let mut account_txs = AccountTransactions::new();
+        let mut account_types = AccountTypes::new();
+        account_types.add_type("consumer", account_types::AccountPolicy { provisional_credit: true, interest: None });
+        account_types.assign(2, "consumer").unwrap();
+        account_txs.set_account_types(account_types);
+        account_txs.set_deficit_recovery(true);
+        let mut limits = WithdrawalLimits::new();
+        limits.set_default(withdrawal_limits::WithdrawalLimit { max_single: None, max_cumulative: Some(units(100)) });
+        account_txs.set_withdrawal_limits(limits);
+        let mut schedule = fee_schedule::FeeSchedule::new(None);
+        schedule.add_tier(fee_schedule::FeeTier { after: 10, ..fee_schedule::FeeTier::default() });
+        let mut schedules = FeeSchedules::new();
+        schedules.set(TransactionType::DEPOSIT, schedule).unwrap();
+        account_txs.set_fee_schedules(schedules);
+
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(50))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(100))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DISPUTE, 2, 2, 0)).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 2, 3, units(80))).unwrap();
+        apply(&mut account_txs, message(TransactionType::CHARGEBACK, 2, 2, 0)).unwrap();
+        assert_eq!(account_txs.recoveries().iter().map(|r| (r.client, r.deficit)).collect::<Vec<_>>(),
+                   vec![(2, units(80))]);
+
+        account_txs.merge_clients(2, 1).unwrap();
+        assert_eq!((account_txs.withdrawal_limits().withdrawn(1), account_txs.withdrawal_limits().withdrawn(2)),
+                   (units(80), 0));
+        let usage = account_txs.fee_schedules().usage();
+        assert_eq!(usage.keys().map(|(client, _, _)| *client).collect::<Vec<u16>>(), vec![1]);
+        assert_eq!(usage[&(1, TransactionType::DEPOSIT, 0)].count, 2);
+        assert_eq!(account_txs.recoveries().iter().map(|r| (r.client, r.deficit)).collect::<Vec<_>>(),
+                   vec![(1, units(80))]);
+        // The merged account is locked, but still pays in towards the deficit it took on
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 4, units(30))).unwrap();
+        assert_eq!(account_txs.recoveries()[0].recovered, units(30));
+    }
+
+    fn rejection(result: Result<()>) -> Option<Rejection> {
+        match result {
+            Err(Error::PolicyViolation { reason, .. }) => Some(reason),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn withdrawals_over_the_approval_threshold_wait_to_be_approved_or_denied() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_approval_threshold(Some(units(50)));
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(100))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(10))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 3, units(60))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 4, units(20))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 5, units(100))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 6, units(70))).unwrap();
+        let account = account_txs.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (units(40), units(130), units(170)));
+
+        apply(&mut account_txs, message(TransactionType::APPROVE, 1, 3, 0)).unwrap();
+        apply(&mut account_txs, message(TransactionType::DENY, 1, 6, 0)).unwrap();
+        let account = account_txs.get_account(1).unwrap();
+        assert_eq!((account.available, account.held, account.total), (units(110), 0, units(110)));
+
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::APPROVE, 1, 3, 0))),
+                   Some(Rejection::NotPending));
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::DENY, 1, 2, 0))),
+                   Some(Rejection::NotPending));
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::APPROVE, 1, 99, 0))),
+                   Some(Rejection::UnknownTx));
+    }
+
+    #[test]
+    fn a_refunded_escrow_leaves_the_account_and_cannot_be_released() {
+        let mut account_txs = AccountTransactions::new();
+        apply(&mut account_txs, message(TransactionType::ESCROW, 7, 1, units(30))).unwrap();
+        let account = account_txs.get_account(7).unwrap();
+        assert_eq!((account.available, account.escrow, account.total), (0, units(30), units(30)));
+
+        apply(&mut account_txs, message(TransactionType::REFUND, 7, 1, 0)).unwrap();
+        let account = account_txs.get_account(7).unwrap();
+        assert_eq!((account.available, account.escrow, account.total), (0, 0, 0));
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::RELEASE, 7, 1, 0))),
+                   Some(Rejection::NotInEscrow));
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 7, 2, units(5))).unwrap();
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::RELEASE, 7, 2, 0))),
+                   Some(Rejection::NotInEscrow));
+    }
+
+    #[test]
+    fn a_chargeback_locks_the_account_against_new_funds() {
+        let mut account_txs = AccountTransactions::new();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 3, 1, units(20))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 3, 2, units(5))).unwrap();
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::CHARGEBACK, 3, 1, 0))),
+                   Some(Rejection::NotDisputed));
+        apply(&mut account_txs, message(TransactionType::DISPUTE, 3, 1, 0)).unwrap();
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::DISPUTE, 3, 1, 0))),
+                   Some(Rejection::AlreadyDisputed));
+        apply(&mut account_txs, message(TransactionType::CHARGEBACK, 3, 1, 0)).unwrap();
+        let account = account_txs.get_account(3).unwrap();
+        assert_eq!((account.available, account.held, account.total, account.locked), (units(5), 0, units(5), true));
+
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::DEPOSIT, 3, 3, units(1)))),
+                   Some(Rejection::LockedAccount));
+        assert_eq!(rejection(apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 3, 4, units(1)))),
+                   Some(Rejection::LockedAccount));
+    }
+
+    struct FailingObserver;
+
+    impl EngineObserver for FailingObserver {
+        fn name(&self) -> &str {
+            "failing"
+        }
+
+        fn account_changed(&mut self, _change: &AccountChange) -> Result<()> {
+            Err(Error::SinkError(String::from("unreachable")))
+        }
+    }
+
+    #[test]
+    fn a_failing_observer_leaves_the_result_to_the_engine() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.add_observer(Box::new(FailingObserver));
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        assert!(matches!(apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(20))),
+                         Err(Error::PolicyViolation { reason: Rejection::InsufficientFunds, .. })));
+        assert_eq!(account_txs.get_account(1).unwrap().available, units(10));
+        #[cfg(not(feature = "io"))]
+        assert_eq!(account_txs.take_observer_errors().len(), 1);
+    }
+}
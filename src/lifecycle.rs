@@ -0,0 +1,94 @@
+use std::fmt;
+
+use crate::{Error, Result};
+
+// What happened to a transaction in the dispute process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisputeEventKind {
+    Dispute,
+    Resolve,
+    Chargeback,
+    // Resolved by the engine for staying open too long
+    Expire,
+    // Resolved by an administrator's close-dispute
+    Close,
+    // The chargeback fee posted once the chargeback completed
+    Fee,
+    // Part of the deficit the chargeback left swept from a deposit, at the deposit's row
+    Recover,
+}
+
+impl fmt::Display for DisputeEventKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            DisputeEventKind::Dispute => "dispute",
+            DisputeEventKind::Resolve => "resolve",
+            DisputeEventKind::Chargeback => "chargeback",
+            DisputeEventKind::Expire => "expire",
+            DisputeEventKind::Close => "close",
+            DisputeEventKind::Fee => "fee",
+            DisputeEventKind::Recover => "recover",
+        })
+    }
+}
+
+impl std::str::FromStr for DisputeEventKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<DisputeEventKind> {
+        match s {
+            "dispute" => Ok(DisputeEventKind::Dispute),
+            "resolve" => Ok(DisputeEventKind::Resolve),
+            "chargeback" => Ok(DisputeEventKind::Chargeback),
+            "expire" => Ok(DisputeEventKind::Expire),
+            "close" => Ok(DisputeEventKind::Close),
+            "fee" => Ok(DisputeEventKind::Fee),
+            "recover" => Ok(DisputeEventKind::Recover),
+            _ => Err(Error::ParseError(format!("'{}' is not a valid dispute event", s))),
+        }
+    }
+}
+
+// One step of a transaction's dispute lifecycle, at the tx_time of its row. An expiry or an administrator's close has
+// no row of its own and takes the tx_time of the last row handled before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DisputeEvent {
+    pub kind: DisputeEventKind,
+    pub tx_time: u32,
+}
+
+// The events as they are stored and exported, e.g. dispute@3;resolve@5, empty when there are none
+pub fn history_text(events: &[DisputeEvent]) -> String {
+    let events: Vec<String> = events.iter().map(|event| format!("{}@{}", event.kind, event.tx_time)).collect();
+    events.join(";")
+}
+
+pub fn parse_history(text: &str) -> Result<Vec<DisputeEvent>> {
+    text.split(';')
+        .filter(|event| !event.is_empty())
+        .map(|event| {
+            let (kind, tx_time) = event.split_once('@')
+                .ok_or_else(|| Error::ParseError(format!("'{}' is not a valid dispute event", event)))?;
+            let tx_time = tx_time.parse()
+                .map_err(|_| Error::ParseError(format!("'{}' is not a valid dispute event", event)))?;
+            Ok(DisputeEvent { kind: kind.parse()?, tx_time })
+        })
+        .collect()
+}
+
+// For Tx::history, kept as its text so it fits in a CSV column
+#[cfg(feature = "serde")]
+pub(crate) mod history_serde {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    use super::DisputeEvent;
+
+    pub fn serialize<S: Serializer>(events: &[DisputeEvent], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&super::history_text(events))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<DisputeEvent>, D::Error> {
+        let text = String::deserialize(deserializer)?;
+        super::parse_history(&text).map_err(serde::de::Error::custom)
+    }
+}
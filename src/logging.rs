@@ -0,0 +1,185 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+#[cfg(unix)]
+use std::process;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::{LogConfig, LogDestination};
+use crate::{Error, Result};
+
+// Writes a diagnostic line to the configured log destination, STDERR until logging::init is called
+#[macro_export]
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        $crate::logging::write(&format!($($arg)*))
+    };
+}
+
+// syslog facility user (1) with severity notice (5)
+#[cfg(unix)]
+const SYSLOG_PRIORITY: u32 = 8 + 5;
+#[cfg(unix)]
+const IDENTIFIER: &str = "tx_acct";
+
+enum Sink {
+    Stderr,
+    File(RotatingFile),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+    // Lines kept back while something else draws on STDERR, see hold
+    Held(Vec<String>),
+}
+
+static SINK: Mutex<Option<Sink>> = Mutex::new(None);
+
+struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    keep: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: PathBuf, max_bytes: u64, keep: u32) -> Result<RotatingFile> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile { path, max_bytes, keep, file, size })
+    }
+
+    fn write_line(&mut self, line: &str) -> Result<()> {
+        if self.size > 0 && self.size + line.len() as u64 + 1 > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    // path.<keep> is dropped, every other generation moves up by one, and path starts empty
+    fn rotate(&mut self) -> Result<()> {
+        let generation = |n: u32| PathBuf::from(format!("{}.{}", self.path.display(), n));
+        if self.keep == 0 {
+            self.file = File::create(&self.path)?;
+        } else {
+            let _ = fs::remove_file(generation(self.keep));
+            for n in (1..self.keep).rev() {
+                if generation(n).exists() {
+                    fs::rename(generation(n), generation(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, generation(1))?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        self.size = 0;
+        Ok(())
+    }
+}
+
+pub fn init(config: &LogConfig) -> Result<()> {
+    let sink = match config.destination {
+        LogDestination::Stderr => Sink::Stderr,
+        LogDestination::File => {
+            let path = config.path.as_ref()
+                .ok_or_else(|| Error::ConfigError(String::from("Log destination 'file' needs a path.")))?;
+            Sink::File(RotatingFile::open(PathBuf::from(path), config.max_bytes, config.keep)?)
+        }
+        #[cfg(unix)]
+        LogDestination::Syslog => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(&config.syslog_socket)?;
+            Sink::Syslog(socket)
+        }
+        #[cfg(unix)]
+        LogDestination::Journald => {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(&config.journald_socket)?;
+            Sink::Journald(socket)
+        }
+        #[cfg(not(unix))]
+        LogDestination::Syslog | LogDestination::Journald => {
+            return Err(Error::ConfigError(String::from("Syslog and journald logging need a Unix socket, which this platform lacks.")));
+        }
+    };
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(sink);
+    Ok(())
+}
+
+// Keeps lines bound for STDERR in memory until release, so they do not tear through a full-screen display. Other
+// destinations are left alone.
+pub fn hold() {
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    if matches!(*sink, None | Some(Sink::Stderr)) {
+        *sink = Some(Sink::Held(Vec::new()));
+    }
+}
+
+// Writes out the lines held since hold, and goes back to writing straight to STDERR
+pub fn release() {
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(Sink::Held(lines)) = sink.as_mut() {
+        for line in lines.drain(..) {
+            eprintln!("{}", line);
+        }
+        *sink = Some(Sink::Stderr);
+    }
+}
+
+pub fn write(message: &str) {
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    let result: Result<()> = match sink.as_mut() {
+        None | Some(Sink::Stderr) => {
+            eprintln!("{}", message);
+            Ok(())
+        }
+        Some(Sink::Held(lines)) => {
+            lines.push(message.to_string());
+            Ok(())
+        }
+        Some(Sink::File(file)) => file.write_line(&format!("{} {}", utc_timestamp(), message)),
+        #[cfg(unix)]
+        Some(Sink::Syslog(socket)) => {
+            let line = format!("<{}>{}[{}]: {}", SYSLOG_PRIORITY, IDENTIFIER, process::id(), message);
+            socket.send(line.as_bytes()).map(|_| ()).map_err(Error::from)
+        }
+        #[cfg(unix)]
+        Some(Sink::Journald(socket)) => {
+            let entry = format!("MESSAGE={}\nSYSLOG_IDENTIFIER={}\nPRIORITY=5\n",
+                                message.replace('\n', " "), IDENTIFIER);
+            socket.send(entry.as_bytes()).map(|_| ()).map_err(Error::from)
+        }
+    };
+    // Never lose a diagnostic because the log destination went away
+    if let Err(e) = result {
+        eprintln!("{}", message);
+        eprintln!("Failed to write to log destination. {}", e);
+    }
+}
+
+// RFC 3339 UTC timestamp with second precision
+pub fn utc_timestamp() -> String {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    format_utc(secs)
+}
+
+pub fn format_utc(secs: u64) -> String {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rem / 3600, rem / 60 % 60, rem % 60)
+}
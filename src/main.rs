@@ -1,302 +1,1598 @@
+use std::collections::{BTreeMap, HashMap};
 use std::env;
-use std::error::Error;
-use std::fmt::Debug;
-use std::str::FromStr;
-
-use serde::Deserialize;
-use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufWriter, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::sync::Arc;
 
-#[derive(Debug, Deserialize)]
-struct TxInputRecord {
-    #[serde(rename = "type")]
-    tx_type: String,
-    client: u16,
-    tx: u32,
-    amount: String,
-}
-
-// Expect amount to be currency subunit, fraction of main unit like cents for USD
-#[derive(Debug,Clone)]
-struct TransactionMessage {
-  tx_time: u32,
-  tx: u32,
-  tx_type: TransactionType,
-  client: u16,
-  amount: u64,
-}
-
-// Limit tx_type to either WITHDRAWAL or DEPOSIT
-#[derive(Debug,Clone)]
-struct Tx {
-    tx: u32,
-    tx_type: TransactionType,
-    client: u16,
-    amount: u64,
-    disputed: bool,
-}
-
-#[derive(Debug,Clone)]
-enum TransactionType {
-  WITHDRAWAL,
-  DEPOSIT,
-  DISPUTE,
-  RESOLVE,
-  CHARGEBACK,
-}
-
-// Expect available, held, total to be currency subunit, fraction of main unit
-#[derive(Debug)]
-struct Account {
-    client: u16,
-    available: u64,
-    held: u64,
-    total: u64,
-    locked: bool,
-}
-
-impl std::str::FromStr for TransactionType {
-    type Err = String;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "withdraw" => Ok(TransactionType::WITHDRAWAL),
-            "deposit" => Ok(TransactionType::DEPOSIT),
-            "dispute" => Ok(TransactionType::DISPUTE),
-            "resolve" => Ok(TransactionType::RESOLVE),
-            "chargeback" => Ok(TransactionType::CHARGEBACK),
-            _ => Err(format!("'{}' is not a valid TransactionType", s)),
-        }
-    }
-}
-
-#[derive(Debug)]
-struct AccountTransactions {
-    txs_txid: HashMap<u32, Tx>,
-    account_client: HashMap<u16, Account>,
-    tx_msgs_time: HashMap<u32, TransactionMessage>
-}
-
-impl AccountTransactions {
-    fn new() -> AccountTransactions {
-        AccountTransactions {
-            txs_txid: HashMap::new(),
-            tx_msgs_time: HashMap::new(),
-            account_client: HashMap::new(),
-        }
-    }
-
-    fn handle_tx_message(&mut self, transaction_msg: &TransactionMessage) {
-        self.tx_msgs_time.insert(transaction_msg.tx_time, (*transaction_msg).clone());
-
-        match transaction_msg.tx_type  {
-            TransactionType::DEPOSIT => self.deposit_tx(transaction_msg),
-            TransactionType::WITHDRAWAL => self.withdrawal_tx(transaction_msg),
-            TransactionType::DISPUTE => self.dispute_tx(transaction_msg),
-            TransactionType::RESOLVE => self.resolve_tx(transaction_msg),
-            TransactionType::CHARGEBACK => self.chargeback_tx(transaction_msg)
-        }
-    }
-
-    fn deposit_tx(&mut self, transaction_msg: &TransactionMessage) {
-        self.txs_txid.insert(transaction_msg.tx,
-                             Tx {
-                                 tx: transaction_msg.tx,
-                                 tx_type: transaction_msg.tx_type.clone(),
-                                 client: transaction_msg.client,
-                                 amount: transaction_msg.amount,
-                                 disputed: false,
-                             });
-        if let Some(acct) = self.account_client.get_mut(&transaction_msg.client) {
-            acct.available += transaction_msg.amount;
-            acct.total = acct.available + acct.held;
-        } else {
-            let new_acct = Account {
-                client: transaction_msg.client,
-                available: transaction_msg.amount,
-                held: 0,
-                total: transaction_msg.amount,
-                locked: false,
-            };
-            self.account_client.insert(transaction_msg.client, new_acct);
-        }
-    }
-
-    fn withdrawal_tx(&mut self, transaction_msg: &TransactionMessage) {
-        self.txs_txid.insert(transaction_msg.tx,
-                             Tx {
-                                 tx: transaction_msg.tx,
-                                 tx_type: transaction_msg.tx_type.clone(),
-                                 client: transaction_msg.client,
-                                 amount: transaction_msg.amount,
-                                 disputed: false,
-                             });
-        if let Some(acct) = self.account_client.get_mut(&transaction_msg.client) {
-            if acct.available >= transaction_msg.amount {
-                acct.available -= transaction_msg.amount;
-                acct.total = acct.available + acct.held;
-            } else {
-                eprintln!("Insufficient funds for withdrawal. Ignored transaction. Client: {}, Transaction ID: {}.",
-                          transaction_msg.client, transaction_msg.tx);
+use tx_acct::account_types::{AccountPolicy, AccountTypes};
+use tx_acct::admin::{signed_subunits, AdminAction, AuditLog};
+use tx_acct::anomaly::{Anomaly, AnomalyRules};
+#[cfg(feature = "bench")]
+use tx_acct::bench::{self, CountingAlloc};
+use tx_acct::cancel::CancelToken;
+use tx_acct::category::CategoryTotals;
+use tx_acct::chargeback_fee::{ChargebackFee, FeePayer};
+use tx_acct::cli::{self, Shell};
+use tx_acct::config::{self, Config, DedupConfig, ServeAuth, SigningConfig};
+use tx_acct::counterparty::CounterpartyStats;
+#[cfg(feature = "tui")]
+use tx_acct::dashboard::Dashboard;
+use tx_acct::decrypt;
+use tx_acct::dispute_expiry::DisputeExpiry;
+use tx_acct::encryption;
+use tx_acct::fee_schedule::{FeeSchedule, FeeSchedules, FeeTier};
+use tx_acct::filter::{ClientList, Filter};
+use tx_acct::hooks::HookConfig;
+use tx_acct::interest::{Accrual, InterestRate};
+use tx_acct::latency::Latencies;
+use tx_acct::lifecycle;
+use tx_acct::output::{output_accounts, write_all, AccountFilter, OutputFormat, PartitionedSink};
+use tx_acct::repl::Repl;
+use tx_acct::report::{Report, ReportOptions};
+use tx_acct::scheduler;
+use tx_acct::server::{self, ServeOptions};
+#[cfg(feature = "signing")]
+use tx_acct::signing::{self, Signer};
+use tx_acct::state::{open_store, StateStore, Store};
+use tx_acct::withdrawal_limits::{LimitBreach, WithdrawalLimits};
+use tx_acct::source::{Chain, CsvSource, TransactionSource};
+use tx_acct::{diag, diff, generate, interrupt, kafka, logging, otel, read_source, rounding::{self, RoundingMode}, statsd};
+use tx_acct::{format_amount, to_ppm};
+use tx_acct::{Account, AccountTransactions, Amount, Configure, Error, PrunedRange, ReadOptions, ReadReport, Rejection, Result,
+              Retention, SignedAmount, Tx};
+
+const EXIT_INTERRUPTED: i32 = 130;
+
+// Counts allocations for tx_acct bench, at the cost of two relaxed atomic adds on each, so only bench builds pay it
+#[cfg(feature = "bench")]
+#[global_allocator]
+static ALLOCATOR: CountingAlloc = CountingAlloc;
+
+fn main() {
+    let mut args: Vec<String> = env::args().collect();
+    let config = match take_config(&mut args) {
+        Ok(config) => config,
+        Err(e) => { eprintln!("Failed to load configuration. {}", e); exit(2) }
+    };
+    if let Err(e) = logging::init(&config.log) {
+        eprintln!("Failed to open log destination. {}", e);
+        exit(1)
+    }
+    if let Some(tracing) = &config.tracing {
+        if let Err(e) = otel::init(tracing) {
+            diag!("Failed to start trace export. {}", e);
+            exit(1)
+        }
+    }
+    if let Some(statsd) = &config.statsd {
+        if let Err(e) = statsd::init(statsd) {
+            diag!("Failed to start StatsD metrics. {}", e);
+            exit(1)
+        }
+    }
+    if let Some(encryption) = &config.encryption {
+        if let Err(e) = encryption::init(encryption) {
+            diag!("Failed to load the state encryption key. {}", e);
+            exit(2)
+        }
+    }
+
+    if args.len() < 2 {
+        eprintln!("Usage: tx_acct [--config <file>] [--profile <name>] <command or input options>");
+        eprintln!("       tx_acct [--state <dir> | --store <spec>] [--retain-age <rows> | --retain-count <n>]");
+        eprintln!("               [--output-partitions <n> [--output-dir <dir>]] [--output-format <csv | json>]");
+        eprintln!("               [--changed-only] [--dry-run]");
+        eprintln!("               [--quiet] [--dashboard] [--error-log <errors.jsonl>] [--quarantine <bad.csv>]");
+        eprintln!("               [--dead-letter <rejected.csv>] [--cdc <changes.jsonl>] [--filter <expression>]");
+        eprintln!("               [--dedup] [--output <accounts.csv>] [--summary <summary.json>]");
+        eprintln!("               [--decrypt-key <key file>] [--only-clients <clients.txt>]");
+        eprintln!("               [--only-locked] [--min-total <amount>] [--output-clients <clients.txt>]");
+        eprintln!("               [--withdrawal-limits <limits.csv> [--limit-breaches <breaches.csv>]]");
+        eprintln!("               [--approval-threshold <amount>] [--dispute-expiry <rows>]");
+        eprintln!("               [--max-open-disputes <n>]");
+        eprintln!("               [--chargeback-fee <amount> [--chargeback-fee-to <client | loss>]]");
+        eprintln!("               [--recover-deficits]");
+        eprintln!("               [--netting [--settlement-report <settlements.csv>]]");
+        eprintln!("               [--counterparty-report <counterparties.csv>] [--category-report <categories.csv>]");
+        eprintln!("               [--report <chargeback-fees | client-volume | dispute-aging | disputes | exposure");
+        eprintln!("                          | fee-usage | interest | locked | recoveries | rounding | totals");
+        eprintln!("                          | volume> ...]");
+        eprintln!("               [--stale-dispute-age <rows>]");
+        eprintln!("               [--anomalies <anomalies.csv> [--anomaly-z-score <z>] [--anomaly-history <n>]");
+        eprintln!("                [--dispute-burst <n>] [--dispute-burst-rows <rows>]]");
+        eprintln!("               <input.csv | -> ...");
+        eprintln!("       tx_acct serve [--listen <addr>] [--state <dir> | --store <spec>]");
+        eprintln!("                     [--replication-listen <addr> | --standby-of <primary addr>]");
+        eprintln!("                     [--unix-socket <path>] [--tcp-listen <addr>] [--no-auth]");
+        eprintln!("       tx_acct consume --store snapshot:<file>");
+        eprintln!("       tx_acct repl [--state <dir> | --store <spec>]");
+        eprintln!("       tx_acct diff <a.csv> <b.csv>");
+        eprintln!("       tx_acct bench [run options] <input.csv>");
+        eprintln!("       tx_acct statement --client <id> [--output <statement.csv>] (--state <dir> | --store <spec>)");
+        eprintln!("       tx_acct verify-signature --public-key <hex> [--signature <file.sig>] <file>");
+        eprintln!("       tx_acct completions <bash | zsh | fish>");
+        eprintln!("       tx_acct generate [--rows <n>] [--clients <n>] [--seed <n>] [--malformed-rate <r>]");
+        eprintln!("                        [--duplicate-rate <r>] [--cross-client-rate <r>] [--out-of-range-rate <r>]");
+        eprintln!("       tx_acct admin erase-client --client <id> --state <dir>");
+        eprintln!("       tx_acct admin prune (--retain-age <rows> | --retain-count <n>) --state <dir>");
+        eprintln!("       tx_acct admin compact [--keep-messages <n>] --state <dir>");
+        eprintln!("       tx_acct admin merge-clients --from <id> --into <id> --state <dir>");
+        eprintln!("       tx_acct admin adjust --client <id> --amount <signed amount> --reason <text> (--state <dir> | --store <spec>)");
+        eprintln!("       tx_acct admin close-dispute --tx <id> --reason <text> (--state <dir> | --store <spec>)");
+        eprintln!("       tx_acct admin unlock --client <id> --reason <text> (--state <dir> | --store <spec>)");
+        eprintln!("       tx_acct admin (approve | deny) --tx <id> --reason <text> (--state <dir> | --store <spec>)");
+        exit(2)
+    }
+
+    if args[1] == "admin" {
+        let result = run_admin(&args[2..], &config);
+        otel::shutdown();
+        statsd::shutdown();
+        if let Err(e) = result {
+            diag!("Admin command failed. {}", e);
+            exit(1)
+        }
+        return;
+    }
+
+    if args[1] == "completions" {
+        match args.get(2).map(|shell| shell.parse::<Shell>()) {
+            Some(Ok(shell)) if args.len() == 3 => print!("{}", cli::completions(shell)),
+            Some(Err(e)) => { eprintln!("{}", e); exit(2) }
+            _ => { eprintln!("Usage: tx_acct completions <bash | zsh | fish>"); exit(2) }
+        }
+        return;
+    }
+
+    if args[1] == "diff" {
+        if args.len() != 4 {
+            eprintln!("Usage: tx_acct diff <a.csv> <b.csv>");
+            exit(2)
+        }
+        if let Err(e) = cli::check_inputs(&args[2..]) {
+            eprintln!("{}", e);
+            exit(2)
+        }
+        match diff::diff_outputs(&args[2], &args[3]) {
+            Ok(summary) => {
+                diag!("{} added, {} removed, {} changed clients.", summary.added, summary.removed, summary.changed);
+                exit(summary.exit_code())
             }
+            Err(e) => { diag!("Diff failed. {}", e); exit(2) }
+        }
+    }
 
-        } else {
-            let new_acct = Account {
-                client: transaction_msg.client,
-                available: 0,
-                held: 0,
-                total: 0,
-                locked: false,
-            };
-            self.account_client.insert(transaction_msg.client, new_acct);
-            eprintln!("Ignored withdrawal on non-existent client, {}. New client account created with 0.000 total balance.", transaction_msg.client);
+    if args[1] == "bench" {
+        run_bench(&args[2..], &config);
+        return;
+    }
+
+    if args[1] == "statement" {
+        if let Err(e) = run_statement(&args[2..], &config) {
+            diag!("Statement failed. {}", e);
+            exit(1)
         }
+        return;
     }
 
-    fn dispute_tx(&mut self, transaction_msg: &TransactionMessage) {
-        if let Some(acct) = self.account_client.get_mut(&transaction_msg.client) {
-            if let Some(tx) = self.txs_txid.get_mut(&transaction_msg.tx) {
-                if tx.amount >= acct.available {
-                    acct.held += tx.amount;
-                    acct.available -= tx.amount;
-                    tx.disputed = true;
-                } else {
-                    eprintln!("Unable to hold funds for dispute of transaction, {}, from client, {}. Ignoring dispute.", transaction_msg.tx, transaction_msg.client);
-                }
-            } else {
-                eprintln!("Failed to location transaction, {}. Ignoring dispute.", transaction_msg.tx);
+    if args[1] == "verify-signature" {
+        match verify_signature(&args[2..]) {
+            Ok(None) => { diag!("Signature verified."); exit(0) }
+            Ok(Some(reason)) => { diag!("Signature not verified. {}", reason); exit(1) }
+            Err(e) => { diag!("Verify failed. {}", e); exit(2) }
+        }
+    }
+
+    if args[1] == "serve" {
+        if let Err(e) = run_serve(&args[2..], &config) {
+            diag!("Server failed. {}", e);
+            exit(1)
+        }
+        return;
+    }
+
+    if args[1] == "consume" {
+        let result = run_consume(&args[2..], &config);
+        otel::shutdown();
+        statsd::shutdown();
+        if let Err(e) = result {
+            diag!("Consume failed. {}", e);
+            exit(1)
+        }
+        return;
+    }
+
+    if args[1] == "repl" {
+        if let Err(e) = run_repl(&args[2..], &config) {
+            diag!("REPL failed. {}", e);
+            exit(1)
+        }
+        return;
+    }
+
+    if args[1] == "generate" {
+        if let Err(e) = run_generate(&args[2..]) {
+            diag!("Generate failed. {}", e);
+            exit(2)
+        }
+        return;
+    }
+
+    let options = match parse_options(&args[1..], &config) {
+        Ok(options) => options,
+        Err(e) => { eprintln!("{}", e); exit(2) }
+    };
+    // Loaded before anything is read, so a bad key fails the run at once
+    let signer = config.signing.as_ref().map(open_signer);
+    let filename = &options.inputs.join(", ");
+    let cancel = interrupt::install();
+    // Parent of the load, read and save spans, so one run is one trace
+    let mut run_span = otel::span("run");
+    run_span.set("input", filename.as_str());
+
+    let mut account_txs = match HookConfig::new(&config).load() {
+        Ok(validators) => AccountTransactions::with_validators(validators),
+        Err(e) => { diag!("{}", e); exit(1) }
+    };
+    let mut store = match options.store.as_deref().map(open_store).transpose() {
+        Ok(store) => store,
+        Err(e) => { diag!("Failed to open state store. {}", e); exit(1) }
+    };
+    if let Some(store) = store.as_mut() {
+        if let Err(e) = store.load(&mut account_txs) {
+            diag!("Failed to load state from {}. {}", store.describe(), e);
+            exit(1)
+        }
+    }
+    configure_engine(&mut account_txs, &options);
+    let prior_accounts: HashMap<u16, Account> = account_txs.iter_accounts()
+        .map(|acct| (acct.client, acct.clone()))
+        .collect();
+
+    let read_options = run_read_options(&options, &config, &cancel);
+    let dashboard = match options.dashboard {
+        true => Some(start_dashboard(filename, &cancel, &mut account_txs)),
+        false => None,
+    };
+    // A dry run reads the stored state but must not write to it
+    let recording = match store.as_mut() {
+        Some(store) if !options.dry_run => Some(store.as_mut() as &mut dyn Store),
+        _ => None,
+    };
+    let result = open_source(&options.inputs, options.decrypt_key.as_deref())
+        .and_then(|mut source| read_source(source.as_mut(), &mut account_txs, &read_options, recording));
+    if let Some(dashboard) = dashboard {
+        finish_dashboard(dashboard);
+    }
+    let report = match result {
+        Ok(report) => { diag!("Read the input file, {}.", filename); report }
+        Err(e) => { diag!("Input file read failed, {}. {}", filename, e); exit(1) }
+    };
+    if let Some(reason) = &report.stopped {
+        diag!("PARTIAL RESULTS. Stopped reading {} after {} rows. {}", filename, report.rows, reason);
+    }
+
+    if options.dry_run {
+        if options.netting {
+            settle_transfers(&mut account_txs, None, None);
+        }
+        drop(run_span);
+        otel::shutdown();
+        statsd::shutdown();
+        diag!("Dry run. {} rows: {} would apply, {} would be rejected, {} malformed. No state or output files written.",
+                  report.rows, report.applied, report.rejected, report.malformed);
+        diag!("Rejections by reason: {}.", report.rejection_counts());
+        report_merkle(&report, &account_txs);
+        output_accounts(&listed_accounts(&account_txs, &options), options.output_format);
+        exit_if_interrupted(report.cancelled);
+        return;
+    }
+
+    diag!("Processed {} rows: {} applied, {} rejected, {} malformed.",
+          report.rows, report.applied, report.rejected, report.malformed);
+    diag!("Rejections by reason: {}.", report.rejection_counts());
+    report_merkle(&report, &account_txs);
+    report_throughput(&report);
+    report_latency(&report.latency);
+    if let Some(depth) = config.ingest.queue_depth {
+        diag!("Peak ingest queue depth {} of {}.", report.peak_queue_depth, depth);
+    }
+    if let Some(path) = &options.quarantine {
+        diag!("Quarantined {} rows in {}.", report.quarantined, path);
+    }
+    if let Some(clients) = &options.only_clients {
+        diag!("Skipped {} rows for clients not among the {} listed.", report.excluded_clients, clients.len());
+    }
+    if options.filter.is_some() {
+        diag!("Filtered out {} rows.", report.filtered);
+    }
+    if options.withdrawal_limits.is_some() {
+        report_limit_breaches(account_txs.withdrawal_limits().breaches(), options.limit_breaches.as_deref());
+    }
+    // Pending withdrawals loaded from the state count too, whether or not this run has a threshold
+    let (pending, pending_amount) = account_txs.pending_withdrawals()
+        .fold((0, 0), |(count, amount), tx| (count + 1, amount + tx.amount));
+    if pending > 0 || options.approval_threshold.is_some() {
+        diag!("{} withdrawals, {} in all, are waiting for approval.", pending, format_amount(pending_amount));
+    }
+    let (escrows, escrow_amount) = account_txs.open_escrows()
+        .fold((0, 0), |(count, amount), tx| (count + 1, amount + tx.amount));
+    if escrows > 0 {
+        diag!("{} escrows, {} in all, are waiting for a release or refund.", escrows, format_amount(escrow_amount));
+    }
+    if options.chargeback_fee.is_some() {
+        let fees = account_txs.assessed_fees();
+        let client_paid: Amount = fees.iter().map(|fee| fee.client_paid).sum();
+        diag!("{} chargeback fees posted: {} paid by clients, {} in the loss account.", fees.len(),
+              format_amount(client_paid), format_amount(account_txs.loss_account()));
+    }
+    if !options.fee_schedules.is_empty() {
+        let (charged, fees) = account_txs.fee_schedules().usage().values()
+            .fold((0, 0), |(count, fees), usage| (count + usage.count, fees + usage.fees));
+        diag!("{} transactions under the fee schedules paid {} in fees.", charged, format_amount(fees));
+    }
+    let rounding = account_txs.rounding_account();
+    if rounding.fees.inexact + rounding.interest.inexact > 0 {
+        diag!("{} fees and {} accruals were rounded down, leaving {} and {} millionths of a subunit in the rounding \
+               account.", rounding.fees.inexact, rounding.interest.inexact, rounding.fees.dropped_ppm,
+              rounding.interest.dropped_ppm);
+    }
+    let recoveries = account_txs.recoveries();
+    if !recoveries.is_empty() || options.recover_deficits {
+        let deficit: Amount = recoveries.iter().map(|recovery| recovery.deficit).sum();
+        diag!("{} clients owe {} in all on deficits left by chargebacks.", recoveries.len(), format_amount(deficit));
+    }
+    let (provisional, provisional_amount) = account_txs.provisional_credit();
+    if provisional > 0 {
+        diag!("{} disputes, {} in all, are carried as provisional credit.", provisional,
+              format_amount(provisional_amount));
+    }
+    if report.replayed > 0 {
+        diag!("Skipped {} replayed rows.", report.replayed);
+    }
+    if read_options.dedup.enabled {
+        diag!("Skipped {} duplicate rows.", report.duplicates);
+    }
+    if let Some(path) = &options.cdc {
+        diag!("Appended {} account changes to {}.", report.changes, path);
+    }
+    if let Some(kafka) = &config.kafka {
+        diag!("Published {} account changes to Kafka topic {}.", report.published, kafka.topic);
+    }
+    if let Some(redis) = &config.redis {
+        diag!("Cached {} account balances in Redis at {}.", report.cached, redis.addr);
+    }
+    if let Some(path) = &options.dead_letter {
+        diag!("Dead-lettered {} rejected messages in {}.", report.dead_lettered, path);
+    }
+
+    if let Some(path) = &options.counterparty_report {
+        report_counterparties(account_txs.counterparties(), path);
+    }
+    if let Some(path) = &options.category_report {
+        report_categories(account_txs.categories(), path);
+    }
+    if let (Some(path), Some(detector)) = (&options.anomalies, account_txs.anomaly_detector()) {
+        report_anomalies(detector.anomalies(), path);
+    }
+    if options.netting {
+        let store = store.as_mut().map(|store| store.as_mut() as &mut dyn Store);
+        settle_transfers(&mut account_txs, store, options.settlement_report.as_deref());
+    }
+    if !account_txs.accruals().is_empty() {
+        report_accruals(account_txs.accruals());
+    }
+    if let Some(expiry) = account_txs.dispute_expiry() {
+        record_expired_disputes(&account_txs, expiry, &config.admin.audit_log);
+    }
+    if let Some(retention) = &options.retention {
+        report_pruned(account_txs.prune(retention));
+    }
+
+    if let Some(store) = store.as_mut() {
+        if let Err(e) = store.save(&account_txs) {
+            diag!("Failed to save state to {}. {}", store.describe(), e);
+            exit(1)
+        }
+    }
+    drop(run_span);
+    otel::shutdown();
+    statsd::shutdown();
+
+    let mut accounts = listed_accounts(&account_txs, &options);
+    if !options.account_filter.is_empty() {
+        diag!("{} of {} accounts pass the output filter.", accounts.len(), account_txs.account_count());
+    }
+    if options.changed_only {
+        let total = accounts.len();
+        accounts.retain(|acct| prior_accounts.get(&acct.client) != Some(*acct));
+        diag!("{} of {} accounts changed in this run.", accounts.len(), total);
+    }
+
+    let mut written = Vec::new();
+    match (options.output_partitions, &options.output) {
+        (Some(partitions), _) => {
+            let mut sink = PartitionedSink::new(partitions, &options.output_dir, options.output_format);
+            if let Err(e) = write_all(&mut sink, accounts) {
+                diag!("Failed to write output partitions to {}. {}", options.output_dir, e);
+                exit(1)
+            }
+            written.extend(sink.files());
+        }
+        (None, Some(path)) => {
+            let result = fs::File::create(path)
+                .map_err(Error::from)
+                .and_then(|file| write_all(options.output_format.sink(BufWriter::new(file)).as_mut(), accounts));
+            if let Err(e) = result {
+                diag!("Failed to write accounts to {}. {}", path, e);
+                exit(1)
+            }
+            written.push(PathBuf::from(path));
+        }
+        (None, None) => {
+            if signer.is_some() {
+                diag!("Accounts written to STDOUT are not signed. Write them with --output <file> to sign them.");
             }
+            output_accounts(&accounts, options.output_format)
+        }
+    }
+    for report in &options.reports {
+        let path = Path::new(&options.output_dir).join(report.file_name());
+        if let Err(e) = report.write(&account_txs, &options.report_options).and_then(|csv| Ok(fs::write(&path, csv)?)) {
+            diag!("Failed to write the {} report to {}. {}", report, path.display(), e);
+            exit(1)
+        }
+        diag!("Wrote the {} report to {}.", report, path.display());
+        written.push(path);
+    }
+    if let Some(path) = &options.summary {
+        let summary = serde_json::to_string_pretty(&report.summary()).expect("A JSON value serializes.");
+        if let Err(e) = fs::write(path, summary + "\n") {
+            diag!("Failed to write the summary to {}. {}", path, e);
+            exit(1)
+        }
+        written.push(PathBuf::from(path));
+    }
+    if let Some(signer) = &signer {
+        sign_files(signer, &written);
+    }
+    exit_if_interrupted(report.cancelled);
+}
 
-        } else {
-            let new_acct = Account {
-                client: transaction_msg.client,
-                available: 0,
-                held: 0,
-                total: 0,
-                locked: false,
-            };
-            self.account_client.insert(transaction_msg.client, new_acct);
-            eprintln!("Ignored dispute on non-existent client, {}. New client account created with 0.000 total balance.", transaction_msg.client);
+// The run's diagnostics are held back while the dashboard has the terminal, and written out once it is closed
+#[cfg(feature = "tui")]
+fn start_dashboard(title: &str, cancel: &CancelToken, account_txs: &mut AccountTransactions) -> Dashboard {
+    match Dashboard::start(title, cancel.clone()) {
+        Ok((dashboard, observer)) => {
+            account_txs.add_observer(Box::new(observer));
+            logging::hold();
+            dashboard
         }
+        Err(e) => { diag!("Failed to start the dashboard. {}", e); exit(1) }
+    }
+}
+
+#[cfg(feature = "tui")]
+fn finish_dashboard(dashboard: Dashboard) {
+    let result = dashboard.finish();
+    logging::release();
+    if let Err(e) = result {
+        diag!("The dashboard failed. {}", e);
+    }
+}
+
+#[cfg(not(feature = "tui"))]
+struct Dashboard;
+
+#[cfg(not(feature = "tui"))]
+fn start_dashboard(_title: &str, _cancel: &CancelToken, _account_txs: &mut AccountTransactions) -> Dashboard {
+    diag!("This build has no dashboard. Rebuild with --features tui.");
+    exit(2)
+}
+
+#[cfg(not(feature = "tui"))]
+fn finish_dashboard(_dashboard: Dashboard) {}
+
+#[cfg(feature = "signing")]
+fn open_signer(config: &SigningConfig) -> Signer {
+    match Signer::load(config) {
+        Ok(signer) => signer,
+        Err(e) => { diag!("{}", e); exit(2) }
     }
+}
+
+#[cfg(feature = "signing")]
+fn sign_files(signer: &Signer, files: &[PathBuf]) {
+    for file in files {
+        match signer.sign_file(file) {
+            Ok(sig_path) => diag!("Signed {} in {}.", file.display(), sig_path.display()),
+            Err(e) => { diag!("Failed to sign {}. {}", file.display(), e); exit(1) }
+        }
+    }
+    if !files.is_empty() {
+        diag!("Verify with tx_acct verify-signature --public-key {} <file>.", signer.public_key());
+    }
+}
+
+// Whether the file verifies against its signature, with the reason when it does not
+#[cfg(feature = "signing")]
+fn verify_signature(args: &[String]) -> Result<Option<String>> {
+    let mut public_key = None;
+    let mut sig_path = None;
+    let mut files = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--public-key" => public_key = Some(option_value(&mut iter, arg)?),
+            "--signature" => sig_path = Some(PathBuf::from(option_value(&mut iter, arg)?)),
+            _ if arg.starts_with("--") => return Err(cli::unknown_option(&cli::VERIFY_SIGNATURE, arg)),
+            _ => files.push(arg.clone()),
+        }
+    }
+    let public_key = public_key.ok_or_else(|| missing_option("--public-key <hex>"))?;
+    let file = match &files[..] {
+        [file] => PathBuf::from(file),
+        _ => return Err(Error::InvalidArgument(String::from("Expected one file to verify."))),
+    };
+    cli::check_inputs(&files)?;
+    let sig_path = sig_path.unwrap_or_else(|| signing::signature_path(&file));
+    signing::verify_file(&file, &sig_path, &public_key)
+}
+
+// Times a dry run of one input with the run's flags and [run] policies, against an engine with no stored state
+#[cfg(feature = "bench")]
+fn run_bench(args: &[String], config: &Config) {
+    let mut options = match parse_options(args, config) {
+        Ok(options) if options.inputs.len() == 1 => options,
+        Ok(_) => { eprintln!("Usage: tx_acct bench [run options] <input.csv>"); exit(2) }
+        Err(e) => { eprintln!("{}", e); exit(2) }
+    };
+    options.dry_run = true;
+    options.quiet = true;
+    let mut account_txs = match HookConfig::new(config).load() {
+        Ok(validators) => AccountTransactions::with_validators(validators),
+        Err(e) => { diag!("{}", e); exit(1) }
+    };
+    configure_engine(&mut account_txs, &options);
+    let read_options = run_read_options(&options, config, &interrupt::install());
+    match bench::bench_file(&options.inputs[0], &mut account_txs, &read_options) {
+        Ok(report) => println!("{}", report.to_json()),
+        Err(e) => { diag!("Bench failed. {}", e); exit(1) }
+    }
+}
+
+#[cfg(not(feature = "bench"))]
+fn run_bench(_args: &[String], _config: &Config) {
+    diag!("This build has no bench. Rebuild with --features bench.");
+    exit(2)
+}
+
+#[cfg(not(feature = "signing"))]
+struct Signer;
+
+#[cfg(not(feature = "signing"))]
+fn open_signer(_config: &SigningConfig) -> Signer {
+    diag!("This build has no signing. Rebuild with --features signing.");
+    exit(2)
+}
+
+#[cfg(not(feature = "signing"))]
+fn sign_files(_signer: &Signer, _files: &[PathBuf]) {}
+
+#[cfg(not(feature = "signing"))]
+fn verify_signature(_args: &[String]) -> Result<Option<String>> {
+    Err(Error::InvalidArgument(String::from("This build has no signing. Rebuild with --features signing.")))
+}
+
+// Distinguishes a run cut short by SIGINT or SIGTERM, whose state and output hold only the rows read before it
+fn exit_if_interrupted(interrupted: bool) {
+    if interrupted {
+        exit(EXIT_INTERRUPTED)
+    }
+}
+
+// --config and --profile are accepted anywhere on the command line and apply to every subcommand. TX_ACCT_CONFIG and
+// TX_ACCT_PROFILE stand in for them when not given, and TX_ACCT_ variables override what the file sets.
+fn take_config(args: &mut Vec<String>) -> Result<Config> {
+    let path = take_global(args, "--config")?.or_else(|| env::var(config::ENV_CONFIG).ok());
+    let profile = take_global(args, "--profile")?.or_else(|| env::var(config::ENV_PROFILE).ok());
+    // Variables that are not valid Unicode cannot be settings
+    let vars = env::vars_os().filter_map(|(name, value)| Some((name.into_string().ok()?, value.into_string().ok()?)));
+    Config::load_with_env(path.as_deref(), profile.as_deref(), vars)
+}
+
+fn take_global(args: &mut Vec<String>, name: &str) -> Result<Option<String>> {
+    match args.iter().position(|arg| arg == name) {
+        Some(index) => {
+            let value = args.get(index + 1).ok_or_else(|| missing_value(name))?.clone();
+            args.drain(index..index + 2);
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+// The --store spec for a command given neither --state nor --store
+fn default_store(config: &Config) -> Option<String> {
+    env::var(config::ENV_STORE).ok().or_else(|| config.run.store.clone())
+}
+
+struct Options {
+    // Read one after another, - for STDIN
+    inputs: Vec<String>,
+    // --store spec, with --state <dir> standing for dir:<dir>
+    store: Option<String>,
+    retention: Option<Retention>,
+    output_partitions: Option<u32>,
+    output_dir: String,
+    output_format: OutputFormat,
+    changed_only: bool,
+    dry_run: bool,
+    quiet: bool,
+    dashboard: bool,
+    error_log: Option<String>,
+    quarantine: Option<String>,
+    dead_letter: Option<String>,
+    cdc: Option<String>,
+    filter: Option<Filter>,
+    dedup: bool,
+    // Accounts file written instead of STDOUT
+    output: Option<String>,
+    summary: Option<String>,
+    // Key for inputs encrypted with age or PGP
+    decrypt_key: Option<String>,
+    // Only rows for these clients are applied and only their accounts written
+    only_clients: Option<ClientList>,
+    // Which of the accounts are written
+    account_filter: AccountFilter,
+    withdrawal_limits: Option<WithdrawalLimits>,
+    // Where the withdrawals refused for going over a limit are listed
+    limit_breaches: Option<String>,
+    // Withdrawals of more than this many subunits wait for an approve or deny
+    approval_threshold: Option<Amount>,
+    // Disputes still open this many rows after they were opened are resolved
+    dispute_expiry: Option<u32>,
+    max_open_disputes: Option<u32>,
+    chargeback_fee: Option<ChargebackFee>,
+    // Chargebacks may leave a deficit, swept from the client's later deposits
+    recover_deficits: bool,
+    // Transfers are settled at the end of the run, one net movement per pair of clients
+    netting: bool,
+    settlement_report: Option<String>,
+    counterparty_report: Option<String>,
+    category_report: Option<String>,
+    // Where the transactions flagged by the anomaly rules are listed, the rules only set when it is given
+    anomalies: Option<String>,
+    anomaly_rules: Option<AnomalyRules>,
+    // From the config file's [[account_type]] sections
+    account_types: AccountTypes,
+    // From the config file's [[fee_schedule]] sections
+    fee_schedules: FeeSchedules,
+    // How amounts with more than four decimal places are read, in input rows, flags and config
+    rounding: RoundingMode,
+    // Written to output_dir once the state is saved
+    reports: Vec<Report>,
+    report_options: ReportOptions,
+}
+
+// How a run reads its input, from its flags and the config file
+fn run_read_options(options: &Options, config: &Config, cancel: &CancelToken) -> ReadOptions {
+    ReadOptions {
+        lenient: options.dry_run,
+        progress: !options.quiet && !options.dashboard,
+        // A dry run writes no files and must not tell anyone downstream about changes it will not keep
+        error_log: if options.dry_run { None } else { options.error_log.clone() },
+        quarantine: if options.dry_run { None } else { options.quarantine.clone() },
+        dead_letter: if options.dry_run { None } else { options.dead_letter.clone() },
+        kafka: if options.dry_run { None } else { config.kafka.clone() },
+        cdc: if options.dry_run { None } else { options.cdc.clone() },
+        webhooks: if options.dry_run { Vec::new() } else { config.webhooks.clone() },
+        redis: if options.dry_run { None } else { config.redis.clone() },
+        limits: config.limits.clone(),
+        ingest: config.ingest.clone(),
+        cancel: Some(cancel.clone()),
+        filter: options.filter.clone(),
+        only_clients: options.only_clients.clone(),
+        idempotency: config.idempotency.clone(),
+        dedup: DedupConfig { enabled: config.dedup.enabled || options.dedup, ..config.dedup.clone() },
+    }
+}
 
-    fn resolve_tx(&mut self, transaction_msg: &TransactionMessage) {
-        if let Some(acct) = self.account_client.get_mut(&transaction_msg.client) {
-            if let Some(tx) = self.txs_txid.get_mut(&transaction_msg.tx) {
-                if tx.disputed && tx.amount <= acct.held {
-                    acct.held -= tx.amount;
-                    acct.available += tx.amount;
-                    acct.total = acct.held + acct.available;
-                    tx.disputed = false;
-                } else {
-                    eprintln!("Unable to resolve held funds for disputed transaction, {}, from client, {}. Ignoring resolve.", transaction_msg.tx, transaction_msg.client);
+// Flags not given fall back to the config file's [run] section
+fn parse_options(args: &[String], config: &Config) -> Result<Options> {
+    let options = configured_options(args, config)?;
+    if options.inputs.is_empty() {
+        return Err(Error::InvalidArgument(String::from("Missing input file.")));
+    }
+    // Checked before the state is loaded, so a mistyped path fails at once
+    cli::check_inputs(&options.inputs)?;
+    Ok(options)
+}
+
+// The run command's flags, without the input files it needs. The modes that take no policy flags pass none and get
+// the [run] section's policies.
+fn configured_options(args: &[String], config: &Config) -> Result<Options> {
+    // Amounts given by flags and config round as input rows do
+    let rounding = rounding_mode(config)?;
+    let amount = |value: &str| rounding::parse_subunits(value, rounding);
+    let mut inputs = Vec::new();
+    let mut store = None;
+    let mut retention = None;
+    let mut output_partitions = config.run.output_partitions;
+    let mut output_dir = config.run.output_dir.clone().unwrap_or_else(|| String::from("."));
+    let mut output_format = config.run.output_format.as_deref().map_or(Ok(OutputFormat::Csv), str::parse)?;
+    let mut changed_only = false;
+    let mut dry_run = false;
+    let mut quiet = false;
+    let mut dashboard = false;
+    let mut error_log = None;
+    let mut quarantine = None;
+    let mut dead_letter = None;
+    let mut cdc = None;
+    let mut filter = None;
+    let mut dedup = false;
+    let mut output = None;
+    let mut summary = None;
+    let mut decrypt_key = None;
+    let mut only_clients = None;
+    let mut account_filter = AccountFilter::default();
+    let mut withdrawal_limits = config.run.withdrawal_limits.clone();
+    let mut limit_breaches = None;
+    let mut approval_threshold = match &config.run.approval_threshold {
+        Some(value) => Some(amount(value).map_err(|_| Error::InvalidArgument(format!(
+            "approval_threshold in [run] is not an amount, {}.", value)))?),
+        None => None,
+    };
+    let mut dispute_expiry = config.run.dispute_expiry;
+    let mut max_open_disputes = config.run.max_open_disputes;
+    let mut chargeback_fee = match &config.run.chargeback_fee {
+        Some(value) => Some(amount(value).map_err(|_| Error::InvalidArgument(format!(
+            "chargeback_fee in [run] is not an amount, {}.", value)))?),
+        None => None,
+    };
+    let mut fee_payer = config.run.chargeback_fee_to.as_deref().map_or(Ok(FeePayer::Client), str::parse)?;
+    let mut fee_payer_flag = false;
+    let mut recover_deficits = config.run.recover_deficits;
+    let mut netting = config.run.netting;
+    let mut settlement_report = None;
+    let mut counterparty_report = None;
+    let mut category_report = None;
+    let mut anomalies = None;
+    let mut anomaly_rules = AnomalyRules::default();
+    let mut anomaly_flags = Vec::new();
+    let mut reports = Vec::new();
+    let mut report_options = ReportOptions { stale_dispute_age: config.run.stale_dispute_age };
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--state" => store = Some(format!("dir:{}", option_value(&mut iter, arg)?)),
+            "--store" => store = Some(option_value(&mut iter, arg)?),
+            "--retain-age" | "--retain-count" => retention = Some(retention_value(&mut iter, arg)?),
+            "--output-partitions" => {
+                let value = option_value(&mut iter, arg)?;
+                match value.parse::<u32>() {
+                    Ok(partitions) if partitions > 0 => output_partitions = Some(partitions),
+                    _ => return Err(invalid_value(arg, &value)),
                 }
-            } else {
-                eprintln!("Failed to location transaction, {}. Ignoring resolve.", transaction_msg.tx);
             }
-
-        } else {
-            let new_acct = Account {
-                client: transaction_msg.client,
-                available: 0,
-                held: 0,
-                total: 0,
-                locked: false,
-            };
-            self.account_client.insert(transaction_msg.client, new_acct);
-            eprintln!("Ignored resolve on non-existent client, {}. New client account created with 0.000 total balance.", transaction_msg.client);
-        }
-    }
-
-    fn chargeback_tx(&mut self, transaction_msg: &TransactionMessage) {
-        if let Some(acct) = self.account_client.get_mut(&transaction_msg.client) {
-            if let Some(tx) = self.txs_txid.get_mut(&transaction_msg.tx) {
-                if tx.disputed && tx.amount <= acct.held {
-                    acct.held -= tx.amount;
-                    acct.total = acct.held + acct.available;
-                    acct.locked = true;
-                    tx.disputed = false;
-                } else {
-                    eprintln!("Failed to complete chargeback. Hold less chargeback amount: {}, Disputed: {}, transaction: {}.",
-                              acct.held - tx.amount, tx.disputed, transaction_msg.tx);
+            "--output-dir" => output_dir = option_value(&mut iter, arg)?,
+            "--output-format" => output_format = option_value(&mut iter, arg)?.parse()?,
+            "--changed-only" => changed_only = true,
+            "--dry-run" => dry_run = true,
+            "--quiet" => quiet = true,
+            "--dashboard" => dashboard = true,
+            "--error-log" => error_log = Some(option_value(&mut iter, arg)?),
+            "--quarantine" => quarantine = Some(option_value(&mut iter, arg)?),
+            "--dead-letter" => dead_letter = Some(option_value(&mut iter, arg)?),
+            "--cdc" => cdc = Some(option_value(&mut iter, arg)?),
+            "--filter" => filter = Some(option_value(&mut iter, arg)?.parse()?),
+            "--dedup" => dedup = true,
+            "--output" => output = Some(option_value(&mut iter, arg)?),
+            "--summary" => summary = Some(option_value(&mut iter, arg)?),
+            "--decrypt-key" => decrypt_key = Some(option_value(&mut iter, arg)?),
+            "--only-clients" => only_clients = Some(ClientList::read(&option_value(&mut iter, arg)?)?),
+            "--only-locked" => account_filter.only_locked = true,
+            "--min-total" => {
+                let value = option_value(&mut iter, arg)?;
+                account_filter.min_total = Some(amount(&value).map_err(|_| invalid_value(arg, &value))?);
+            }
+            "--output-clients" => account_filter.clients = Some(ClientList::read(&option_value(&mut iter, arg)?)?),
+            "--withdrawal-limits" => withdrawal_limits = Some(option_value(&mut iter, arg)?),
+            "--limit-breaches" => limit_breaches = Some(option_value(&mut iter, arg)?),
+            "--approval-threshold" => {
+                let value = option_value(&mut iter, arg)?;
+                approval_threshold = Some(amount(&value).map_err(|_| invalid_value(arg, &value))?);
+            }
+            "--dispute-expiry" => {
+                let value = option_value(&mut iter, arg)?;
+                match value.parse::<u32>() {
+                    Ok(rows) if rows > 0 => dispute_expiry = Some(rows),
+                    _ => return Err(invalid_value(arg, &value)),
+                }
+            }
+            "--max-open-disputes" => max_open_disputes = Some(parsed_value(&mut iter, arg)?),
+            "--chargeback-fee" => {
+                let value = option_value(&mut iter, arg)?;
+                chargeback_fee = Some(amount(&value).map_err(|_| invalid_value(arg, &value))?);
+            }
+            "--chargeback-fee-to" => {
+                let value = option_value(&mut iter, arg)?;
+                fee_payer = value.parse().map_err(|_| invalid_value(arg, &value))?;
+                fee_payer_flag = true;
+            }
+            "--recover-deficits" => recover_deficits = true,
+            "--netting" => netting = true,
+            "--settlement-report" => settlement_report = Some(option_value(&mut iter, arg)?),
+            "--counterparty-report" => counterparty_report = Some(option_value(&mut iter, arg)?),
+            "--category-report" => category_report = Some(option_value(&mut iter, arg)?),
+            "--anomalies" => anomalies = Some(option_value(&mut iter, arg)?),
+            "--anomaly-z-score" => {
+                let value = option_value(&mut iter, arg)?;
+                match value.parse::<f64>() {
+                    Ok(z) if z.is_finite() && z > 0.0 => anomaly_rules.max_z_score = z,
+                    _ => return Err(invalid_value(arg, &value)),
                 }
-            } else {
-                eprintln!("Failed to location transaction, {}. Ignoring resolve.", transaction_msg.tx);
+                anomaly_flags.push(arg);
+            }
+            "--anomaly-history" => {
+                anomaly_rules.min_history = parsed_value(&mut iter, arg)?;
+                anomaly_flags.push(arg);
+            }
+            "--dispute-burst" => {
+                anomaly_rules.dispute_burst = parsed_value(&mut iter, arg)?;
+                anomaly_flags.push(arg);
             }
+            "--dispute-burst-rows" => {
+                anomaly_rules.burst_rows = parsed_value(&mut iter, arg)?;
+                anomaly_flags.push(arg);
+            }
+            "--report" => reports.push(option_value(&mut iter, arg)?.parse()?),
+            "--stale-dispute-age" => report_options.stale_dispute_age = Some(parsed_value(&mut iter, arg)?),
+            _ if arg.starts_with("--") => return Err(cli::unknown_option(&cli::RUN, arg)),
+            _ => inputs.push(arg.clone()),
+        }
+    }
+    // The flag refuses 0 itself, so a 0 here came from the config file
+    if output_partitions == Some(0) {
+        return Err(Error::InvalidArgument(String::from("output_partitions in [run] must be at least 1.")));
+    }
+    if dispute_expiry == Some(0) {
+        return Err(Error::InvalidArgument(String::from("dispute_expiry in [run] must be at least 1.")));
+    }
+    if limit_breaches.is_some() && withdrawal_limits.is_none() {
+        return Err(Error::InvalidArgument(String::from("--limit-breaches needs --withdrawal-limits.")));
+    }
+    if fee_payer_flag && chargeback_fee.is_none() {
+        return Err(Error::InvalidArgument(String::from("--chargeback-fee-to needs --chargeback-fee.")));
+    }
+    if settlement_report.is_some() && !netting {
+        return Err(Error::InvalidArgument(String::from("--settlement-report needs --netting.")));
+    }
+    if let Some(flag) = anomaly_flags.first().filter(|_| anomalies.is_none()) {
+        return Err(Error::InvalidArgument(format!("{} needs --anomalies.", flag)));
+    }
+    let withdrawal_limits = withdrawal_limits.as_deref().map(|path| WithdrawalLimits::read(path, rounding)).transpose()?;
+    let store = store.or_else(|| default_store(config));
+    if let Some(key) = decrypt_key.as_deref().filter(|key| !std::path::Path::new(key).is_file()) {
+        return Err(Error::InvalidArgument(format!("--decrypt-key {} is not a key file.", key)));
+    }
+    if let Some(dir) = store.as_deref().and_then(|spec| spec.strip_prefix("dir:")) {
+        cli::check_dir("--state", dir)?;
+    }
+    if output_partitions.is_some() || !reports.is_empty() {
+        cli::check_dir("--output-dir", &output_dir)?;
+    }
+    reports.sort_unstable();
+    reports.dedup();
+    for (option, path) in [("--error-log", &error_log), ("--quarantine", &quarantine), ("--dead-letter", &dead_letter),
+                           ("--cdc", &cdc), ("--output", &output), ("--summary", &summary),
+                           ("--limit-breaches", &limit_breaches), ("--settlement-report", &settlement_report),
+                           ("--counterparty-report", &counterparty_report), ("--category-report", &category_report),
+                           ("--anomalies", &anomalies)] {
+        if let Some(path) = path {
+            cli::check_output_file(option, path)?;
+        }
+    }
 
-        } else {
-            let new_acct = Account {
-                client: transaction_msg.client,
-                available: 0,
-                held: 0,
-                total: 0,
-                locked: false,
-            };
-            self.account_client.insert(transaction_msg.client, new_acct);
-            eprintln!("Ignored chargeback_tx on non-existent client, {}. New client account created with 0.000 total balance.", transaction_msg.client);
+    Ok(Options {
+        inputs,
+        store,
+        retention,
+        output_partitions,
+        output_dir,
+        output_format,
+        changed_only,
+        dry_run,
+        quiet,
+        dashboard,
+        error_log,
+        quarantine,
+        dead_letter,
+        cdc,
+        filter,
+        dedup,
+        output,
+        summary,
+        decrypt_key,
+        only_clients,
+        account_filter,
+        withdrawal_limits,
+        limit_breaches,
+        approval_threshold,
+        dispute_expiry,
+        max_open_disputes,
+        chargeback_fee: chargeback_fee.map(|amount| ChargebackFee { amount, payer: fee_payer }),
+        recover_deficits,
+        netting,
+        settlement_report,
+        counterparty_report,
+        category_report,
+        reports,
+        report_options,
+        anomaly_rules: anomalies.as_ref().map(|_| anomaly_rules),
+        anomalies,
+        account_types: read_account_types(config)?,
+        fee_schedules: read_fee_schedules(config, rounding)?,
+        rounding,
+    })
+}
+
+fn rounding_mode(config: &Config) -> Result<RoundingMode> {
+    config.run.rounding.as_deref().map_or(Ok(RoundingMode::default()), str::parse)
+        .map_err(|e| Error::ConfigError(format!("rounding in [run]: {}", e)))
+}
+
+// Sets the policies from the flags or [run] on an engine whose state is loaded. Every mode that applies
+// transactions calls it, so serve, repl and consume enforce the same rules as a run.
+fn configure_engine(account_txs: &mut AccountTransactions, options: &Options) {
+    if let Some(limits) = &options.withdrawal_limits {
+        account_txs.set_withdrawal_limits(limits.clone());
+    }
+    account_txs.set_approval_threshold(options.approval_threshold);
+    account_txs.set_dispute_expiry(options.dispute_expiry);
+    account_txs.set_max_open_disputes(options.max_open_disputes);
+    account_txs.set_chargeback_fee(options.chargeback_fee);
+    account_txs.set_deficit_recovery(options.recover_deficits);
+    account_txs.set_netting(options.netting);
+    account_txs.set_anomaly_rules(options.anomaly_rules);
+    account_txs.set_account_types(options.account_types.clone());
+    account_txs.set_fee_schedules(options.fee_schedules.clone());
+    account_txs.set_rounding_mode(options.rounding);
+}
+
+fn read_account_types(config: &Config) -> Result<AccountTypes> {
+    let mut account_types = AccountTypes::new();
+    for account_type in &config.account_types {
+        let interest = match (&account_type.interest_percent, account_type.interest_period_rows) {
+            (Some(percent), Some(period_rows)) if period_rows > 0 => Some(InterestRate {
+                rate_ppm: to_ppm(percent).map_err(|_| Error::InvalidArgument(format!(
+                    "interest_percent of account type {} is not a percentage, {}.", account_type.name, percent)))?,
+                period_rows,
+            }),
+            (None, None) => None,
+            _ => return Err(Error::InvalidArgument(format!(
+                "Account type {} needs both interest_percent and an interest_period_rows of at least 1.",
+                account_type.name))),
+        };
+        account_types.add_type(&account_type.name, AccountPolicy { provisional_credit: account_type.provisional_credit,
+                                                                   interest });
+    }
+    for account_type in &config.account_types {
+        for client in ClientList::read(&account_type.clients)?.iter() {
+            account_types.assign(client, &account_type.name)?;
         }
+    }
+    Ok(account_types)
+}
 
+fn read_fee_schedules(config: &Config, rounding: RoundingMode) -> Result<FeeSchedules> {
+    let amount = |name: &str, value: &Option<String>| match value {
+        Some(value) => rounding::parse_subunits(value, rounding).map_err(|_| Error::InvalidArgument(format!(
+            "{} in [[fee_schedule]] is not an amount, {}.", name, value))),
+        None => Ok(0),
+    };
+    let percent = |value: &Option<String>| match value {
+        Some(value) => to_ppm(value).map_err(|_| Error::InvalidArgument(format!(
+            "percent in [[fee_schedule]] is not a percentage, {}.", value))),
+        None => Ok(0),
+    };
+    let mut schedules = FeeSchedules::new();
+    for config in &config.fee_schedules {
+        let mut schedule = FeeSchedule::new(config.period_rows);
+        for tier in &config.tiers {
+            schedule.add_tier(FeeTier { after: tier.after, flat: amount("flat", &tier.flat)?,
+                                        rate_ppm: percent(&tier.percent)?, above: amount("above", &tier.above)? });
+        }
+        schedules.set(config.tx_type.parse()?, schedule)?;
     }
+    Ok(schedules)
 }
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
-    let filename = &args[1];
+// Expired disputes go in the audit log. Each went to the store with the row it expired after.
+fn record_expired_disputes(account_txs: &AccountTransactions, expiry: &DisputeExpiry, audit_log: &str) {
+    let expired = expiry.expired();
+    diag!("Resolved {} disputes still open after {} rows.", expired.len(), expiry.max_rows());
+    if expired.is_empty() {
+        return;
+    }
+    let result = AuditLog::open(audit_log)
+        .and_then(|mut audit| expired.iter().try_for_each(|dispute| audit.record_expired(account_txs, dispute)));
+    if let Err(e) = result {
+        diag!("Failed to record the expired disputes in {}. {}", audit_log, e);
+        exit(1)
+    }
+}
 
-    let mut account_txs = AccountTransactions::new();
-    let result = read_file(filename, &mut account_txs);
-    match result {
-        Ok(_) => { eprintln!("Read the input file, {}.", filename); }
-        Err(e) => { eprintln!("Input file read failed, {}. {}", filename, e); exit(1) }
+// Each accrual went to the store with the row it was posted before, so only the total is left to report
+fn report_accruals(accruals: &[Accrual]) {
+    let paid: Amount = accruals.iter().map(|accrual| accrual.amount).sum();
+    diag!("Posted {} interest accruals, {} in all.", accruals.len(), format_amount(paid));
+}
+
+// Settles the transfers netted during the run. Each account a settlement moved funds between goes to the store as the
+// adjustment it amounts to, and the report has one row per pair of clients, amounts in currency units.
+fn settle_transfers(account_txs: &mut AccountTransactions, mut store: Option<&mut dyn Store>, path: Option<&str>) {
+    let transfers = account_txs.netting().map_or(0, |netting| netting.transfers());
+    let settlements = account_txs.settle_netting();
+    let unsettled = settlements.iter().filter(|settlement| settlement.reason.is_some()).count();
+    diag!("Netted {} transfers into {} settlements, {} left unsettled.", transfers, settlements.len(), unsettled);
+    if let Some(store) = store.as_mut() {
+        let moved = settlements.iter().filter(|settlement| settlement.reason.is_none() && settlement.net > 0);
+        let result = moved.flat_map(|settlement| [(settlement.from, -(settlement.net as SignedAmount)),
+                                                 (settlement.to, settlement.net as SignedAmount)])
+            .try_for_each(|(client, amount)| store.record_admin(account_txs, &AdminAction::Adjust { client, amount }));
+        if let Err(e) = result {
+            diag!("Failed to record the settlements in {}. {}", store.describe(), e);
+            exit(1)
+        }
+    }
+    let path = match path {
+        Some(path) => path,
+        None => return,
+    };
+    let mut report = String::from("from,to,owed,offset,net,status\n");
+    for settlement in &settlements {
+        let status = settlement.reason.as_ref().map_or("settled", Rejection::code);
+        report.push_str(&format!("{},{},{},{},{},{}\n", settlement.from, settlement.to, format_amount(settlement.owed),
+                                 format_amount(settlement.offset), format_amount(settlement.net), status));
     }
+    if let Err(e) = fs::write(path, report) {
+        diag!("Failed to write the settlement report to {}. {}", path, e);
+        exit(1)
+    }
+}
 
-    output_accounts(&account_txs);
+// One row per counterparty in name order, volume in currency units and rates as fractions of its transactions. Names
+// are free text, so the rows are written as CSV records.
+fn report_counterparties(counterparties: &BTreeMap<String, CounterpartyStats>, path: &str) {
+    diag!("Reported {} counterparties in {}.", counterparties.len(), path);
+    let report = || -> Result<Vec<u8>> {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        wtr.write_record(["counterparty", "transactions", "volume", "disputes", "chargebacks", "dispute_rate",
+                          "chargeback_rate"])?;
+        for (counterparty, stats) in counterparties {
+            wtr.write_record([counterparty.clone(), stats.transactions.to_string(),
+                              format_amount(stats.volume), stats.disputes.to_string(),
+                              stats.chargebacks.to_string(), format!("{:.4}", stats.dispute_rate()),
+                              format!("{:.4}", stats.chargeback_rate())])?;
+        }
+        Ok(wtr.into_inner().map_err(|e| io::Error::other(e.to_string()))?)
+    };
+    if let Err(e) = report().and_then(|report| Ok(fs::write(path, report)?)) {
+        diag!("Failed to write the counterparty report to {}. {}", path, e);
+        exit(1)
+    }
 }
 
-fn read_file(filename: &String, account_txs: &mut AccountTransactions) -> Result<(), Box<dyn Error>> {
-    let mut rdr = csv::Reader::from_path(filename)?;
-    let mut counter: u32 = 1;
-    for result in rdr.deserialize() {
-        let record: TxInputRecord = result?;
-        let message = input_record_to_transaction(&record, counter);
-        account_txs.handle_tx_message(&message);
-        counter += 1;
+// One row per client and category in that order, amounts in currency units. Categories are free text, like
+// counterparty names.
+fn report_categories(categories: &BTreeMap<(u16, String), CategoryTotals>, path: &str) {
+    diag!("Reported {} client categories in {}.", categories.len(), path);
+    let report = || -> Result<Vec<u8>> {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        wtr.write_record(["client", "category", "deposits", "deposited", "withdrawals", "withdrawn"])?;
+        for ((client, category), totals) in categories {
+            wtr.write_record([client.to_string(), category.clone(), totals.deposits.to_string(),
+                              format_amount(totals.deposited), totals.withdrawals.to_string(),
+                              format_amount(totals.withdrawn)])?;
+        }
+        Ok(wtr.into_inner().map_err(|e| io::Error::other(e.to_string()))?)
+    };
+    if let Err(e) = report().and_then(|report| Ok(fs::write(path, report)?)) {
+        diag!("Failed to write the category report to {}. {}", path, e);
+        exit(1)
+    }
+}
+
+// One row per flagged transaction in the order they applied, amounts in currency units. The transactions applied all the
+// same, so the report is for review rather than a list of what the run refused.
+fn report_anomalies(anomalies: &[Anomaly], path: &str) {
+    diag!("Flagged {} anomalous transactions in {}.", anomalies.len(), path);
+    let report = || -> Result<Vec<u8>> {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        wtr.write_record(["client", "tx", "row", "type", "amount", "kind", "score"])?;
+        for anomaly in anomalies {
+            wtr.write_record([anomaly.client.to_string(), anomaly.tx.to_string(), anomaly.tx_time.to_string(),
+                              anomaly.tx_type.to_string(), format_amount(anomaly.amount),
+                              anomaly.kind.to_string(), format!("{:.2}", anomaly.score)])?;
+        }
+        Ok(wtr.into_inner().map_err(|e| io::Error::other(e.to_string()))?)
+    };
+    if let Err(e) = report().and_then(|report| Ok(fs::write(path, report)?)) {
+        diag!("Failed to write the anomalies to {}. {}", path, e);
+        exit(1)
     }
-    Ok(())
 }
 
-fn output_accounts(accts: &AccountTransactions) {
-    println!("client,available,held,total,locked");
-    for (client, account) in accts.account_client.iter() {
-        println!("{},{},{},{},{}",
-                 client, to_currency_unit(account.available),
-                 to_currency_unit(account.held),
-                 to_currency_unit(account.total),
-                 account.locked);
+// One row per refused withdrawal, amounts in currency units
+fn report_limit_breaches(breaches: &[LimitBreach], path: Option<&str>) {
+    diag!("Refused {} withdrawals over client limits.", breaches.len());
+    let path = match path {
+        Some(path) => path,
+        None => return,
+    };
+    let mut report = String::from("client,tx,amount,reason,limit,withdrawn\n");
+    for breach in breaches {
+        report.push_str(&format!("{},{},{},{},{},{}\n", breach.client, breach.tx, format_amount(breach.amount),
+                                 breach.reason, format_amount(breach.limit), format_amount(breach.withdrawn)));
+    }
+    if let Err(e) = fs::write(path, report) {
+        diag!("Failed to write the limit breaches to {}. {}", path, e);
+        exit(1)
     }
 }
 
-fn to_subunit(amount_unit: &String) -> u64 {
-    let amount_orig: f64 = amount_unit.parse().expect("Failed to convert to floating point.");
-    (amount_orig * 1.0e+4_f64) as u64
+// The accounts a run writes, those of the --only-clients list when one is given that pass the output filter
+fn listed_accounts<'a>(account_txs: &'a AccountTransactions, options: &Options) -> Vec<&'a Account> {
+    account_txs.iter_accounts()
+        .filter(|acct| options.only_clients.as_ref().is_none_or(|clients| clients.contains(acct.client)))
+        .filter(|acct| options.account_filter.matches(acct))
+        .collect()
 }
 
-fn to_currency_unit(amount_subunit: u64) -> f64 {
-    amount_subunit as f64 / 1.0e+4_f64
+// With a decryption key, inputs encrypted with age or PGP are decrypted as they are read and the rest read as they are
+fn open_source(inputs: &[String], decrypt_key: Option<&str>) -> Result<Box<dyn TransactionSource>> {
+    let mut sources = inputs.iter()
+        .map(|input| -> Result<Box<dyn TransactionSource>> {
+            match input.as_str() {
+                "-" => Ok(Box::new(CsvSource::stdin()?)),
+                path => match decrypt::detect(path)? {
+                    Some(format) => {
+                        let key = decrypt_key.ok_or_else(|| Error::InvalidArgument(format!(
+                            "{} is encrypted with {}. Give its key with --decrypt-key.", path, format)))?;
+                        Ok(Box::new(CsvSource::decrypted(path, format, key)?))
+                    }
+                    None => Ok(Box::new(CsvSource::open(path)?)),
+                },
+            }
+        })
+        .collect::<Result<Vec<Box<dyn TransactionSource>>>>()?;
+    if sources.len() == 1 {
+        return Ok(sources.remove(0));
+    }
+    Ok(Box::new(Chain::new(sources)))
 }
 
-fn input_record_to_transaction(record: &TxInputRecord, time: u32) -> TransactionMessage {
-    let converted_amount = if record.amount.len() > 0 {
-        to_subunit(&(record.amount))
+fn retention_value(iter: &mut std::slice::Iter<String>, name: &str) -> Result<Retention> {
+    match name {
+        "--retain-age" => Ok(Retention::Age(parsed_value(iter, name)?)),
+        _ => Ok(Retention::Count(parsed_value(iter, name)?)),
+    }
+}
+
+// Anyone applying the same rows gets the same root, so two runs can be compared without comparing their rows
+fn report_merkle(report: &ReadReport, account_txs: &AccountTransactions) {
+    if let Some(root) = report.merkle.root_hex() {
+        diag!("Merkle root of the {} applied transactions: {}.", report.merkle.leaves(), root);
+    }
+    // A snapshot carries the tree on from earlier runs
+    let merkle = account_txs.merkle();
+    if let Some(root) = merkle.root_hex().filter(|_| merkle.leaves() > report.merkle.leaves()) {
+        diag!("Merkle root of the {} transactions applied to the state: {}.", merkle.leaves(), root);
+    }
+}
+
+fn report_throughput(report: &ReadReport) {
+    if report.bytes > 0 {
+        diag!("Took {:.1?}: {:.0} rows/sec, {:.1} MiB/sec.", report.elapsed, report.rows_per_second(),
+              report.bytes_per_second() / 1_048_576.0);
     } else {
-        0 as u64
+        diag!("Took {:.1?}: {:.0} rows/sec.", report.elapsed, report.rows_per_second());
+    }
+    diag!("Peak of {} retained transactions, estimated peak memory {:.1} MiB.", report.peak_transactions,
+          report.peak_memory_bytes as f64 / 1_048_576.0);
+}
+
+// Per-type handling times, then the total time in each phase so the dominant one stands out
+fn report_latency(latency: &Latencies) {
+    if latency.is_empty() {
+        return;
+    }
+    for (tx_type, histogram) in &latency.by_type {
+        diag!("Latency for {}: {}.", tx_type, histogram);
+    }
+    diag!("Time by phase: engine {:.1?}, store {:.1?}, sinks {:.1?}.",
+          latency.engine.total(), latency.store.total(), latency.sinks.total());
+}
+
+fn report_pruned(pruned: Option<PrunedRange>) {
+    match pruned {
+        Some(range) => diag!("Pruned {} transactions and {} messages between times {} and {}.",
+                                 range.transactions, range.messages, range.from_time, range.to_time),
+        None => diag!("No transactions eligible for pruning."),
+    }
+}
+
+fn option_value(iter: &mut std::slice::Iter<String>, name: &str) -> Result<String> {
+    iter.next().cloned().ok_or_else(|| missing_value(name))
+}
+
+fn parsed_value<T: std::str::FromStr>(iter: &mut std::slice::Iter<String>, name: &str) -> Result<T> {
+    let value = option_value(iter, name)?;
+    value.parse().map_err(|_| invalid_value(name, &value))
+}
+
+fn missing_value(name: &str) -> Error {
+    Error::InvalidArgument(format!("Missing value for {}.", name))
+}
+
+fn invalid_value(name: &str, value: &str) -> Error {
+    Error::InvalidArgument(format!("Invalid value for {}, {}.", name, value))
+}
+
+fn missing_option(usage: &str) -> Error {
+    Error::InvalidArgument(format!("Missing {}.", usage))
+}
+
+fn run_generate(args: &[String]) -> Result<()> {
+    let mut config = generate::GeneratorConfig::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--rows" => config.rows = parsed_value(&mut iter, arg)?,
+            "--clients" => config.clients = parsed_value(&mut iter, arg)?,
+            "--seed" => config.seed = parsed_value(&mut iter, arg)?,
+            "--malformed-rate" => config.malformed_rate = parsed_value(&mut iter, arg)?,
+            "--duplicate-rate" => config.duplicate_rate = parsed_value(&mut iter, arg)?,
+            "--cross-client-rate" => config.cross_client_rate = parsed_value(&mut iter, arg)?,
+            "--out-of-range-rate" => config.out_of_range_rate = parsed_value(&mut iter, arg)?,
+            _ => return Err(cli::unknown_option(&cli::GENERATE, arg)),
+        }
+    }
+
+    let report = generate::generate_stdout(&config)?;
+    diag!("Generated {} rows. Injected {} malformed, {} duplicate tx ids, {} cross-client, {} out-of-range.",
+              report.rows, report.malformed, report.duplicates, report.cross_client, report.out_of_range);
+    Ok(())
+}
+
+fn run_serve(args: &[String], config: &Config) -> Result<()> {
+    let mut options = ServeOptions {
+        listen: String::from("127.0.0.1:8080"),
+        webhooks: config.webhooks.clone(),
+        redis: config.redis.clone(),
+        admin: config.admin.clone(),
+        api_keys: config.api_keys.clone(),
+        tls: config.tls.clone(),
+        rate_limit: config.rate_limit.clone(),
+        idempotency: config.idempotency.clone(),
+        serve: config.serve.clone(),
+        ..ServeOptions::default()
+    };
+    let mut store = None;
+    let mut primary = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--listen" => options.listen = option_value(&mut iter, arg)?,
+            "--replication-listen" => options.replication = Some(option_value(&mut iter, arg)?),
+            "--unix-socket" => options.unix_socket = Some(option_value(&mut iter, arg)?),
+            "--tcp-listen" => options.tcp_listen = Some(option_value(&mut iter, arg)?),
+            "--no-auth" => options.serve.auth = ServeAuth::None,
+            "--standby-of" => primary = Some(option_value(&mut iter, arg)?),
+            "--state" => store = Some(format!("dir:{}", option_value(&mut iter, arg)?)),
+            "--store" => store = Some(option_value(&mut iter, arg)?),
+            _ => return Err(cli::unknown_option(&cli::SERVE, arg)),
+        }
+    }
+
+    let engine_options = configured_options(&[], config)?;
+    let mut account_txs = AccountTransactions::with_validators(HookConfig::new(config).load()?);
+    let mut store = store.or_else(|| default_store(config)).as_deref().map(open_store).transpose()?;
+    if let Some(store) = store.as_mut() {
+        store.load(&mut account_txs)?;
+    }
+    configure_engine(&mut account_txs, &engine_options);
+    let configure: Configure = Arc::new(move |account_txs: &mut AccountTransactions| {
+        configure_engine(account_txs, &engine_options)
+    });
+    match primary {
+        Some(primary) => server::standby(&options, &primary, account_txs, store, configure),
+        None => {
+            let read_options = ReadOptions {
+                kafka: config.kafka.clone(),
+                webhooks: config.webhooks.clone(),
+                redis: config.redis.clone(),
+                limits: config.limits.clone(),
+                ingest: config.ingest.clone(),
+                idempotency: config.idempotency.clone(),
+                dedup: config.dedup.clone(),
+                ..ReadOptions::default()
+            };
+            scheduler::start(&config.schedules, &read_options, &HookConfig::new(config), &configure)?;
+            server::serve(&options, account_txs, store)
+        }
+    }
+}
+
+// Commands come from STDIN, prompted for when it is a terminal
+fn run_repl(args: &[String], config: &Config) -> Result<()> {
+    let mut store = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--state" => store = Some(format!("dir:{}", option_value(&mut iter, arg)?)),
+            "--store" => store = Some(option_value(&mut iter, arg)?),
+            _ => return Err(cli::unknown_option(&cli::REPL, arg)),
+        }
+    }
+    let options = configured_options(&[], config)?;
+    let mut account_txs = AccountTransactions::with_validators(HookConfig::new(config).load()?);
+    let mut store = store.or_else(|| default_store(config)).as_deref().map(open_store).transpose()?;
+    if let Some(store) = store.as_mut() {
+        store.load(&mut account_txs)?;
+        diag!("Loaded {} accounts from {}.", account_txs.account_count(), store.describe());
+    }
+    configure_engine(&mut account_txs, &options);
+    let stdin = io::stdin();
+    let prompt = if stdin.is_terminal() { Some("tx_acct> ") } else { None };
+    let mut repl = Repl::new(&mut account_txs, store.as_mut().map(|store| store.as_mut() as &mut dyn Store));
+    repl.run(stdin.lock(), &mut io::stdout(), prompt)
+}
+
+// The client's retained transactions in the order they were applied, with the counterparty, memo and category given
+// in the input, amounts in currency units
+fn run_statement(args: &[String], config: &Config) -> Result<()> {
+    let mut client = None;
+    let mut output = None;
+    let mut store = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--client" => client = Some(parsed_value(&mut iter, arg)?),
+            "--output" => output = Some(option_value(&mut iter, arg)?),
+            "--state" => store = Some(format!("dir:{}", option_value(&mut iter, arg)?)),
+            "--store" => store = Some(option_value(&mut iter, arg)?),
+            _ => return Err(cli::unknown_option(&cli::STATEMENT, arg)),
+        }
+    }
+    let client: u16 = client.ok_or_else(|| missing_option("--client <id>"))?;
+    let spec = store.or_else(|| default_store(config)).ok_or_else(|| missing_option("--state <dir> or --store <spec>"))?;
+    if let Some(path) = &output {
+        cli::check_output_file("--output", path)?;
+    }
+    let mut store = open_store(&spec)?;
+    let mut account_txs = AccountTransactions::new();
+    store.load(&mut account_txs)?;
+    let acct = account_txs.get_account(client)
+        .ok_or_else(|| Error::InvalidArgument(format!("Client {} has no account in {}.", client, store.describe())))?;
+
+    let mut txs: Vec<&Tx> = account_txs.client_transactions(client).collect();
+    txs.sort_by_key(|tx| (tx.tx_time, tx.tx));
+    let mut wtr = csv::Writer::from_writer(Vec::new());
+    wtr.write_record(["tx", "tx_time", "type", "amount", "state", "pending", "counterparty", "memo", "category",
+                      "history"])?;
+    for tx in &txs {
+        wtr.write_record([tx.tx.to_string(), tx.tx_time.to_string(), tx.tx_type.to_string(),
+                          format_amount(tx.amount), tx.state.to_string(), tx.pending.to_string(),
+                          tx.counterparty.clone().unwrap_or_default(), tx.memo.clone().unwrap_or_default(),
+                          tx.category.clone().unwrap_or_default(), lifecycle::history_text(&tx.history)])?;
+        // Fees are lines of their own under the transaction, a chargeback fee with the tx_time of the chargeback
+        if tx.service_fee > 0 {
+            wtr.write_record([tx.tx.to_string(), tx.tx_time.to_string(), String::from("service_fee"),
+                              format_amount(tx.service_fee), String::new(), String::new(),
+                              String::new(), String::new(), String::new(), String::new()])?;
+        }
+        if tx.fee > 0 {
+            wtr.write_record([tx.tx.to_string(), tx.chargeback_time.to_string(), String::from("chargeback_fee"),
+                              format_amount(tx.fee), String::new(), String::new(), String::new(),
+                              String::new(), String::new(), String::new()])?;
+        }
+    }
+    let statement = wtr.into_inner().map_err(|e| io::Error::other(e.to_string()))?;
+    match &output {
+        Some(path) => fs::write(path, statement)?,
+        None => io::Write::write_all(&mut io::stdout(), &statement)?,
+    }
+    diag!("{} transactions for client {}: available {}, held {}, escrow {}, total {}{}.", txs.len(), client,
+          format_amount(acct.available), format_amount(acct.held), format_amount(acct.escrow),
+          format_amount(acct.total), if acct.locked { ", locked" } else { "" });
+    Ok(())
+}
+
+// Runs until SIGINT or SIGTERM, then writes the accounts like a normal run
+fn run_consume(args: &[String], config: &Config) -> Result<()> {
+    let mut store = None;
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--store" => store = Some(option_value(&mut iter, arg)?),
+            _ => return Err(cli::unknown_option(&cli::CONSUME, arg)),
+        }
+    }
+    let input = config.kafka_input.as_ref()
+        .ok_or_else(|| Error::ConfigError(String::from("consume needs a [kafka_input] section in the --config file.")))?;
+    let mut store = open_store(&store.or_else(|| default_store(config)).ok_or_else(|| missing_option("--store <spec>"))?)?;
+    kafka::check_store(store.as_ref())?;
+
+    let options = configured_options(&[], config)?;
+    let mut account_txs = AccountTransactions::with_validators(HookConfig::new(config).load()?);
+    store.load(&mut account_txs)?;
+    configure_engine(&mut account_txs, &options);
+    let read_options = ReadOptions {
+        kafka: config.kafka.clone(),
+        webhooks: config.webhooks.clone(),
+        redis: config.redis.clone(),
+        cancel: Some(interrupt::install()),
+        idempotency: config.idempotency.clone(),
+        dedup: config.dedup.clone(),
+        ..ReadOptions::default()
     };
+    let report = kafka::consume(input, &mut account_txs, store.as_mut(), &read_options)?;
+    diag!("Consumed {} records: {} applied, {} rejected, {} malformed, {} replayed, {} duplicates.",
+          report.rows, report.applied, report.rejected, report.malformed, report.replayed, report.duplicates);
+    diag!("Rejections by reason: {}.", report.rejection_counts());
+    report_merkle(&report, &account_txs);
+    report_throughput(&report);
+    report_latency(&report.latency);
+    output_accounts(&account_txs.iter_accounts().collect::<Vec<&Account>>(), OutputFormat::Csv);
+    Ok(())
+}
 
-    TransactionMessage {
-        tx_time: time,
-        tx: record.tx,
-        tx_type: TransactionType::from_str(&record.tx_type.as_str()).expect("Failed to convert tx_type"),
-        client: record.client,
-        amount: converted_amount,
+fn run_admin(args: &[String], config: &Config) -> Result<()> {
+    let command = args.first().ok_or_else(|| missing_option("admin command"))?;
+    let mut client = None;
+    let mut tx = None;
+    let mut amount = None;
+    let mut reason = None;
+    let mut state = None;
+    let mut spec = None;
+    let mut retention = None;
+    let mut keep_messages = 0;
+    let mut from = None;
+    let mut into = None;
+    let mut iter = args[1..].iter();
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--client" => client = Some(parsed_value(&mut iter, arg)?),
+            "--tx" => tx = Some(parsed_value(&mut iter, arg)?),
+            "--amount" => amount = Some(option_value(&mut iter, arg)?),
+            "--reason" => reason = Some(option_value(&mut iter, arg)?),
+            "--from" => from = Some(parsed_value(&mut iter, arg)?),
+            "--into" => into = Some(parsed_value(&mut iter, arg)?),
+            "--keep-messages" => keep_messages = parsed_value(&mut iter, arg)?,
+            "--state" => state = Some(option_value(&mut iter, arg)?),
+            "--store" => spec = Some(option_value(&mut iter, arg)?),
+            "--retain-age" | "--retain-count" => retention = Some(retention_value(&mut iter, arg)?),
+            _ => return Err(cli::unknown_option(&cli::ADMIN, arg)),
+        }
     }
-}
\ No newline at end of file
+
+    // Manual adjustments work with any store and are written to the audit log
+    let action = match command.as_str() {
+        "adjust" => {
+            let amount = amount.ok_or_else(|| missing_option("--amount <signed amount>"))?;
+            Some(AdminAction::Adjust { client: client.ok_or_else(|| missing_option("--client <id>"))?,
+                                       amount: signed_subunits(&amount)? })
+        }
+        "close-dispute" => Some(AdminAction::CloseDispute { tx: tx.ok_or_else(|| missing_option("--tx <id>"))? }),
+        "approve" => Some(AdminAction::Approve { tx: tx.ok_or_else(|| missing_option("--tx <id>"))? }),
+        "deny" => Some(AdminAction::Deny { tx: tx.ok_or_else(|| missing_option("--tx <id>"))? }),
+        "unlock" => Some(AdminAction::Unlock { client: client.ok_or_else(|| missing_option("--client <id>"))? }),
+        _ => None,
+    };
+    if let Some(action) = action {
+        let reason = reason.ok_or_else(|| missing_option("--reason <text>"))?;
+        let spec = spec.or_else(|| state.map(|dir| format!("dir:{}", dir))).or_else(|| default_store(config))
+            .ok_or_else(|| missing_option("--state <dir> or --store <spec>"))?;
+        let mut store = open_store(&spec)?;
+        let mut account_txs = AccountTransactions::new();
+        store.load(&mut account_txs)?;
+        let actor = env::var("USER").unwrap_or_else(|_| String::from("cli"));
+        AuditLog::open(&config.admin.audit_log)?.apply(&mut account_txs, &action, &reason, &actor)?;
+        store.record_admin(&account_txs, &action)?;
+        store.save(&account_txs)?;
+        diag!("Applied {:?} to {}. Logged in {}.", action, store.describe(), config.admin.audit_log);
+        return Ok(());
+    }
+    // The rest rewrite a state directory's files, which the other stores do not keep
+    let dir = match spec {
+        Some(spec) => match spec.strip_prefix("dir:") {
+            Some(dir) => dir.to_string(),
+            None => return Err(Error::InvalidArgument(format!(
+                "admin {} only works on a state directory, and {} is not one. Use --state <dir>.", command, spec))),
+        },
+        None => state.ok_or_else(|| missing_option("--state <dir>"))?,
+    };
+    let store = StateStore::new(dir);
+
+    match command.as_str() {
+        "erase-client" => {
+            let client = client.ok_or_else(|| missing_option("--client <id>"))?;
+            let tombstone = store.erase_client(client)?;
+            diag!("Erased client, {}. Removed {} transactions and {} messages. Tombstone {} records {} total balance.",
+                      client, tombstone.transactions, tombstone.messages,
+                      tombstone.seq, format_amount(tombstone.total));
+            Ok(())
+        }
+        "prune" => {
+            let retention = retention.ok_or_else(|| missing_option("--retain-age <rows> or --retain-count <n>"))?;
+            let mut account_txs = AccountTransactions::new();
+            store.load(&mut account_txs)?;
+            report_pruned(account_txs.prune(&retention));
+            store.save(&account_txs)?;
+            Ok(())
+        }
+        "merge-clients" => {
+            let from = from.ok_or_else(|| missing_option("--from <id>"))?;
+            let into = into.ok_or_else(|| missing_option("--into <id>"))?;
+            let mut account_txs = AccountTransactions::new();
+            store.load(&mut account_txs)?;
+            let merged = account_txs.merge_clients(from, into)?;
+            store.save(&account_txs)?;
+            store.record_merge(&merged)?;
+            diag!("Merged client, {}, into client, {}. Re-pointed {} transactions and {} messages.",
+                      merged.from, merged.into, merged.transactions, merged.messages);
+            if merged.lock_conflict {
+                diag!("Only one of the merged accounts was locked. Client, {}, is now locked.", merged.into);
+            }
+            Ok(())
+        }
+        "compact" => {
+            let compaction = store.compact(keep_messages)?;
+            diag!("Compacted state through time {}. Dropped {} messages, reclaimed {} bytes ({} -> {}).",
+                      compaction.through_time, compaction.messages,
+                      compaction.bytes_before.saturating_sub(compaction.bytes_after),
+                      compaction.bytes_before, compaction.bytes_after);
+            Ok(())
+        }
+        _ => Err(Error::InvalidArgument(format!("Unknown admin command, {}.", command))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compacting_another_store_is_unsupported() {
+        let args: Vec<String> = ["compact", "--store", "snapshot:state.zst"].iter().map(|arg| arg.to_string()).collect();
+        assert!(matches!(run_admin(&args, &Config::default()), Err(Error::InvalidArgument(_))));
+    }
+
+    #[test]
+    fn dry_run_writes_no_logs() {
+        let dir = env::temp_dir().join(format!("tx_acct-test-{}-dry-run", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        fs::write(path("input.csv"), "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,two,1.0\nwithdraw,1,3,5.0\n").unwrap();
+        let args: Vec<String> = ["--dry-run", "--error-log", "errors.jsonl", "--quarantine", "bad.csv", "--dead-letter", "rejected.csv"]
+            .iter()
+            .map(|arg| if arg.starts_with("--") { arg.to_string() } else { path(arg) })
+            .chain(std::iter::once(path("input.csv")))
+            .collect();
+        let config = Config::default();
+        let options = parse_options(&args, &config).unwrap();
+        let read_options = run_read_options(&options, &config, &CancelToken::new());
+        let mut account_txs = AccountTransactions::new();
+
+        let report = read_source(&mut CsvSource::open(&path("input.csv")).unwrap(), &mut account_txs, &read_options, None).unwrap();
+        assert_eq!((report.applied, report.rejected, report.malformed), (1, 1, 1));
+        for name in ["errors.jsonl", "bad.csv", "rejected.csv"] {
+            assert!(!dir.join(name).exists(), "{} was written", name);
+        }
+        let _ = fs::remove_dir_all(dir);
+    }
+}
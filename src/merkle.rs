@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{Error, Result, TransactionMessage};
+
+pub type Hash = [u8; 32];
+
+// Merkle tree over applied transactions in the order they were applied, built as they stream past so only one hash
+// per level is kept. The tree is shaped as in RFC 6962: a leaf is SHA-256 of 0x00 and the row, a node SHA-256 of
+// 0x01 and its two children, and the left subtree of each node is the largest power of two that fits. A row is
+// type,client,tx,amount with the amount in currency subunits, so the root depends only on the rows and not on how
+// their amounts were written or when they were handled. Two parties applying the same file get the same root.
+#[derive(Debug, Default, Clone)]
+pub struct MerkleTree {
+    leaves: u64,
+    // Roots of the perfect subtrees covering the leaves so far, largest first, one for each bit set in leaves
+    frontier: Vec<Hash>,
+}
+
+// A MerkleTree as a snapshot keeps it, so the tree carries on across runs
+#[derive(Serialize, Deserialize)]
+pub(crate) struct MerkleRecord {
+    leaves: u64,
+    root: Option<String>,
+    frontier: Vec<String>,
+}
+
+impl MerkleTree {
+    pub fn push(&mut self, msg: &TransactionMessage) {
+        let mut hash = leaf(msg);
+        let mut leaves = self.leaves;
+        while leaves & 1 == 1 {
+            let left = self.frontier.pop().expect("A set bit of leaves has a subtree in the frontier.");
+            hash = node(&left, &hash);
+            leaves >>= 1;
+        }
+        self.frontier.push(hash);
+        self.leaves += 1;
+    }
+
+    pub fn leaves(&self) -> u64 {
+        self.leaves
+    }
+
+    // None before the first leaf
+    pub fn root(&self) -> Option<Hash> {
+        let mut subtrees = self.frontier.iter().rev();
+        let last = *subtrees.next()?;
+        Some(subtrees.fold(last, |right, left| node(left, &right)))
+    }
+
+    pub fn root_hex(&self) -> Option<String> {
+        self.root().map(|root| hex(&root))
+    }
+
+    pub(crate) fn record(&self) -> MerkleRecord {
+        let frontier = self.frontier.iter().map(|hash| hex(hash)).collect();
+        MerkleRecord { leaves: self.leaves, root: self.root_hex(), frontier }
+    }
+
+    pub(crate) fn restore(record: MerkleRecord) -> Result<MerkleTree> {
+        let invalid = || Error::StorageError(String::from("Snapshot Merkle frontier does not match its leaf count"));
+        if record.frontier.len() != record.leaves.count_ones() as usize {
+            return Err(invalid());
+        }
+        let frontier = record.frontier.iter().map(|hash| from_hex(hash).ok_or_else(invalid)).collect::<Result<_>>()?;
+        Ok(MerkleTree { leaves: record.leaves, frontier })
+    }
+}
+
+// The leaf hash of an applied transaction
+pub fn leaf(msg: &TransactionMessage) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update(format!("{},{},{},{}", msg.tx_type, msg.client, msg.tx, msg.amount));
+    hasher.finalize().into()
+}
+
+// The audit path showing the leaf at index is in the tree over leaves, as RFC 6962 defines it: the sibling subtree
+// roots from the leaf up. Empty when index is out of range.
+pub fn prove(leaves: &[Hash], index: usize) -> Vec<Hash> {
+    if index >= leaves.len() || leaves.len() == 1 {
+        return Vec::new();
+    }
+    let split = split(leaves.len());
+    let (mut path, sibling) = match index < split {
+        true => (prove(&leaves[..split], index), subtree_root(&leaves[split..])),
+        false => (prove(&leaves[split..], index - split), subtree_root(&leaves[..split])),
+    };
+    path.push(sibling);
+    path
+}
+
+// Whether path proves leaf is at index in a tree of size leaves with root, as RFC 9162 section 2.1.3.2 checks it
+pub fn verify(leaf: &Hash, index: u64, size: u64, path: &[Hash], root: &Hash) -> bool {
+    if index >= size {
+        return false;
+    }
+    let (mut fn_, mut sn) = (index, size - 1);
+    let mut hash = *leaf;
+    for sibling in path {
+        if sn == 0 {
+            return false;
+        }
+        if fn_ & 1 == 1 || fn_ == sn {
+            hash = node(sibling, &hash);
+            while fn_ & 1 == 0 && fn_ != 0 {
+                fn_ >>= 1;
+                sn >>= 1;
+            }
+        } else {
+            hash = node(&hash, sibling);
+        }
+        fn_ >>= 1;
+        sn >>= 1;
+    }
+    sn == 0 && hash == *root
+}
+
+// Size of the left subtree of a node over n leaves, the largest power of two below n
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+fn subtree_root(leaves: &[Hash]) -> Hash {
+    if leaves.len() == 1 {
+        return leaves[0];
+    }
+    let split = split(leaves.len());
+    node(&subtree_root(&leaves[..split]), &subtree_root(&leaves[split..]))
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+pub fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// N bytes from 2N hex digits
+pub(crate) fn from_hex<const N: usize>(hex: &str) -> Option<[u8; N]> {
+    let mut bytes = [0; N];
+    if hex.len() != N * 2 || !hex.is_ascii() {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{message, units};
+    use crate::{Amount, TransactionType};
+
+    fn messages(count: u32) -> Vec<TransactionMessage> {
+        (1..=count).map(|tx| message(TransactionType::DEPOSIT, (tx % 7) as u16 + 1, tx, units(Amount::from(tx)))).collect()
+    }
+
+    #[test]
+    fn root_matches_a_known_vector() {
+        let mut tree = MerkleTree::default();
+        tree.push(&message(TransactionType::DEPOSIT, 1, 1, 15_000));
+        assert_eq!(tree.root_hex().unwrap(), "f14a7d05b7800b813a404802dc4fa6d2a3620d4f300b48632117e6e122b4a7ac");
+        tree.push(&message(TransactionType::DEPOSIT, 2, 2, 20_000));
+        tree.push(&message(TransactionType::WITHDRAWAL, 1, 3, 5_000));
+        assert_eq!(tree.root_hex().unwrap(), "f63c883f72962b5bd8c26f2ce64d48c80fc82dc412245837895804f1c22c5a0e");
+    }
+
+    #[test]
+    fn proves_every_leaf() {
+        for size in 1..=17 {
+            let messages = messages(size);
+            let mut tree = MerkleTree::default();
+            messages.iter().for_each(|msg| tree.push(msg));
+            let root = tree.root().unwrap();
+            let leaves: Vec<Hash> = messages.iter().map(leaf).collect();
+            for (index, hash) in leaves.iter().enumerate() {
+                let path = prove(&leaves, index);
+                assert!(verify(hash, index as u64, size as u64, &path, &root), "leaf {} of {}", index, size);
+            }
+        }
+    }
+
+    #[test]
+    fn refuses_a_tampered_leaf() {
+        let mut messages = messages(6);
+        let mut tree = MerkleTree::default();
+        messages.iter().for_each(|msg| tree.push(msg));
+        let root = tree.root().unwrap();
+        let leaves: Vec<Hash> = messages.iter().map(leaf).collect();
+        let path = prove(&leaves, 3);
+
+        messages[3].amount += 1;
+        assert!(!verify(&leaf(&messages[3]), 3, 6, &path, &root));
+        let mut forged = path.clone();
+        forged[0][0] ^= 1;
+        assert!(!verify(&leaves[3], 3, 6, &forged, &root));
+    }
+}
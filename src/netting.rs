@@ -0,0 +1,235 @@
+use std::collections::BTreeMap;
+use std::mem;
+
+use crate::error::Rejection;
+use crate::{rejected, Account, AccountTransactions, Amount, Error, Result, TransactionMessage, TransactionType, Tx, TxState};
+
+// Transfers recorded while netting, as what each client owes each other client
+#[derive(Debug, Clone, Default)]
+pub struct Netting {
+    // Subunits owed by (from, to)
+    owed: BTreeMap<(u16, u16), Amount>,
+    transfers: u32,
+}
+
+impl Netting {
+    // Transfers recorded since the last settlement
+    pub fn transfers(&self) -> u32 {
+        self.transfers
+    }
+}
+
+// The net movement between two clients. from is the client that owed more, owed what it owed and offset what the
+// other owed it back, so net is owed less offset. reason is None when the net moved, otherwise why it could not.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settlement {
+    pub from: u16,
+    pub to: u16,
+    pub owed: Amount,
+    pub offset: Amount,
+    pub net: Amount,
+    pub reason: Option<Rejection>,
+}
+
+impl AccountTransactions {
+    // With netting on, transfers move no funds when applied. They are added up by pair of clients and settled as one
+    // net movement per pair by settle_netting.
+    pub fn set_netting(&mut self, enabled: bool) {
+        self.netting = if enabled { Some(Netting::default()) } else { None };
+    }
+
+    pub fn netting(&self) -> Option<&Netting> {
+        self.netting.as_ref()
+    }
+
+    // Pays to_client from the client's available funds, or with netting on records the obligation. The paying client
+    // must exist and neither account be locked.
+    pub(crate) fn transfer_tx(&mut self, transaction_msg: &TransactionMessage) -> Result<()> {
+        let to = transaction_msg.to_client.ok_or_else(|| Error::InvalidArgument(format!(
+            "Transfer, {}, names no to_client.", transaction_msg.tx)))?;
+        if !self.account_client.contains_key(&transaction_msg.client) {
+            return Err(self.unknown_client(transaction_msg));
+        }
+        self.check_new_tx(transaction_msg)?;
+        if to == transaction_msg.client {
+            return Err(rejected(Rejection::SelfTransfer,
+                                format!("Transfer to the same client. Ignored transaction. Client: {}, Transaction ID: {}.",
+                                        transaction_msg.client, transaction_msg.tx)));
+        }
+        if self.account_client.get(&to).is_some_and(|acct| acct.locked) {
+            return Err(rejected(Rejection::LockedAccount,
+                                format!("Account for client, {}, is locked. Ignoring transfer transaction, {}, to it.",
+                                        to, transaction_msg.tx)));
+        }
+        match self.netting.as_mut() {
+            Some(netting) => {
+                *netting.owed.entry((transaction_msg.client, to)).or_insert(0) += transaction_msg.amount;
+                netting.transfers += 1;
+            }
+            None => {
+                let acct = self.account_client.get_mut(&transaction_msg.client).expect("Account checked above.");
+                if acct.available < transaction_msg.amount {
+                    return Err(rejected(Rejection::InsufficientFunds,
+                                        format!("Insufficient funds for transfer. Ignored transaction. Client: {}, \
+                                                 Transaction ID: {}.", transaction_msg.client, transaction_msg.tx)));
+                }
+                acct.available -= transaction_msg.amount;
+                acct.total = acct.available + acct.held + acct.escrow;
+                self.credit(to, transaction_msg.amount);
+            }
+        }
+        self.txs_txid.insert(transaction_msg.tx,
+                             Tx {
+                                 tx: transaction_msg.tx,
+                                 tx_time: transaction_msg.tx_time,
+                                 tx_type: TransactionType::TRANSFER,
+                                 client: transaction_msg.client,
+                                 amount: transaction_msg.amount,
+                                 state: TxState::Posted,
+                                 dispute_time: 0,
+                                 chargeback_time: 0,
+                                 pending: false,
+                                 provisional: false,
+                                 fee: 0,
+                                 fee_loss: 0,
+                                 recovery: 0,
+                                 recovered: 0,
+                                 service_fee: 0,
+                                 counterparty: transaction_msg.counterparty.clone(),
+                                 memo: transaction_msg.memo.clone(),
+                                 category: transaction_msg.category.clone(),
+                                 history: Vec::new(),
+                             });
+        Ok(())
+    }
+
+    // Moves the net of each pair's obligations, pairs in order of their client ids, and starts netting afresh. A net
+    // the paying client has not the available funds for, or between locked accounts, is left unmoved.
+    pub fn settle_netting(&mut self) -> Vec<Settlement> {
+        let owed = match self.netting.as_mut() {
+            Some(netting) => {
+                netting.transfers = 0;
+                mem::take(&mut netting.owed)
+            }
+            None => return Vec::new(),
+        };
+        // (low, high) to what low owes high and what high owes low
+        let mut pairs: BTreeMap<(u16, u16), (Amount, Amount)> = BTreeMap::new();
+        for ((from, to), amount) in owed {
+            match from < to {
+                true => pairs.entry((from, to)).or_default().0 += amount,
+                false => pairs.entry((to, from)).or_default().1 += amount,
+            }
+        }
+        let mut settlements = Vec::with_capacity(pairs.len());
+        for ((low, high), (low_owes, high_owes)) in pairs {
+            let (from, to, owed, offset) = match low_owes >= high_owes {
+                true => (low, high, low_owes, high_owes),
+                false => (high, low, high_owes, low_owes),
+            };
+            let net = owed - offset;
+            let reason = self.move_net(from, to, net);
+            settlements.push(Settlement { from, to, owed, offset, net, reason });
+        }
+        settlements
+    }
+
+    fn move_net(&mut self, from: u16, to: u16, net: Amount) -> Option<Rejection> {
+        if net == 0 {
+            return None;
+        }
+        if [from, to].iter().any(|client| self.account_client.get(client).is_some_and(|acct| acct.locked)) {
+            return Some(Rejection::LockedAccount);
+        }
+        let acct = match self.account_client.get_mut(&from) {
+            Some(acct) if acct.available >= net => acct,
+            _ => return Some(Rejection::InsufficientFunds),
+        };
+        acct.available -= net;
+        acct.total = acct.available + acct.held + acct.escrow;
+        self.credit(to, net);
+        None
+    }
+
+    // Adds to a client's available funds, opening its account if it has none
+    fn credit(&mut self, client: u16, amount: Amount) {
+        let acct = self.account_client.entry(client).or_insert_with(|| Account {
+            client,
+            available: 0,
+            held: 0,
+            escrow: 0,
+            total: 0,
+            locked: false,
+        });
+        acct.available += amount;
+        acct.total = acct.available + acct.held + acct.escrow;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{apply, message, units};
+
+    fn transfer(client: u16, tx: u32, to: u16, amount: Amount) -> TransactionMessage {
+        TransactionMessage { to_client: Some(to), ..message(TransactionType::TRANSFER, client, tx, amount) }
+    }
+
+    fn rejection(result: Result<()>) -> Option<Rejection> {
+        match result {
+            Err(Error::PolicyViolation { reason, .. }) => Some(reason),
+            _ => None,
+        }
+    }
+
+    fn available(account_txs: &AccountTransactions, client: u16) -> Amount {
+        account_txs.get_account(client).map_or(0, |acct| acct.available)
+    }
+
+    #[test]
+    fn a_transfer_pays_the_other_client_at_once_without_netting() {
+        let mut account_txs = AccountTransactions::new();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        apply(&mut account_txs, transfer(1, 2, 2, units(4))).unwrap();
+        assert_eq!((available(&account_txs, 1), available(&account_txs, 2)), (units(6), units(4)));
+
+        assert_eq!(rejection(apply(&mut account_txs, transfer(1, 3, 2, units(7)))), Some(Rejection::InsufficientFunds));
+        assert_eq!(rejection(apply(&mut account_txs, transfer(1, 4, 1, units(1)))), Some(Rejection::SelfTransfer));
+        assert!(matches!(apply(&mut account_txs, message(TransactionType::TRANSFER, 1, 5, units(1))),
+                         Err(Error::InvalidArgument(_))));
+        assert_eq!((available(&account_txs, 1), available(&account_txs, 2)), (units(6), units(4)));
+    }
+
+    #[test]
+    fn netting_moves_one_net_per_pair_when_settled() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_netting(true);
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(1))).unwrap();
+        apply(&mut account_txs, transfer(1, 3, 2, units(8))).unwrap();
+        apply(&mut account_txs, transfer(2, 4, 1, units(5))).unwrap();
+        // Client 2 owes more than it holds, which netting allows until settlement
+        assert_eq!(account_txs.netting().map(Netting::transfers), Some(2));
+        assert_eq!((available(&account_txs, 1), available(&account_txs, 2)), (units(10), units(1)));
+
+        let settlements = account_txs.settle_netting();
+        assert_eq!(settlements, vec![Settlement { from: 1, to: 2, owed: units(8), offset: units(5), net: units(3),
+                                                  reason: None }]);
+        assert_eq!((available(&account_txs, 1), available(&account_txs, 2)), (units(7), units(4)));
+        assert_eq!(account_txs.netting().map(Netting::transfers), Some(0));
+        assert!(account_txs.settle_netting().is_empty());
+    }
+
+    #[test]
+    fn a_net_the_payer_cannot_cover_is_left_unmoved() {
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_netting(true);
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(2))).unwrap();
+        apply(&mut account_txs, transfer(1, 2, 3, units(5))).unwrap();
+
+        let settlements = account_txs.settle_netting();
+        assert_eq!(settlements.len(), 1);
+        assert_eq!(settlements[0].reason, Some(Rejection::InsufficientFunds));
+        assert_eq!((available(&account_txs, 1), available(&account_txs, 3)), (units(2), 0));
+    }
+}
@@ -0,0 +1,62 @@
+use std::fmt;
+
+use crate::change::AccountChange;
+use crate::{Error, Rejection, Result, TransactionMessage};
+
+// Told what the engine did with each message, after it was handled. Each account change goes to exactly one of
+// account_created, account_locked and balance_changed, which all default to account_changed for observers that treat
+// every change alike. An Err stops processing like a storage failure would.
+pub trait EngineObserver: Send {
+    fn name(&self) -> &str;
+
+    // Any change to an account's balances or lock status
+    fn account_changed(&mut self, _change: &AccountChange) -> Result<()> {
+        Ok(())
+    }
+
+    // The message named a client not seen before. Only a deposit applies to a new client, anything else is
+    // rejected after creating the account empty.
+    fn account_created(&mut self, change: &AccountChange) -> Result<()> {
+        self.account_changed(change)
+    }
+
+    // An applied message moved funds on an existing account without locking it
+    fn balance_changed(&mut self, change: &AccountChange) -> Result<()> {
+        self.account_changed(change)
+    }
+
+    // A chargeback locked the account
+    fn account_locked(&mut self, change: &AccountChange) -> Result<()> {
+        self.account_changed(change)
+    }
+
+    // The engine, or a middleware, refused the message. Reported after any account the message created.
+    fn tx_rejected(&mut self, _msg: &TransactionMessage, _reason: Rejection, _message: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Sorts out which callbacks one handled message amounts to. change is None when no account changed.
+pub(crate) fn dispatch(observer: &mut dyn EngineObserver, change: Option<&AccountChange>, msg: &TransactionMessage,
+                       result: &Result<()>) -> Result<()> {
+    if let Some(change) = change {
+        match &change.old {
+            None => observer.account_created(change)?,
+            Some(old) if change.new.locked && !old.locked => observer.account_locked(change)?,
+            Some(_) => observer.balance_changed(change)?,
+        }
+    }
+    if let Err(Error::PolicyViolation { reason, message }) = result {
+        observer.tx_rejected(msg, *reason, message)?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub(crate) struct Observers(pub(crate) Vec<Box<dyn EngineObserver>>);
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.iter().map(|observer| observer.name())).finish()
+    }
+}
@@ -0,0 +1,257 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+
+use crate::config::TracingConfig;
+use crate::http::{self, Url};
+use crate::{Result, TransactionMessage};
+
+// Longest a finished span waits in the exporter before it is sent
+const EXPORT_INTERVAL: Duration = Duration::from_secs(1);
+// OTLP SpanKind
+const KIND_INTERNAL: u8 = 1;
+const KIND_SERVER: u8 = 2;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static SAMPLE_EVERY: AtomicU32 = AtomicU32::new(1);
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+static EXPORTER: Mutex<Option<Exporter>> = Mutex::new(None);
+
+thread_local! {
+    // Trace and span ids of the spans open on this thread, innermost last
+    static CURRENT: RefCell<Vec<(u128, u64)>> = const { RefCell::new(Vec::new()) };
+}
+
+struct Exporter {
+    sender: Sender<SpanData>,
+    worker: JoinHandle<()>,
+}
+
+// An operation timed from creation to drop. New spans are children of the innermost span still open on the same
+// thread, so spans must be dropped in the reverse of the order they were made, as scoped guards are. Without
+// tracing configured every span is a no-op.
+pub struct Span {
+    data: Option<SpanData>,
+}
+
+struct SpanData {
+    trace_id: u128,
+    span_id: u64,
+    parent_id: Option<u64>,
+    name: &'static str,
+    kind: u8,
+    start_unix_nanos: u128,
+    start: Instant,
+    end_unix_nanos: u128,
+    attributes: Vec<(&'static str, Value)>,
+    error: Option<String>,
+}
+
+// Starts exporting spans to the OTLP/HTTP endpoint as JSON
+pub fn init(config: &TracingConfig) -> Result<()> {
+    let url = Url::parse(&config.endpoint)?.join("v1/traces");
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64) ^ u64::from(process::id()) << 32;
+    NEXT_ID.store(seed, Ordering::Relaxed);
+    SAMPLE_EVERY.store(config.transaction_sample_every.max(1), Ordering::Relaxed);
+
+    let (sender, receiver) = mpsc::channel();
+    let resource = json!({ "attributes": [attribute("service.name", &Value::from(config.service_name.as_str()))] });
+    let batch_size = config.batch_size.max(1);
+    let worker = thread::spawn(move || export(&url, &resource, batch_size, &receiver));
+    *EXPORTER.lock().expect("Exporter lock poisoned.") = Some(Exporter { sender, worker });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Sends the spans that have ended and stops the exporter. Spans ended after this are dropped.
+pub fn shutdown() {
+    ENABLED.store(false, Ordering::Relaxed);
+    if let Some(exporter) = EXPORTER.lock().expect("Exporter lock poisoned.").take() {
+        drop(exporter.sender);
+        let _ = exporter.worker.join();
+    }
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn span(name: &'static str) -> Span {
+    start(name, KIND_INTERNAL)
+}
+
+// A span for a request received from a client, such as POST /transactions
+pub fn server_span(name: &'static str) -> Span {
+    start(name, KIND_SERVER)
+}
+
+// For per-transaction work: only one in every transaction_sample_every keys gets a recording span, keeping the
+// trace volume for large files manageable. Use the message's tx_time as the key so the spans for one message are
+// sampled together.
+pub fn sampled_span(name: &'static str, key: u32) -> Span {
+    if !enabled() || !key.is_multiple_of(SAMPLE_EVERY.load(Ordering::Relaxed)) {
+        return Span { data: None };
+    }
+    start(name, KIND_INTERNAL)
+}
+
+// The sampled span for handling one message, tagged with its type, client and tx
+pub fn transaction_span(msg: &TransactionMessage) -> Span {
+    let mut span = sampled_span("transaction", msg.tx_time);
+    if span.is_recording() {
+        span.set("tx.type", msg.tx_type.to_string());
+        span.set("tx.client", msg.client);
+        span.set("tx.id", msg.tx);
+    }
+    span
+}
+
+fn start(name: &'static str, kind: u8) -> Span {
+    if !enabled() {
+        return Span { data: None };
+    }
+    let parent = CURRENT.with(|current| current.borrow().last().copied());
+    let trace_id = parent.map_or_else(|| u128::from(next_id()) << 64 | u128::from(next_id()), |(trace_id, _)| trace_id);
+    let span_id = next_id();
+    CURRENT.with(|current| current.borrow_mut().push((trace_id, span_id)));
+    Span {
+        data: Some(SpanData {
+            trace_id,
+            span_id,
+            parent_id: parent.map(|(_, span_id)| span_id),
+            name,
+            kind,
+            start_unix_nanos: unix_nanos(),
+            start: Instant::now(),
+            end_unix_nanos: 0,
+            attributes: Vec::new(),
+            error: None,
+        }),
+    }
+}
+
+impl Span {
+    pub fn set<V: Into<Value>>(&mut self, key: &'static str, value: V) {
+        if let Some(data) = self.data.as_mut() {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    // Marks the span as failed
+    pub fn fail(&mut self, error: &dyn fmt::Display) {
+        if let Some(data) = self.data.as_mut() {
+            data.error = Some(error.to_string());
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.data.is_some()
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let mut data = match self.data.take() {
+            Some(data) => data,
+            None => return,
+        };
+        CURRENT.with(|current| {
+            let mut current = current.borrow_mut();
+            if current.last().is_some_and(|&(_, span_id)| span_id == data.span_id) {
+                current.pop();
+            }
+        });
+        data.end_unix_nanos = data.start_unix_nanos + data.start.elapsed().as_nanos();
+        if let Some(exporter) = EXPORTER.lock().expect("Exporter lock poisoned.").as_ref() {
+            let _ = exporter.sender.send(data);
+        }
+    }
+}
+
+// Batches spans until batch_size are waiting or EXPORT_INTERVAL passes. A batch the collector refuses is
+// reported and dropped rather than retried, so a collector outage never backs up ingestion.
+fn export(url: &Url, resource: &Value, batch_size: usize, receiver: &Receiver<SpanData>) {
+    let mut batch = Vec::new();
+    let mut reported = false;
+    loop {
+        let done = match receiver.recv_timeout(EXPORT_INTERVAL) {
+            Ok(span) => { batch.push(span); false }
+            Err(RecvTimeoutError::Timeout) => false,
+            Err(RecvTimeoutError::Disconnected) => true,
+        };
+        batch.extend(receiver.try_iter().take(batch_size.saturating_sub(batch.len())));
+        if !batch.is_empty() && (done || batch.len() >= batch_size || oldest_waited(&batch)) {
+            let body = json!({ "resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{ "scope": { "name": "tx_acct" }, "spans": batch.iter().map(encode).collect::<Vec<Value>>() }],
+            }] });
+            let result = http::send("POST", url, &[("Content-Type", "application/json")], body.to_string().as_bytes());
+            match result {
+                Ok(response) if (200..300).contains(&response.status) => reported = false,
+                // Reported once per outage so a missing collector does not flood the log
+                Ok(_) | Err(_) if reported => {}
+                Ok(response) => { diag!("Trace export to {} failed with HTTP status {}.", url.addr, response.status); reported = true }
+                Err(e) => { diag!("Trace export to {} failed. {}", url.addr, e); reported = true }
+            }
+            batch.clear();
+        }
+        if done {
+            return;
+        }
+    }
+}
+
+// The oldest span in the batch has waited a full interval
+fn oldest_waited(batch: &[SpanData]) -> bool {
+    batch.first().is_some_and(|span| unix_nanos().saturating_sub(span.end_unix_nanos) >= EXPORT_INTERVAL.as_nanos())
+}
+
+fn encode(span: &SpanData) -> Value {
+    let mut encoded = json!({
+        "traceId": format!("{:032x}", span.trace_id),
+        "spanId": format!("{:016x}", span.span_id),
+        "name": span.name,
+        "kind": span.kind,
+        "startTimeUnixNano": span.start_unix_nanos.to_string(),
+        "endTimeUnixNano": span.end_unix_nanos.to_string(),
+        "attributes": span.attributes.iter().map(|(key, value)| attribute(key, value)).collect::<Vec<Value>>(),
+    });
+    if let Some(parent_id) = span.parent_id {
+        encoded["parentSpanId"] = json!(format!("{:016x}", parent_id));
+    }
+    if let Some(error) = &span.error {
+        // STATUS_CODE_ERROR
+        encoded["status"] = json!({ "code": 2, "message": error });
+    }
+    encoded
+}
+
+fn attribute(key: &str, value: &Value) -> Value {
+    let value = match value {
+        Value::Bool(value) => json!({ "boolValue": value }),
+        Value::Number(number) if number.is_i64() || number.is_u64() => json!({ "intValue": number.to_string() }),
+        Value::Number(number) => json!({ "doubleValue": number }),
+        Value::String(value) => json!({ "stringValue": value }),
+        other => json!({ "stringValue": other.to_string() }),
+    };
+    json!({ "key": key, "value": value })
+}
+
+// splitmix64 over a counter seeded from the clock and pid, so ids differ across processes without a rand crate
+fn next_id() -> u64 {
+    let mut z = NEXT_ID.fetch_add(0x9e37_79b9_7f4a_7c15, Ordering::Relaxed).wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)).max(1)
+}
+
+fn unix_nanos() -> u128 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos())
+}
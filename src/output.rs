@@ -0,0 +1,234 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use crate::filter::ClientList;
+use crate::{format_amount, Account, Amount, Error, Result};
+
+const MANIFEST_FILE: &str = "manifest.csv";
+
+// Where the final account balances are written. Accounts arrive one at a time, finish is called once after the last.
+pub trait AccountSink {
+    fn write(&mut self, account: &Account) -> Result<()>;
+    fn finish(&mut self) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    // One JSON object per line
+    Json,
+}
+
+impl OutputFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            OutputFormat::Csv => "csv",
+            OutputFormat::Json => "jsonl",
+        }
+    }
+
+    // A sink for this format writing to out
+    pub fn sink<'a, W: Write + 'a>(&self, out: W) -> Box<dyn AccountSink + 'a> {
+        match self {
+            OutputFormat::Csv => Box::new(CsvSink::new(out)),
+            OutputFormat::Json => Box::new(JsonSink::new(out)),
+        }
+    }
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<OutputFormat> {
+        match s {
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(Error::InvalidArgument(format!("Unknown output format, {}. Expected csv or json.", s))),
+        }
+    }
+}
+
+// The client,available,held,total,locked CSV the tool has always written. The header is written with the first
+// account, or on finish when there are none.
+pub struct CsvSink<W: Write> {
+    out: W,
+    started: bool,
+}
+
+impl<W: Write> CsvSink<W> {
+    pub fn new(out: W) -> CsvSink<W> {
+        CsvSink { out, started: false }
+    }
+
+    fn start(&mut self) -> Result<()> {
+        if !self.started {
+            writeln!(self.out, "client,available,held,total,locked")?;
+            self.started = true;
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> AccountSink for CsvSink<W> {
+    fn write(&mut self, account: &Account) -> Result<()> {
+        self.start()?;
+        writeln!(self.out, "{},{},{},{},{}",
+                 account.client, format_amount(account.available),
+                 format_amount(account.held),
+                 format_amount(account.total),
+                 account.locked)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.start()?;
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// JSON Lines with the same fields as the CSV
+#[derive(Serialize)]
+struct AccountRecord {
+    client: u16,
+    available: String,
+    held: String,
+    total: String,
+    locked: bool,
+}
+
+pub struct JsonSink<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonSink<W> {
+    pub fn new(out: W) -> JsonSink<W> {
+        JsonSink { out }
+    }
+}
+
+impl<W: Write> AccountSink for JsonSink<W> {
+    fn write(&mut self, account: &Account) -> Result<()> {
+        serde_json::to_writer(&mut self.out, &AccountRecord {
+            client: account.client,
+            available: format_amount(account.available),
+            held: format_amount(account.held),
+            total: format_amount(account.total),
+            locked: account.locked,
+        })?;
+        self.out.write_all(b"\n")?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+// Writes every account to sink, then finishes it
+// Which accounts are written, for reports meant for one audience. Every condition given must hold.
+#[derive(Debug, Clone, Default)]
+pub struct AccountFilter {
+    pub only_locked: bool,
+    // Currency subunits
+    pub min_total: Option<Amount>,
+    pub clients: Option<ClientList>,
+}
+
+impl AccountFilter {
+    pub fn matches(&self, account: &Account) -> bool {
+        (!self.only_locked || account.locked)
+            && self.min_total.is_none_or(|min_total| account.total >= min_total)
+            && self.clients.as_ref().is_none_or(|clients| clients.contains(account.client))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.only_locked && self.min_total.is_none() && self.clients.is_none()
+    }
+}
+
+pub fn write_all<'a, I: IntoIterator<Item = &'a Account>>(sink: &mut dyn AccountSink, accounts: I) -> Result<()> {
+    for account in accounts {
+        sink.write(account)?;
+    }
+    sink.finish()
+}
+
+pub fn output_accounts(accounts: &[&Account], format: OutputFormat) {
+    let stdout = io::stdout();
+    if let Err(e) = write_all(format.sink(stdout.lock()).as_mut(), accounts.iter().copied()) {
+        diag!("Failed to write accounts. {}", e);
+    }
+}
+
+pub fn write_accounts<'a, W: Write, I: Iterator<Item = &'a Account>>(out: &mut W, accounts: I) -> Result<()> {
+    write_all(&mut CsvSink::new(out), accounts)
+}
+
+// FNV-1a over the client id, so partition assignment is stable across runs, builds and platforms
+pub fn partition_for(client: u16, partitions: u32) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in client.to_be_bytes().iter() {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash % partitions
+}
+
+// Writes accounts-NNNN.csv (or .jsonl) for each partition plus a manifest listing every file and its account count.
+// Accounts are held until finish, so each file can be sorted by client.
+pub struct PartitionedSink {
+    dir: PathBuf,
+    format: OutputFormat,
+    buckets: Vec<Vec<Account>>,
+}
+
+impl PartitionedSink {
+    pub fn new(partitions: u32, dir: &str, format: OutputFormat) -> PartitionedSink {
+        PartitionedSink {
+            dir: PathBuf::from(dir),
+            format,
+            buckets: (0..partitions).map(|_| Vec::new()).collect(),
+        }
+    }
+
+    // The manifest, then each partition's file
+    pub fn files(&self) -> Vec<PathBuf> {
+        let partitions = (0..self.buckets.len()).map(|partition| self.dir.join(file_name(partition, self.format)));
+        std::iter::once(self.dir.join(MANIFEST_FILE)).chain(partitions).collect()
+    }
+}
+
+fn file_name(partition: usize, format: OutputFormat) -> String {
+    format!("accounts-{:04}.{}", partition, format.extension())
+}
+
+impl AccountSink for PartitionedSink {
+    fn write(&mut self, account: &Account) -> Result<()> {
+        let partitions = self.buckets.len() as u32;
+        self.buckets[partition_for(account.client, partitions) as usize].push(account.clone());
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let mut manifest = csv::Writer::from_path(self.dir.join(MANIFEST_FILE))?;
+        manifest.write_record(["partition", "file", "accounts"])?;
+        for (partition, accounts) in self.buckets.iter_mut().enumerate() {
+            accounts.sort_by_key(|acct| acct.client);
+            let file_name = file_name(partition, self.format);
+            let out = io::BufWriter::new(fs::File::create(self.dir.join(&file_name))?);
+            write_all(self.format.sink(out).as_mut(), accounts.iter())?;
+            manifest.write_record([partition.to_string(), file_name, accounts.len().to_string()])?;
+        }
+        manifest.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,401 @@
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use postgres::{Client, NoTls, Transaction};
+
+use crate::admin::AdminAction;
+use crate::fee_schedule::FeeUsageRecord;
+use crate::idempotency::IdempotencyRecord;
+use crate::lifecycle;
+use crate::rounding::RemainderRecord;
+use crate::state::Store;
+use crate::withdrawal_limits::WithdrawnRecord;
+use crate::{Account, AccountTransactions, Error, Result, TransactionMessage, TransactionType, Tx};
+
+// How long /readyz waits on the server before reporting the store as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Applied in order, each exactly once. Never edit a released migration, add a new one instead.
+const MIGRATIONS: &[&str] = &[
+    "CREATE TABLE accounts (
+        client INTEGER PRIMARY KEY,
+        available BIGINT NOT NULL,
+        held BIGINT NOT NULL,
+        total BIGINT NOT NULL,
+        locked BOOLEAN NOT NULL
+    );
+    CREATE TABLE transactions (
+        tx BIGINT PRIMARY KEY,
+        tx_time BIGINT NOT NULL,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        amount BIGINT NOT NULL
+    );
+    CREATE TABLE disputes (
+        tx BIGINT PRIMARY KEY REFERENCES transactions (tx) ON DELETE CASCADE,
+        client INTEGER NOT NULL,
+        opened_time BIGINT NOT NULL
+    );
+    CREATE TABLE messages (
+        tx_time BIGINT PRIMARY KEY,
+        type TEXT NOT NULL,
+        client INTEGER NOT NULL,
+        tx BIGINT NOT NULL,
+        amount BIGINT NOT NULL
+    );
+    CREATE TABLE engine (
+        id BOOLEAN PRIMARY KEY DEFAULT TRUE CHECK (id),
+        last_tx_time BIGINT NOT NULL
+    );
+    INSERT INTO engine (last_tx_time) VALUES (0);",
+    "CREATE TABLE idempotency_keys (
+        key TEXT PRIMARY KEY,
+        tx_time BIGINT NOT NULL
+    );",
+    "ALTER TABLE transactions ADD COLUMN pending BOOLEAN NOT NULL DEFAULT FALSE;",
+    "ALTER TABLE accounts ADD COLUMN escrow BIGINT NOT NULL DEFAULT 0;",
+    "ALTER TABLE messages ADD COLUMN to_client INTEGER;",
+    "ALTER TABLE transactions ADD COLUMN counterparty TEXT;
+     ALTER TABLE messages ADD COLUMN counterparty TEXT;",
+    "ALTER TABLE transactions ADD COLUMN memo TEXT;
+     ALTER TABLE messages ADD COLUMN memo TEXT;",
+    "ALTER TABLE transactions ADD COLUMN category TEXT;
+     ALTER TABLE messages ADD COLUMN category TEXT;",
+    "ALTER TABLE transactions ADD COLUMN chargeback_time BIGINT NOT NULL DEFAULT 0;",
+    "ALTER TABLE transactions ADD COLUMN state TEXT NOT NULL DEFAULT 'posted';
+     UPDATE transactions SET state = 'disputed' WHERE tx IN (SELECT tx FROM disputes);
+     UPDATE transactions SET state = 'charged_back' WHERE chargeback_time <> 0;",
+    "ALTER TABLE transactions ADD COLUMN history TEXT NOT NULL DEFAULT '';",
+    "ALTER TABLE transactions ADD COLUMN provisional BOOLEAN NOT NULL DEFAULT FALSE;",
+    "ALTER TABLE transactions ADD COLUMN fee BIGINT NOT NULL DEFAULT 0;
+     ALTER TABLE transactions ADD COLUMN fee_loss BIGINT NOT NULL DEFAULT 0;",
+    "ALTER TABLE transactions ADD COLUMN recovery BIGINT NOT NULL DEFAULT 0;
+     ALTER TABLE transactions ADD COLUMN recovered BIGINT NOT NULL DEFAULT 0;",
+    "ALTER TABLE transactions ADD COLUMN service_fee BIGINT NOT NULL DEFAULT 0;",
+    "CREATE TABLE withdrawn (
+        client INTEGER PRIMARY KEY,
+        amount BIGINT NOT NULL
+    );",
+    "CREATE TABLE fee_usage (
+        client INTEGER NOT NULL,
+        type TEXT NOT NULL,
+        period BIGINT NOT NULL,
+        count BIGINT NOT NULL,
+        fees BIGINT NOT NULL,
+        PRIMARY KEY (client, type, period)
+    );",
+    "CREATE TABLE rounding (
+        kind TEXT PRIMARY KEY,
+        inexact BIGINT NOT NULL,
+        subunits BIGINT NOT NULL,
+        carry_ppm BIGINT NOT NULL
+    );",
+];
+
+// PostgreSQL store. Each handled message is written in its own database transaction, so the tables never hold
+// half of a message's effects. The schema is created and upgraded by the crate on connect.
+pub struct PgStore {
+    client: Client,
+    url: String,
+}
+
+impl PgStore {
+    pub fn connect(url: &str) -> Result<PgStore> {
+        let client = Client::connect(url, NoTls).map_err(|e| pg_error("connect", e))?;
+        let mut store = PgStore { client, url: redact(url) };
+        store.migrate()?;
+        Ok(store)
+    }
+
+    fn migrate(&mut self) -> Result<()> {
+        let mut db = self.client.transaction().map_err(|e| pg_error("migrate", e))?;
+        db.batch_execute("CREATE TABLE IF NOT EXISTS schema_migrations (
+                              version INTEGER PRIMARY KEY,
+                              applied_at TIMESTAMPTZ NOT NULL DEFAULT now()
+                          );
+                          LOCK TABLE schema_migrations IN EXCLUSIVE MODE;")
+            .map_err(|e| pg_error("migrate", e))?;
+        let row = db.query_one("SELECT COALESCE(MAX(version), 0) FROM schema_migrations", &[])
+            .map_err(|e| pg_error("migrate", e))?;
+        let applied: i32 = row.get(0);
+        for (version, migration) in MIGRATIONS.iter().enumerate().skip(applied as usize) {
+            db.batch_execute(migration).map_err(|e| pg_error("migrate", e))?;
+            db.execute("INSERT INTO schema_migrations (version) VALUES ($1)", &[&(version as i32 + 1)])
+                .map_err(|e| pg_error("migrate", e))?;
+        }
+        db.commit().map_err(|e| pg_error("migrate", e))
+    }
+}
+
+impl Store for PgStore {
+    fn load(&mut self, account_txs: &mut AccountTransactions) -> Result<()> {
+        let db = &mut self.client;
+        for row in db.query("SELECT client, available, held, total, locked, escrow FROM accounts", &[]).map_err(|e| pg_error("load", e))? {
+            let client = from_db(row.get::<_, i32>(0), "client")?;
+            account_txs.account_client.insert(client, Account {
+                client,
+                available: from_db_amount(row.get(1)),
+                held: from_db_amount(row.get(2)),
+                escrow: from_db_amount(row.get(5)),
+                total: from_db_amount(row.get(3)),
+                locked: row.get(4),
+            });
+        }
+
+        let query = "SELECT t.tx, t.tx_time, t.type, t.client, t.amount, t.state, t.pending, d.opened_time,
+                            t.counterparty, t.memo, t.category, t.chargeback_time, t.history, t.provisional,
+                            t.fee, t.fee_loss, t.recovery, t.recovered, t.service_fee
+                     FROM transactions t LEFT JOIN disputes d ON d.tx = t.tx";
+        for row in db.query(query, &[]).map_err(|e| pg_error("load", e))? {
+            let tx = from_db(row.get::<_, i64>(0), "tx")?;
+            account_txs.txs_txid.insert(tx, Tx {
+                tx,
+                tx_time: from_db(row.get::<_, i64>(1), "tx_time")?,
+                tx_type: row.get::<_, String>(2).parse()?,
+                client: from_db(row.get::<_, i32>(3), "client")?,
+                amount: from_db_amount(row.get(4)),
+                state: row.get::<_, String>(5).parse()?,
+                dispute_time: row.get::<_, Option<i64>>(7).map_or(Ok(0), |time| from_db(time, "opened_time"))?,
+                pending: row.get(6),
+                provisional: row.get(13),
+                fee: from_db_amount(row.get(14)),
+                fee_loss: from_db_amount(row.get(15)),
+                recovery: from_db_amount(row.get(16)),
+                recovered: from_db_amount(row.get(17)),
+                service_fee: from_db_amount(row.get(18)),
+                counterparty: row.get(8),
+                memo: row.get(9),
+                category: row.get(10),
+                chargeback_time: from_db(row.get::<_, i64>(11), "chargeback_time")?,
+                history: lifecycle::parse_history(&row.get::<_, String>(12))?,
+            });
+        }
+
+        for row in db.query("SELECT tx_time, type, client, tx, amount, to_client, counterparty, memo, category FROM messages", &[]).map_err(|e| pg_error("load", e))? {
+            let tx_time = from_db(row.get::<_, i64>(0), "tx_time")?;
+            account_txs.tx_msgs_time.insert(tx_time, TransactionMessage {
+                tx_time,
+                tx_type: row.get::<_, String>(1).parse()?,
+                client: from_db(row.get::<_, i32>(2), "client")?,
+                tx: from_db(row.get::<_, i64>(3), "tx")?,
+                amount: from_db_amount(row.get(4)),
+                to_client: row.get::<_, Option<i32>>(5).map(|client| from_db(client, "to_client")).transpose()?,
+                counterparty: row.get(6),
+                memo: row.get(7),
+                category: row.get(8),
+            });
+        }
+
+        let mut keys = Vec::new();
+        for row in db.query("SELECT key, tx_time FROM idempotency_keys", &[]).map_err(|e| pg_error("load", e))? {
+            keys.push(IdempotencyRecord { key: row.get(0), tx_time: from_db(row.get::<_, i64>(1), "tx_time")? });
+        }
+        account_txs.idempotency_keys.restore(keys);
+
+        let mut withdrawn = Vec::new();
+        for row in db.query("SELECT client, amount FROM withdrawn", &[]).map_err(|e| pg_error("load", e))? {
+            withdrawn.push(WithdrawnRecord { client: from_db(row.get::<_, i32>(0), "client")?,
+                                             amount: from_db_amount(row.get(1)) });
+        }
+        account_txs.withdrawal_limits.restore(withdrawn);
+
+        let mut fee_usage = Vec::new();
+        for row in db.query("SELECT client, type, period, count, fees FROM fee_usage", &[]).map_err(|e| pg_error("load", e))? {
+            fee_usage.push(FeeUsageRecord {
+                client: from_db(row.get::<_, i32>(0), "client")?,
+                tx_type: row.get::<_, String>(1).parse()?,
+                period: from_db(row.get::<_, i64>(2), "period")?,
+                count: from_db(row.get::<_, i64>(3), "count")?,
+                fees: from_db_amount(row.get(4)),
+            });
+        }
+        account_txs.fee_schedules.restore(fee_usage);
+
+        let mut rounding = Vec::new();
+        for row in db.query("SELECT kind, inexact, subunits, carry_ppm FROM rounding", &[]).map_err(|e| pg_error("load", e))? {
+            rounding.push(RemainderRecord { kind: row.get(0), inexact: from_db_amount(row.get(1)),
+                                            subunits: from_db_amount(row.get(2)), carry_ppm: from_db_amount(row.get(3)) });
+        }
+        account_txs.rounding.restore(rounding)?;
+
+        // Message times already used, even by messages since pruned, must not be handed out again
+        let row = db.query_one("SELECT last_tx_time FROM engine", &[]).map_err(|e| pg_error("load", e))?;
+        let last_tx_time: u32 = from_db(row.get::<_, i64>(0), "last_tx_time")?;
+        account_txs.compacted_through = account_txs.compacted_through.max(last_tx_time);
+        Ok(())
+    }
+
+    fn record(&mut self, account_txs: &AccountTransactions, msg: &TransactionMessage) -> Result<()> {
+        let mut db = self.client.transaction().map_err(|e| pg_error("record", e))?;
+        write_message(&mut db, account_txs, msg).map_err(|e| pg_error("record", e))?;
+        db.commit().map_err(|e| pg_error("record", e))
+    }
+
+    fn record_admin(&mut self, account_txs: &AccountTransactions, action: &AdminAction) -> Result<()> {
+        let mut db = self.client.transaction().map_err(|e| pg_error("record", e))?;
+        if let Some(acct) = action.client(account_txs).and_then(|client| account_txs.account_client.get(&client)) {
+            write_account(&mut db, acct).map_err(|e| pg_error("record", e))?;
+        }
+        if let AdminAction::CloseDispute { tx } = action {
+            db.execute("DELETE FROM disputes WHERE tx = $1", &[&i64::from(*tx)]).map_err(|e| pg_error("record", e))?;
+            if let Some(tx) = account_txs.txs_txid.get(tx) {
+                db.execute("UPDATE transactions SET state = $2, history = $3 WHERE tx = $1",
+                           &[&i64::from(tx.tx), &tx.state.to_string(), &lifecycle::history_text(&tx.history)])
+                    .map_err(|e| pg_error("record", e))?;
+            }
+        }
+        if let AdminAction::Approve { tx } | AdminAction::Deny { tx } = action {
+            db.execute("UPDATE transactions SET pending = FALSE WHERE tx = $1", &[&i64::from(*tx)])
+                .map_err(|e| pg_error("record", e))?;
+        }
+        db.commit().map_err(|e| pg_error("record", e))
+    }
+
+    // Every message was written as it was handled, so only rows dropped from memory since, by pruning or
+    // erasure, are left to remove
+    fn save(&mut self, account_txs: &AccountTransactions) -> Result<()> {
+        let clients: Vec<i32> = account_txs.account_client.keys().map(|client| i32::from(*client)).collect();
+        let txs: Vec<i64> = account_txs.txs_txid.keys().map(|tx| i64::from(*tx)).collect();
+        let times: Vec<i64> = account_txs.tx_msgs_time.keys().map(|time| i64::from(*time)).collect();
+        let mut db = self.client.transaction().map_err(|e| pg_error("save", e))?;
+        db.execute("DELETE FROM accounts WHERE client <> ALL($1)", &[&clients]).map_err(|e| pg_error("save", e))?;
+        db.execute("DELETE FROM transactions WHERE tx <> ALL($1)", &[&txs]).map_err(|e| pg_error("save", e))?;
+        db.execute("DELETE FROM messages WHERE tx_time <> ALL($1)", &[&times]).map_err(|e| pg_error("save", e))?;
+        db.commit().map_err(|e| pg_error("save", e))
+    }
+
+    fn describe(&self) -> String {
+        self.url.clone()
+    }
+
+    fn check(&mut self) -> Result<()> {
+        self.client.is_valid(CHECK_TIMEOUT).map_err(|e| pg_error("check", e))
+    }
+}
+
+fn write_message(db: &mut Transaction, account_txs: &AccountTransactions, msg: &TransactionMessage)
+                 -> std::result::Result<(), postgres::Error> {
+    db.execute("INSERT INTO messages (tx_time, type, client, tx, amount, to_client, counterparty, memo, category)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9) ON CONFLICT (tx_time) DO NOTHING",
+               &[&i64::from(msg.tx_time), &msg.tx_type.to_string(), &i32::from(msg.client), &i64::from(msg.tx),
+                 &to_db(msg.amount), &msg.to_client.map(i32::from), &msg.counterparty, &msg.memo,
+                 &msg.category])?;
+    db.execute("UPDATE engine SET last_tx_time = GREATEST(last_tx_time, $1)", &[&i64::from(msg.tx_time)])?;
+    for key in account_txs.idempotency_keys.added_with(msg.tx_time) {
+        db.execute("INSERT INTO idempotency_keys (key, tx_time) VALUES ($1, $2) ON CONFLICT (key) DO NOTHING",
+                   &[&key, &i64::from(msg.tx_time)])?;
+    }
+
+    let withdrawn = account_txs.withdrawal_limits.withdrawn(msg.client);
+    if msg.tx_type == TransactionType::WITHDRAWAL && withdrawn > 0 {
+        db.execute("INSERT INTO withdrawn (client, amount) VALUES ($1, $2)
+                    ON CONFLICT (client) DO UPDATE SET amount = EXCLUDED.amount",
+                   &[&i32::from(msg.client), &to_db(withdrawn)])?;
+    }
+    if let Some(usage) = account_txs.fee_schedules.record_of(msg) {
+        db.execute("INSERT INTO fee_usage (client, type, period, count, fees) VALUES ($1, $2, $3, $4, $5)
+                    ON CONFLICT (client, type, period) DO UPDATE SET count = EXCLUDED.count, fees = EXCLUDED.fees",
+                   &[&i32::from(usage.client), &usage.tx_type.to_string(), &i64::from(usage.period),
+                     &i64::from(usage.count), &to_db(usage.fees)])?;
+    }
+
+    for remainder in account_txs.rounding.records() {
+        db.execute("INSERT INTO rounding (kind, inexact, subunits, carry_ppm) VALUES ($1, $2, $3, $4)
+                    ON CONFLICT (kind) DO UPDATE
+                    SET inexact = EXCLUDED.inexact, subunits = EXCLUDED.subunits, carry_ppm = EXCLUDED.carry_ppm",
+                   &[&remainder.kind, &to_db(remainder.inexact), &to_db(remainder.subunits),
+                     &to_db(remainder.carry_ppm)])?;
+    }
+
+    for client in account_txs.touched_accounts() {
+        if let Some(acct) = account_txs.account_client.get(&client) {
+            write_account(db, acct)?;
+        }
+    }
+
+    if let Some(tx) = account_txs.txs_txid.get(&msg.tx).filter(|tx| tx.client == msg.client) {
+        if matches!(msg.tx_type, TransactionType::DEPOSIT | TransactionType::WITHDRAWAL | TransactionType::ESCROW
+                                 | TransactionType::TRANSFER) {
+            db.execute("INSERT INTO transactions (tx, tx_time, type, client, amount, pending, counterparty, memo, category,
+                                                  service_fee)
+                        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) ON CONFLICT (tx) DO NOTHING",
+                       &[&i64::from(tx.tx), &i64::from(tx.tx_time), &tx.tx_type.to_string(), &i32::from(tx.client),
+                         &to_db(tx.amount), &tx.pending, &tx.counterparty, &tx.memo,
+                         &tx.category, &to_db(tx.service_fee)])?;
+        }
+        if matches!(msg.tx_type, TransactionType::APPROVE | TransactionType::DENY | TransactionType::RELEASE
+                                 | TransactionType::REFUND) {
+            db.execute("UPDATE transactions SET pending = $2 WHERE tx = $1", &[&i64::from(tx.tx), &tx.pending])?;
+        }
+        if msg.tx_type == TransactionType::CHARGEBACK {
+            db.execute("UPDATE transactions SET chargeback_time = $2, fee = $3, fee_loss = $4, recovery = $5
+                        WHERE tx = $1",
+                       &[&i64::from(tx.tx), &i64::from(tx.chargeback_time), &to_db(tx.fee), &to_db(tx.fee_loss),
+                         &to_db(tx.recovery)])?;
+        }
+        if matches!(msg.tx_type, TransactionType::DISPUTE | TransactionType::RESOLVE | TransactionType::CHARGEBACK) {
+            db.execute("UPDATE transactions SET state = $2, history = $3, provisional = $4 WHERE tx = $1",
+                       &[&i64::from(tx.tx), &tx.state.to_string(), &lifecycle::history_text(&tx.history),
+                         &tx.provisional])?;
+        }
+        if tx.disputed() {
+            db.execute("INSERT INTO disputes (tx, client, opened_time) VALUES ($1, $2, $3) ON CONFLICT (tx) DO NOTHING",
+                       &[&i64::from(tx.tx), &i32::from(tx.client), &i64::from(msg.tx_time)])?;
+        } else {
+            db.execute("DELETE FROM disputes WHERE tx = $1", &[&i64::from(tx.tx)])?;
+        }
+    }
+    for swept in account_txs.swept_by(msg) {
+        db.execute("UPDATE transactions SET recovery = $2, recovered = $3, history = $4 WHERE tx = $1",
+                   &[&i64::from(swept.tx), &to_db(swept.recovery), &to_db(swept.recovered),
+                     &lifecycle::history_text(&swept.history)])?;
+    }
+    for expired in account_txs.expired_by(msg).filter_map(|expired| account_txs.txs_txid.get(&expired.tx)) {
+        db.execute("DELETE FROM disputes WHERE tx = $1", &[&i64::from(expired.tx)])?;
+        db.execute("UPDATE transactions SET state = $2, history = $3 WHERE tx = $1",
+                   &[&i64::from(expired.tx), &expired.state.to_string(), &lifecycle::history_text(&expired.history)])?;
+    }
+    Ok(())
+}
+
+fn write_account(db: &mut Transaction, acct: &Account) -> std::result::Result<(), postgres::Error> {
+    db.execute("INSERT INTO accounts (client, available, held, total, locked, escrow) VALUES ($1, $2, $3, $4, $5, $6)
+                ON CONFLICT (client) DO UPDATE
+                SET available = EXCLUDED.available, held = EXCLUDED.held, total = EXCLUDED.total,
+                    locked = EXCLUDED.locked, escrow = EXCLUDED.escrow",
+               &[&i32::from(acct.client), &to_db(acct.available), &to_db(acct.held), &to_db(acct.total), &acct.locked,
+                 &to_db(acct.escrow)])?;
+    Ok(())
+}
+
+// Amounts are u64 in the engine and BIGINT in the database. Amounts beyond i64::MAX subunits are stored
+// bit for bit and read back unchanged.
+fn to_db(amount: u64) -> i64 {
+    amount as i64
+}
+
+fn from_db_amount(amount: i64) -> u64 {
+    amount as u64
+}
+
+fn from_db<T: TryFrom<i64>, V: Into<i64>>(value: V, column: &str) -> Result<T> {
+    let value = value.into();
+    T::try_from(value)
+        .map_err(|_| Error::StorageError(format!("PostgreSQL column {} holds out of range value {}.", column, value)))
+}
+
+fn pg_error(action: &str, e: postgres::Error) -> Error {
+    Error::StorageError(format!("PostgreSQL {} failed. {}", action, e))
+}
+
+// Connection URL for messages, without any password
+fn redact(url: &str) -> String {
+    match (url.find("://"), url.rfind('@')) {
+        (Some(scheme), Some(at)) if at > scheme => {
+            let userinfo = &url[scheme + 3..at];
+            let user = userinfo.split(':').next().unwrap_or("");
+            format!("{}{}{}", &url[..scheme + 3], user, &url[at..])
+        }
+        _ => url.to_string(),
+    }
+}
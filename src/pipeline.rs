@@ -0,0 +1,77 @@
+use std::fmt;
+
+use crate::{Account, AccountTransactions, Result, TransactionMessage};
+
+// Where a middleware sits in the chain. Rows are parsed by the reader before the chain and applied by the engine at
+// its end, so each message passes through normalize → validate → policy → apply → observe, observers seeing the
+// result on the way back out. Middlewares in the same stage run in the order added.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    // Rewrites messages into the form the engine expects
+    Normalize,
+    // Drops or rejects messages that cannot be applied, such as duplicates
+    Validate,
+    // Business rules, where the configured rules file, plugins and script run
+    Policy,
+    // Watches what the engine did, e.g. for an audit trail
+    Observe,
+}
+
+// One step of handling a message. A middleware passes the message on with next.run, possibly rewritten, and gets
+// back the result of everything after it. Returning without calling next.run drops the message, an Err rejects it.
+// A rewritten message must keep its tx_time, which is where the engine keeps it.
+pub trait Middleware: Send {
+    fn name(&self) -> &str;
+
+    fn stage(&self) -> Stage {
+        Stage::Policy
+    }
+
+    fn handle(&mut self, msg: &TransactionMessage, next: &mut Next<'_>) -> Result<()>;
+}
+
+// The rest of the chain after a middleware, ending in the engine
+pub struct Next<'a> {
+    engine: &'a mut AccountTransactions,
+    rest: &'a mut [Box<dyn Middleware>],
+}
+
+impl Next<'_> {
+    pub(crate) fn start<'a>(engine: &'a mut AccountTransactions, middlewares: &'a mut [Box<dyn Middleware>]) -> Next<'a> {
+        Next { engine, rest: middlewares }
+    }
+
+    pub fn run(&mut self, msg: &TransactionMessage) -> Result<()> {
+        match self.rest.split_first_mut() {
+            Some((middleware, rest)) => middleware.handle(msg, &mut Next { engine: &mut *self.engine, rest }),
+            None => self.engine.apply(msg),
+        }
+    }
+
+    // The client's account as it stands, None for a client not seen yet
+    pub fn account(&self, client: u16) -> Option<&Account> {
+        self.engine.get_account(client)
+    }
+
+    // Attaches a note that goes out with the message's account change to the change sinks
+    pub fn annotate(&mut self, key: impl Into<String>, value: impl Into<String>) {
+        self.engine.annotations.insert(key.into(), value.into());
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Pipeline(pub(crate) Vec<Box<dyn Middleware>>);
+
+impl Pipeline {
+    // Keeps the chain ordered by stage, after any middleware already added to the same stage
+    pub(crate) fn add(&mut self, middleware: Box<dyn Middleware>) {
+        let at = self.0.partition_point(|added| added.stage() <= middleware.stage());
+        self.0.insert(at, middleware);
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.0.iter().map(|middleware| middleware.name())).finish()
+    }
+}
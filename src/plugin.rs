@@ -0,0 +1,93 @@
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use serde::Deserialize;
+use serde_json::json;
+use wasmi::{Engine, Linker, Memory, Module, Store, TypedFunc};
+
+use crate::change::AccountState;
+use crate::config::PluginConfig;
+use crate::hooks::{Validator, Verdict};
+use crate::{format_amount, Account, Error, Result, TransactionMessage};
+
+// A validator compiled to WebAssembly. The module exports its memory and two functions:
+//   alloc(len: i32) -> i32                a buffer of len bytes for the host to write the message into
+//   validate(ptr: i32, len: i32) -> i64   checks the message written there
+// The message is a JSON object with type, client, tx, amount and account, amounts in currency units and account
+// null for a new client. validate returns 0 to accept, or a JSON verdict's address in the high 32 bits and its
+// length in the low 32 bits: {"veto": "<reason>"} or {"annotations": {"<key>": "<value>", ...}}. The instance
+// lives as long as the engine, so a plugin may keep state between messages.
+pub struct WasmPlugin {
+    name: String,
+    fuel: u64,
+    store: Store<()>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    validate: TypedFunc<(i32, i32), i64>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PluginVerdict {
+    veto: Option<String>,
+    #[serde(default)]
+    annotations: BTreeMap<String, String>,
+}
+
+impl WasmPlugin {
+    pub fn load(config: &PluginConfig) -> Result<WasmPlugin> {
+        let failed = |e: &dyn fmt::Display| Error::PluginError(format!("Failed to load plugin, {}. {}", config.path, e));
+        let wasm = fs::read(&config.path).map_err(|e| failed(&e))?;
+        let mut engine_config = wasmi::Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let module = Module::new(&engine, &wasm).map_err(|e| failed(&e))?;
+        let mut store = Store::new(&engine, ());
+        store.set_fuel(config.fuel).map_err(|e| failed(&e))?;
+        let instance = Linker::<()>::new(&engine).instantiate_and_start(&mut store, &module).map_err(|e| failed(&e))?;
+        let memory = instance.get_memory(&store, "memory").ok_or_else(|| failed(&"It exports no memory."))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&store, "alloc").map_err(|e| failed(&e))?;
+        let validate = instance.get_typed_func::<(i32, i32), i64>(&store, "validate").map_err(|e| failed(&e))?;
+        let name = Path::new(&config.path).file_stem().map_or_else(|| config.path.clone(), |stem| stem.to_string_lossy().into_owned());
+        Ok(WasmPlugin { name, fuel: config.fuel, store, memory, alloc, validate })
+    }
+}
+
+impl Validator for WasmPlugin {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&mut self, msg: &TransactionMessage, account: Option<&Account>) -> Result<Verdict> {
+        let name = &self.name;
+        let failed = |e: &dyn fmt::Display| Error::PluginError(format!("Plugin, {}, failed on transaction, {}. {}", name, msg.tx, e));
+        let input = json!({
+            "type": msg.tx_type.to_string(),
+            "client": msg.client,
+            "tx": msg.tx,
+            "amount": format_amount(msg.amount),
+            "account": account.map(AccountState::from),
+        }).to_string();
+        let len = i32::try_from(input.len()).map_err(|e| failed(&e))?;
+
+        self.store.set_fuel(self.fuel).map_err(|e| failed(&e))?;
+        let ptr = self.alloc.call(&mut self.store, len).map_err(|e| failed(&e))?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, input.as_bytes()).map_err(|e| failed(&e))?;
+        let packed = self.validate.call(&mut self.store, (ptr, len)).map_err(|e| failed(&e))? as u64;
+        if packed == 0 {
+            return Ok(Verdict::Accept);
+        }
+
+        let mut output = vec![0; (packed & 0xffff_ffff) as usize];
+        self.memory.read(&self.store, (packed >> 32) as usize, &mut output).map_err(|e| failed(&e))?;
+        let verdict: PluginVerdict = serde_json::from_slice(&output).map_err(|e| failed(&e))?;
+        Ok(match verdict.veto {
+            Some(reason) => Verdict::Veto(reason),
+            None if verdict.annotations.is_empty() => Verdict::Accept,
+            None => Verdict::Annotate(verdict.annotations),
+        })
+    }
+}
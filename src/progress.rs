@@ -0,0 +1,81 @@
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+const BAR_WIDTH: usize = 30;
+// Nothing is drawn before this, so small files never show a bar
+const FIRST_DRAW: Duration = Duration::from_secs(1);
+const REDRAW: Duration = Duration::from_millis(200);
+
+// Single-line progress bar on STDERR, only drawn when STDERR is a terminal
+pub struct Progress {
+    enabled: bool,
+    total_bytes: u64,
+    start: Instant,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    pub fn new(total_bytes: u64, enabled: bool) -> Progress {
+        Progress {
+            enabled: enabled && io::stderr().is_terminal(),
+            total_bytes,
+            start: Instant::now(),
+            last_draw: None,
+        }
+    }
+
+    pub fn update(&mut self, rows: u32, bytes: u64) {
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        let due = match self.last_draw {
+            Some(last) => now.duration_since(last) >= REDRAW,
+            None => now.duration_since(self.start) >= FIRST_DRAW,
+        };
+        if due {
+            self.draw(rows, bytes, now);
+            self.last_draw = Some(now);
+        }
+    }
+
+    pub fn finish(&mut self, rows: u32, bytes: u64) {
+        if self.enabled && self.last_draw.is_some() {
+            self.draw(rows, bytes, Instant::now());
+            eprintln!();
+        }
+    }
+
+    fn draw(&self, rows: u32, bytes: u64, now: Instant) {
+        let elapsed = now.duration_since(self.start).as_secs_f64().max(0.001);
+        let fraction = if self.total_bytes > 0 {
+            (bytes as f64 / self.total_bytes as f64).min(1.0)
+        } else {
+            0.0
+        };
+        let filled = (fraction * BAR_WIDTH as f64) as usize;
+        let bytes_per_sec = bytes as f64 / elapsed;
+        let eta = if bytes_per_sec > 0.0 {
+            format_duration(self.total_bytes.saturating_sub(bytes) as f64 / bytes_per_sec)
+        } else {
+            String::from("--:--")
+        };
+
+        let stderr = io::stderr();
+        let mut err = stderr.lock();
+        let _ = write!(err, "\r[{}{}] {:>5.1}% {} rows, {:.0} rows/s, {:.1} MiB/s, ETA {}",
+                       "#".repeat(filled), " ".repeat(BAR_WIDTH - filled), fraction * 100.0,
+                       rows, rows as f64 / elapsed, bytes_per_sec / 1_048_576.0, eta);
+        let _ = err.flush();
+    }
+}
+
+fn format_duration(seconds: f64) -> String {
+    let seconds = seconds.round() as u64;
+    if seconds >= 3600 {
+        format!("{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    } else {
+        format!("{:02}:{:02}", seconds / 60, seconds % 60)
+    }
+}
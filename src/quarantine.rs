@@ -0,0 +1,84 @@
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+
+use crate::Result;
+
+// Copies unparseable rows byte for byte out of the input file. The quarantine file starts with the input's
+// header and marks each row with a `# line N` comment, which the reader skips, so it can be fixed and
+// submitted again as an ordinary input file.
+pub struct Quarantine {
+    out: BufWriter<File>,
+    // Walked forward alongside the CSV reader, counting lines itself since the reader's count skips comments
+    source: BufReader<File>,
+    offset: u64,
+    line: u64,
+    rows: u32,
+}
+
+impl Quarantine {
+    // header_end is the byte offset just past the input's header row
+    pub fn create(path: &str, input: &str, header_end: u64) -> Result<Quarantine> {
+        let mut quarantine = Quarantine {
+            out: BufWriter::new(File::create(path)?),
+            source: BufReader::new(File::open(input)?),
+            offset: 0,
+            line: 1,
+            rows: 0,
+        };
+        let header = quarantine.read_row(header_end)?;
+        quarantine.write_row(&header)?;
+        quarantine.line += header.iter().filter(|byte| **byte == b'\n').count() as u64;
+        Ok(quarantine)
+    }
+
+    // start..end is the byte range of the row in the input, as reported by the CSV reader
+    pub fn copy(&mut self, start: u64, end: u64) -> Result<()> {
+        self.skip_to(start)?;
+        let row = self.read_row(end)?;
+        writeln!(self.out, "# line {}", self.line)?;
+        self.write_row(&row)?;
+        self.line += row.iter().filter(|byte| **byte == b'\n').count() as u64;
+        self.rows += 1;
+        Ok(())
+    }
+
+    pub fn rows(&self) -> u32 {
+        self.rows
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush()?;
+        Ok(())
+    }
+
+    fn skip_to(&mut self, offset: u64) -> Result<()> {
+        let mut skipped = Vec::new();
+        (&mut self.source).take(offset.saturating_sub(self.offset)).read_to_end(&mut skipped)?;
+        self.offset = offset;
+        self.line += skipped.iter().filter(|byte| **byte == b'\n').count() as u64;
+        Ok(())
+    }
+
+    // Comment and blank lines ahead of the row are skipped over, they were never part of it
+    fn read_row(&mut self, end: u64) -> Result<Vec<u8>> {
+        let mut raw = Vec::new();
+        (&mut self.source).take(end.saturating_sub(self.offset)).read_to_end(&mut raw)?;
+        self.offset = end;
+
+        let mut start = 0;
+        while raw.get(start).is_some_and(|byte| matches!(byte, b'#' | b'\r' | b'\n')) {
+            start = raw[start..].iter().position(|byte| *byte == b'\n').map_or(raw.len(), |i| start + i + 1);
+            self.line += 1;
+        }
+        raw.drain(..start);
+        Ok(raw)
+    }
+
+    fn write_row(&mut self, row: &[u8]) -> Result<()> {
+        self.out.write_all(row)?;
+        if !row.ends_with(b"\n") {
+            self.out.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,583 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::change::AccountChange;
+use crate::config::{DedupConfig, IdempotencyConfig, IngestConfig, KafkaConfig, LimitAction, LimitsConfig, RedisConfig,
+                    WebhookConfig};
+use crate::dead_letter::DeadLetter;
+use crate::dedup::Dedup;
+use crate::error_log::{ErrorLog, ErrorRecord};
+use crate::filter::{ClientList, Filter};
+use crate::idempotency;
+use crate::latency::Latencies;
+use crate::merkle::MerkleTree;
+use crate::progress::Progress;
+use crate::quarantine::Quarantine;
+use crate::rounding::{self, RoundingMode};
+use crate::sinks::ChangeSinks;
+use crate::source::{CsvSource, MessageSource, SourceRow, TransactionSource};
+use crate::state::Store;
+use crate::throttle::Throttle;
+use crate::{otel, statsd, AccountTransactions, CancelToken, Error, Rejection, Result, TransactionMessage,
+            TransactionType};
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct TxInputRecord {
+    #[serde(rename = "type")]
+    tx_type: String,
+    client: u16,
+    tx: u32,
+    amount: String,
+    // Optional, see IdempotencyKeys
+    #[serde(default)]
+    pub(crate) idempotency_key: Option<String>,
+    // The client a transfer pays, required for transfers
+    #[serde(default)]
+    to_client: Option<u16>,
+    // The merchant or other party on the far side of a deposit or withdrawal, optional
+    #[serde(default)]
+    counterparty: Option<String>,
+    // Free text kept with the transaction
+    #[serde(default)]
+    memo: Option<String>,
+    // Optional, for the category report
+    #[serde(default)]
+    category: Option<String>,
+}
+
+impl AccountTransactions {
+    // Applies the messages in order, as read_file does the rows of a file, and reports how they went. Each message is
+    // given the engine's next tx_time, whatever it had.
+    pub fn process<I>(&mut self, messages: I) -> Result<ReadReport>
+        where I: IntoIterator<Item = TransactionMessage>, I::IntoIter: Send {
+        read_source(&mut MessageSource::new(messages.into_iter()), self, &ReadOptions::default(), None)
+    }
+}
+
+// Counts from one pass over an input file
+#[derive(Debug, Default)]
+pub struct ReadReport {
+    pub rows: u32,
+    pub applied: u32,
+    pub rejected: u32,
+    pub malformed: u32,
+    pub quarantined: u32,
+    pub dead_lettered: u32,
+    // Rows skipped because they did not match ReadOptions::filter
+    pub filtered: u32,
+    // Rows skipped because their client is not in ReadOptions::only_clients
+    pub excluded_clients: u32,
+    // Rows skipped because a message with the same idempotency key was already handled
+    pub replayed: u32,
+    // Rows skipped because they exactly repeated an earlier row, see ReadOptions::dedup
+    pub duplicates: u32,
+    // Over the rows this read applied
+    pub merkle: MerkleTree,
+    // Account changes appended to the CDC log
+    pub changes: u32,
+    // Account changes delivered to Kafka
+    pub published: u32,
+    // Account balances written to the Redis cache
+    pub cached: u32,
+    pub rejections: BTreeMap<Rejection, u32>,
+    // Set when a limit with on_limit = "stop" or cancellation ended the read early, the rows after it were not read
+    pub stopped: Option<String>,
+    pub cancelled: bool,
+    // Most rows waiting between the parser and the engine at once, always 0 without an ingest queue
+    pub peak_queue_depth: usize,
+    pub latency: Latencies,
+    // Wall time of the read and input bytes consumed, 0 bytes for Kafka
+    pub elapsed: Duration,
+    pub bytes: u64,
+    // Most transactions retained at once and the highest memory estimate, checked after every message
+    pub peak_transactions: usize,
+    pub peak_memory_bytes: u64,
+}
+
+impl ReadReport {
+    // "insufficient_funds=3, unknown_tx=1", or "none"
+    pub fn rejection_counts(&self) -> String {
+        if self.rejections.is_empty() {
+            return String::from("none");
+        }
+        self.rejections.iter()
+            .map(|(reason, count)| format!("{}={}", reason, count))
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    // What --summary writes, the counts and Merkle root of the read as JSON
+    pub fn summary(&self) -> serde_json::Value {
+        let rejections: serde_json::Map<String, serde_json::Value> = self.rejections.iter()
+            .map(|(reason, count)| (reason.to_string(), json!(count)))
+            .collect();
+        json!({
+            "rows": self.rows,
+            "applied": self.applied,
+            "rejected": self.rejected,
+            "malformed": self.malformed,
+            "filtered": self.filtered,
+            "excluded_clients": self.excluded_clients,
+            "replayed": self.replayed,
+            "duplicates": self.duplicates,
+            "rejections": rejections,
+            "merkle_root": self.merkle.root_hex(),
+            "stopped": self.stopped,
+        })
+    }
+
+    pub fn rows_per_second(&self) -> f64 {
+        f64::from(self.rows) / self.elapsed.as_secs_f64().max(0.001)
+    }
+
+    pub fn bytes_per_second(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64().max(0.001)
+    }
+
+    pub(crate) fn observe(&mut self, account_txs: &AccountTransactions) {
+        self.peak_transactions = self.peak_transactions.max(account_txs.txs_txid.len());
+        self.peak_memory_bytes = self.peak_memory_bytes.max(account_txs.estimated_bytes());
+    }
+}
+
+// How read_file treats the input. When lenient, rows that fail to parse are counted and skipped
+// instead of failing the whole read. Giving an error log, quarantine or dead letter file implies it, as they are there
+// to collect the bad rows of a read that carries on.
+#[derive(Debug, Clone, Default)]
+pub struct ReadOptions {
+    pub lenient: bool,
+    pub progress: bool,
+    pub error_log: Option<String>,
+    pub quarantine: Option<String>,
+    pub dead_letter: Option<String>,
+    pub kafka: Option<KafkaConfig>,
+    pub cdc: Option<String>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub redis: Option<RedisConfig>,
+    pub limits: LimitsConfig,
+    pub ingest: IngestConfig,
+    // Checked before each row. A cancelled read returns Ok with the rows applied so far left in the
+    // AccountTransactions and ReadReport::cancelled set.
+    pub cancel: Option<CancelToken>,
+    // Only rows matching it are applied, the rest are counted in ReadReport::filtered
+    pub filter: Option<Filter>,
+    // Only rows for these clients are applied, the rest are counted in ReadReport::excluded_clients
+    pub only_clients: Option<ClientList>,
+    pub idempotency: IdempotencyConfig,
+    pub dedup: DedupConfig,
+}
+
+pub fn read_file(filename: &str, account_txs: &mut AccountTransactions, read_options: &ReadOptions) -> Result<ReadReport> {
+    read_file_with_store(filename, account_txs, read_options, None)
+}
+
+// As read_file, also passing each handled message to a store that writes as it goes
+pub fn read_file_with_store(filename: &str, account_txs: &mut AccountTransactions, read_options: &ReadOptions,
+                            store: Option<&mut dyn Store>) -> Result<ReadReport> {
+    read_source(&mut CsvSource::open(filename)?, account_txs, read_options, store)
+}
+
+// As read_file_with_store, taking the rows from any source
+pub fn read_source(source: &mut dyn TransactionSource, account_txs: &mut AccountTransactions, read_options: &ReadOptions,
+                   store: Option<&mut dyn Store>) -> Result<ReadReport> {
+    let start = Instant::now();
+    let mut sinks = ChangeSinks::open(read_options.cdc.as_deref(), read_options.kafka.as_ref(), &read_options.webhooks,
+                                      read_options.redis.as_ref())?;
+    let mut report = read_source_with(source, account_txs, read_options, store, &mut sinks, None)?;
+    {
+        let _span = otel::span("sinks.finish");
+        sinks.finish(&mut report)?;
+    }
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+// What read_source did with one row, for a RowHandler
+pub(crate) enum RowOutcome<'a> {
+    // The engine applied or rejected the message, changing the account or not
+    Handled { message: &'a TransactionMessage, result: &'a Result<()>, change: Option<&'a AccountChange> },
+    // The message's idempotency key was handled before
+    Replayed(&'a TransactionMessage),
+    Malformed(&'a str),
+}
+
+// Told about each row as read_source_with handles it, for callers like serve that answer for every row or tell
+// others about it. An Err stops the read.
+pub(crate) trait RowHandler {
+    fn handled(&mut self, account_txs: &AccountTransactions, outcome: RowOutcome) -> Result<()>;
+}
+
+// As read_source, with changes going to sinks the caller keeps open across reads and each row passed to the handler.
+// The sinks are neither flushed nor finished.
+pub(crate) fn read_source_with(source: &mut dyn TransactionSource, account_txs: &mut AccountTransactions,
+                               read_options: &ReadOptions, store: Option<&mut dyn Store>, sinks: &mut ChangeSinks,
+                               handler: Option<&mut dyn RowHandler>) -> Result<ReadReport> {
+    let mut span = otel::span("read_file");
+    span.set("file", source.describe());
+    let result = read_source_traced(source, account_txs, read_options, store, sinks, handler);
+    match &result {
+        Ok(report) => {
+            span.set("rows", report.rows);
+            span.set("applied", report.applied);
+            span.set("rejected", report.rejected);
+            span.set("malformed", report.malformed);
+        }
+        Err(e) => span.fail(e),
+    }
+    result
+}
+
+fn read_source_traced(source: &mut dyn TransactionSource, account_txs: &mut AccountTransactions,
+                      read_options: &ReadOptions, mut store: Option<&mut dyn Store>, sinks: &mut ChangeSinks,
+                      mut handler: Option<&mut dyn RowHandler>) -> Result<ReadReport> {
+    let start = Instant::now();
+    source.set_rounding(account_txs.rounding_mode());
+    let mut report = ReadReport::default();
+    report.observe(account_txs);
+    let mut progress = Progress::new(source.total_bytes().unwrap_or(0), read_options.progress);
+    let mut error_log = match &read_options.error_log {
+        Some(path) => Some(ErrorLog::create(path)?),
+        None => None,
+    };
+    let mut quarantine = match &read_options.quarantine {
+        Some(path) => {
+            let (input, header_end) = source.file().ok_or_else(|| Error::InvalidArgument(format!(
+                "Quarantine copies rows out of the input file, and {} is not one.", source.describe())))?;
+            Some(Quarantine::create(path, input, header_end)?)
+        }
+        None => None,
+    };
+    let mut dead_letter = match &read_options.dead_letter {
+        Some(path) => Some(DeadLetter::create(path)?),
+        None => None,
+    };
+    let lenient = read_options.lenient || error_log.is_some() || quarantine.is_some() || dead_letter.is_some();
+    let mut position = source.file().map_or(0, |(_, header_end)| header_end);
+    let queued = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let mut next_row: Box<dyn FnMut() -> Option<RawRow>> = match read_options.ingest.queue_depth {
+            None => Box::new(move || source.next_row().map(|row| RawRow { row, queued: 0 })),
+            // Parsing runs ahead on its own thread, blocking once depth rows are waiting to be applied
+            Some(depth) => {
+                let (sender, receiver) = mpsc::sync_channel(depth);
+                let queued = &queued;
+                scope.spawn(move || {
+                    while let Some(row) = source.next_row().map(|row| RawRow { row, queued: 0 }) {
+                        // Counted before send so the engine never sees the count dip below zero
+                        queued.fetch_add(1, Ordering::SeqCst);
+                        if sender.send(row).is_err() {
+                            break;
+                        }
+                    }
+                });
+                Box::new(move || {
+                    let mut row: RawRow = receiver.recv().ok()?;
+                    row.queued = queued.fetch_sub(1, Ordering::SeqCst).min(depth);
+                    Some(row)
+                })
+            }
+        };
+
+        let throttle = Throttle::new(read_options.ingest.rows_per_second);
+        let mut dedup = Dedup::new(&read_options.dedup);
+        let mut counter = account_txs.next_tx_time();
+        let mut checkpoint = false;
+        loop {
+            // Saved before waiting on the source for the next row, which may not come for a while
+            if checkpoint {
+                sinks.flush()?;
+                if let Some(store) = store.as_deref_mut() {
+                    store.save(account_txs)?;
+                }
+            }
+            throttle.wait(report.rows);
+            if read_options.cancel.as_ref().is_some_and(CancelToken::is_cancelled) {
+                report.stopped = Some(String::from("Processing was cancelled."));
+                report.cancelled = true;
+                break;
+            }
+            progress.update(report.rows, position);
+            let row = match next_row() {
+                Some(row) => row,
+                None => break,
+            };
+            report.peak_queue_depth = report.peak_queue_depth.max(row.queued);
+            let parsed = row.row.parsed.map(|mut message| {
+                message.tx_time = counter;
+                message
+            });
+            if let Some(limit) = limit_reached(&read_options.limits, account_txs, report.rows, parsed.as_ref().ok()) {
+                match read_options.limits.on_limit {
+                    LimitAction::Abort => {
+                        flush_logs(&mut error_log, &mut quarantine)?;
+                        return Err(Error::LimitExceeded(limit));
+                    }
+                    LimitAction::Stop => {
+                        report.stopped = Some(limit);
+                        break;
+                    }
+                }
+            }
+            position = row.row.end;
+            counter += 1;
+            report.rows += 1;
+            // Whatever becomes of the row, it is not read again
+            if let Some((partition, offset)) = row.row.resume {
+                account_txs.source_offsets.insert(partition, offset);
+            }
+            checkpoint = row.row.checkpoint;
+            let message = match parsed {
+                Ok(message) => message,
+                Err(e @ Error::ParseError(_)) => {
+                    if let Some(error_log) = error_log.as_mut() {
+                        let message = e.to_string();
+                        error_log.record(&ErrorRecord { code: "malformed", message: &message, row: report.rows, client: None, tx: None })?;
+                    }
+                    if let Some(quarantine) = quarantine.as_mut() {
+                        quarantine.copy(row.row.start, row.row.end)?;
+                    }
+                    if !lenient {
+                        flush_logs(&mut error_log, &mut quarantine)?;
+                        return Err(e);
+                    }
+                    diag!("Skipped malformed row {}. {}", report.rows, e);
+                    if let (Some(handler), Error::ParseError(reason)) = (handler.as_deref_mut(), &e) {
+                        handler.handled(account_txs, RowOutcome::Malformed(reason))?;
+                    }
+                    report.malformed += 1;
+                    statsd::count("malformed", 1, &[]);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if read_options.only_clients.as_ref().is_some_and(|clients| !clients.contains(message.client)) {
+                report.excluded_clients += 1;
+                continue;
+            }
+            if read_options.filter.as_ref().is_some_and(|filter| !filter.matches(&message)) {
+                report.filtered += 1;
+                continue;
+            }
+            let key = idempotency::key_for(row.row.idempotency_key, &message, &read_options.idempotency);
+            if key.as_deref().is_some_and(|key| account_txs.idempotency_keys.contains(key)) {
+                if let Some(handler) = handler.as_deref_mut() {
+                    handler.handled(account_txs, RowOutcome::Replayed(&message))?;
+                }
+                report.replayed += 1;
+                continue;
+            }
+            if dedup.as_mut().is_some_and(|dedup| dedup.repeated(&message, &mut account_txs.idempotency_keys)) {
+                report.duplicates += 1;
+                continue;
+            }
+            let mut tx_span = otel::transaction_span(&message);
+            let mut stopwatch = report.latency.start(&message.tx_type);
+            let before = account_txs.account_client.get(&message.client).cloned();
+            let result = account_txs.handle_tx_message(&message);
+            if let Some(key) = key {
+                account_txs.idempotency_keys.insert(key, message.tx_time);
+            }
+            stopwatch.engine();
+            if let Some(store) = store.as_deref_mut() {
+                store.record(account_txs, &message)?;
+                stopwatch.store();
+            }
+            let change = sinks.record(before.as_ref(), account_txs.account_client.get(&message.client), &message,
+                                      &result, &account_txs.annotations)?;
+            stopwatch.sinks();
+            if let Some(handler) = handler.as_deref_mut() {
+                handler.handled(account_txs, RowOutcome::Handled { message: &message, result: &result,
+                                                                   change: change.as_ref() })?;
+            }
+            drop(stopwatch);
+            report.observe(account_txs);
+            match result {
+                Ok(()) => {
+                    report.applied += 1;
+                    report.merkle.push(&message);
+                    statsd::message(&message, None)
+                }
+                Err(Error::PolicyViolation { reason, message: reject_message }) => {
+                    tx_span.set("rejected", reason.code());
+                    statsd::message(&message, Some(reason));
+                    diag!("{}", reject_message);
+                    if let Some(error_log) = error_log.as_mut() {
+                        error_log.record(&ErrorRecord {
+                            code: reason.code(),
+                            message: &reject_message,
+                            row: report.rows,
+                            client: Some(message.client),
+                            tx: Some(message.tx),
+                        })?;
+                    }
+                    if let Some(dead_letter) = dead_letter.as_mut() {
+                        dead_letter.record(&message, reason, &reject_message, report.rows)?;
+                    }
+                    report.rejected += 1;
+                    *report.rejections.entry(reason).or_insert(0) += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    })?;
+    progress.finish(report.rows, position);
+    flush_logs(&mut error_log, &mut quarantine)?;
+    if let Some(quarantine) = &quarantine {
+        report.quarantined = quarantine.rows();
+    }
+    if let Some(dead_letter) = dead_letter.as_mut() {
+        dead_letter.flush()?;
+        report.dead_lettered = dead_letter.messages();
+    }
+    report.bytes = position;
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+struct RawRow {
+    row: SourceRow,
+    // Rows waiting in the ingest queue when this one was taken off it, counting itself
+    queued: usize,
+}
+
+// Describes the first limit the next row would cross, checked before the row is counted or applied
+fn limit_reached(limits: &LimitsConfig, account_txs: &AccountTransactions, rows: u32,
+                 message: Option<&TransactionMessage>) -> Option<String> {
+    if let Some(max) = limits.max_rows.filter(|max| rows >= *max) {
+        return Some(format!("Row limit of {} reached.", max));
+    }
+    if let Some(max) = limits.max_memory_bytes.filter(|max| account_txs.estimated_bytes() >= *max) {
+        return Some(format!("Memory estimate limit of {} bytes reached after {} rows.", max, rows));
+    }
+    let message = message?;
+    if let Some(max) = limits.max_clients {
+        if account_txs.account_client.len() >= max && !account_txs.account_client.contains_key(&message.client) {
+            return Some(format!("Client limit of {} reached at client, {}, after {} rows.", max, message.client, rows));
+        }
+    }
+    if let Some(max) = limits.max_transactions {
+        // Every type that creates a transaction, which is kept for disputes, releases or refunds
+        let new_tx = matches!(message.tx_type, TransactionType::DEPOSIT | TransactionType::WITHDRAWAL
+                                               | TransactionType::ESCROW | TransactionType::TRANSFER)
+            && !account_txs.txs_txid.contains_key(&message.tx);
+        if new_tx && account_txs.txs_txid.len() >= max {
+            return Some(format!("Retained transaction limit of {} reached at transaction, {}, after {} rows.",
+                                max, message.tx, rows));
+        }
+    }
+    None
+}
+
+fn flush_logs(error_log: &mut Option<ErrorLog>, quarantine: &mut Option<Quarantine>) -> Result<()> {
+    if let Some(error_log) = error_log.as_mut() {
+        error_log.flush()?;
+    }
+    if let Some(quarantine) = quarantine.as_mut() {
+        quarantine.flush()?;
+    }
+    Ok(())
+}
+
+// I/O failures while reading the file stay I/O errors, anything wrong with the row itself is a ParseError
+pub(crate) fn parse_row(result: std::result::Result<TxInputRecord, csv::Error>, time: u32, rounding: RoundingMode)
+                        -> Result<TransactionMessage> {
+    input_record_to_transaction(&result.map_err(csv_error)?, time, rounding)
+}
+
+// An unreadable input is an error, a row that would not deserialize only malformed
+fn csv_error(e: csv::Error) -> Error {
+    if e.is_io_error() { Error::Csv(e) } else { Error::ParseError(e.to_string()) }
+}
+
+// One line of a line protocol: the input file's columns in order without a header, or a JSON object with the
+// same fields as a Kafka input record. Returned with the line's idempotency_key, when it has one.
+pub(crate) fn parse_line(line: &str, time: u32, rounding: RoundingMode) -> Result<(TransactionMessage, Option<String>)> {
+    let record: TxInputRecord = if line.starts_with('{') {
+        serde_json::from_str(line).map_err(|e| Error::ParseError(e.to_string()))?
+    } else {
+        let mut rdr = csv::ReaderBuilder::new().has_headers(false).from_reader(line.as_bytes());
+        match rdr.deserialize::<TxInputRecord>().next() {
+            Some(result) => result.map_err(csv_error)?,
+            None => return Err(Error::ParseError(String::from("Empty line"))),
+        }
+    };
+    let message = input_record_to_transaction(&record, time, rounding)?;
+    Ok((message, record.idempotency_key))
+}
+
+pub(crate) fn input_record_to_transaction(record: &TxInputRecord, time: u32, rounding: RoundingMode)
+                                          -> Result<TransactionMessage> {
+    let converted_amount = if !record.amount.is_empty() {
+        rounding::parse_subunits(&record.amount, rounding)?
+    } else {
+        0
+    };
+
+    let tx_type = TransactionType::from_str(record.tx_type.as_str())?;
+    let to_client = match tx_type {
+        TransactionType::TRANSFER => Some(record.to_client.ok_or_else(|| Error::ParseError(format!(
+            "Transfer, {}, has no to_client", record.tx)))?),
+        _ => None,
+    };
+    Ok(TransactionMessage {
+        tx_time: time,
+        tx: record.tx,
+        tx_type,
+        client: record.client,
+        amount: converted_amount,
+        to_client,
+        counterparty: record.counterparty.clone().filter(|counterparty| !counterparty.is_empty()),
+        memo: record.memo.clone().filter(|memo| !memo.is_empty()),
+        category: record.category.clone().filter(|category| !category.is_empty()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{apply, message, temp_path, units};
+
+    #[test]
+    fn transaction_limit_counts_escrows_and_transfers() {
+        let limits = LimitsConfig { max_transactions: Some(2), ..LimitsConfig::default() };
+        let mut account_txs = AccountTransactions::new();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        apply(&mut account_txs, message(TransactionType::ESCROW, 1, 2, units(5))).unwrap();
+
+        let mut transfer = message(TransactionType::TRANSFER, 1, 3, units(1));
+        transfer.to_client = Some(2);
+        assert!(limit_reached(&limits, &account_txs, 2, Some(&transfer)).is_some());
+        let escrow = message(TransactionType::ESCROW, 1, 4, units(1));
+        assert!(limit_reached(&limits, &account_txs, 2, Some(&escrow)).is_some());
+        // Messages naming a retained transaction add none
+        let release = message(TransactionType::RELEASE, 1, 2, 0);
+        assert!(limit_reached(&limits, &account_txs, 2, Some(&release)).is_none());
+    }
+
+    #[test]
+    fn quarantine_skips_malformed_rows() {
+        let input = temp_path("quarantine-input.csv");
+        let quarantined = temp_path("quarantine-output.csv");
+        std::fs::write(&input, "type,client,tx,amount\ndeposit,1,1,1.0\ndeposit,1,two,1.0\ndeposit,1,3,2.0\n").unwrap();
+        let read_options = ReadOptions {
+            quarantine: Some(quarantined.to_str().unwrap().to_string()),
+            ..ReadOptions::default()
+        };
+        let mut account_txs = AccountTransactions::new();
+
+        let report = read_file(input.to_str().unwrap(), &mut account_txs, &read_options).unwrap();
+        assert_eq!((report.malformed, report.quarantined, report.applied), (1, 1, 2));
+        assert_eq!(account_txs.account_client[&1].available, units(3));
+        assert!(std::fs::read_to_string(&quarantined).unwrap().contains("deposit,1,two,1.0"));
+        let _ = std::fs::remove_file(input);
+        let _ = std::fs::remove_file(quarantined);
+    }
+}
@@ -0,0 +1,117 @@
+use std::collections::{BTreeMap, HashSet};
+
+use crate::lifecycle::DisputeEventKind;
+use crate::{AccountTransactions, Amount};
+#[cfg(any(feature = "sled", feature = "postgres"))]
+use crate::{TransactionMessage, TransactionType, Tx};
+
+// What a client still owes for chargebacks its available funds could not cover. Balances never go negative, so the
+// deficit is kept on the charged back transactions rather than the account.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Recovery {
+    pub client: u16,
+    // Charged back transactions with a deficit outstanding
+    pub transactions: usize,
+    // Outstanding, in subunits
+    pub deficit: Amount,
+    // Swept from deposits so far against the outstanding transactions, in subunits
+    pub recovered: Amount,
+    // tx_time of the oldest outstanding chargeback
+    pub since: u32,
+}
+
+impl AccountTransactions {
+    // With recovery on, a chargeback of provisional credit the client has since spent takes what is available and
+    // leaves the rest as a deficit, and the client's later deposits are swept against it. Off, such chargebacks are
+    // rejected and deficits already outstanding are left as they are.
+    pub fn set_deficit_recovery(&mut self, enabled: bool) {
+        self.recovering = match enabled {
+            true => Some(self.iter_transactions().filter(|tx| tx.recovery > 0).map(|tx| tx.client).collect()),
+            false => None,
+        };
+    }
+
+    pub fn deficit_recovery(&self) -> bool {
+        self.recovering.is_some()
+    }
+
+    // Clients with a deficit outstanding, in client order
+    pub fn recoveries(&self) -> Vec<Recovery> {
+        let mut by_client: BTreeMap<u16, Recovery> = BTreeMap::new();
+        for tx in self.iter_transactions().filter(|tx| tx.recovery > 0) {
+            let recovery = by_client.entry(tx.client)
+                .or_insert_with(|| Recovery { client: tx.client, since: tx.chargeback_time, ..Recovery::default() });
+            recovery.transactions += 1;
+            recovery.deficit += tx.recovery;
+            recovery.recovered += tx.recovered;
+            recovery.since = recovery.since.min(tx.chargeback_time);
+        }
+        by_client.into_values().collect()
+    }
+
+    // The charged back transactions a deposit's row swept from, for stores that write what each row changed
+    #[cfg(any(feature = "sled", feature = "postgres"))]
+    pub(crate) fn swept_by<'a>(&'a self, msg: &'a TransactionMessage) -> impl Iterator<Item = &'a Tx> + 'a {
+        let deposit = msg.tx_type == TransactionType::DEPOSIT;
+        self.client_transactions(msg.client).filter(move |tx| {
+            deposit && tx.history.last().is_some_and(|event| event.kind == DisputeEventKind::Recover
+                                                     && event.tx_time == msg.tx_time)
+        })
+    }
+
+    pub(crate) fn in_recovery(&self, client: u16) -> bool {
+        self.recovering.as_ref().is_some_and(|clients| clients.contains(&client))
+    }
+
+    // Client from's outstanding deficits are now client into's, its transactions having moved to into
+    pub(crate) fn merge_recovery(&mut self, from: u16, into: u16) {
+        if let Some(recovering) = self.recovering.as_mut() {
+            if recovering.remove(&from) {
+                recovering.insert(into);
+            }
+        }
+    }
+
+    // Called for a provisional chargeback of tx the client's available funds fall short of by shortfall
+    pub(crate) fn open_recovery(&mut self, tx: u32, shortfall: Amount) {
+        let recovering: &mut HashSet<u16> = match self.recovering.as_mut() {
+            Some(recovering) => recovering,
+            None => return,
+        };
+        if let Some(charged_back) = self.txs_txid.get_mut(&tx) {
+            charged_back.recovery += shortfall;
+            recovering.insert(charged_back.client);
+        }
+    }
+
+    // Takes what it can of a deposit of amount for the client's deficits, oldest chargeback first, and returns what
+    // was swept
+    pub(crate) fn sweep_deposit(&mut self, client: u16, amount: Amount, tx_time: u32) -> Amount {
+        if !self.in_recovery(client) {
+            return 0;
+        }
+        let mut outstanding: Vec<(u32, u32)> = self.client_transactions(client)
+            .filter(|tx| tx.recovery > 0)
+            .map(|tx| (tx.chargeback_time, tx.tx))
+            .collect();
+        outstanding.sort_unstable();
+        let mut left = amount;
+        for (_, tx) in &outstanding {
+            if left == 0 {
+                break;
+            }
+            let charged_back = self.txs_txid.get_mut(tx).expect("Transaction found above.");
+            let swept = left.min(charged_back.recovery);
+            charged_back.recovery -= swept;
+            charged_back.recovered += swept;
+            charged_back.record_event(DisputeEventKind::Recover, tx_time);
+            left -= swept;
+        }
+        if !self.client_transactions(client).any(|tx| tx.recovery > 0) {
+            if let Some(recovering) = self.recovering.as_mut() {
+                recovering.remove(&client);
+            }
+        }
+        amount - left
+    }
+}
@@ -0,0 +1,103 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::change::AccountChange;
+use crate::config::RedisConfig;
+use crate::observer::EngineObserver;
+use crate::{Error, Result};
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+// Mirrors each account's current balances into a Redis hash at <key_prefix><client>. Commands are pipelined and
+// their replies checked once per batch, since waiting on every write would cap ingestion at one per round trip.
+pub struct RedisCache {
+    wtr: BufWriter<TcpStream>,
+    rdr: BufReader<TcpStream>,
+    key_prefix: String,
+    batch_size: usize,
+    pending: usize,
+    written: u32,
+}
+
+impl RedisCache {
+    pub fn connect(config: &RedisConfig) -> Result<RedisCache> {
+        let stream = TcpStream::connect(&config.addr)?;
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let mut cache = RedisCache {
+            wtr: BufWriter::new(stream.try_clone()?),
+            rdr: BufReader::new(stream),
+            key_prefix: config.key_prefix.clone(),
+            batch_size: config.batch_size.max(1),
+            pending: 0,
+            written: 0,
+        };
+        if let Some(password) = &config.password {
+            cache.command(&["AUTH", password])?;
+        }
+        if config.database != 0 {
+            cache.command(&["SELECT", &config.database.to_string()])?;
+        }
+        cache.flush()?;
+        Ok(cache)
+    }
+
+    pub fn update(&mut self, change: &AccountChange) -> Result<()> {
+        let key = format!("{}{}", self.key_prefix, change.client);
+        self.command(&[
+            "HSET", &key,
+            "available", &change.new.available.to_string(),
+            "held", &change.new.held.to_string(),
+            "total", &change.new.total.to_string(),
+            "locked", &change.new.locked.to_string(),
+        ])?;
+        self.written += 1;
+        if self.pending >= self.batch_size {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn written(&self) -> u32 {
+        self.written
+    }
+
+    // Sends everything buffered and reads one reply per command, failing on the first error reply
+    pub fn flush(&mut self) -> Result<()> {
+        self.wtr.flush()?;
+        let mut line = String::new();
+        while self.pending > 0 {
+            line.clear();
+            self.rdr.read_line(&mut line)?;
+            self.pending -= 1;
+            if line.is_empty() {
+                return Err(Error::SinkError(String::from("Redis closed the connection.")));
+            }
+            if let Some(message) = line.strip_prefix('-') {
+                return Err(Error::SinkError(format!("Redis answered with an error: {}", message.trim())));
+            }
+        }
+        Ok(())
+    }
+
+    // RESP array of bulk strings. Only commands with simple or integer replies are sent.
+    fn command(&mut self, args: &[&str]) -> Result<()> {
+        write!(self.wtr, "*{}\r\n", args.len())?;
+        for arg in args {
+            write!(self.wtr, "${}\r\n{}\r\n", arg.len(), arg)?;
+        }
+        self.pending += 1;
+        Ok(())
+    }
+}
+
+impl EngineObserver for RedisCache {
+    fn name(&self) -> &str {
+        "redis"
+    }
+
+    fn account_changed(&mut self, change: &AccountChange) -> Result<()> {
+        self.update(change)
+    }
+}
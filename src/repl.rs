@@ -0,0 +1,246 @@
+use std::io::{BufRead, Write};
+
+use crate::merkle::MerkleTree;
+use crate::output::write_accounts;
+use crate::rounding::{self, RoundingMode};
+use crate::source::{CsvSource, TransactionSource};
+use crate::state::Store;
+use crate::{format_amount, Account, AccountTransactions, Error, Result, TransactionMessage,
+            TransactionType, Tx, TxState};
+
+const HELP: &str = "\
+deposit <client> <tx> <amount>    withdraw <client> <tx> <amount>
+dispute <client> <tx>             resolve <client> <tx>             chargeback <client> <tx>
+account <client>     accounts     tx <id>     disputes     history [n]
+load <file.csv>      applies every row of an input file, each one a step
+undo [n]             takes back the last n steps, 1 by default
+save                 writes the state to the store
+quit";
+
+// What one step changed, enough to put it back. A message only ever touches its client's account, the transaction
+// it names, its own entry in the message log and the Merkle tree.
+struct Step {
+    msg: TransactionMessage,
+    account: Option<Account>,
+    tx: Option<Tx>,
+    merkle: MerkleTree,
+}
+
+// An interactive session against an engine, one command per line of input, answered on out. Nothing is written to
+// the store until save. Undo restores the engine's books, not the state validators keep, such as a rules file's
+// velocity counts.
+pub struct Repl<'a> {
+    account_txs: &'a mut AccountTransactions,
+    store: Option<&'a mut dyn Store>,
+    steps: Vec<Step>,
+    next_time: u32,
+    unsaved: bool,
+}
+
+impl<'a> Repl<'a> {
+    pub fn new(account_txs: &'a mut AccountTransactions, store: Option<&'a mut dyn Store>) -> Repl<'a> {
+        let next_time = account_txs.next_tx_time();
+        Repl { account_txs, store, steps: Vec::new(), next_time, unsaved: false }
+    }
+
+    // Reads commands until quit or the end of input. prompt is shown before each command, for a terminal.
+    pub fn run<R: BufRead, W: Write>(&mut self, input: R, out: &mut W, prompt: Option<&str>) -> Result<()> {
+        let mut quitting = false;
+        let mut lines = input.lines();
+        loop {
+            if let Some(prompt) = prompt {
+                write!(out, "{}", prompt)?;
+                out.flush()?;
+            }
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break,
+            };
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.first().copied() {
+                None => continue,
+                Some("quit") | Some("exit") if self.unsaved && self.store.is_some() && !quitting => {
+                    writeln!(out, "There are unsaved changes. save them, or quit again to discard them.")?;
+                    quitting = true;
+                    continue;
+                }
+                Some("quit") | Some("exit") => break,
+                Some(_) => quitting = false,
+            }
+            if let Err(e) = self.command(&words, out) {
+                writeln!(out, "error: {}", e)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn command<W: Write>(&mut self, words: &[&str], out: &mut W) -> Result<()> {
+        match words {
+            ["help"] => writeln!(out, "{}", HELP)?,
+            [tx_type, args @ ..] if tx_type.parse::<TransactionType>().is_ok() => {
+                let msg = transaction(tx_type, args, self.account_txs.rounding_mode())?;
+                self.step(msg, out)?;
+            }
+            ["account", client] => match self.account_txs.get_account(number(client)?) {
+                Some(account) => write_accounts(out, std::iter::once(account))?,
+                None => writeln!(out, "No account for client, {}.", client)?,
+            },
+            ["accounts"] => {
+                let mut accounts: Vec<&Account> = self.account_txs.iter_accounts().collect();
+                accounts.sort_by_key(|account| account.client);
+                write_accounts(out, accounts.into_iter())?;
+            }
+            ["tx", tx] => match self.account_txs.get_transaction(number(tx)?) {
+                Some(tx) => write_tx(out, tx)?,
+                None => writeln!(out, "No retained transaction, {}.", tx)?,
+            },
+            ["disputes"] => {
+                let mut disputes: Vec<&Tx> = self.account_txs.open_disputes().collect();
+                disputes.sort_by_key(|tx| tx.tx);
+                if disputes.is_empty() {
+                    writeln!(out, "No open disputes.")?;
+                }
+                for tx in disputes {
+                    write_tx(out, tx)?;
+                }
+            }
+            ["history"] => self.history(10, out)?,
+            ["history", n] => self.history(number(n)?, out)?,
+            ["load", path] => self.load(path, out)?,
+            ["undo"] => self.undo(1, out)?,
+            ["undo", n] => self.undo(number(n)?, out)?,
+            ["save"] => match self.store.as_deref_mut() {
+                Some(store) => {
+                    store.save(self.account_txs)?;
+                    self.unsaved = false;
+                    writeln!(out, "Saved to {}.", store.describe())?;
+                }
+                None => writeln!(out, "No store to save to. Start the session with --state or --store.")?,
+            },
+            [command, ..] => return Err(Error::InvalidArgument(format!("Unknown command, {}. Try help.", command))),
+            [] => {}
+        }
+        Ok(())
+    }
+
+    // Applies one message, then shows its client's account or why it was refused
+    fn step<W: Write>(&mut self, msg: TransactionMessage, out: &mut W) -> Result<()> {
+        let client = msg.client;
+        match self.apply(msg)? {
+            None => {
+                let account = self.account_txs.get_account(client).expect("Applied messages have an account.");
+                write_accounts(out, std::iter::once(account))?;
+            }
+            Some(reason) => writeln!(out, "rejected: {}", reason)?,
+        }
+        Ok(())
+    }
+
+    // The rejection message when the engine refused the message
+    fn apply(&mut self, msg: TransactionMessage) -> Result<Option<String>> {
+        let msg = TransactionMessage { tx_time: self.next_time, ..msg };
+        self.next_time += 1;
+        let account = self.account_txs.account_client.get(&msg.client).cloned();
+        let tx = self.account_txs.txs_txid.get(&msg.tx).cloned();
+        let merkle = self.account_txs.merkle.clone();
+        let result = self.account_txs.handle_tx_message(&msg);
+        self.steps.push(Step { msg, account, tx, merkle });
+        self.unsaved = true;
+        match result {
+            Ok(()) => Ok(None),
+            Err(Error::PolicyViolation { message, .. }) => Ok(Some(message)),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn load<W: Write>(&mut self, path: &str, out: &mut W) -> Result<()> {
+        let mut source = CsvSource::open(path)
+            .map_err(|e| Error::InvalidArgument(format!("Cannot read {}. {}", path, e)))?;
+        let (mut applied, mut rejected, mut malformed) = (0, 0, 0);
+        while let Some(row) = source.next_row() {
+            match row.parsed {
+                Ok(msg) => match self.apply(msg)? {
+                    None => applied += 1,
+                    Some(_) => rejected += 1,
+                },
+                Err(Error::ParseError(_)) => malformed += 1,
+                Err(e) => return Err(e),
+            }
+        }
+        writeln!(out, "Loaded {}: {} applied, {} rejected, {} malformed rows skipped.", path, applied, rejected, malformed)?;
+        Ok(())
+    }
+
+    fn undo<W: Write>(&mut self, n: usize, out: &mut W) -> Result<()> {
+        for _ in 0..n {
+            let step = match self.steps.pop() {
+                Some(step) => step,
+                None => {
+                    writeln!(out, "Nothing left to undo.")?;
+                    break;
+                }
+            };
+            let account_txs = &mut *self.account_txs;
+            match step.account {
+                Some(account) => account_txs.account_client.insert(step.msg.client, account),
+                None => account_txs.account_client.remove(&step.msg.client),
+            };
+            match step.tx {
+                Some(tx) => account_txs.txs_txid.insert(step.msg.tx, tx),
+                None => account_txs.txs_txid.remove(&step.msg.tx),
+            };
+            account_txs.tx_msgs_time.remove(&step.msg.tx_time);
+            account_txs.merkle = step.merkle;
+            self.unsaved = true;
+            writeln!(out, "Undid {}.", describe(&step.msg))?;
+        }
+        Ok(())
+    }
+
+    fn history<W: Write>(&self, n: usize, out: &mut W) -> Result<()> {
+        if self.steps.is_empty() {
+            writeln!(out, "No steps in this session.")?;
+        }
+        let skip = self.steps.len().saturating_sub(n);
+        for (i, step) in self.steps.iter().enumerate().skip(skip) {
+            writeln!(out, "{:>4}  {}", i + 1, describe(&step.msg))?;
+        }
+        Ok(())
+    }
+}
+
+// type client tx [amount], the amount in currency units rounded as the engine rounds input rows
+fn transaction(tx_type: &str, args: &[&str], rounding: RoundingMode) -> Result<TransactionMessage> {
+    let tx_type: TransactionType = tx_type.parse()?;
+    let carries_amount = matches!(tx_type, TransactionType::DEPOSIT | TransactionType::WITHDRAWAL);
+    let (client, tx, amount) = match (args, carries_amount) {
+        ([client, tx, amount], true) => (client, tx, rounding::parse_subunits(amount, rounding)?),
+        ([client, tx], false) => (client, tx, 0),
+        _ => return Err(Error::InvalidArgument(format!(
+            "Expected {} <client> <tx>{}.", tx_type, if carries_amount { " <amount>" } else { "" }))),
+    };
+    Ok(TransactionMessage { tx_time: 0, tx_type, client: number(client)?, tx: number(tx)?, amount, to_client: None,
+                            counterparty: None, memo: None, category: None })
+}
+
+fn number<T: std::str::FromStr>(word: &str) -> Result<T> {
+    word.parse().map_err(|_| Error::InvalidArgument(format!("'{}' is not a valid number.", word)))
+}
+
+fn describe(msg: &TransactionMessage) -> String {
+    match msg.tx_type {
+        TransactionType::DEPOSIT | TransactionType::WITHDRAWAL => format!("{} {} {} {}", msg.tx_type, msg.client, msg.tx,
+                                                                          format_amount(msg.amount)),
+        _ => format!("{} {} {}", msg.tx_type, msg.client, msg.tx),
+    }
+}
+
+fn write_tx<W: Write>(out: &mut W, tx: &Tx) -> Result<()> {
+    let state = match tx.state {
+        TxState::Posted => String::new(),
+        state => format!(", {}", state),
+    };
+    writeln!(out, "tx {}: {} of {} by client {}, time {}{}", tx.tx, tx.tx_type, format_amount(tx.amount), tx.client,
+             tx.tx_time, state)?;
+    Ok(())
+}
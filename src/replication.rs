@@ -0,0 +1,318 @@
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::iter;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::admin::AdminAction;
+use crate::change::AccountChange;
+use crate::snapshot::Payload;
+use crate::state::Store;
+use crate::{AccountTransactions, Configure, Error, Result, TransactionMessage};
+
+// Lines a standby can fall behind by before the primary drops it. It reconnects and starts over from a snapshot.
+const REPLICA_BUFFER: usize = 65536;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+// Sent from the primary to a standby as one JSON object per line. A connection starts with a snapshot of the
+// primary's state, followed by every message the primary handles after it, in order. Adjacently tagged, so the body
+// is read straight into its type rather than buffered, which could not hold amount128's amounts.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "frame", content = "body", rename_all = "lowercase")]
+enum Frame {
+    Snapshot(Payload),
+    Message(TransactionMessage),
+    Admin(AdminAction),
+}
+
+// Standbys connected to a primary
+pub(crate) struct Replicas {
+    replicas: Mutex<Vec<Replica>>,
+}
+
+struct Replica {
+    sender: SyncSender<String>,
+    // Lines queued for the standby that have not been written to it yet
+    lag: Arc<AtomicUsize>,
+}
+
+// How a standby's replication stream is doing, for /readyz
+#[derive(Debug, Clone, Default)]
+pub(crate) struct FollowStatus {
+    pub(crate) connected: bool,
+    // A snapshot from the primary has been applied since the standby started
+    pub(crate) synced: bool,
+    pub(crate) frames: u64,
+    pub(crate) last_frame: Option<Instant>,
+}
+
+impl Replicas {
+    pub(crate) fn new() -> Replicas {
+        Replicas { replicas: Mutex::new(Vec::new()) }
+    }
+
+    // Lines each connected standby is behind by
+    pub(crate) fn lags(&self) -> Vec<usize> {
+        self.replicas.lock().expect("Replica lock poisoned.").iter()
+            .map(|replica| replica.lag.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    // Must be called with the engine locked, so no message slips between the snapshot and the stream
+    pub(crate) fn attach(&self, account_txs: &AccountTransactions, stream: TcpStream) -> Result<()> {
+        let snapshot = serde_json::to_string(&Frame::Snapshot(Payload::capture(account_txs)))?;
+        let peer = stream.peer_addr()?;
+        let (sender, receiver) = mpsc::sync_channel::<String>(REPLICA_BUFFER);
+        sender.try_send(snapshot).expect("New replica channel has room.");
+        let lag = Arc::new(AtomicUsize::new(1));
+        self.replicas.lock().expect("Replica lock poisoned.").push(Replica { sender, lag: Arc::clone(&lag) });
+        diag!("Standby {} connected.", peer);
+
+        thread::spawn(move || {
+            let mut writer = BufWriter::new(stream);
+            while let Ok(line) = receiver.recv() {
+                // Whatever else is already queued goes out in the same flush
+                let mut written = 0;
+                let result = iter::once(line).chain(receiver.try_iter())
+                    .try_for_each(|line| { written += 1; writeln!(writer, "{}", line) })
+                    .and_then(|_| writer.flush());
+                lag.fetch_sub(written, Ordering::Relaxed);
+                if let Err(e) = result {
+                    diag!("Lost standby {}. {}", peer, e);
+                    return;
+                }
+            }
+            diag!("Dropped standby {} for falling behind.", peer);
+        });
+        Ok(())
+    }
+
+    pub(crate) fn ship(&self, msg: &TransactionMessage) -> Result<()> {
+        self.send(&Frame::Message(msg.clone()))
+    }
+
+    pub(crate) fn ship_admin(&self, action: &AdminAction) -> Result<()> {
+        self.send(&Frame::Admin(action.clone()))
+    }
+
+    fn send(&self, frame: &Frame) -> Result<()> {
+        let mut replicas = self.replicas.lock().expect("Replica lock poisoned.");
+        if replicas.is_empty() {
+            return Ok(());
+        }
+        let line = serde_json::to_string(frame)?;
+        replicas.retain(|replica| {
+            replica.lag.fetch_add(1, Ordering::Relaxed);
+            replica.sender.try_send(line.clone()).is_ok()
+        });
+        Ok(())
+    }
+}
+
+// Told of each message a standby applies from its primary, with what it changed and how it was handled, for the
+// standby's /ws subscribers
+pub(crate) type Announce<'a> = &'a (dyn Fn(&TransactionMessage, Option<&AccountChange>, &Result<()>) + Sync);
+
+// Runs on a standby for as long as the process lives: applies the primary's stream to the shared engine, and
+// reconnects after the primary goes away
+pub(crate) fn follow(primary: &str, key: Option<&str>, configure: &Configure, engine: &Mutex<AccountTransactions>,
+                     store: &Mutex<Option<Box<dyn Store>>>, status: &Mutex<FollowStatus>, announce: Announce) {
+    let mut reported = false;
+    loop {
+        match TcpStream::connect(primary) {
+            Ok(stream) => {
+                diag!("Following primary {}.", primary);
+                status.lock().expect("Status lock poisoned.").connected = true;
+                let result = apply_stream(stream, key, configure, engine, store, status, announce);
+                status.lock().expect("Status lock poisoned.").connected = false;
+                if let Err(e) = result {
+                    diag!("Replication from {} failed. {}", primary, e);
+                } else {
+                    diag!("Primary {} closed the replication stream.", primary);
+                }
+                reported = false;
+                // What was applied so far stays queryable and is kept in the store until the next snapshot
+                let account_txs = engine.lock().expect("Engine lock poisoned.");
+                if let Some(store) = store.lock().expect("Store lock poisoned.").as_mut() {
+                    if let Err(e) = store.save(&account_txs) {
+                        diag!("Failed to save state to {}. {}", store.describe(), e);
+                    }
+                }
+            }
+            Err(e) if !reported => {
+                diag!("Unable to reach primary {}. {} Retrying.", primary, e);
+                reported = true;
+            }
+            Err(_) => {}
+        }
+        thread::sleep(RECONNECT_DELAY);
+    }
+}
+
+// A primary with API keys configured answers the key with "authenticated" before it sends the snapshot
+fn apply_stream(stream: TcpStream, key: Option<&str>, configure: &Configure, engine: &Mutex<AccountTransactions>,
+                store: &Mutex<Option<Box<dyn Store>>>, status: &Mutex<FollowStatus>, announce: Announce)
+                -> Result<()> {
+    let mut rdr = BufReader::new(stream.try_clone()?);
+    if let Some(key) = key {
+        writeln!(&stream, "auth {}", key)?;
+        let mut ack = String::new();
+        rdr.read_line(&mut ack)?;
+        if ack.trim_end() != "authenticated" {
+            return Err(Error::ConfigError(format!("The primary refused replication_key. {}", ack.trim_end())));
+        }
+    }
+    for line in rdr.lines() {
+        let frame: Frame = serde_json::from_str(&line?)?;
+        let snapshot = matches!(frame, Frame::Snapshot(_));
+        let mut account_txs = engine.lock().expect("Engine lock poisoned.");
+        let mut store = store.lock().expect("Store lock poisoned.");
+        match frame {
+            Frame::Snapshot(payload) => {
+                let mut fresh = AccountTransactions::new();
+                payload.restore(&mut fresh)?;
+                configure(&mut fresh);
+                fresh.pipeline = std::mem::take(&mut account_txs.pipeline);
+                fresh.observers = std::mem::take(&mut account_txs.observers);
+                *account_txs = fresh;
+                if let Some(store) = store.as_mut() {
+                    store.save(&account_txs)?;
+                }
+            }
+            Frame::Message(msg) => {
+                // Rejections were already reported on the primary and reproduce the same state here. Anything else
+                // means this copy has diverged, so the stream is dropped and the standby resyncs from a new snapshot.
+                let before = account_txs.get_account(msg.client).cloned();
+                let result = account_txs.handle_tx_message(&msg);
+                if let Err(e) = &result {
+                    if !matches!(e, Error::PolicyViolation { .. }) {
+                        return result;
+                    }
+                }
+                if let Some(store) = store.as_mut() {
+                    store.record(&account_txs, &msg)?;
+                }
+                let change = AccountChange::between(before.as_ref(), account_txs.get_account(msg.client), &msg)
+                    .map(|mut change| {
+                        change.annotations.clone_from(account_txs.annotations());
+                        change
+                    });
+                announce(&msg, change.as_ref(), &result);
+            }
+            Frame::Admin(action) => {
+                // The primary only ships actions it applied and audited
+                account_txs.apply_admin(&action)?;
+                if let Some(store) = store.as_mut() {
+                    store.record_admin(&account_txs, &action)?;
+                }
+            }
+        }
+        let mut status = status.lock().expect("Status lock poisoned.");
+        status.synced |= snapshot;
+        status.frames += 1;
+        status.last_frame = Some(Instant::now());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::net::TcpListener;
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::testing::{apply, message, units};
+    use crate::TransactionType;
+
+    // Serves one standby: checks its auth line and, when it is the expected key, sends frames
+    fn primary(key: &'static str, frames: Vec<Frame>) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut line = String::new();
+            BufReader::new(&stream).read_line(&mut line).unwrap();
+            if line.trim_end() != format!("auth {}", key) {
+                writeln!(&stream, "unauthorized Unknown API key.").unwrap();
+                return;
+            }
+            writeln!(&stream, "authenticated").unwrap();
+            for frame in frames {
+                writeln!(&stream, "{}", serde_json::to_string(&frame).unwrap()).unwrap();
+            }
+        });
+        TcpStream::connect(addr).unwrap()
+    }
+
+    fn follow_once(stream: TcpStream, key: &str, configure: &Configure) -> (Result<()>, AccountTransactions) {
+        let (result, engine, _) = follow_announced(stream, key, configure);
+        (result, engine)
+    }
+
+    // Also returns what was announced, as (tx, whether an account changed, whether the message applied)
+    fn follow_announced(stream: TcpStream, key: &str, configure: &Configure)
+                        -> (Result<()>, AccountTransactions, Vec<(u32, bool, bool)>) {
+        let (engine, store, status) = (Mutex::new(AccountTransactions::new()), Mutex::new(None), Mutex::default());
+        let announced = Mutex::new(RefCell::new(Vec::new()));
+        let announce = |msg: &TransactionMessage, change: Option<&AccountChange>, result: &Result<()>| {
+            announced.lock().unwrap().borrow_mut().push((msg.tx, change.is_some(), result.is_ok()));
+        };
+        let result = apply_stream(stream, Some(key), configure, &engine, &store, &status, &announce);
+        (result, engine.into_inner().unwrap(), announced.into_inner().unwrap().into_inner())
+    }
+
+    #[test]
+    fn restores_snapshots_with_the_configured_policies() {
+        let mut source = AccountTransactions::new();
+        apply(&mut source, message(TransactionType::DEPOSIT, 1, 1, units(100))).unwrap();
+        let mut withdrawal = message(TransactionType::WITHDRAWAL, 1, 2, units(60));
+        withdrawal.tx_time = source.next_tx_time();
+        let stream = primary("rk_1", vec![Frame::Snapshot(Payload::capture(&source)), Frame::Message(withdrawal)]);
+        let configure: Configure = Arc::new(|account_txs: &mut AccountTransactions| {
+            account_txs.set_approval_threshold(Some(units(50)))
+        });
+
+        let (result, standby, announced) = follow_announced(stream, "rk_1", &configure);
+        result.unwrap();
+        assert_eq!(standby.get_account(1).map(|account| account.available), Some(units(40)));
+        assert_eq!(standby.pending_withdrawals().count(), 1);
+        assert_eq!(announced, vec![(2, true, true)]);
+    }
+
+    #[test]
+    fn applies_rejections_the_primary_also_made() {
+        let mut overdraw = message(TransactionType::WITHDRAWAL, 1, 1, units(5));
+        overdraw.tx_time = 1;
+        let stream = primary("rk_1", vec![Frame::Snapshot(Payload::capture(&AccountTransactions::new())),
+                                          Frame::Message(overdraw)]);
+        let (result, standby, announced) =
+            follow_announced(stream, "rk_1", &(Arc::new(|_: &mut AccountTransactions| {}) as Configure));
+        result.unwrap();
+        assert_eq!(standby.get_account(1).map_or(0, |account| account.total), 0);
+        assert_eq!(announced, vec![(1, true, false)]);
+    }
+
+    #[test]
+    fn applies_admin_adjustments() {
+        let mut source = AccountTransactions::new();
+        apply(&mut source, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        let adjust = AdminAction::Adjust { client: 1, amount: -25_000 };
+        let stream = primary("rk_1", vec![Frame::Snapshot(Payload::capture(&source)), Frame::Admin(adjust)]);
+        let (result, standby) = follow_once(stream, "rk_1", &(Arc::new(|_: &mut AccountTransactions| {}) as Configure));
+        result.unwrap();
+        assert_eq!(standby.get_account(1).map(|account| account.available), Some(75_000));
+    }
+
+    #[test]
+    fn fails_when_the_primary_refuses_the_key() {
+        let stream = primary("rk_1", Vec::new());
+        let (result, _) = follow_once(stream, "rk_2", &(Arc::new(|_: &mut AccountTransactions| {}) as Configure));
+        assert!(matches!(result, Err(Error::ConfigError(_))));
+    }
+}
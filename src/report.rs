@@ -0,0 +1,325 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::{format_amount, AccountTransactions, Amount, Error, Result, TransactionType};
+
+// A CSV a run writes beside its accounts, asked for with --report <name> and written to <name>.csv
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Report {
+    // Fees posted for chargebacks, with what the client paid and what the loss account absorbed
+    ChargebackFees,
+    // Messages applied this run and their amounts, by client and type
+    ClientVolume,
+    // How old the open disputes are, how many are stale, and how long resolved ones stayed open
+    DisputeAging,
+    // Transactions still disputed, oldest dispute first, so stale ones can be chased
+    Disputes,
+    // Transactions under the fee schedules and the fees they paid, by client, type and period
+    FeeUsage,
+    // Funds held across all accounts, by client and by the size of the transaction holding them
+    Exposure,
+    // Interest posted this run, one row per accrual
+    Interest,
+    // Locked accounts with the chargeback that locked each one
+    Locked,
+    // Clients with a deficit outstanding from chargebacks, and what has been recovered so far
+    Recoveries,
+    // What fees and interest dropped past the subunit, held in the rounding account
+    Rounding,
+    // Control figures summed over every account, to tie the run out against the source system
+    Totals,
+    // Messages applied this run and their amounts, by type
+    Volume,
+}
+
+const REPORTS: &[Report] = &[Report::ChargebackFees, Report::ClientVolume, Report::DisputeAging, Report::Disputes, Report::Exposure, Report::FeeUsage, Report::Interest, Report::Locked, Report::Recoveries,
+                                 Report::Rounding, Report::Totals, Report::Volume];
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Report::ChargebackFees => "chargeback-fees",
+            Report::ClientVolume => "client-volume",
+            Report::DisputeAging => "dispute-aging",
+            Report::Disputes => "disputes",
+            Report::Exposure => "exposure",
+            Report::FeeUsage => "fee-usage",
+            Report::Interest => "interest",
+            Report::Locked => "locked",
+            Report::Recoveries => "recoveries",
+            Report::Rounding => "rounding",
+            Report::Totals => "totals",
+            Report::Volume => "volume",
+        })
+    }
+}
+
+impl FromStr for Report {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Report> {
+        REPORTS.iter().copied().find(|report| report.to_string() == s).ok_or_else(|| {
+            let names: Vec<String> = REPORTS.iter().map(Report::to_string).collect();
+            Error::InvalidArgument(format!("Unknown report, {}. Expected one of {}.", s, names.join(", ")))
+        })
+    }
+}
+
+// Settings the reports take from the command line
+#[derive(Debug, Clone, Default)]
+pub struct ReportOptions {
+    // Open disputes at least this many rows old are flagged stale
+    pub stale_dispute_age: Option<u32>,
+}
+
+impl Report {
+    pub fn file_name(&self) -> String {
+        format!("{}.csv", self)
+    }
+
+    // The report's CSV for the engine as it stands, amounts in currency units
+    pub fn write(&self, account_txs: &AccountTransactions, options: &ReportOptions) -> Result<Vec<u8>> {
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        match self {
+            Report::ChargebackFees => chargeback_fees(&mut wtr, account_txs)?,
+            Report::ClientVolume => client_volume(&mut wtr, account_txs)?,
+            Report::DisputeAging => dispute_aging(&mut wtr, account_txs, options)?,
+            Report::Disputes => disputes(&mut wtr, account_txs, options)?,
+            Report::Exposure => exposure(&mut wtr, account_txs)?,
+            Report::FeeUsage => fee_usage(&mut wtr, account_txs)?,
+            Report::Interest => interest(&mut wtr, account_txs)?,
+            Report::Locked => locked(&mut wtr, account_txs)?,
+            Report::Recoveries => recoveries(&mut wtr, account_txs)?,
+            Report::Rounding => rounding(&mut wtr, account_txs)?,
+            Report::Totals => totals(&mut wtr, account_txs)?,
+            Report::Volume => volume(&mut wtr, account_txs)?,
+        }
+        Ok(wtr.into_inner().map_err(|e| io::Error::other(e.to_string()))?)
+    }
+}
+
+// One row per open dispute. age is how many rows ago the dispute opened, counting from the last row handled, and
+// dispute_row, age and stale are empty for disputes from state written before disputes recorded when they were opened,
+// and stale is empty for all of them without a stale age.
+fn disputes(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions, options: &ReportOptions)
+            -> Result<()> {
+    wtr.write_record(["client", "tx", "type", "amount", "dispute_row", "age", "stale"])?;
+    let now = account_txs.next_tx_time() - 1;
+    let mut disputed: Vec<_> = account_txs.open_disputes().collect();
+    // Unrecorded dispute times sort last, after every dispute whose age is known
+    disputed.sort_by_key(|tx| (tx.dispute_time == 0, tx.dispute_time, tx.tx));
+    for tx in disputed {
+        let (dispute_row, age, stale) = match tx.dispute_time {
+            0 => (String::new(), String::new(), String::new()),
+            time => {
+                let age = now.saturating_sub(time);
+                let stale = options.stale_dispute_age.map(|stale| (age >= stale).to_string()).unwrap_or_default();
+                (time.to_string(), age.to_string(), stale)
+            }
+        };
+        wtr.write_record([tx.client.to_string(), tx.tx.to_string(), tx.tx_type.to_string(),
+                          format_amount(tx.amount), dispute_row, age, stale])?;
+    }
+    Ok(())
+}
+
+// One row. Ages are in rows and cover the open disputes whose dispute row was recorded, counted in aged, with the
+// percentiles taken by nearest rank. stale is empty without a stale age, and the age columns without aged disputes.
+fn dispute_aging(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions, options: &ReportOptions)
+                 -> Result<()> {
+    wtr.write_record(["open", "aged", "p50_age", "p90_age", "max_age", "mean_age", "stale", "resolved",
+                      "mean_rows_to_resolve"])?;
+    let ages = account_txs.open_dispute_ages();
+    let percentile = |p: usize| match ages.len() {
+        0 => String::new(),
+        len => ages[(len * p).div_ceil(100).max(1) - 1].to_string(),
+    };
+    let mean_age = match ages.len() {
+        0 => String::new(),
+        len => (ages.iter().map(|&age| u64::from(age)).sum::<u64>() as f64 / len as f64).to_string(),
+    };
+    let stale = options.stale_dispute_age
+        .map(|stale| ages.iter().filter(|&&age| age >= stale).count().to_string())
+        .unwrap_or_default();
+    let resolutions = account_txs.resolutions();
+    wtr.write_record([account_txs.open_disputes().count().to_string(), ages.len().to_string(), percentile(50),
+                      percentile(90), percentile(100), mean_age, stale, resolutions.resolved.to_string(),
+                      resolutions.mean_rows().map(|rows| rows.to_string()).unwrap_or_default()])?;
+    Ok(())
+}
+
+// Upper bounds in currency units of the transaction sizes exposure groups held funds by, the last bucket open ended
+const SIZE_BUCKETS: &[Amount] = &[1, 10, 100, 1_000, 10_000];
+
+// A total row over all accounts, then a row per client holding funds in client order, then a row per size bucket
+// holding funds, smallest first. Funds are held by open disputes and by withdrawals waiting for approval, and
+// transactions counts those. The total and client rows take held from the accounts, so they include funds whose
+// pending withdrawal was pruned, which no size bucket has. Disputes opened with provisional credit hold nothing and
+// get a provisional row of their own after the total, when there are any.
+fn exposure(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["breakdown", "key", "transactions", "held"])?;
+    let holding: Vec<_> = account_txs.iter_transactions()
+        .filter(|tx| (tx.disputed() && !tx.provisional) || (tx.pending && tx.tx_type == TransactionType::WITHDRAWAL))
+        .collect();
+    let held: Amount = account_txs.iter_accounts().map(|acct| acct.held).sum();
+    wtr.write_record(["total".to_string(), String::new(), holding.len().to_string(),
+                      format_amount(held)])?;
+    let (provisional, provisional_amount) = account_txs.provisional_credit();
+    if provisional > 0 {
+        wtr.write_record(["provisional".to_string(), String::new(), provisional.to_string(),
+                          format_amount(provisional_amount)])?;
+    }
+
+    let mut clients: Vec<_> = account_txs.iter_accounts().filter(|acct| acct.held > 0).collect();
+    clients.sort_by_key(|acct| acct.client);
+    for acct in clients {
+        let transactions = holding.iter().filter(|tx| tx.client == acct.client).count();
+        wtr.write_record(["client".to_string(), acct.client.to_string(), transactions.to_string(),
+                          format_amount(acct.held)])?;
+    }
+
+    let mut buckets = vec![(0, 0); SIZE_BUCKETS.len() + 1];
+    for tx in &holding {
+        let bucket = SIZE_BUCKETS.iter().position(|&bound| tx.amount < bound * 10_000).unwrap_or(SIZE_BUCKETS.len());
+        buckets[bucket].0 += 1;
+        buckets[bucket].1 += tx.amount;
+    }
+    for (bucket, (transactions, held)) in buckets.into_iter().enumerate().filter(|(_, (count, _))| *count > 0) {
+        let from = if bucket == 0 { 0 } else { SIZE_BUCKETS[bucket - 1] };
+        let key = match SIZE_BUCKETS.get(bucket) {
+            Some(to) => format!("{}-{}", from, to),
+            None => format!("{}+", from),
+        };
+        wtr.write_record(["size".to_string(), key, transactions.to_string(), format_amount(held)])?;
+    }
+    Ok(())
+}
+
+// One row per locked account in client order. The chargeback is the first one recorded against the client's
+// transactions, and its columns are empty for accounts locked before chargebacks were recorded or whose charged back
+// transaction was pruned. deposit_row and chargeback_row are the tx_times of the two rows.
+fn locked(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["client", "tx", "type", "amount", "deposit_row", "chargeback_row", "available", "held", "escrow",
+                      "total"])?;
+    let mut accounts: Vec<_> = account_txs.iter_accounts().filter(|acct| acct.locked).collect();
+    accounts.sort_by_key(|acct| acct.client);
+    for acct in accounts {
+        let charged_back = account_txs.client_transactions(acct.client)
+            .filter(|tx| tx.chargeback_time != 0)
+            .min_by_key(|tx| (tx.chargeback_time, tx.tx));
+        let chargeback = match charged_back {
+            Some(tx) => [tx.tx.to_string(), tx.tx_type.to_string(), format_amount(tx.amount),
+                         tx.tx_time.to_string(), tx.chargeback_time.to_string()],
+            None => Default::default(),
+        };
+        let mut record = vec![acct.client.to_string()];
+        record.extend(chargeback);
+        let balances = [acct.available, acct.held, acct.escrow, acct.total];
+        record.extend(balances.map(format_amount));
+        wtr.write_record(&record)?;
+    }
+    Ok(())
+}
+
+// One row for all accounts, whether or not the run's output filters list them
+fn totals(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["accounts", "locked", "available", "held", "escrow", "total"])?;
+    let (mut locked, mut available, mut held, mut escrow, mut total) = (0, 0, 0, 0, 0);
+    for acct in account_txs.iter_accounts() {
+        locked += acct.locked as usize;
+        available += acct.available;
+        held += acct.held;
+        escrow += acct.escrow;
+        total += acct.total;
+    }
+    let mut record = vec![account_txs.account_count().to_string(), locked.to_string()];
+    record.extend([available, held, escrow, total].map(format_amount));
+    wtr.write_record(&record)?;
+    Ok(())
+}
+
+// One row per client, type and period in that order, for the transactions applied this run. period_start is the
+// first row of the period, 1 for a schedule without periods.
+fn fee_usage(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["client", "type", "period", "period_start", "transactions", "fees"])?;
+    let schedules = account_txs.fee_schedules();
+    for ((client, tx_type, period), usage) in schedules.usage() {
+        let rows = schedules.schedule(tx_type).and_then(|schedule| schedule.period_rows).unwrap_or(0);
+        wtr.write_record([client.to_string(), tx_type.to_string(), period.to_string(),
+                          (u64::from(*period) * u64::from(rows) + 1).to_string(), usage.count.to_string(),
+                          format_amount(usage.fees)])?;
+    }
+    Ok(())
+}
+
+// One row per accrual in the order they were posted, period being the one that ended, numbered from 0
+fn interest(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["client", "account_type", "period", "amount"])?;
+    for accrual in account_txs.accruals() {
+        wtr.write_record([accrual.client.to_string(), accrual.account_type.clone(), accrual.period.to_string(),
+                          format_amount(accrual.amount)])?;
+    }
+    Ok(())
+}
+
+// One row per client with a deficit outstanding, in client order. since is the row of the oldest chargeback still
+// being recovered, and recovered what deposits have swept against the outstanding chargebacks.
+fn recoveries(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["client", "transactions", "deficit", "recovered", "since", "locked"])?;
+    for recovery in account_txs.recoveries() {
+        let locked = account_txs.get_account(recovery.client).is_some_and(|acct| acct.locked);
+        wtr.write_record([recovery.client.to_string(), recovery.transactions.to_string(),
+                          format_amount(recovery.deficit), format_amount(recovery.recovered),
+                          recovery.since.to_string(), locked.to_string()])?;
+    }
+    Ok(())
+}
+
+// One row each for fees, interest and the net balance of the rounding account. subunits is the whole subunits dropped,
+// in currency units, and carry_ppm what is left past them in millionths of a subunit. The net row is negative when
+// the fee remainders are the larger.
+fn rounding(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["source", "inexact", "subunits", "carry_ppm"])?;
+    let account = account_txs.rounding_account();
+    for (source, remainder) in [("fees", account.fees), ("interest", account.interest)] {
+        wtr.write_record([source.to_string(), remainder.inexact.to_string(),
+                          format_amount(remainder.subunits()), remainder.carry().to_string()])?;
+    }
+    let balance = account.balance_ppm();
+    let sign = if balance < 0 { "-" } else { "" };
+    let (subunits, carry) = (balance.unsigned_abs() / 1_000_000, balance.unsigned_abs() % 1_000_000);
+    wtr.write_record([String::from("net"), (account.fees.inexact + account.interest.inexact).to_string(),
+                      format!("{}{}", sign, format_amount(subunits as Amount)), format!("{}{}", sign, carry)])?;
+    Ok(())
+}
+
+// One row per fee in the order of the chargebacks, chargeback_row being the chargeback's tx_time. loss is the part of
+// the fee the loss account absorbed.
+fn chargeback_fees(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["client", "tx", "chargeback_row", "client_paid", "loss"])?;
+    for fee in account_txs.assessed_fees() {
+        wtr.write_record([fee.client.to_string(), fee.tx.to_string(), fee.tx_time.to_string(),
+                          format_amount(fee.client_paid), format_amount(fee.loss)])?;
+    }
+    Ok(())
+}
+
+// One row per type that applied this run, in the order of TransactionType
+fn volume(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["type", "count", "amount"])?;
+    for (tx_type, volume) in account_txs.type_volumes() {
+        wtr.write_record([tx_type.to_string(), volume.count.to_string(), format_amount(volume.amount)])?;
+    }
+    Ok(())
+}
+
+// One row per client and type that applied this run, in that order
+fn client_volume(wtr: &mut csv::Writer<Vec<u8>>, account_txs: &AccountTransactions) -> Result<()> {
+    wtr.write_record(["client", "type", "count", "amount"])?;
+    for ((client, tx_type), volume) in account_txs.volumes() {
+        wtr.write_record([client.to_string(), tx_type.to_string(), volume.count.to_string(),
+                          format_amount(volume.amount)])?;
+    }
+    Ok(())
+}
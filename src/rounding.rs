@@ -0,0 +1,285 @@
+use std::convert::TryFrom;
+use std::fmt;
+
+#[cfg(feature = "io")]
+use serde::{Deserialize, Serialize};
+
+use crate::error::Rejection;
+use crate::{rejected, AccountTransactions, Amount, Error, Result};
+
+// How an amount with more than four decimal places becomes whole subunits
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum RoundingMode {
+    // Drops the extra places
+    #[default]
+    Truncate,
+    // Rounds to the nearest subunit, halves away from zero
+    HalfUp,
+    // Rounds to the nearest subunit, halves to the even one
+    HalfEven,
+    // Refuses the amount
+    RejectInexact,
+}
+
+const MODES: &[RoundingMode] = &[RoundingMode::Truncate, RoundingMode::HalfUp, RoundingMode::HalfEven,
+                                 RoundingMode::RejectInexact];
+
+impl fmt::Display for RoundingMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RoundingMode::Truncate => "truncate",
+            RoundingMode::HalfUp => "half-up",
+            RoundingMode::HalfEven => "half-even",
+            RoundingMode::RejectInexact => "reject-inexact",
+        })
+    }
+}
+
+impl std::str::FromStr for RoundingMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<RoundingMode> {
+        MODES.iter().copied().find(|mode| mode.to_string() == s).ok_or_else(|| {
+            let names: Vec<String> = MODES.iter().map(RoundingMode::to_string).collect();
+            Error::ParseError(format!("'{}' is not a rounding mode, expected one of {}", s, names.join(", ")))
+        })
+    }
+}
+
+// Parses a decimal amount in currency units into subunits, exactly, rounding the places past the fourth by mode.
+// Forms a float parses but that are not plain decimals, such as 1e3, are read as the shortest decimal of that float.
+pub fn parse_subunits(amount_unit: &str, mode: RoundingMode) -> Result<Amount> {
+    let trimmed = amount_unit.trim();
+    let out_of_range = || Error::ParseError(format!("'{}' is out of range for an amount", amount_unit));
+    let decimal;
+    let (whole, fraction) = match split_decimal(trimmed) {
+        Some(parts) => parts,
+        None => {
+            let value: f64 = trimmed.parse()
+                .map_err(|_| Error::ParseError(format!("'{}' is not a valid amount", amount_unit)))?;
+            if !value.is_finite() || value < 0.0 {
+                return Err(out_of_range());
+            }
+            decimal = format!("{}", value.abs());
+            split_decimal(&decimal).ok_or_else(out_of_range)?
+        }
+    };
+    let digits = |text: &str| text.bytes().try_fold(0, |n: Amount, digit| {
+        n.checked_mul(10).and_then(|n| n.checked_add(Amount::from(digit - b'0')))
+    });
+    let (places, rest) = fraction.split_at(fraction.len().min(4));
+    let subunits = digits(whole).and_then(|whole| whole.checked_mul(10_000))
+        .and_then(|whole| whole.checked_add(digits(places)? * Amount::pow(10, 4 - places.len() as u32)))
+        .ok_or_else(out_of_range)?;
+    if rest.bytes().all(|digit| digit == b'0') {
+        return Ok(subunits);
+    }
+    let first = rest.as_bytes()[0];
+    let past_half = rest[1..].bytes().any(|digit| digit != b'0');
+    let round_up = match mode {
+        RoundingMode::Truncate => false,
+        RoundingMode::HalfUp => first >= b'5',
+        RoundingMode::HalfEven => first > b'5' || (first == b'5' && (past_half || subunits % 2 == 1)),
+        RoundingMode::RejectInexact => return Err(Error::ParseError(format!(
+            "'{}' has more than four decimal places", amount_unit))),
+    };
+    if round_up { subunits.checked_add(1).ok_or_else(out_of_range) } else { Ok(subunits) }
+}
+
+// The digits before and after the point of a plain decimal such as 12, 12.5 or .5, with an optional leading +
+fn split_decimal(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_prefix('+').unwrap_or(text);
+    let (whole, fraction) = text.split_once('.').unwrap_or((text, ""));
+    let all_digits = |part: &str| part.bytes().all(|digit| digit.is_ascii_digit());
+    (!(whole.is_empty() && fraction.is_empty()) && all_digits(whole) && all_digits(fraction)).then_some((whole, fraction))
+}
+
+// Applies a rate in parts per million to an amount in subunits, rounded down, with what was dropped in millionths of
+// a subunit. Refused as amount_overflow when the result would not fit in an amount.
+#[cfg_attr(feature = "amount128", allow(clippy::useless_conversion))]
+pub fn apply_rate(amount: Amount, rate_ppm: u64) -> Result<(Amount, u64)> {
+    let exact = u128::from(amount).checked_mul(u128::from(rate_ppm));
+    let applied = exact.and_then(|exact| Some((Amount::try_from(exact / 1_000_000).ok()?, (exact % 1_000_000) as u64)));
+    applied.ok_or_else(|| rejected(Rejection::AmountOverflow, format!(
+        "{} subunits at {} parts per million is out of range for an amount.", amount, rate_ppm)))
+}
+
+// What the derived amounts of one kind dropped past the subunit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Remainder {
+    // Derived amounts that dropped anything
+    pub inexact: u64,
+    // In millionths of a subunit
+    pub dropped_ppm: u128,
+}
+
+impl Remainder {
+    pub(crate) fn add(&mut self, dropped_ppm: u64) {
+        if dropped_ppm > 0 {
+            self.inexact += 1;
+            self.dropped_ppm += u128::from(dropped_ppm);
+        }
+    }
+
+    // The whole subunits dropped so far
+    pub fn subunits(&self) -> Amount {
+        (self.dropped_ppm / 1_000_000) as Amount
+    }
+
+    // What is left past the whole subunits, in millionths of a subunit
+    pub fn carry(&self) -> u64 {
+        (self.dropped_ppm % 1_000_000) as u64
+    }
+}
+
+// The system account the remainders of derived amounts go to. Fees dropped are fee income the account gives up,
+// interest dropped is interest it keeps, so its balance is the interest remainders less the fee remainders. It is kept
+// with the state like any other account.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RoundingAccount {
+    pub fees: Remainder,
+    pub interest: Remainder,
+}
+
+impl RoundingAccount {
+    // In millionths of a subunit, negative when fee remainders are the larger
+    pub fn balance_ppm(&self) -> i128 {
+        self.interest.dropped_ppm as i128 - self.fees.dropped_ppm as i128
+    }
+}
+
+// One remainder of the rounding account, as the state files and stores keep it, in whole subunits and the millionths
+// past them since JSON numbers stop short of u128
+#[cfg(feature = "io")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RemainderRecord {
+    pub(crate) kind: String,
+    pub(crate) inexact: u64,
+    pub(crate) subunits: Amount,
+    pub(crate) carry_ppm: u64,
+}
+
+#[cfg(feature = "io")]
+impl RoundingAccount {
+    pub(crate) fn records(&self) -> Vec<RemainderRecord> {
+        [("fees", &self.fees), ("interest", &self.interest)].iter()
+            .map(|(kind, remainder)| RemainderRecord { kind: kind.to_string(), inexact: remainder.inexact,
+                                                       subunits: remainder.subunits(), carry_ppm: remainder.carry() })
+            .collect()
+    }
+
+    // Remainders of kinds this release does not know are refused rather than dropped
+    #[cfg_attr(feature = "amount128", allow(clippy::useless_conversion))]
+    pub(crate) fn restore(&mut self, records: Vec<RemainderRecord>) -> Result<()> {
+        for record in records {
+            let remainder = match record.kind.as_str() {
+                "fees" => &mut self.fees,
+                "interest" => &mut self.interest,
+                kind => return Err(Error::StorageError(format!("Unknown rounding account remainder, {}.", kind))),
+            };
+            let dropped_ppm = u128::from(record.subunits) * 1_000_000 + u128::from(record.carry_ppm);
+            *remainder = Remainder { inexact: record.inexact, dropped_ppm };
+        }
+        Ok(())
+    }
+}
+
+impl AccountTransactions {
+    // Amounts in rows read from here on, from any source, round past the fourth place by mode
+    pub fn set_rounding_mode(&mut self, mode: RoundingMode) {
+        self.rounding_mode = mode;
+    }
+
+    pub fn rounding_mode(&self) -> RoundingMode {
+        self.rounding_mode
+    }
+
+    // The remainders of the fees and interest derived on these books
+    pub fn rounding_account(&self) -> &RoundingAccount {
+        &self.rounding
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_exact_decimals_and_rounds_past_the_fourth_place_by_mode() {
+        assert_eq!(parse_subunits("1.5", RoundingMode::RejectInexact).unwrap(), 15_000);
+        assert_eq!(parse_subunits(" +.25 ", RoundingMode::Truncate).unwrap(), 2_500);
+        assert_eq!(parse_subunits("1.00000", RoundingMode::RejectInexact).unwrap(), 10_000);
+        assert_eq!(parse_subunits("1e2", RoundingMode::Truncate).unwrap(), 1_000_000);
+        assert_eq!(parse_subunits("0.00019", RoundingMode::Truncate).unwrap(), 1);
+        assert_eq!(parse_subunits("0.00015", RoundingMode::HalfUp).unwrap(), 2);
+        assert_eq!(parse_subunits("0.00025", RoundingMode::HalfEven).unwrap(), 2);
+        assert_eq!(parse_subunits("0.00015", RoundingMode::HalfEven).unwrap(), 2);
+        assert_eq!(parse_subunits("0.000251", RoundingMode::HalfEven).unwrap(), 3);
+        assert!(parse_subunits("0.00001", RoundingMode::RejectInexact).is_err());
+    }
+
+    #[test]
+    fn refuses_amounts_that_are_not_in_range_decimals() {
+        for amount in ["", "-1", "abc", "1.2.3", "inf", "99999999999999999999999999999999999999999"] {
+            assert!(parse_subunits(amount, RoundingMode::Truncate).is_err(), "{}", amount);
+        }
+    }
+
+    #[test]
+    fn modes_round_trip_through_their_names() {
+        for mode in MODES {
+            assert_eq!(mode.to_string().parse::<RoundingMode>().unwrap(), *mode);
+        }
+        assert!("nearest".parse::<RoundingMode>().is_err());
+    }
+
+    #[test]
+    fn apply_rate_rounds_down_and_refuses_overflow() {
+        assert_eq!(apply_rate(12_345, 10_000).unwrap(), (123, 450_000));
+        assert_eq!(apply_rate(0, u64::MAX).unwrap(), (0, 0));
+        assert!(matches!(apply_rate(Amount::MAX, 2_000_000),
+                         Err(Error::PolicyViolation { reason: Rejection::AmountOverflow, .. })));
+    }
+
+    #[test]
+    fn remainders_carry_whole_subunits_and_count_inexact_amounts() {
+        let mut remainder = Remainder::default();
+        remainder.add(0);
+        remainder.add(600_000);
+        remainder.add(700_000);
+        assert_eq!((remainder.inexact, remainder.subunits(), remainder.carry()), (2, 1, 300_000));
+        let account = RoundingAccount { fees: remainder, interest: Remainder::default() };
+        assert_eq!(account.balance_ppm(), -1_300_000);
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn rounding_account_round_trips_through_its_records() {
+        let mut account = RoundingAccount::default();
+        account.fees.add(999_999);
+        account.interest.add(1);
+        let mut restored = RoundingAccount::default();
+        restored.restore(account.records()).unwrap();
+        assert_eq!(restored, account);
+
+        let unknown = RemainderRecord { kind: String::from("tax"), inexact: 1, subunits: 0, carry_ppm: 1 };
+        assert!(restored.restore(vec![unknown]).is_err());
+    }
+
+    #[cfg(feature = "io")]
+    #[test]
+    fn each_engine_reads_rows_by_its_own_mode() {
+        use crate::source::CsvSource;
+        use crate::{read_source, AccountTransactions, ReadOptions};
+        let rows = "type,client,tx,amount\ndeposit,1,1,1.00005\n";
+        let read = |mode: RoundingMode| {
+            let mut account_txs = AccountTransactions::new();
+            account_txs.set_rounding_mode(mode);
+            let mut source = CsvSource::new("rows", rows.as_bytes()).unwrap();
+            read_source(&mut source, &mut account_txs, &ReadOptions::default(), None).unwrap();
+            account_txs.get_account(1).unwrap().available
+        };
+        assert_eq!(read(RoundingMode::HalfUp), 10_001);
+        assert_eq!(read(RoundingMode::Truncate), 10_000);
+    }
+}
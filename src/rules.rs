@@ -0,0 +1,137 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::str::FromStr;
+
+use serde::Deserialize;
+
+use crate::config::RulesConfig;
+use crate::hooks::{Validator, Verdict};
+use crate::{to_subunit, Account, Amount, Error, Result, TransactionMessage, TransactionType};
+
+// A policies file, e.g.
+//   verified_clients = [1, 2, 3]
+//
+//   [[rule]]
+//   name = "large-unverified-withdrawal"
+//   types = ["withdraw"]
+//   amount_over = 10000.0
+//   verified = false
+//   action = "reject"
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    #[serde(default)]
+    verified_clients: HashSet<u16>,
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleFile>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleFile {
+    name: String,
+    action: RuleAction,
+    reason: Option<String>,
+    #[serde(default)]
+    types: Vec<String>,
+    amount_over: Option<f64>,
+    verified: Option<bool>,
+    disputes_over: Option<u32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RuleAction {
+    // Veto the message
+    Reject,
+    // Let it through, logging it and annotating its account change with the rule's name
+    Flag,
+}
+
+// A rule matches a message when every condition it sets holds
+#[derive(Debug)]
+struct Rule {
+    name: String,
+    action: RuleAction,
+    // Includes the rule's name
+    reason: String,
+    types: Vec<TransactionType>,
+    amount_over: Option<Amount>,
+    verified: Option<bool>,
+    disputes_over: Option<u32>,
+}
+
+// Evaluates a rules file against each message, in the order the rules are listed. The first reject wins, every
+// matching flag is reported. Disputes are counted per client from the messages this engine has seen.
+#[derive(Debug)]
+pub struct RulesEngine {
+    name: String,
+    verified_clients: HashSet<u16>,
+    rules: Vec<Rule>,
+    disputes: HashMap<u16, u32>,
+}
+
+impl RulesEngine {
+    pub fn load(config: &RulesConfig) -> Result<RulesEngine> {
+        let failed = |e: &dyn std::fmt::Display| Error::ConfigError(format!("{}: {}", config.path, e));
+        let text = fs::read_to_string(&config.path).map_err(|e| failed(&e))?;
+        let file: RulesFile = toml::from_str(&text).map_err(|e| failed(&e))?;
+        let rules = file.rules.into_iter().map(|rule| {
+            let types = rule.types.iter()
+                .map(|name| TransactionType::from_str(name))
+                .collect::<Result<Vec<TransactionType>>>()
+                .map_err(|e| failed(&format!("rule {}: {}", rule.name, e)))?;
+            let amount_over = rule.amount_over
+                .map(|amount| to_subunit(&amount.to_string()))
+                .transpose()
+                .map_err(|e| failed(&format!("rule {}: {}", rule.name, e)))?;
+            let reason = match &rule.reason {
+                Some(reason) => format!("{} (rule {})", reason, rule.name),
+                None => format!("Matched rule {}.", rule.name),
+            };
+            Ok(Rule {
+                reason,
+                name: rule.name,
+                action: rule.action,
+                types,
+                amount_over,
+                verified: rule.verified,
+                disputes_over: rule.disputes_over,
+            })
+        }).collect::<Result<Vec<Rule>>>()?;
+        Ok(RulesEngine { name: String::from("rules"), verified_clients: file.verified_clients, rules, disputes: HashMap::new() })
+    }
+
+    fn matches(&self, rule: &Rule, msg: &TransactionMessage) -> bool {
+        (rule.types.is_empty() || rule.types.contains(&msg.tx_type))
+            && rule.amount_over.is_none_or(|over| msg.amount > over)
+            && rule.verified.is_none_or(|verified| self.verified_clients.contains(&msg.client) == verified)
+            && rule.disputes_over.is_none_or(|over| self.disputes.get(&msg.client).copied().unwrap_or(0) > over)
+    }
+}
+
+impl Validator for RulesEngine {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&mut self, msg: &TransactionMessage, _account: Option<&Account>) -> Result<Verdict> {
+        if msg.tx_type == TransactionType::DISPUTE {
+            *self.disputes.entry(msg.client).or_insert(0) += 1;
+        }
+        let mut flags = Vec::new();
+        for rule in self.rules.iter().filter(|rule| self.matches(rule, msg)) {
+            match rule.action {
+                RuleAction::Reject => return Ok(Verdict::Veto(rule.reason.clone())),
+                RuleAction::Flag => {
+                    diag!("Flagged {} transaction, {}, from client, {}. {}", msg.tx_type, msg.tx, msg.client, rule.reason);
+                    flags.push(rule.name.as_str());
+                }
+            }
+        }
+        if flags.is_empty() {
+            return Ok(Verdict::Accept);
+        }
+        Ok(Verdict::Annotate(BTreeMap::from([(String::from("flags"), flags.join(","))])))
+    }
+}
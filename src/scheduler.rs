@@ -0,0 +1,346 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config::ScheduleConfig;
+use crate::hooks::HookConfig;
+use crate::output::write_accounts;
+use crate::state::{open_store, Store};
+use crate::{read_file_with_store, AccountTransactions, Configure, Error, ReadOptions, Result};
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Ok,
+    Failed,
+    // Another run of the same schedule held the lock
+    Skipped,
+}
+
+// One line of <name>.history.jsonl
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RunRecord {
+    pub schedule: String,
+    pub started_at: u64,
+    pub finished_at: u64,
+    pub status: RunStatus,
+    pub files: Vec<FileRun>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FileRun {
+    pub path: String,
+    pub rows: u32,
+    pub applied: u32,
+    pub rejected: u32,
+    pub malformed: u32,
+}
+
+struct Job {
+    config: ScheduleConfig,
+    // Seconds after midnight UTC
+    at: u64,
+    read_options: ReadOptions,
+    hooks: HookConfig,
+    configure: Configure,
+}
+
+// Starts one thread per schedule, each sleeping until its next run. Settings are checked up front so a bad
+// schedule fails serve at startup rather than at 02:00.
+pub fn start(schedules: &[ScheduleConfig], read_options: &ReadOptions, hooks: &HookConfig, configure: &Configure)
+             -> Result<()> {
+    let jobs = schedules.iter().map(|config| {
+        Ok(Job {
+            at: time_of_day(config)?,
+            config: config.clone(),
+            read_options: read_options.clone(),
+            hooks: hooks.clone(),
+            configure: Arc::clone(configure),
+        })
+    }).collect::<Result<Vec<Job>>>()?;
+    if !jobs.is_empty() {
+        hooks.load()?;
+    }
+
+    for job in jobs {
+        diag!("Scheduled {} daily at {} UTC for {}.", job.config.name, job.config.at, job.config.input);
+        thread::spawn(move || loop {
+            let now = now_secs();
+            thread::sleep(Duration::from_secs(next_run(now, job.at) - now));
+            let record = job.run();
+            match &record.error {
+                Some(e) => diag!("Scheduled run {} {:?}. {}", job.config.name, record.status, e),
+                None => diag!("Scheduled run {} processed {} files.", job.config.name, record.files.len()),
+            }
+            if let Err(e) = job.append_history(&record) {
+                diag!("Failed to record scheduled run {}. {}", job.config.name, e);
+            }
+        });
+    }
+    Ok(())
+}
+
+// The first time after now, in seconds since the epoch, that is at seconds after midnight UTC
+fn next_run(now: u64, at: u64) -> u64 {
+    let today = now - now % SECS_PER_DAY + at;
+    if today > now { today } else { today + SECS_PER_DAY }
+}
+
+fn time_of_day(config: &ScheduleConfig) -> Result<u64> {
+    let parsed = config.at.split_once(':')
+        .and_then(|(hours, minutes)| Some((hours.parse::<u64>().ok()?, minutes.parse::<u64>().ok()?)))
+        .filter(|&(hours, minutes)| hours < 24 && minutes < 60);
+    match parsed {
+        Some((hours, minutes)) => Ok(hours * 3600 + minutes * 60),
+        None => Err(Error::ConfigError(format!("Schedule {} has at = \"{}\". Expected HH:MM.", config.name, config.at))),
+    }
+}
+
+impl Job {
+    fn run(&self) -> RunRecord {
+        let mut record = RunRecord {
+            schedule: self.config.name.clone(),
+            started_at: now_secs(),
+            finished_at: 0,
+            status: RunStatus::Ok,
+            files: Vec::new(),
+            error: None,
+        };
+        let result = match self.lock() {
+            Ok(Some(_lock)) => self.process(&mut record.files),
+            Ok(None) => {
+                record.status = RunStatus::Skipped;
+                Err(Error::StorageError(format!("{} is held by another run.", self.lock_path().display())))
+            }
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            if record.status == RunStatus::Ok {
+                record.status = RunStatus::Failed;
+            }
+            record.error = Some(e.to_string());
+        }
+        record.finished_at = now_secs();
+        record
+    }
+
+    // Reads every new input into a fresh engine loaded from the schedule's store, saving the store after each
+    // file so the history's files always match what the store holds
+    fn process(&self, files: &mut Vec<FileRun>) -> Result<()> {
+        let done = self.processed()?;
+        let pending: Vec<PathBuf> = matching_files(&self.config.input)?.into_iter()
+            .filter(|path| !done.contains(&path.display().to_string()))
+            .collect();
+
+        let mut account_txs = AccountTransactions::with_validators(self.hooks.load()?);
+        let mut store = self.config.store.as_deref().map(open_store).transpose()?;
+        if let Some(store) = store.as_mut() {
+            store.load(&mut account_txs)?;
+        }
+        (self.configure)(&mut account_txs);
+        for path in &pending {
+            let filename = path.display().to_string();
+            let recording = store.as_mut().map(|store| store.as_mut() as &mut dyn Store);
+            let report = read_file_with_store(&filename, &mut account_txs, &self.read_options, recording)?;
+            if let Some(store) = store.as_mut() {
+                store.save(&account_txs)?;
+            }
+            files.push(FileRun {
+                path: filename,
+                rows: report.rows,
+                applied: report.applied,
+                rejected: report.rejected,
+                malformed: report.malformed,
+            });
+        }
+
+        if let Some(output) = &self.config.output {
+            let mut out = BufWriter::new(File::create(output)?);
+            write_accounts(&mut out, account_txs.iter_accounts())?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+
+    // Files read to the end by any earlier run, including runs that failed on a later file
+    fn processed(&self) -> Result<HashSet<String>> {
+        let file = match File::open(self.history_path()) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashSet::new()),
+            Err(e) => return Err(e.into()),
+        };
+        let mut done = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let record: RunRecord = serde_json::from_str(&line?)?;
+            done.extend(record.files.into_iter().map(|file| file.path));
+        }
+        Ok(done)
+    }
+
+    fn append_history(&self, record: &RunRecord) -> Result<()> {
+        fs::create_dir_all(&self.config.history_dir)?;
+        let mut file = OpenOptions::new().create(true).append(true).open(self.history_path())?;
+        writeln!(file, "{}", serde_json::to_string(record)?)?;
+        Ok(())
+    }
+
+    // None when the lock file already exists. A lock left behind by a crashed process must be removed by hand.
+    fn lock(&self) -> Result<Option<RunLock>> {
+        fs::create_dir_all(&self.config.history_dir)?;
+        let path = self.lock_path();
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                writeln!(file, "{}", std::process::id())?;
+                Ok(Some(RunLock { path }))
+            }
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn lock_path(&self) -> PathBuf {
+        Path::new(&self.config.history_dir).join(format!("{}.lock", self.config.name))
+    }
+
+    fn history_path(&self) -> PathBuf {
+        Path::new(&self.config.history_dir).join(format!("{}.history.jsonl", self.config.name))
+    }
+}
+
+struct RunLock {
+    path: PathBuf,
+}
+
+impl Drop for RunLock {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            diag!("Failed to remove schedule lock {}. {}", self.path.display(), e);
+        }
+    }
+}
+
+// Files in the pattern's directory whose names match its last component, in name order
+fn matching_files(pattern: &str) -> Result<Vec<PathBuf>> {
+    let pattern = Path::new(pattern);
+    let name_pattern = pattern.file_name().and_then(|name| name.to_str())
+        .ok_or_else(|| Error::ConfigError(format!("Schedule input, {}, names no files.", pattern.display())))?;
+    let dir = match pattern.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let matched = entry.file_name().to_str().is_some_and(|name| wildcard_match(name_pattern.as_bytes(), name.as_bytes()));
+        if matched && entry.file_type()?.is_file() {
+            files.push(entry.path());
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn wildcard_match(pattern: &[u8], name: &[u8]) -> bool {
+    match (pattern.split_first(), name.split_first()) {
+        (None, None) => true,
+        (Some((b'*', rest)), _) => wildcard_match(rest, name) || (!name.is_empty() && wildcard_match(pattern, &name[1..])),
+        (Some((b'?', rest)), Some((_, name_rest))) => wildcard_match(rest, name_rest),
+        (Some((p, rest)), Some((n, name_rest))) if p == n => wildcard_match(rest, name_rest),
+        _ => false,
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn job(name: &str, input: &Path, output: &Path) -> Job {
+        let config = ScheduleConfig {
+            name: name.to_string(),
+            input: input.display().to_string(),
+            at: String::from("02:00"),
+            store: None,
+            output: Some(output.display().to_string()),
+            history_dir: temp_path(&format!("{}-history", name)).display().to_string(),
+        };
+        Job {
+            at: time_of_day(&config).unwrap(),
+            config,
+            read_options: ReadOptions::default(),
+            hooks: HookConfig::default(),
+            configure: Arc::new(|_: &mut AccountTransactions| ()),
+        }
+    }
+
+    #[test]
+    fn next_run_is_the_next_time_of_day() {
+        let day = 20_000 * SECS_PER_DAY;
+        let at = time_of_day(&job("times", Path::new("in.csv"), Path::new("out.csv")).config).unwrap();
+        assert_eq!(at, 2 * 3600);
+        // Before the time it runs today, at or after it tomorrow, including across midnight
+        assert_eq!(next_run(day + 3600, at), day + at);
+        assert_eq!(next_run(day + at, at), day + SECS_PER_DAY + at);
+        assert_eq!(next_run(day + 23 * 3600 + 59 * 60, at), day + SECS_PER_DAY + at);
+        assert_eq!(next_run(day + SECS_PER_DAY - 1, 0), day + SECS_PER_DAY);
+        assert_eq!(next_run(day, 0), day + SECS_PER_DAY);
+    }
+
+    #[test]
+    fn refuses_to_run_while_the_lock_is_held() {
+        let job = job("locked", &temp_path("locked-*.csv"), &temp_path("locked-out.csv"));
+        let held = job.lock().unwrap().unwrap();
+        assert!(job.lock().unwrap().is_none());
+
+        let record = job.run();
+        assert_eq!(record.status, RunStatus::Skipped);
+        assert!(record.error.unwrap().contains("held by another run"));
+        assert!(record.files.is_empty());
+        // The skipped run leaves the holder's lock in place
+        assert!(job.lock_path().exists());
+        drop(held);
+        assert!(!job.lock_path().exists());
+    }
+
+    #[test]
+    fn history_records_each_file_once() {
+        let input = temp_path("history-in-1.csv");
+        fs::write(&input, "type,client,tx,amount\ndeposit,1,1,2.5\nwithdraw,1,2,5\nbogus,1,3,1\n").unwrap();
+        let output = temp_path("history-out.csv");
+        let mut job = job("history", &temp_path("history-in-*.csv"), &output);
+        job.read_options.lenient = true;
+        let _ = fs::remove_dir_all(&job.config.history_dir);
+
+        let record = job.run();
+        assert_eq!((record.schedule.as_str(), record.status, &record.error), ("history", RunStatus::Ok, &None));
+        assert!(record.started_at <= record.finished_at);
+        assert_eq!(record.files.len(), 1);
+        let file = &record.files[0];
+        assert_eq!((file.path.as_str(), file.rows, file.applied, file.rejected, file.malformed),
+                   (input.to_str().unwrap(), 3, 1, 1, 1));
+        job.append_history(&record).unwrap();
+        assert!(fs::read_to_string(&output).unwrap().contains("1,2.5,0,2.5,false"));
+
+        let history = fs::read_to_string(job.history_path()).unwrap();
+        let recorded: RunRecord = serde_json::from_str(history.lines().next().unwrap()).unwrap();
+        assert_eq!(recorded.files[0].path, file.path);
+        // A later run leaves files an earlier one read alone
+        let again = job.run();
+        assert_eq!((again.status, again.files.len()), (RunStatus::Ok, 0));
+        let _ = fs::remove_dir_all(&job.config.history_dir);
+    }
+}
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use rhai::{CallFnOptions, Dynamic, Engine, FuncArgs, Map, Scope, AST};
+
+use crate::config::ScriptConfig;
+use crate::hooks::{Validator, Verdict};
+use crate::{to_currency_unit, Account, Error, Rejection, Result, TransactionMessage};
+
+// Rhai hooks, each optional:
+//   on_transaction(msg, account)  -> () or true to accept, false or a reason string to veto, a map to annotate
+//   on_reject(msg, reason, account)
+//   on_account_update(msg, before, after)
+// msg is a map of type, client, tx and amount, accounts are maps of available, held, escrow, total and locked, with ()
+// for an account that does not exist yet. Amounts are in currency units. print and debug go to the log, since the
+// accounts are written to STDOUT.
+pub struct ScriptHooks {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    scope: Scope<'static>,
+    on_transaction: bool,
+    on_reject: bool,
+    on_account_update: bool,
+}
+
+impl ScriptHooks {
+    pub fn load(config: &ScriptConfig) -> Result<ScriptHooks> {
+        let failed = |e: &dyn std::fmt::Display| Error::PluginError(format!("Failed to load script, {}. {}", config.path, e));
+        let mut engine = Engine::new();
+        engine.set_max_operations(config.max_operations);
+        engine.on_print(|text| diag!("{}", text));
+        engine.on_debug(|text, _, position| diag!("{} {}", position, text));
+        let ast = engine.compile_file(PathBuf::from(&config.path)).map_err(|e| failed(&e))?;
+        // Top-level statements run once, here, rather than before every hook call
+        let mut scope = Scope::new();
+        engine.run_ast_with_scope(&mut scope, &ast).map_err(|e| failed(&e))?;
+        let defines = |hook: &str| ast.iter_functions().any(|function| function.name == hook);
+        Ok(ScriptHooks {
+            name: Path::new(&config.path).file_stem()
+                .map_or_else(|| config.path.clone(), |stem| stem.to_string_lossy().into_owned()),
+            on_transaction: defines("on_transaction"),
+            on_reject: defines("on_reject"),
+            on_account_update: defines("on_account_update"),
+            engine,
+            ast,
+            scope,
+        })
+    }
+
+    fn call(&mut self, hook: &str, msg: &TransactionMessage, args: impl FuncArgs) -> Result<Dynamic> {
+        let options = CallFnOptions::new().eval_ast(false);
+        self.engine.call_fn_with_options(options, &mut self.scope, &self.ast, hook, args)
+            .map_err(|e| Error::PluginError(format!("Script, {}, failed in {} for transaction, {}. {}",
+                                                    self.name, hook, msg.tx, e)))
+    }
+}
+
+impl Validator for ScriptHooks {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&mut self, msg: &TransactionMessage, account: Option<&Account>) -> Result<Verdict> {
+        if !self.on_transaction {
+            return Ok(Verdict::Accept);
+        }
+        let result = self.call("on_transaction", msg, (message_map(msg), account_map(account)))?;
+        if result.is_unit() {
+            return Ok(Verdict::Accept);
+        }
+        if let Ok(accept) = result.as_bool() {
+            return Ok(if accept { Verdict::Accept } else { Verdict::Veto(String::from("Refused by script.")) });
+        }
+        if result.is_string() {
+            return Ok(Verdict::Veto(result.into_string().unwrap_or_default()));
+        }
+        match result.try_cast::<Map>() {
+            Some(notes) if notes.is_empty() => Ok(Verdict::Accept),
+            Some(notes) => Ok(Verdict::Annotate(notes.into_iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect::<BTreeMap<String, String>>())),
+            None => Err(Error::PluginError(format!(
+                "Script, {}, on_transaction returned something other than (), a bool, a string or a map for transaction, {}.",
+                self.name, msg.tx))),
+        }
+    }
+
+    fn rejected(&mut self, msg: &TransactionMessage, reason: Rejection, account: Option<&Account>) -> Result<()> {
+        if self.on_reject {
+            let _ = self.call("on_reject", msg, (message_map(msg), Dynamic::from(reason.code()), account_map(account)))?;
+        }
+        Ok(())
+    }
+
+    fn updated(&mut self, msg: &TransactionMessage, before: Option<&Account>, after: &Account) -> Result<()> {
+        if self.on_account_update {
+            let _ = self.call("on_account_update", msg, (message_map(msg), account_map(before), account_map(Some(after))))?;
+        }
+        Ok(())
+    }
+}
+
+fn message_map(msg: &TransactionMessage) -> Dynamic {
+    let mut map = Map::new();
+    map.insert("type".into(), Dynamic::from(msg.tx_type.to_string()));
+    map.insert("client".into(), Dynamic::from(i64::from(msg.client)));
+    map.insert("tx".into(), Dynamic::from(i64::from(msg.tx)));
+    map.insert("amount".into(), Dynamic::from(to_currency_unit(msg.amount)));
+    Dynamic::from_map(map)
+}
+
+fn account_map(account: Option<&Account>) -> Dynamic {
+    let account = match account {
+        Some(account) => account,
+        None => return Dynamic::UNIT,
+    };
+    let mut map = Map::new();
+    map.insert("client".into(), Dynamic::from(i64::from(account.client)));
+    map.insert("available".into(), Dynamic::from(to_currency_unit(account.available)));
+    map.insert("held".into(), Dynamic::from(to_currency_unit(account.held)));
+    map.insert("escrow".into(), Dynamic::from(to_currency_unit(account.escrow)));
+    map.insert("total".into(), Dynamic::from(to_currency_unit(account.total)));
+    map.insert("locked".into(), Dynamic::from(account.locked));
+    Dynamic::from_map(map)
+}
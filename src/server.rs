@@ -0,0 +1,874 @@
+#[cfg(unix)]
+use std::fs;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{IpAddr, TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(unix)]
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::{Arc, Mutex, TryLockError};
+use std::thread;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::admin::{signed_subunits, AdminAction, AuditLog};
+use crate::change::{AccountChange, AccountState};
+use crate::config::{AdminConfig, ApiKeyConfig, IdempotencyConfig, Permission, RateLimitConfig, RedisConfig, ServeAuth,
+                    ServeConfig, TlsConfig};
+use crate::http::{self, Request};
+use crate::output::write_accounts;
+use crate::config::WebhookConfig;
+use crate::replication::{self, FollowStatus, Replicas};
+use crate::state::Store;
+use crate::sinks::ChangeSinks;
+use crate::read::{read_source_with, RowHandler, RowOutcome};
+use crate::source::{CsvSource, LineSource};
+use crate::{otel, websocket, AccountTransactions, Configure, Error, ReadOptions, Rejection, Result, TransactionMessage};
+
+mod auth;
+#[cfg(feature = "graphql")]
+mod graphql;
+mod rate_limit;
+#[cfg(feature = "tls")]
+mod tls;
+
+use auth::ApiKeys;
+use rate_limit::RateLimits;
+
+// Events a /ws subscriber can hold before it counts as stalled and is dropped
+const FEED_BUFFER: usize = 1024;
+// Longest "auth <key>" line a standby may open with
+const MAX_AUTH_LINE: u64 = 4096;
+// Longest the accepting thread waits to tell a connection over max_connections it is refused
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+enum LineOutcome {
+    Applied(u32),
+    Rejected(Rejection, u32),
+    Replayed(u32),
+    Malformed(String),
+}
+
+// Sent to every /ws subscriber as one JSON text frame each
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum FeedEvent<'a> {
+    Change(&'a AccountChange),
+    Rejection { code: &'a str, message: &'a str, client: u16, tx: u32 },
+}
+
+// Body of a POST to /admin/adjust, /admin/close-dispute, /admin/unlock, /admin/approve or /admin/deny. Each endpoint
+// takes the fields its CLI command does, with amount as a decimal string such as "-2.5".
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AdminRequest {
+    client: Option<u16>,
+    tx: Option<u32>,
+    amount: Option<String>,
+    #[serde(default)]
+    reason: String,
+}
+
+impl AdminRequest {
+    fn action(&self, path: &str) -> Result<AdminAction> {
+        let client = || self.client.ok_or_else(|| Error::InvalidArgument(String::from("Missing client.")));
+        let tx = || self.tx.ok_or_else(|| Error::InvalidArgument(String::from("Missing tx.")));
+        match path {
+            "/admin/adjust" => {
+                let amount = self.amount.as_deref().ok_or_else(|| Error::InvalidArgument(String::from("Missing amount.")))?;
+                Ok(AdminAction::Adjust { client: client()?, amount: signed_subunits(amount)? })
+            }
+            "/admin/close-dispute" => Ok(AdminAction::CloseDispute { tx: tx()? }),
+            "/admin/unlock" => Ok(AdminAction::Unlock { client: client()? }),
+            "/admin/approve" => Ok(AdminAction::Approve { tx: tx()? }),
+            "/admin/deny" => Ok(AdminAction::Deny { tx: tx()? }),
+            _ => Err(Error::NotFound(format!("No admin endpoint at {}.", path))),
+        }
+    }
+}
+
+// The engine shared by every connection. Requests that apply transactions take the lock for the whole body, so
+// the changes a subscriber sees are in the order they were made.
+struct Server {
+    account_txs: Mutex<AccountTransactions>,
+    store: Mutex<Option<Box<dyn Store>>>,
+    subscribers: Mutex<Vec<SyncSender<String>>>,
+    sinks: Mutex<ChangeSinks>,
+    replicas: Replicas,
+    // Primary this instance follows. A standby answers queries but refuses transactions.
+    primary: Option<String>,
+    following: Mutex<FollowStatus>,
+    keys: ApiKeys,
+    limits: RateLimits,
+    // Where the /admin endpoints log their actions. None when no key may use them.
+    audit: Option<Mutex<AuditLog>>,
+    idempotency: IdempotencyConfig,
+    connections: ServeConfig,
+    // Connections being served, counted against connections.max_connections
+    open: Arc<AtomicUsize>,
+    #[cfg(feature = "graphql")]
+    schema: graphql::Schema,
+    #[cfg(feature = "tls")]
+    tls: Option<tls::Acceptor>,
+}
+
+// What serve listens on and where account changes go besides the /ws feed
+#[derive(Debug, Clone, Default)]
+pub struct ServeOptions {
+    pub listen: String,
+    // Standbys connect here to follow every message this instance handles
+    pub replication: Option<String>,
+    // Unix socket accepting newline-delimited records, see ingest_lines
+    pub unix_socket: Option<String>,
+    // TCP address accepting the same records, each answered with an acknowledgement line
+    pub tcp_listen: Option<String>,
+    pub webhooks: Vec<WebhookConfig>,
+    pub redis: Option<RedisConfig>,
+    pub admin: AdminConfig,
+    pub api_keys: Vec<ApiKeyConfig>,
+    // Serve HTTPS instead of HTTP on listen
+    pub tls: Option<TlsConfig>,
+    pub rate_limit: Option<RateLimitConfig>,
+    pub idempotency: IdempotencyConfig,
+    pub serve: ServeConfig,
+}
+
+// Serves the engine over HTTP until the process is stopped:
+//   POST /transactions  CSV rows with the input file's header, applied in order
+//   GET  /accounts      accounts CSV in the same format as the command line output
+//   GET  /ws            WebSocket feed of account changes and rejections
+//   GET  /healthz       200 while the process is serving
+//   GET  /readyz        200 while the store is reachable and, on a standby, once it has synced, otherwise 503
+//   POST /admin/...     manual adjustments, see AdminRequest
+pub fn serve(options: &ServeOptions, account_txs: AccountTransactions, store: Option<Box<dyn Store>>) -> Result<()> {
+    let sinks = ChangeSinks::open(None, None, &options.webhooks, options.redis.as_ref())?;
+    let listener = TcpListener::bind(&options.listen)?;
+    let replication = options.replication.as_deref().map(TcpListener::bind).transpose()?;
+    #[cfg(unix)]
+    let unix_socket = options.unix_socket.as_deref().map(bind_unix).transpose()?;
+    #[cfg(not(unix))]
+    if options.unix_socket.is_some() {
+        return Err(Error::InvalidArgument(String::from("Unix sockets are not available on this platform.")));
+    }
+    let tcp_lines = options.tcp_listen.as_deref().map(TcpListener::bind).transpose()?;
+    let tls = check_tls(options)?;
+    diag!("Listening on {}://{}.", if tls { "https" } else { "http" }, listener.local_addr()?);
+    let keys = ApiKeys::new(&options.api_keys, options.admin.token.as_deref(), check_auth(options)?);
+    let audit = if keys.grant_admin() { Some(Mutex::new(AuditLog::open(&options.admin.audit_log)?)) } else { None };
+    let mut server = Server::new(account_txs, store, sinks, None);
+    server.keys = keys;
+    server.limits = RateLimits::new(options.rate_limit.clone());
+    server.audit = audit;
+    server.idempotency = options.idempotency.clone();
+    server.connections = options.serve.clone();
+    #[cfg(feature = "tls")]
+    { server.tls = options.tls.as_ref().map(tls::Acceptor::load).transpose()?; }
+    let server = Arc::new(server);
+    if let Some(replication) = replication {
+        diag!("Accepting standbys on {}.", replication.local_addr()?);
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.accept_standbys(replication));
+    }
+    #[cfg(unix)]
+    if let Some((listener, path)) = unix_socket {
+        diag!("Accepting transactions on {}.", path);
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = stream.set_read_timeout(server.connections.timeout()) {
+                            diag!("Failed to accept connection on {}. {}", path, e);
+                            continue;
+                        }
+                        let slot = match server.admit() {
+                            Some(slot) => slot,
+                            None => { diag!("Refused connection on {}. Too many connections.", path); continue }
+                        };
+                        let server = Arc::clone(&server);
+                        thread::spawn(move || {
+                            server.ingest_lines(BufReader::new(stream), None::<UnixStream>, None);
+                            drop(slot);
+                        });
+                    }
+                    Err(e) => diag!("Failed to accept connection on {}. {}", path, e),
+                }
+            }
+        });
+    }
+    if let Some(listener) = tcp_lines {
+        diag!("Accepting transaction lines on {}.", listener.local_addr()?);
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let timeout = server.connections.timeout();
+                let streams = stream.and_then(|stream| {
+                    stream.set_read_timeout(timeout)?;
+                    stream.set_write_timeout(timeout)?;
+                    Ok((stream.try_clone()?, stream))
+                });
+                match streams {
+                    Ok((reader, mut writer)) => {
+                        let slot = match server.admit() {
+                            Some(slot) => slot,
+                            None => {
+                                diag!("Refused line connection. Too many connections.");
+                                let _ = writeln!(writer, "unavailable Too many connections. Retry later.");
+                                continue;
+                            }
+                        };
+                        let server = Arc::clone(&server);
+                        thread::spawn(move || {
+                            let peer = writer.peer_addr().map(|addr| addr.ip());
+                            let (mut reader, mut writer) = (BufReader::new(reader), BufWriter::new(writer));
+                            let source = match peer {
+                                Ok(peer) => server.authenticate_lines(&mut reader, &mut writer, peer),
+                                Err(e) => { diag!("Failed to accept line connection. {}", e); None }
+                            };
+                            if let Some(source) = source {
+                                server.ingest_lines(reader, Some(writer), Some(&source));
+                            }
+                            drop(slot);
+                        });
+                    }
+                    Err(e) => diag!("Failed to accept line connection. {}", e),
+                }
+            }
+        });
+    }
+    server.run(listener);
+    Ok(())
+}
+
+// A socket file left by an earlier run that is no longer listening is replaced
+#[cfg(unix)]
+fn bind_unix(path: &str) -> Result<(UnixListener, String)> {
+    if Path::new(path).exists() && UnixStream::connect(path).is_err() {
+        fs::remove_file(path)?;
+    }
+    Ok((UnixListener::bind(path)?, path.to_string()))
+}
+
+// Serves GET /accounts and GET /ws from a warm copy of the primary's state, kept current over the primary's
+// replication stream, so queries can move here if the primary dies. Each snapshot from the primary is restored into
+// a new engine that configure sets the policies on.
+pub fn standby(options: &ServeOptions, primary: &str, account_txs: AccountTransactions, store: Option<Box<dyn Store>>,
+               configure: Configure) -> Result<()> {
+    let listener = TcpListener::bind(&options.listen)?;
+    let tls = check_tls(options)?;
+    diag!("Listening on {}://{} as a standby of {}.", if tls { "https" } else { "http" }, listener.local_addr()?, primary);
+    let sinks = ChangeSinks::open(None, None, &[], None)?;
+    let mut server = Server::new(account_txs, store, sinks, Some(primary.to_string()));
+    server.keys = ApiKeys::new(&options.api_keys, None, check_auth(options)?);
+    server.connections = options.serve.clone();
+    #[cfg(feature = "tls")]
+    { server.tls = options.tls.as_ref().map(tls::Acceptor::load).transpose()?; }
+    let server = Arc::new(server);
+    {
+        let server = Arc::clone(&server);
+        let primary = primary.to_string();
+        let key = options.serve.replication_key.clone();
+        thread::spawn(move || {
+            let announce = |msg: &TransactionMessage, change: Option<&AccountChange>, result: &Result<()>| {
+                server.announce(msg, change, result)
+            };
+            replication::follow(&primary, key.as_deref(), &configure, &server.account_txs, &server.store,
+                                &server.following, &announce)
+        });
+    }
+    server.run(listener);
+    Ok(())
+}
+
+// Whether the HTTP listener serves TLS. A [tls] section is refused by builds without the tls feature rather than
+// silently serving plain HTTP.
+fn check_tls(options: &ServeOptions) -> Result<bool> {
+    if options.tls.is_some() && cfg!(not(feature = "tls")) {
+        return Err(Error::ConfigError(String::from("This build has no TLS support. Rebuild with --features tls.")));
+    }
+    Ok(options.tls.is_some())
+}
+
+// Whether the endpoints are open to anyone. Serving without [[api_key]] tables has to be asked for with
+// [serve] auth = "none" or --no-auth, and is logged on every start.
+fn check_auth(options: &ServeOptions) -> Result<bool> {
+    match (options.serve.auth, options.api_keys.is_empty()) {
+        (ServeAuth::ApiKeys, true) => Err(Error::ConfigError(String::from(
+            "No [[api_key]] is configured. Add one, or set [serve] auth = \"none\" or pass --no-auth to serve without authentication."))),
+        (ServeAuth::None, false) => Err(Error::ConfigError(String::from(
+            "[serve] auth = \"none\" conflicts with the configured [[api_key]] tables. Remove one or the other."))),
+        (ServeAuth::None, true) => {
+            diag!("WARNING: authentication is disabled. Anyone who can reach this server may submit transactions and query accounts.");
+            Ok(true)
+        }
+        (ServeAuth::ApiKeys, false) => Ok(false),
+    }
+}
+
+impl Server {
+    fn new(account_txs: AccountTransactions, store: Option<Box<dyn Store>>, sinks: ChangeSinks,
+           primary: Option<String>) -> Server {
+        Server {
+            account_txs: Mutex::new(account_txs),
+            store: Mutex::new(store),
+            subscribers: Mutex::new(Vec::new()),
+            sinks: Mutex::new(sinks),
+            replicas: Replicas::new(),
+            primary,
+            following: Mutex::new(FollowStatus::default()),
+            keys: ApiKeys::new(&[], None, false),
+            limits: RateLimits::new(None),
+            audit: None,
+            idempotency: IdempotencyConfig::default(),
+            connections: ServeConfig::default(),
+            open: Arc::new(AtomicUsize::new(0)),
+            #[cfg(feature = "graphql")]
+            schema: graphql::schema(),
+            #[cfg(feature = "tls")]
+            tls: None,
+        }
+    }
+
+    fn run(self: &Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => { diag!("Failed to accept connection. {}", e); continue }
+            };
+            let slot = match self.admit() {
+                Some(slot) => slot,
+                None => { self.refuse(stream); continue }
+            };
+            let server = Arc::clone(self);
+            thread::spawn(move || {
+                if let Err(e) = server.connect(stream) {
+                    diag!("Request failed. {}", e);
+                }
+                drop(slot);
+            });
+        }
+    }
+
+    // A slot for one more connection, or None once max_connections are being served
+    fn admit(&self) -> Option<ConnectionSlot> {
+        let max = self.connections.max_connections;
+        self.open.fetch_update(Ordering::AcqRel, Ordering::Acquire,
+                               |open| Some(open + 1).filter(|_| max == 0 || open < max))
+            .ok()
+            .map(|_| ConnectionSlot { open: Arc::clone(&self.open) })
+    }
+
+    // Answers an HTTP connection over the limit without reading its request, on the accepting thread so a flood of
+    // them cannot start threads either. Over TLS the client sees the connection close instead.
+    fn refuse(&self, mut stream: TcpStream) {
+        diag!("Refused connection. {} connections are being served.", self.connections.max_connections);
+        #[cfg(feature = "tls")]
+        if self.tls.is_some() {
+            return;
+        }
+        let _ = stream.set_write_timeout(Some(REFUSAL_TIMEOUT));
+        let _ = http::write_response(&mut stream, 503, "text/plain", b"Too many connections. Retry later.");
+    }
+
+    fn accept_standbys(self: &Arc<Self>, listener: TcpListener) {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(e) => { diag!("Failed to accept standby. {}", e); continue }
+            };
+            let server = Arc::clone(self);
+            thread::spawn(move || {
+                if let Err(e) = server.attach_standby(stream) {
+                    diag!("Failed to attach standby. {}", e);
+                }
+            });
+        }
+    }
+
+    // With API keys configured, a standby must open with "auth <key>" for a key with the replicate permission,
+    // answered as on the TCP line protocol, before it is sent any state
+    fn attach_standby(&self, mut stream: TcpStream) -> Result<()> {
+        let peer = stream.peer_addr()?.ip();
+        stream.set_read_timeout(self.connections.timeout())?;
+        stream.set_write_timeout(self.connections.timeout())?;
+        if self.keys.required() {
+            let mut line = String::new();
+            BufReader::new((&stream).take(MAX_AUTH_LINE)).read_line(&mut line)?;
+            match self.keys.authorize(line.trim_end().strip_prefix("auth "), Permission::Replicate) {
+                Ok(name) => {
+                    diag!("Standby {} authenticated as {}.", peer, name);
+                    writeln!(stream, "authenticated")?;
+                }
+                Err(refusal) => {
+                    diag!("Refused standby {}. {}", peer, refusal);
+                    return writeln!(stream, "unauthorized {}", refusal).map_err(Error::from);
+                }
+            }
+        }
+        let account_txs = self.account_txs.lock().expect("Engine lock poisoned.");
+        self.replicas.attach(&account_txs, stream)
+    }
+
+    fn connect(&self, stream: TcpStream) -> Result<()> {
+        let peer = stream.peer_addr()?.ip();
+        stream.set_read_timeout(self.connections.timeout())?;
+        stream.set_write_timeout(self.connections.timeout())?;
+        #[cfg(feature = "tls")]
+        if let Some(acceptor) = &self.tls {
+            return self.handle(acceptor.accept(stream)?, peer);
+        }
+        self.handle(stream, peer)
+    }
+
+    fn handle<S: Read + Write>(&self, mut stream: S, peer: IpAddr) -> Result<()> {
+        let request = match http::read_request(&mut BufReader::new(&mut stream), self.connections.max_body_bytes) {
+            Ok(request) => request,
+            Err(e @ Error::LimitExceeded(_)) => {
+                diag!("Refused request from {}. {}", peer, e);
+                return http::write_response(&mut stream, 413, "text/plain", e.to_string().as_bytes());
+            }
+            Err(e) => return Err(e),
+        };
+        let actor = match required_permission(&request).map(|permission| self.keys.authorize_request(&request, permission)) {
+            Some(Ok(name)) => name,
+            Some(Err(refusal)) => {
+                diag!("Refused {} {}. {}", request.method, request.path, refusal);
+                return http::write_response(&mut stream, refusal.status, "text/plain", refusal.reason.as_bytes());
+            }
+            None => String::new(),
+        };
+        match (request.method.as_str(), request.path.as_str()) {
+            ("POST", "/transactions") if self.primary.is_some() => {
+                let message = format!("This is a standby of {}. Send transactions to the primary.",
+                                      self.primary.as_deref().unwrap_or_default());
+                http::write_response(&mut stream, 503, "text/plain", message.as_bytes())
+            }
+            ("POST", "/transactions") if self.limits.take(&self.source(&actor, peer)).is_err() => {
+                let message = format!("Rate limit exceeded for {}. Slow down and retry.", self.source(&actor, peer));
+                http::write_response(&mut stream, 429, "text/plain", message.as_bytes())
+            }
+            ("POST", "/transactions") => match self.ingest(&request.body) {
+                Ok(summary) => http::write_response(&mut stream, 200, "application/json", summary.to_string().as_bytes()),
+                Err(e @ Error::ParseError(_)) | Err(e @ Error::Csv(_)) => {
+                    http::write_response(&mut stream, 400, "text/plain", e.to_string().as_bytes())
+                }
+                Err(e) => {
+                    http::write_response(&mut stream, 500, "text/plain", e.to_string().as_bytes())?;
+                    Err(e)
+                }
+            },
+            ("GET", "/accounts") => {
+                let mut body = Vec::new();
+                let account_txs = self.account_txs.lock().expect("Engine lock poisoned.");
+                write_accounts(&mut body, account_txs.iter_accounts())?;
+                http::write_response(&mut stream, 200, "text/csv", &body)
+            }
+            ("GET", "/ws") => self.feed(stream, &request),
+            ("GET", "/healthz") => http::write_response(&mut stream, 200, "application/json", br#"{"status":"ok"}"#),
+            ("GET", "/readyz") => {
+                let (status, body) = self.readiness();
+                http::write_response(&mut stream, status, "application/json", body.to_string().as_bytes())
+            }
+            ("POST", path) if path.starts_with("/admin/") => {
+                let (status, body) = self.admin(&request, &actor);
+                http::write_response(&mut stream, status, "application/json", body.to_string().as_bytes())
+            }
+            #[cfg(feature = "graphql")]
+            ("POST", "/graphql") => {
+                let (status, body) = graphql::execute(&self.schema, self, &request.body);
+                http::write_response(&mut stream, status, "application/json", &body)
+            }
+            (_, "/transactions") | (_, "/accounts") | (_, "/ws") | (_, "/graphql")
+            | (_, "/healthz") | (_, "/readyz") => {
+                http::write_response(&mut stream, 405, "text/plain", b"Method not allowed.")
+            }
+            _ => http::write_response(&mut stream, 404, "text/plain", b"Not found."),
+        }
+    }
+
+    // Never waits on the engine, so a long POST /transactions does not fail the probe. A store held by a request
+    // is in use and counts as reachable.
+    fn readiness(&self) -> (u16, serde_json::Value) {
+        let store = match self.store.try_lock() {
+            Ok(mut store) => match store.as_mut().map(|store| store.check()) {
+                None => json!({ "ok": true, "configured": false }),
+                Some(Ok(())) => json!({ "ok": true }),
+                Some(Err(e)) => json!({ "ok": false, "error": e.to_string() }),
+            },
+            Err(TryLockError::WouldBlock) => json!({ "ok": true, "busy": true }),
+            Err(TryLockError::Poisoned(_)) => json!({ "ok": false, "error": "Store lock poisoned." }),
+        };
+        let mut ready = store["ok"] == true;
+
+        let replication = match &self.primary {
+            Some(primary) => {
+                let following = self.following.lock().expect("Status lock poisoned.").clone();
+                // A standby that lost its primary keeps serving the last state it applied, so it stays ready
+                ready &= following.synced;
+                json!({
+                    "role": "standby",
+                    "primary": primary,
+                    "connected": following.connected,
+                    "synced": following.synced,
+                    "frames": following.frames,
+                    "last_frame_age_ms": following.last_frame.map(|at| at.elapsed().as_millis() as u64),
+                })
+            }
+            None => {
+                let lags = self.replicas.lags();
+                json!({ "role": "primary", "standbys": lags.len(), "max_lag": lags.iter().max() })
+            }
+        };
+        let queues = json!({
+            "webhooks": self.sinks.try_lock().ok().map(|sinks| sinks.queued()),
+            "subscribers": self.subscribers.lock().expect("Subscriber lock poisoned.").len(),
+        });
+        let body = json!({ "ready": ready, "store": store, "replication": replication, "queues": queues });
+        (if ready { 200 } else { 503 }, body)
+    }
+
+    // Applies an admin action, logs it and returns the account it changed
+    fn admin(&self, request: &Request, actor: &str) -> (u16, serde_json::Value) {
+        let audit = match (&self.audit, &self.primary) {
+            (_, Some(primary)) => return (503, json!({ "error": format!("This is a standby of {}.", primary) })),
+            (None, None) => return (404, json!({ "error": "Admin endpoints are disabled." })),
+            (Some(audit), None) => audit,
+        };
+
+        let result = serde_json::from_slice::<AdminRequest>(&request.body).map_err(Error::from)
+            .and_then(|body| Ok((body.action(&request.path)?, body.reason)))
+            .and_then(|(action, reason)| {
+                let mut span = otel::server_span("POST /admin");
+                span.set("actor", actor);
+                let mut account_txs = self.account_txs.lock().expect("Engine lock poisoned.");
+                let mut store = self.store.lock().expect("Store lock poisoned.");
+                audit.lock().expect("Audit log lock poisoned.").apply(&mut account_txs, &action, &reason, actor)?;
+                diag!("Admin {:?} applied by {}. {}", action, actor, reason);
+                self.replicas.ship_admin(&action)?;
+                if let Some(store) = store.as_mut() {
+                    store.record_admin(&account_txs, &action)?;
+                    store.save(&account_txs)?;
+                }
+                let client = action.client(&account_txs);
+                Ok(client.and_then(|client| account_txs.get_account(client))
+                    .map(|acct| (acct.client, AccountState::from(acct))))
+            });
+        match result {
+            Ok(Some((client, state))) => (200, json!({ "client": client, "account": state })),
+            Ok(None) => (200, json!({})),
+            Err(e @ Error::NotFound(_)) => (404, json!({ "error": e.to_string() })),
+            Err(e @ Error::InvalidArgument(_)) | Err(e @ Error::ParseError(_)) | Err(e @ Error::Json(_)) => {
+                (400, json!({ "error": e.to_string() }))
+            }
+            Err(e) => {
+                diag!("Admin request failed. {}", e);
+                (500, json!({ "error": e.to_string() }))
+            }
+        }
+    }
+
+    // Applies the CSV body like an input file. Malformed rows are skipped and counted, and the state is saved
+    // once the whole body has been applied.
+    fn ingest(&self, body: &[u8]) -> Result<serde_json::Value> {
+        let mut span = otel::server_span("POST /transactions");
+        span.set("bytes", body.len());
+        let mut source = CsvSource::new("POST /transactions", body)?;
+        let mut account_txs = self.account_txs.lock().expect("Engine lock poisoned.");
+        let mut sinks = self.sinks.lock().expect("Sink lock poisoned.");
+        let mut store = self.store.lock().expect("Store lock poisoned.");
+        let recording = store.as_mut().map(|store| store.as_mut() as &mut dyn Store);
+        let report = read_source_with(&mut source, &mut account_txs, &self.read_options(), recording, &mut sinks,
+                                      Some(&mut Announcer { server: self, outcome: None }))?;
+        sinks.flush()?;
+        if let Some(store) = store.as_mut() {
+            store.save(&account_txs)?;
+        }
+        span.set("rows", report.rows);
+        span.set("rejected", report.rejected);
+        Ok(json!({ "rows": report.rows, "applied": report.applied, "rejected": report.rejected,
+                  "malformed": report.malformed, "replayed": report.replayed }))
+    }
+
+    // How submissions are read: malformed rows are skipped, and idempotency keys checked as configured
+    fn read_options(&self) -> ReadOptions {
+        ReadOptions { lenient: true, idempotency: self.idempotency.clone(), ..ReadOptions::default() }
+    }
+
+    // What submissions are rate limited by: the API key, or the peer address when no keys are configured
+    fn source(&self, actor: &str, peer: IpAddr) -> String {
+        if self.keys.required() { format!("key {}", actor) } else { format!("address {}", peer) }
+    }
+
+    // With API keys configured, a line connection must open with "auth <key>" for a key with the submit
+    // permission. The line is answered with "authenticated", or "unauthorized <reason>" before the connection
+    // is closed. Returns the connection's rate limit source, or None when it was refused.
+    fn authenticate_lines<R: BufRead, W: Write>(&self, rdr: &mut R, acks: &mut W, peer: IpAddr) -> Option<String> {
+        if !self.keys.required() {
+            return Some(self.source("", peer));
+        }
+        let mut line = String::new();
+        if let Err(e) = rdr.read_line(&mut line) {
+            diag!("Line connection failed. {}", e);
+            return None;
+        }
+        let presented = line.trim_end().strip_prefix("auth ");
+        let (source, ack) = match self.keys.authorize(presented, Permission::Submit) {
+            Ok(name) => {
+                diag!("Line connection authenticated as {}.", name);
+                (Some(self.source(&name, peer)), String::from("authenticated"))
+            }
+            Err(refusal) => { diag!("Refused line connection. {}", refusal); (None, format!("unauthorized {}", refusal)) }
+        };
+        if let Err(e) = writeln!(acks, "{}", ack).and_then(|_| acks.flush()) {
+            diag!("Failed to acknowledge line. {}", e);
+            return None;
+        }
+        source
+    }
+
+    // Applies newline-delimited records until the client closes the connection. Each line holds the input file's
+    // columns without a header, e.g. "deposit,1,1,1.5", or a JSON object with the same fields. Malformed lines are
+    // skipped. A line over the source's rate limit waits for a token rather than being refused. The state is saved
+    // when the connection closes. With acks, every line is answered in order with one of:
+    //   ok <tx>
+    //   rejected <code> <tx>
+    //   replayed <tx>        for a line whose idempotency key was handled before
+    //   malformed <reason>
+    //   skipped              for a blank line, # comment or header line
+    fn ingest_lines<R: BufRead, W: Write>(&self, rdr: R, mut acks: Option<W>, source: Option<&str>) {
+        let (mut applied, mut rejected, mut malformed, mut replayed) = (0, 0, 0, 0);
+        for line in rdr.lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => { diag!("Line connection failed. {}", e); break }
+            };
+            let line = line.trim_end_matches('\r');
+            let ack = if line.is_empty() || line.starts_with('#') || line.starts_with("type,") {
+                String::from("skipped")
+            } else {
+                if let Some(source) = source {
+                    while let Err(wait) = self.limits.take(source) {
+                        thread::sleep(wait);
+                    }
+                }
+                match self.ingest_line(line) {
+                    Ok(Some(LineOutcome::Applied(tx))) => { applied += 1; format!("ok {}", tx) }
+                    Ok(Some(LineOutcome::Rejected(reason, tx))) => { rejected += 1; format!("rejected {} {}", reason, tx) }
+                    Ok(Some(LineOutcome::Replayed(tx))) => { replayed += 1; format!("replayed {}", tx) }
+                    Ok(Some(LineOutcome::Malformed(reason))) => {
+                        diag!("Skipped malformed line, {}. {}", line, reason);
+                        malformed += 1;
+                        format!("malformed {}", reason.replace('\n', " "))
+                    }
+                    Ok(None) => String::from("skipped"),
+                    Err(e) => { diag!("Failed to apply line, {}. {}", line, e); break }
+                }
+            };
+            if let Some(acks) = acks.as_mut() {
+                if let Err(e) = writeln!(acks, "{}", ack).and_then(|_| acks.flush()) {
+                    diag!("Failed to acknowledge line. {}", e);
+                    break;
+                }
+            }
+        }
+
+        let account_txs = self.account_txs.lock().expect("Engine lock poisoned.");
+        if let Some(store) = self.store.lock().expect("Store lock poisoned.").as_mut() {
+            if let Err(e) = store.save(&account_txs) {
+                diag!("Failed to save state to {}. {}", store.describe(), e);
+            }
+        }
+        diag!("Line connection closed after {} applied, {} rejected, {} malformed, {} replayed.", applied, rejected,
+              malformed, replayed);
+    }
+
+    // None for a line holding no record
+    fn ingest_line(&self, line: &str) -> Result<Option<LineOutcome>> {
+        let mut account_txs = self.account_txs.lock().expect("Engine lock poisoned.");
+        let mut sinks = self.sinks.lock().expect("Sink lock poisoned.");
+        let mut store = self.store.lock().expect("Store lock poisoned.");
+        let mut announcer = Announcer { server: self, outcome: None };
+        let recording = store.as_mut().map(|store| store.as_mut() as &mut dyn Store);
+        read_source_with(&mut LineSource::new("line connection", line.as_bytes()), &mut account_txs,
+                         &self.read_options(), recording, &mut sinks, Some(&mut announcer))?;
+        sinks.flush()?;
+        Ok(announcer.outcome)
+    }
+
+    fn feed<S: Write>(&self, mut stream: S, request: &Request) -> Result<()> {
+        let key = match request.header("sec-websocket-key") {
+            Some(key) if request.header("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")) => key,
+            _ => return http::write_response(&mut stream, 400, "text/plain", b"Expected a WebSocket upgrade."),
+        };
+        write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+               websocket::accept_key(key))?;
+        stream.flush()?;
+
+        let (sender, receiver): (SyncSender<String>, Receiver<String>) = mpsc::sync_channel(FEED_BUFFER);
+        self.subscribers.lock().expect("Subscriber lock poisoned.").push(sender);
+        // Ends when the subscriber is dropped for falling behind or the client goes away
+        for event in receiver {
+            if websocket::write_text(&mut stream, &event).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // Feeds /ws subscribers what became of a message, on a primary as it is submitted and on a standby as it arrives
+    // from the primary
+    fn announce(&self, message: &TransactionMessage, change: Option<&AccountChange>, result: &Result<()>) {
+        if let Some(change) = change {
+            self.broadcast(&FeedEvent::Change(change));
+        }
+        if let Err(Error::PolicyViolation { reason, message: reject_message }) = result {
+            self.broadcast(&FeedEvent::Rejection {
+                code: reason.code(),
+                message: reject_message,
+                client: message.client,
+                tx: message.tx,
+            });
+        }
+    }
+
+    fn broadcast(&self, event: &FeedEvent) {
+        let mut subscribers = self.subscribers.lock().expect("Subscriber lock poisoned.");
+        if subscribers.is_empty() {
+            return;
+        }
+        match serde_json::to_string(event) {
+            Ok(text) => subscribers.retain(|subscriber| subscriber.try_send(text.clone()).is_ok()),
+            Err(e) => diag!("Failed to encode feed event. {}", e),
+        }
+    }
+}
+
+// Held for as long as a connection is served
+struct ConnectionSlot {
+    open: Arc<AtomicUsize>,
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.open.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+// Tells standbys, the audit log and /ws subscribers about each row a submission's read handles, keeping what became
+// of the last one for its acknowledgement
+struct Announcer<'a> {
+    server: &'a Server,
+    outcome: Option<LineOutcome>,
+}
+
+impl RowHandler for Announcer<'_> {
+    fn handled(&mut self, account_txs: &AccountTransactions, outcome: RowOutcome) -> Result<()> {
+        let server = self.server;
+        self.outcome = Some(match outcome {
+            RowOutcome::Handled { message, result, change } => {
+                if let Some(audit) = &server.audit {
+                    let mut audit = audit.lock().expect("Audit log lock poisoned.");
+                    for expired in account_txs.expired_by(message) {
+                        audit.record_expired(account_txs, expired)?;
+                    }
+                }
+                server.replicas.ship(message)?;
+                server.announce(message, change, result);
+                match result {
+                    Err(Error::PolicyViolation { reason, .. }) => LineOutcome::Rejected(*reason, message.tx),
+                    _ => LineOutcome::Applied(message.tx),
+                }
+            }
+            RowOutcome::Replayed(message) => LineOutcome::Replayed(message.tx),
+            RowOutcome::Malformed(reason) => LineOutcome::Malformed(reason.to_string()),
+        });
+        Ok(())
+    }
+}
+
+// The permission a key needs for the request, or None for a path serve does not answer
+fn required_permission(request: &Request) -> Option<Permission> {
+    match (request.method.as_str(), request.path.as_str()) {
+        ("POST", "/transactions") => Some(Permission::Submit),
+        ("GET", "/accounts") | ("GET", "/ws") | ("POST", "/graphql") => Some(Permission::Query),
+        ("POST", path) if path.starts_with("/admin/") => Some(Permission::Admin),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(auth: ServeAuth, keys: usize) -> ServeOptions {
+        let key = ApiKeyConfig { name: String::from("ops"), key: String::from("ok_1"), permissions: vec![Permission::Query] };
+        ServeOptions { api_keys: vec![key; keys], serve: ServeConfig { auth, ..ServeConfig::default() }, ..ServeOptions::default() }
+    }
+
+    #[test]
+    fn refuses_to_start_without_keys_unless_told_to() {
+        assert!(matches!(check_auth(&options(ServeAuth::ApiKeys, 0)), Err(Error::ConfigError(_))));
+        assert!(check_auth(&options(ServeAuth::None, 0)).unwrap());
+        assert!(!check_auth(&options(ServeAuth::ApiKeys, 1)).unwrap());
+        assert!(matches!(check_auth(&options(ServeAuth::None, 1)), Err(Error::ConfigError(_))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn replayed_line_is_applied_once() {
+        let sinks = ChangeSinks::open(None, None, &[], None).unwrap();
+        let mut server = Server::new(AccountTransactions::new(), None, sinks, None);
+        server.idempotency.tx_keys = true;
+        let (mut client, socket) = UnixStream::pair().unwrap();
+        client.write_all(b"deposit,1,1,1.5\ndeposit,1,1,1.5\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let mut acks = Vec::new();
+
+        server.ingest_lines(BufReader::new(socket), Some(&mut acks), None);
+        assert_eq!(String::from_utf8(acks).unwrap(), "ok 1\nreplayed 1\n");
+        assert_eq!(server.account_txs.lock().unwrap().account_client[&1].available, 15_000);
+    }
+
+    fn wait_for_open(server: &Server, open: usize) {
+        while server.open.load(Ordering::Acquire) != open {
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn get_healthz(addr: std::net::SocketAddr) -> String {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        status.trim_end().to_string()
+    }
+
+    #[test]
+    fn refuses_connections_over_the_limit() {
+        let sinks = ChangeSinks::open(None, None, &[], None).unwrap();
+        let mut server = Server::new(AccountTransactions::new(), None, sinks, None);
+        server.keys = ApiKeys::new(&[], None, true);
+        server.connections.max_connections = 1;
+        let server = Arc::new(server);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        {
+            let server = Arc::clone(&server);
+            thread::spawn(move || server.run(listener));
+        }
+
+        assert_eq!(get_healthz(addr), "HTTP/1.1 200 OK");
+        wait_for_open(&server, 0);
+        // An idle connection holds the only slot until it closes
+        let idle = TcpStream::connect(addr).unwrap();
+        wait_for_open(&server, 1);
+        assert_eq!(get_healthz(addr), "HTTP/1.1 503 Service Unavailable");
+        drop(idle);
+        wait_for_open(&server, 0);
+        assert_eq!(get_healthz(addr), "HTTP/1.1 200 OK");
+    }
+}
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use crate::config::{ApiKeyConfig, Permission};
+use crate::http::Request;
+
+// Keys serve accepts. The [admin] token counts as a key named admin with only the admin permission.
+pub(super) struct ApiKeys {
+    keys: Vec<ApiKeyConfig>,
+    // Authentication was turned off with [serve] auth = "none", so submitting and querying are open to anyone
+    open: bool,
+}
+
+// Why a request was refused, with the HTTP status to answer it with
+pub(super) struct Refusal {
+    pub(super) status: u16,
+    pub(super) reason: String,
+}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl ApiKeys {
+    pub(super) fn new(api_keys: &[ApiKeyConfig], admin_token: Option<&str>, open: bool) -> ApiKeys {
+        let mut keys = api_keys.to_vec();
+        if let Some(token) = admin_token {
+            keys.push(ApiKeyConfig { name: String::from("admin"), key: token.to_string(), permissions: vec![Permission::Admin] });
+        }
+        ApiKeys { keys, open }
+    }
+
+    pub(super) fn required(&self) -> bool {
+        !self.open
+    }
+
+    pub(super) fn grant_admin(&self) -> bool {
+        self.keys.iter().any(|key| key.permissions.contains(&Permission::Admin))
+    }
+
+    // Returns the name of the key the request carries, as Authorization: Bearer <key> or X-API-Key: <key>
+    pub(super) fn authorize_request(&self, request: &Request, permission: Permission) -> Result<String, Refusal> {
+        let presented = request.header("authorization")
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or_else(|| request.header("x-api-key"));
+        self.authorize(presented.map(str::trim), permission)
+    }
+
+    pub(super) fn authorize(&self, presented: Option<&str>, permission: Permission) -> Result<String, Refusal> {
+        if self.open && permission != Permission::Admin {
+            return Ok(String::from("anonymous"));
+        }
+        let presented = presented.ok_or_else(|| Refusal { status: 401, reason: String::from("Missing API key.") })?;
+        // Every key is compared so the time taken does not reveal which one nearly matched
+        let key = self.keys.iter().fold(None, |found, key| if tokens_match(presented, &key.key) { Some(key) } else { found })
+            .ok_or_else(|| Refusal { status: 401, reason: String::from("Unknown API key.") })?;
+        if !key.permissions.contains(&permission) {
+            return Err(Refusal { status: 403, reason: format!("API key {} lacks the {} permission.", key.name, format!("{:?}", permission).to_lowercase()) });
+        }
+        Ok(key.name.clone())
+    }
+}
+
+// Compares every byte so the time taken does not reveal how much of the token was right
+fn tokens_match(presented: &str, token: &str) -> bool {
+    presented.len() == token.len()
+        && presented.bytes().zip(token.bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys(open: bool) -> ApiKeys {
+        let producer = ApiKeyConfig { name: String::from("producer"), key: String::from("pk_1"), permissions: vec![Permission::Submit] };
+        ApiKeys::new(&[producer], Some("admin-token"), open)
+    }
+
+    #[test]
+    fn accepts_a_key_with_the_permission() {
+        assert_eq!(keys(false).authorize(Some("pk_1"), Permission::Submit).ok(), Some(String::from("producer")));
+        assert_eq!(keys(false).authorize(Some("admin-token"), Permission::Admin).ok(), Some(String::from("admin")));
+    }
+
+    #[test]
+    fn refuses_missing_unknown_and_unpermitted_keys() {
+        let keys = keys(false);
+        assert_eq!(keys.authorize(None, Permission::Submit).err().map(|refusal| refusal.status), Some(401));
+        assert_eq!(keys.authorize(Some("pk_2"), Permission::Submit).err().map(|refusal| refusal.status), Some(401));
+        assert_eq!(keys.authorize(Some("pk_1"), Permission::Query).err().map(|refusal| refusal.status), Some(403));
+        assert_eq!(keys.authorize(Some("admin-token"), Permission::Submit).err().map(|refusal| refusal.status), Some(403));
+    }
+
+    #[test]
+    fn refuses_everything_without_keys_unless_open() {
+        let closed = ApiKeys::new(&[], None, false);
+        assert!(closed.required());
+        assert_eq!(closed.authorize(None, Permission::Query).err().map(|refusal| refusal.status), Some(401));
+        let open = ApiKeys::new(&[], None, true);
+        assert!(!open.required());
+        assert_eq!(open.authorize(None, Permission::Query).ok(), Some(String::from("anonymous")));
+        // Admin always needs a key
+        assert_eq!(open.authorize(None, Permission::Admin).err().map(|refusal| refusal.status), Some(401));
+    }
+}
@@ -0,0 +1,186 @@
+use std::convert::TryFrom;
+
+use juniper::http::GraphQLRequest;
+use juniper::{graphql_object, EmptyMutation, EmptySubscription, GraphQLObject, RootNode, ID};
+use serde_json::json;
+
+use super::Server;
+use crate::lifecycle;
+use crate::{format_amount, to_subunit, Account, Tx};
+
+const DEFAULT_PAGE: i32 = 100;
+const MAX_PAGE: i32 = 1000;
+
+pub(super) type Schema = RootNode<'static, Query, EmptyMutation<Server>, EmptySubscription<Server>>;
+
+pub(super) fn schema() -> Schema {
+    Schema::new(Query, EmptyMutation::new(), EmptySubscription::new())
+}
+
+impl juniper::Context for Server {}
+
+// Runs a POST /graphql body of {"query", "variables", "operationName"}, returning the status and JSON response
+pub(super) fn execute(schema: &Schema, server: &Server, body: &[u8]) -> (u16, Vec<u8>) {
+    let request: GraphQLRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return (400, json!({ "errors": [{ "message": e.to_string() }] }).to_string().into_bytes()),
+    };
+    let response = request.execute_sync(schema, server);
+    let status = if response.is_ok() { 200 } else { 400 };
+    (status, serde_json::to_vec(&response).unwrap_or_default())
+}
+
+#[derive(GraphQLObject)]
+#[graphql(name = "Account")]
+struct AccountNode {
+    client: i32,
+    // Exact decimals of currency units, which a GraphQL Float cannot always hold
+    available: String,
+    held: String,
+    escrow: String,
+    total: String,
+    locked: bool,
+}
+
+impl From<&Account> for AccountNode {
+    fn from(acct: &Account) -> AccountNode {
+        AccountNode {
+            client: i32::from(acct.client),
+            available: format_amount(acct.available),
+            held: format_amount(acct.held),
+            escrow: format_amount(acct.escrow),
+            total: format_amount(acct.total),
+            locked: acct.locked,
+        }
+    }
+}
+
+// Transaction ids can exceed GraphQL's 32-bit Int, so they are IDs
+#[derive(GraphQLObject)]
+#[graphql(name = "Transaction")]
+struct TransactionNode {
+    tx: ID,
+    client: i32,
+    #[graphql(name = "type")]
+    tx_type: String,
+    amount: String,
+    // posted, disputed, resolved or charged_back
+    state: String,
+    disputed: bool,
+    // A withdrawal held until it is approved or denied
+    pending: bool,
+    counterparty: Option<String>,
+    memo: Option<String>,
+    category: Option<String>,
+    // Its disputes, resolves and chargebacks in order, e.g. dispute@3;resolve@5
+    history: String,
+}
+
+impl From<&Tx> for TransactionNode {
+    fn from(tx: &Tx) -> TransactionNode {
+        TransactionNode {
+            tx: ID::new(tx.tx.to_string()),
+            client: i32::from(tx.client),
+            tx_type: tx.tx_type.to_string(),
+            amount: format_amount(tx.amount),
+            state: tx.state.to_string(),
+            disputed: tx.disputed(),
+            pending: tx.pending,
+            counterparty: tx.counterparty.clone(),
+            memo: tx.memo.clone(),
+            category: tx.category.clone(),
+            history: lifecycle::history_text(&tx.history),
+        }
+    }
+}
+
+// A page in id order. Pass end_cursor as after to get the next page.
+#[derive(GraphQLObject)]
+struct AccountConnection {
+    nodes: Vec<AccountNode>,
+    end_cursor: Option<String>,
+    has_next_page: bool,
+    total_count: i32,
+}
+
+#[derive(GraphQLObject)]
+struct TransactionConnection {
+    nodes: Vec<TransactionNode>,
+    end_cursor: Option<String>,
+    has_next_page: bool,
+    total_count: i32,
+}
+
+// Sorts the matches by id and cuts the page after the cursor
+fn page<T>(mut matches: Vec<(u32, T)>, first: Option<i32>, after: Option<String>) -> juniper::FieldResult<(Vec<T>, Option<String>, bool, i32)> {
+    let first = first.unwrap_or(DEFAULT_PAGE);
+    if !(0..=MAX_PAGE).contains(&first) {
+        return Err(format!("first must be between 0 and {}.", MAX_PAGE).into());
+    }
+    let after: Option<u32> = after.map(|cursor| cursor.parse().map_err(|_| format!("Invalid cursor, {}.", cursor))).transpose()?;
+    let total_count = matches.len() as i32;
+    matches.sort_unstable_by_key(|(id, _)| *id);
+    let mut rest = matches.into_iter().filter(|(id, _)| after.is_none_or(|after| *id > after)).peekable();
+    let nodes: Vec<(u32, T)> = rest.by_ref().take(first as usize).collect();
+    let has_next_page = rest.peek().is_some();
+    let end_cursor = nodes.last().map(|(id, _)| id.to_string());
+    Ok((nodes.into_iter().map(|(_, node)| node).collect(), end_cursor, has_next_page, total_count))
+}
+
+fn transactions_page<'a, F: Fn(&Tx) -> bool>(txs: impl Iterator<Item = &'a Tx>, keep: F, first: Option<i32>, after: Option<String>)
+                                              -> juniper::FieldResult<TransactionConnection> {
+    let matches = txs.filter(|tx| keep(tx)).map(|tx| (tx.tx, TransactionNode::from(tx))).collect();
+    let (nodes, end_cursor, has_next_page, total_count) = page(matches, first, after)?;
+    Ok(TransactionConnection { nodes, end_cursor, has_next_page, total_count })
+}
+
+pub(super) struct Query;
+
+#[graphql_object(context = Server)]
+impl Query {
+    fn account(context: &Server, client: i32) -> Option<AccountNode> {
+        let client = u16::try_from(client).ok()?;
+        let account_txs = context.account_txs.lock().expect("Engine lock poisoned.");
+        account_txs.account_client.get(&client).map(AccountNode::from)
+    }
+
+    fn accounts(context: &Server, locked: Option<bool>, min_total: Option<String>, first: Option<i32>, after: Option<String>)
+                -> juniper::FieldResult<AccountConnection> {
+        let min_total = min_total.map(|min_total| to_subunit(&min_total)
+            .map_err(|e| format!("Invalid minTotal, {}. {}", min_total, e))).transpose()?;
+        let account_txs = context.account_txs.lock().expect("Engine lock poisoned.");
+        let matches = account_txs.account_client.values()
+            .filter(|acct| locked.is_none_or(|locked| acct.locked == locked))
+            .filter(|acct| min_total.is_none_or(|min_total| acct.total >= min_total))
+            .map(|acct| (u32::from(acct.client), AccountNode::from(acct)))
+            .collect();
+        let (nodes, end_cursor, has_next_page, total_count) = page(matches, first, after)?;
+        Ok(AccountConnection { nodes, end_cursor, has_next_page, total_count })
+    }
+
+    fn transaction(context: &Server, tx: ID) -> Option<TransactionNode> {
+        let tx: u32 = tx.parse().ok()?;
+        let account_txs = context.account_txs.lock().expect("Engine lock poisoned.");
+        account_txs.txs_txid.get(&tx).map(TransactionNode::from)
+    }
+
+    fn transactions(context: &Server, client: Option<i32>, #[graphql(name = "type")] tx_type: Option<String>,
+                    disputed: Option<bool>,
+                    first: Option<i32>, after: Option<String>) -> juniper::FieldResult<TransactionConnection> {
+        let account_txs = context.account_txs.lock().expect("Engine lock poisoned.");
+        transactions_page(account_txs.txs_txid.values(), |tx| {
+            client.is_none_or(|client| i32::from(tx.client) == client)
+                && tx_type.as_deref().is_none_or(|tx_type| tx.tx_type.to_string() == tx_type)
+                && disputed.is_none_or(|disputed| tx.disputed() == disputed)
+        }, first, after)
+    }
+
+    // Transactions under dispute, whose amounts are held
+    fn open_disputes(context: &Server, client: Option<i32>, first: Option<i32>, after: Option<String>)
+                     -> juniper::FieldResult<TransactionConnection> {
+        let account_txs = context.account_txs.lock().expect("Engine lock poisoned.");
+        transactions_page(account_txs.txs_txid.values(), |tx| {
+            tx.disputed() && client.is_none_or(|client| i32::from(tx.client) == client)
+        }, first, after)
+    }
+}
@@ -0,0 +1,54 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::config::RateLimitConfig;
+
+// Sources tracked before buckets that have refilled are forgotten
+const MAX_IDLE_BUCKETS: usize = 4096;
+
+// One token bucket per API key, or per peer address when no keys are configured
+pub(super) struct RateLimits {
+    config: Option<RateLimitConfig>,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimits {
+    pub(super) fn new(config: Option<RateLimitConfig>) -> RateLimits {
+        RateLimits { config: config.filter(|config| config.requests_per_second > 0.0), buckets: Mutex::new(HashMap::new()) }
+    }
+
+    // Takes a token from the source's bucket, or returns how long until one is available
+    pub(super) fn take(&self, source: &str) -> Result<(), Duration> {
+        let config = match &self.config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+        let burst = f64::from(config.burst());
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().expect("Rate limit lock poisoned.");
+        if buckets.len() > MAX_IDLE_BUCKETS {
+            buckets.retain(|_, bucket| bucket.refilled(now, config.requests_per_second) < burst);
+        }
+        let bucket = buckets.entry(source.to_string()).or_insert(Bucket { tokens: burst, updated: now });
+        bucket.tokens = bucket.refilled(now, config.requests_per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / config.requests_per_second))
+        }
+    }
+}
+
+impl Bucket {
+    fn refilled(&self, now: Instant, rate: f64) -> f64 {
+        self.tokens + now.duration_since(self.updated).as_secs_f64() * rate
+    }
+}
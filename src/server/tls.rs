@@ -0,0 +1,40 @@
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+
+use crate::config::TlsConfig;
+use crate::{Error, Result};
+
+pub(super) type TlsStream = StreamOwned<ServerConnection, TcpStream>;
+
+// Wraps accepted connections in TLS. The handshake runs on the connection's own thread, on its first read.
+pub(super) struct Acceptor {
+    config: Arc<ServerConfig>,
+}
+
+impl Acceptor {
+    pub(super) fn load(tls: &TlsConfig) -> Result<Acceptor> {
+        let certs = CertificateDer::pem_file_iter(&tls.cert)
+            .and_then(|certs| certs.collect::<std::result::Result<Vec<_>, _>>())
+            .map_err(|e| Error::ConfigError(format!("{}: {}", tls.cert, e)))?;
+        if certs.is_empty() {
+            return Err(Error::ConfigError(format!("{} holds no certificates.", tls.cert)));
+        }
+        let key = PrivateKeyDer::from_pem_file(&tls.key).map_err(|e| Error::ConfigError(format!("{}: {}", tls.key, e)))?;
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let config = ServerConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+            .map_err(|e| Error::ConfigError(format!("{}: {}", tls.cert, e)))?;
+        Ok(Acceptor { config: Arc::new(config) })
+    }
+
+    pub(super) fn accept(&self, stream: TcpStream) -> Result<TlsStream> {
+        let connection = ServerConnection::new(Arc::clone(&self.config))
+            .map_err(|e| Error::InvalidArgument(format!("TLS setup failed. {}", e)))?;
+        Ok(StreamOwned::new(connection, stream))
+    }
+}
@@ -0,0 +1,118 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+
+use crate::config::SigningConfig;
+use crate::merkle::{from_hex, hex};
+use crate::{Error, Result};
+
+const ALGORITHM: &str = "ed25519";
+
+// Signs output files with an Ed25519 key, so whoever receives them can check they are the files this run wrote. Each
+// file gets a <file>.sig beside it holding one line: ed25519, the public key and the signature over the file's bytes,
+// all hex. The key file holds the 32-byte private key seed as 64 hex digits.
+pub struct Signer {
+    key: Ed25519KeyPair,
+}
+
+impl Signer {
+    pub fn load(config: &SigningConfig) -> Result<Signer> {
+        let text = fs::read_to_string(&config.key_file)
+            .map_err(|e| Error::ConfigError(format!("Cannot read signing key {}. {}", config.key_file, e)))?;
+        let seed: [u8; 32] = from_hex(text.trim()).ok_or_else(|| Error::ConfigError(format!(
+            "Signing key {} is not 64 hex digits.", config.key_file)))?;
+        let key = Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map_err(|e| Error::ConfigError(format!("Signing key {} is not usable. {}", config.key_file, e)))?;
+        Ok(Signer { key })
+    }
+
+    // What verify-signature is given to check files this signer signed
+    pub fn public_key(&self) -> String {
+        hex(self.key.public_key().as_ref())
+    }
+
+    // Returns the signature file written
+    pub fn sign_file(&self, path: &Path) -> Result<PathBuf> {
+        let bytes = fs::read(path)?;
+        let signature = self.key.sign(&bytes);
+        let sig_path = signature_path(path);
+        fs::write(&sig_path, format!("{} {} {}\n", ALGORITHM, self.public_key(), hex(signature.as_ref())))?;
+        Ok(sig_path)
+    }
+}
+
+pub fn signature_path(path: &Path) -> PathBuf {
+    let mut sig_path = path.as_os_str().to_os_string();
+    sig_path.push(".sig");
+    PathBuf::from(sig_path)
+}
+
+// Checks path against the signature file at sig_path. Returns None when it was signed by public_key and is unchanged,
+// otherwise why it does not verify. A signature file that cannot be read or parsed is an error.
+pub fn verify_file(path: &Path, sig_path: &Path, public_key: &str) -> Result<Option<String>> {
+    let expected: [u8; 32] = from_hex(public_key).ok_or_else(|| Error::InvalidArgument(format!(
+        "Public key {} is not 64 hex digits.", public_key)))?;
+    let text = fs::read_to_string(sig_path)
+        .map_err(|e| Error::InvalidArgument(format!("Cannot read signature {}. {}", sig_path.display(), e)))?;
+    let malformed = || Error::InvalidArgument(format!("{} is not a tx_acct signature file.", sig_path.display()));
+    let (signer, signature): ([u8; 32], [u8; 64]) = match text.split_whitespace().collect::<Vec<&str>>()[..] {
+        [ALGORITHM, signer, signature] => {
+            (from_hex(signer).ok_or_else(malformed)?, from_hex(signature).ok_or_else(malformed)?)
+        }
+        _ => return Err(malformed()),
+    };
+    if signer != expected {
+        return Ok(Some(format!("Signed by a different key, {}.", hex(&signer))));
+    }
+    let bytes = fs::read(path)?;
+    match UnparsedPublicKey::new(&ED25519, expected).verify(&bytes, &signature) {
+        Ok(()) => Ok(None),
+        Err(_) => Ok(Some(String::from("The signature does not match the file's contents."))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_path;
+
+    fn load_signer(name: &str, seed: u8) -> Signer {
+        let key_file = temp_path(name);
+        fs::write(&key_file, hex(&[seed; 32])).unwrap();
+        Signer::load(&SigningConfig { key_file: key_file.to_string_lossy().into_owned() }).unwrap()
+    }
+
+    #[test]
+    fn verifies_what_it_signed() {
+        let signer = load_signer("verifies.key", 7);
+        let path = temp_path("verifies.csv");
+        fs::write(&path, "client,available,held,total,locked\n1,1.5,0,1.5,false\n").unwrap();
+        let sig_path = signer.sign_file(&path).unwrap();
+        assert_eq!(sig_path, signature_path(&path));
+        assert_eq!(verify_file(&path, &sig_path, &signer.public_key()).unwrap(), None);
+
+        fs::write(&path, "client,available,held,total,locked\n1,2.5,0,2.5,false\n").unwrap();
+        assert!(verify_file(&path, &sig_path, &signer.public_key()).unwrap().is_some());
+    }
+
+    #[test]
+    fn refuses_a_tampered_signature() {
+        let signer = load_signer("tampered.key", 9);
+        let path = temp_path("tampered.csv");
+        fs::write(&path, "client,available,held,total,locked\n").unwrap();
+        let sig_path = signer.sign_file(&path).unwrap();
+        let mut text = fs::read_to_string(&sig_path).unwrap().trim_end().to_string();
+        let flipped = if text.ends_with('0') { '1' } else { '0' };
+        text.pop();
+        fs::write(&sig_path, format!("{}{}\n", text, flipped)).unwrap();
+        assert_eq!(verify_file(&path, &sig_path, &signer.public_key()).unwrap(),
+                   Some(String::from("The signature does not match the file's contents.")));
+
+        let other = load_signer("other.key", 10);
+        signer.sign_file(&path).unwrap();
+        assert!(verify_file(&path, &sig_path, &other.public_key()).unwrap().unwrap().starts_with("Signed by a different key"));
+        fs::write(&sig_path, "ed25519 nonsense\n").unwrap();
+        assert!(verify_file(&path, &sig_path, &signer.public_key()).is_err());
+    }
+}
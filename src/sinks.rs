@@ -0,0 +1,82 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use crate::cdc::CdcLog;
+use crate::change::AccountChange;
+use crate::config::{KafkaConfig, RedisConfig, WebhookConfig};
+use crate::kafka::KafkaSink;
+use crate::observer::{self, EngineObserver};
+use crate::redis::RedisCache;
+use crate::webhook::Notifier;
+use crate::{statsd, Account, ReadReport, Result, TransactionMessage};
+
+// Everything account changes are fanned out to as messages are handled, each as an engine observer
+pub(crate) struct ChangeSinks {
+    cdc: Option<CdcLog>,
+    kafka: Option<KafkaSink>,
+    notifier: Option<Notifier>,
+    redis: Option<RedisCache>,
+}
+
+impl ChangeSinks {
+    pub(crate) fn open(cdc: Option<&str>, kafka: Option<&KafkaConfig>, webhooks: &[WebhookConfig],
+                       redis: Option<&RedisConfig>) -> Result<ChangeSinks> {
+        Ok(ChangeSinks {
+            cdc: cdc.map(CdcLog::open).transpose()?,
+            kafka: kafka.map(KafkaSink::new).transpose()?,
+            notifier: if webhooks.is_empty() { None } else { Some(Notifier::new(webhooks)?) },
+            redis: redis.map(RedisCache::connect).transpose()?,
+        })
+    }
+
+    // before is the account as it was before msg was handled, result is how handling it went
+    pub(crate) fn record(&mut self, before: Option<&Account>, after: Option<&Account>, msg: &TransactionMessage,
+                         result: &Result<()>, annotations: &BTreeMap<String, String>) -> Result<Option<AccountChange>> {
+        let change = AccountChange::between(before, after, msg).map(|mut change| {
+            change.annotations.clone_from(annotations);
+            change
+        });
+        let mut sinks: [Option<&mut dyn EngineObserver>; 4] = [
+            self.cdc.as_mut().map(|cdc| cdc as &mut dyn EngineObserver),
+            self.redis.as_mut().map(|redis| redis as &mut dyn EngineObserver),
+            self.kafka.as_mut().map(|kafka| kafka as &mut dyn EngineObserver),
+            self.notifier.as_mut().map(|notifier| notifier as &mut dyn EngineObserver),
+        ];
+        for sink in sinks.iter_mut().flatten() {
+            observer::dispatch(&mut **sink, change.as_ref(), msg, result)?;
+        }
+        Ok(change)
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        let start = Instant::now();
+        if let Some(cdc) = self.cdc.as_mut() {
+            cdc.flush()?;
+        }
+        if let Some(redis) = self.redis.as_mut() {
+            redis.flush()?;
+        }
+        if let Some(kafka) = self.kafka.as_mut() {
+            kafka.flush()?;
+        }
+        statsd::timing("sinks.flush", start.elapsed());
+        Ok(())
+    }
+
+    // Webhook deliveries waiting on the background worker. The other sinks are written synchronously.
+    pub(crate) fn queued(&self) -> usize {
+        self.notifier.as_ref().map_or(0, Notifier::queued)
+    }
+
+    // Flushes every sink, waits for queued webhooks and records what each sink took in the report
+    pub(crate) fn finish(&mut self, report: &mut ReadReport) -> Result<()> {
+        self.flush()?;
+        if let Some(notifier) = self.notifier.as_mut() {
+            notifier.finish();
+        }
+        report.changes = self.cdc.as_ref().map_or(0, CdcLog::changes);
+        report.cached = self.redis.as_ref().map_or(0, RedisCache::written);
+        report.published = self.kafka.as_ref().map_or(0, KafkaSink::published);
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::encryption;
+use crate::fee_schedule::FeeUsageRecord;
+use crate::idempotency::IdempotencyRecord;
+use crate::merkle::{MerkleRecord, MerkleTree};
+use crate::rounding::RemainderRecord;
+use crate::state::{self, Store};
+use crate::withdrawal_limits::WithdrawnRecord;
+use crate::{Account, AccountTransactions, Error, PrunedRange, Result, TransactionMessage, Tx};
+
+// A snapshot file is MAGIC, a big-endian u16 format version, then the zstd-compressed JSON payload. With a key
+// configured the payload is encrypted, the header staying readable.
+const MAGIC: &[u8] = b"TXSNAP";
+const CURRENT_VERSION: u16 = 5;
+#[cfg(not(target_arch = "wasm32"))]
+const COMPRESSION_LEVEL: i32 = 3;
+
+// UPGRADES[n - 1] rewrites a version n payload as version n + 1. Bump CURRENT_VERSION and append a step here
+// whenever Payload changes shape, so snapshots written by older releases stay loadable.
+const UPGRADES: &[fn(&mut Value) -> Result<()>] = &[add_offsets, add_idempotency_keys, add_merkle, add_usage];
+
+// Version 2 added the Kafka input offsets. Snapshots from before it never consumed from Kafka.
+fn add_offsets(payload: &mut Value) -> Result<()> {
+    add_field(payload, 1, "offsets", Value::Array(Vec::new()))
+}
+
+// Version 3 added the idempotency keys. Snapshots from before it never checked any.
+fn add_idempotency_keys(payload: &mut Value) -> Result<()> {
+    add_field(payload, 2, "idempotency_keys", Value::Array(Vec::new()))
+}
+
+// Version 4 added the Merkle tree of applied transactions. Older snapshots start a new tree from the next one.
+fn add_merkle(payload: &mut Value) -> Result<()> {
+    add_field(payload, 3, "merkle", Value::Null)
+}
+
+// Version 5 added what clients have withdrawn towards their cumulative limits, their fee tier usage and the rounding
+// account. Older snapshots count all three from the next transaction.
+fn add_usage(payload: &mut Value) -> Result<()> {
+    add_field(payload, 4, "withdrawn", Value::Array(Vec::new()))?;
+    add_field(payload, 4, "fee_usage", Value::Array(Vec::new()))?;
+    add_field(payload, 4, "rounding", Value::Array(Vec::new()))
+}
+
+fn add_field(payload: &mut Value, version: u16, field: &str, value: Value) -> Result<()> {
+    match payload.as_object_mut() {
+        Some(payload) => {
+            payload.insert(String::from(field), value);
+            Ok(())
+        }
+        None => Err(Error::StorageError(format!("Version {} snapshot payload is not an object", version))),
+    }
+}
+
+// The state a snapshot holds, also sent to a standby when it first connects
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Payload {
+    accounts: Vec<Account>,
+    transactions: Vec<Tx>,
+    messages: Vec<TransactionMessage>,
+    pruned: Vec<PrunedRange>,
+    last_tx_time: u32,
+    offsets: Vec<SourceOffset>,
+    idempotency_keys: Vec<IdempotencyRecord>,
+    merkle: Option<MerkleRecord>,
+    withdrawn: Vec<WithdrawnRecord>,
+    fee_usage: Vec<FeeUsageRecord>,
+    rounding: Vec<RemainderRecord>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SourceOffset {
+    topic: String,
+    partition: u32,
+    offset: u64,
+}
+
+// Whole engine state in a single versioned, compressed file, for shipping state between hosts or releases
+pub struct SnapshotStore {
+    path: PathBuf,
+}
+
+impl Payload {
+    pub(crate) fn capture(account_txs: &AccountTransactions) -> Payload {
+        let (accounts, transactions, messages) = state::records(account_txs);
+        Payload {
+            accounts,
+            transactions,
+            messages,
+            pruned: account_txs.pruned_ranges.clone(),
+            last_tx_time: account_txs.next_tx_time() - 1,
+            offsets: account_txs.source_offsets.iter()
+                .map(|((topic, partition), offset)| SourceOffset { topic: topic.clone(), partition: *partition, offset: *offset })
+                .collect(),
+            idempotency_keys: account_txs.idempotency_keys.records(),
+            merkle: Some(account_txs.merkle.record()),
+            withdrawn: account_txs.withdrawal_limits.records(),
+            fee_usage: account_txs.fee_schedules.records(),
+            rounding: account_txs.rounding.records(),
+        }
+    }
+
+    pub(crate) fn restore(self, account_txs: &mut AccountTransactions) -> Result<()> {
+        state::restore(account_txs, self.accounts, self.transactions, self.messages);
+        account_txs.pruned_ranges.extend(self.pruned);
+        account_txs.compacted_through = account_txs.compacted_through.max(self.last_tx_time);
+        account_txs.source_offsets.extend(self.offsets.into_iter().map(|offset| ((offset.topic, offset.partition), offset.offset)));
+        account_txs.idempotency_keys.restore(self.idempotency_keys);
+        if let Some(merkle) = self.merkle {
+            account_txs.merkle = MerkleTree::restore(merkle)?;
+        }
+        account_txs.withdrawal_limits.restore(self.withdrawn);
+        account_txs.fee_schedules.restore(self.fee_usage);
+        account_txs.rounding.restore(self.rounding)?;
+        Ok(())
+    }
+}
+
+impl SnapshotStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> SnapshotStore {
+        SnapshotStore { path: path.as_ref().to_path_buf() }
+    }
+
+    fn storage_error<E: std::fmt::Display>(&self, e: E) -> Error {
+        Error::StorageError(format!("{}: {}", self.path.display(), e))
+    }
+
+    fn read_payload(&self) -> Result<Option<Payload>> {
+        let bytes = match fs::read(&self.path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(self.storage_error(e)),
+        };
+        let header = MAGIC.len() + 2;
+        if bytes.len() < header || !bytes.starts_with(MAGIC) {
+            return Err(self.storage_error("Not a tx_acct snapshot"));
+        }
+        let version = u16::from_be_bytes([bytes[MAGIC.len()], bytes[MAGIC.len() + 1]]);
+        if version == 0 || version > CURRENT_VERSION {
+            return Err(self.storage_error(format!(
+                "Snapshot format version {} is not supported. This release reads versions 1 to {}.",
+                version, CURRENT_VERSION)));
+        }
+
+        let (header, body) = bytes.split_at(header);
+        let body = encryption::open(body.to_vec(), header).map_err(|e| self.storage_error(e))?;
+        let json = decompress(&body).map_err(|e| self.storage_error(e))?;
+        let mut payload: Value = serde_json::from_slice(&json).map_err(|e| self.storage_error(e))?;
+        for upgrade in &UPGRADES[(version - 1) as usize..] {
+            upgrade(&mut payload)?;
+        }
+        serde_json::from_value(payload).map(Some).map_err(|e| self.storage_error(e))
+    }
+
+    // Written beside the snapshot and renamed over it so a crash never leaves a truncated file
+    fn write_payload(&self, payload: &Payload) -> Result<()> {
+        let json = serde_json::to_vec(payload).map_err(|e| self.storage_error(e))?;
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&CURRENT_VERSION.to_be_bytes());
+        let compressed = compress(&json).map_err(|e| self.storage_error(e))?;
+        let body = encryption::seal(compressed, &header).map_err(|e| self.storage_error(e))?;
+
+        if let Some(parent) = self.path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent).map_err(|e| self.storage_error(e))?;
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let mut file = fs::File::create(&tmp_path).map_err(|e| self.storage_error(e))?;
+        file.write_all(&header)
+            .and_then(|_| file.write_all(&body))
+            .and_then(|_| file.sync_all())
+            .map_err(|e| self.storage_error(e))?;
+        fs::rename(&tmp_path, &self.path).map_err(|e| self.storage_error(e))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn compress(json: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::encode_all(json, COMPRESSION_LEVEL)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn decompress(bytes: &[u8]) -> io::Result<Vec<u8>> {
+    zstd::decode_all(bytes)
+}
+
+// zstd is a C library and is left out of the WebAssembly build
+#[cfg(target_arch = "wasm32")]
+fn compress(_json: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Snapshots are not supported in the WebAssembly build"))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn decompress(_bytes: &[u8]) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "Snapshots are not supported in the WebAssembly build"))
+}
+
+impl Store for SnapshotStore {
+    fn load(&mut self, account_txs: &mut AccountTransactions) -> Result<()> {
+        let payload = match self.read_payload()? {
+            Some(payload) => payload,
+            None => return Ok(()),
+        };
+        payload.restore(account_txs)
+    }
+
+    fn save(&mut self, account_txs: &AccountTransactions) -> Result<()> {
+        self.write_payload(&Payload::capture(account_txs))
+    }
+
+    fn describe(&self) -> String {
+        format!("snapshot:{}", self.path.display())
+    }
+
+    fn check(&mut self) -> Result<()> {
+        state::check_dir(state::parent_dir(&self.path))
+    }
+
+    fn saves_atomically(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{apply, message, temp_path, units};
+    use crate::withdrawal_limits::{WithdrawalLimit, WithdrawalLimits};
+    use crate::TransactionType;
+
+    fn limited() -> WithdrawalLimits {
+        let mut limits = WithdrawalLimits::new();
+        limits.set_default(WithdrawalLimit { max_single: None, max_cumulative: Some(units(50)) });
+        limits
+    }
+
+    #[test]
+    fn keeps_withdrawn_counts_across_runs() {
+        let path = temp_path("snapshot-withdrawn");
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_withdrawal_limits(limited());
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(100))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(40))).unwrap();
+        SnapshotStore::new(&path).save(&account_txs).unwrap();
+
+        let mut restored = AccountTransactions::new();
+        SnapshotStore::new(&path).load(&mut restored).unwrap();
+        restored.set_withdrawal_limits(limited());
+        assert_eq!(restored.withdrawal_limits().withdrawn(1), units(40));
+        assert!(apply(&mut restored, message(TransactionType::WITHDRAWAL, 1, 3, units(20))).is_err());
+        assert_eq!(restored.get_account(1).unwrap().available, units(60));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn upgrades_older_versions() {
+        let path = temp_path("snapshot-v4");
+        let payload = serde_json::json!({
+            "accounts": [], "transactions": [], "messages": [], "pruned": [], "last_tx_time": 7, "offsets": [],
+            "idempotency_keys": [], "merkle": null,
+        });
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&4u16.to_be_bytes());
+        bytes.extend(compress(&serde_json::to_vec(&payload).unwrap()).unwrap());
+        fs::write(&path, bytes).unwrap();
+
+        let mut account_txs = AccountTransactions::new();
+        SnapshotStore::new(&path).load(&mut account_txs).unwrap();
+        assert_eq!(account_txs.next_tx_time(), 8);
+        assert!(account_txs.withdrawal_limits.records().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_newer_and_foreign_files() {
+        let path = temp_path("snapshot-v99");
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&(CURRENT_VERSION + 1).to_be_bytes());
+        fs::write(&path, bytes).unwrap();
+        let e = SnapshotStore::new(&path).load(&mut AccountTransactions::new()).unwrap_err();
+        assert!(e.to_string().contains("is not supported"), "{}", e);
+
+        fs::write(&path, b"client,available").unwrap();
+        let e = SnapshotStore::new(&path).load(&mut AccountTransactions::new()).unwrap_err();
+        assert!(e.to_string().contains("Not a tx_acct snapshot"), "{}", e);
+        fs::remove_file(&path).unwrap();
+    }
+}
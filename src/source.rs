@@ -0,0 +1,265 @@
+use std::fs::{self, File};
+use std::io::{self, BufRead, Cursor, Read};
+
+use crate::decrypt::{self, Decrypted, Format};
+use crate::generate::{generate, GeneratorConfig, GeneratorReport};
+use crate::read::{parse_line, parse_row, TxInputRecord};
+use crate::rounding::RoundingMode;
+use crate::{Result, TransactionMessage};
+
+// One row read from a source. The message's tx_time is left at 0 for the reader to assign, in the order rows are
+// applied, so rows from several sources can be mixed.
+pub struct SourceRow {
+    // A ParseError for a malformed row, any other Err ends the read
+    pub parsed: Result<TransactionMessage>,
+    // The row's idempotency_key column, when it has one
+    pub idempotency_key: Option<String>,
+    // Byte range of the row in the source's input, counted across every source of a Chain
+    pub start: u64,
+    pub end: u64,
+    // For a source read from a saved position, such as a Kafka topic: the topic and partition, and the offset to
+    // resume from once this row is handled. read_source keeps it with the state.
+    pub resume: Option<((String, u32), u64)>,
+    // The state is to be saved once this row is handled, as a source that reads without end needs
+    pub checkpoint: bool,
+}
+
+// Where read_source takes transactions from. Rows are read on the ingest queue's own thread when one is configured,
+// hence Send.
+pub trait TransactionSource: Send {
+    // For log messages and traces, e.g. the file name
+    fn describe(&self) -> String;
+
+    // None once the input is exhausted
+    fn next_row(&mut self) -> Option<SourceRow>;
+
+    // Size of the whole input in bytes when known up front, for the progress bar
+    fn total_bytes(&self) -> Option<u64> {
+        None
+    }
+
+    // The file rows are read from and the byte offset just past its header, for sources that can have malformed
+    // rows copied out of them byte for byte
+    fn file(&self) -> Option<(&str, u64)> {
+        None
+    }
+
+    // How amounts with more than four decimal places are read, set by read_source from the engine's mode before the
+    // first row. Sources of messages already parsed ignore it.
+    fn set_rounding(&mut self, _rounding: RoundingMode) {}
+}
+
+// The type,client,tx,amount CSV the tool reads, with a header row and # comments
+pub struct CsvSource<R: Read> {
+    name: String,
+    path: Option<String>,
+    total_bytes: Option<u64>,
+    header_end: u64,
+    rdr: csv::Reader<R>,
+    headers: csv::ByteRecord,
+    record: csv::ByteRecord,
+    rounding: RoundingMode,
+}
+
+impl CsvSource<File> {
+    pub fn open(path: &str) -> Result<CsvSource<File>> {
+        let mut source = CsvSource::new(path, File::open(path)?)?;
+        source.path = Some(path.to_string());
+        source.total_bytes = Some(fs::metadata(path)?.len());
+        Ok(source)
+    }
+}
+
+impl CsvSource<Decrypted> {
+    // The total is left unknown, as the file's size is not the plaintext's, and there is no file of plaintext for
+    // quarantine to copy rows out of
+    pub fn decrypted(path: &str, format: Format, key_file: &str) -> Result<CsvSource<Decrypted>> {
+        CsvSource::new(path, decrypt::open(path, format, key_file)?)
+    }
+}
+
+impl CsvSource<io::Stdin> {
+    pub fn stdin() -> Result<CsvSource<io::Stdin>> {
+        CsvSource::new("STDIN", io::stdin())
+    }
+}
+
+impl CsvSource<Cursor<Vec<u8>>> {
+    // Generates the rows up front, as the generate command would write them
+    pub fn generated(config: &GeneratorConfig) -> Result<(CsvSource<Cursor<Vec<u8>>>, GeneratorReport)> {
+        let mut rows = Vec::new();
+        let report = generate(config, &mut rows)?;
+        let len = rows.len() as u64;
+        let mut source = CsvSource::new(&format!("generated rows from seed {}", config.seed), Cursor::new(rows))?;
+        source.total_bytes = Some(len);
+        Ok((source, report))
+    }
+}
+
+impl<R: Read> CsvSource<R> {
+    pub fn new(name: &str, input: R) -> Result<CsvSource<R>> {
+        let mut rdr = csv::ReaderBuilder::new().comment(Some(b'#')).from_reader(input);
+        let headers = rdr.byte_headers()?.clone();
+        Ok(CsvSource {
+            name: name.to_string(),
+            path: None,
+            total_bytes: None,
+            header_end: rdr.position().byte(),
+            rdr,
+            headers,
+            record: csv::ByteRecord::new(),
+            rounding: RoundingMode::default(),
+        })
+    }
+}
+
+impl<R: Read + Send> TransactionSource for CsvSource<R> {
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+
+    fn next_row(&mut self) -> Option<SourceRow> {
+        let start = self.rdr.position().byte();
+        let result = match self.rdr.read_byte_record(&mut self.record) {
+            Ok(false) => return None,
+            Ok(true) => self.record.deserialize::<TxInputRecord>(Some(&self.headers)),
+            Err(e) => Err(e),
+        };
+        let idempotency_key = result.as_ref().ok().and_then(|record| record.idempotency_key.clone());
+        Some(SourceRow { parsed: parse_row(result, 0, self.rounding), idempotency_key, start,
+                         end: self.rdr.position().byte(), resume: None, checkpoint: false })
+    }
+
+    fn total_bytes(&self) -> Option<u64> {
+        self.total_bytes
+    }
+
+    fn file(&self) -> Option<(&str, u64)> {
+        self.path.as_deref().map(|path| (path, self.header_end))
+    }
+
+    fn set_rounding(&mut self, rounding: RoundingMode) {
+        self.rounding = rounding;
+    }
+}
+
+// The line protocol the TCP listener speaks, one CSV row without a header or one JSON object per line, from any
+// reader such as a socket. Blank lines and # comments are skipped.
+pub struct LineSource<R: BufRead> {
+    name: String,
+    input: R,
+    line: String,
+    offset: u64,
+    rounding: RoundingMode,
+}
+
+impl<R: BufRead> LineSource<R> {
+    pub fn new(name: &str, input: R) -> LineSource<R> {
+        LineSource { name: name.to_string(), input, line: String::new(), offset: 0, rounding: RoundingMode::default() }
+    }
+}
+
+impl<R: BufRead + Send> TransactionSource for LineSource<R> {
+    fn describe(&self) -> String {
+        self.name.clone()
+    }
+
+    fn next_row(&mut self) -> Option<SourceRow> {
+        loop {
+            self.line.clear();
+            let start = self.offset;
+            let read = match self.input.read_line(&mut self.line) {
+                Ok(0) => return None,
+                Ok(read) => read,
+                Err(e) => return Some(SourceRow { parsed: Err(e.into()), idempotency_key: None, start, end: start,
+                                                  resume: None, checkpoint: false }),
+            };
+            self.offset += read as u64;
+            let line = self.line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (parsed, idempotency_key) = match parse_line(line, 0, self.rounding) {
+                Ok((message, key)) => (Ok(message), key),
+                Err(e) => (Err(e), None),
+            };
+            return Some(SourceRow { parsed, idempotency_key, start, end: self.offset, resume: None, checkpoint: false });
+        }
+    }
+
+    fn set_rounding(&mut self, rounding: RoundingMode) {
+        self.rounding = rounding;
+    }
+}
+
+// Messages already in memory, such as ones built by a test. They have no bytes to count.
+pub struct MessageSource<I> {
+    messages: I,
+}
+
+impl<I: Iterator<Item = TransactionMessage>> MessageSource<I> {
+    pub fn new(messages: I) -> MessageSource<I> {
+        MessageSource { messages }
+    }
+}
+
+impl<I: Iterator<Item = TransactionMessage> + Send> TransactionSource for MessageSource<I> {
+    fn describe(&self) -> String {
+        String::from("messages")
+    }
+
+    fn next_row(&mut self) -> Option<SourceRow> {
+        self.messages.next().map(|msg| SourceRow { parsed: Ok(msg), idempotency_key: None, start: 0, end: 0,
+                                                   resume: None, checkpoint: false })
+    }
+}
+
+// Reads each source to the end in turn
+pub struct Chain {
+    sources: Vec<Box<dyn TransactionSource>>,
+    current: usize,
+    // Bytes read from the sources before the current one
+    offset: u64,
+    last_end: u64,
+}
+
+impl Chain {
+    pub fn new(sources: Vec<Box<dyn TransactionSource>>) -> Chain {
+        Chain { sources, current: 0, offset: 0, last_end: 0 }
+    }
+}
+
+impl TransactionSource for Chain {
+    fn describe(&self) -> String {
+        self.sources.iter().map(|source| source.describe()).collect::<Vec<String>>().join(", ")
+    }
+
+    fn next_row(&mut self) -> Option<SourceRow> {
+        while let Some(source) = self.sources.get_mut(self.current) {
+            match source.next_row() {
+                Some(mut row) => {
+                    self.last_end = row.end;
+                    row.start += self.offset;
+                    row.end += self.offset;
+                    return Some(row);
+                }
+                None => {
+                    self.offset += self.last_end;
+                    self.last_end = 0;
+                    self.current += 1;
+                }
+            }
+        }
+        None
+    }
+
+    fn total_bytes(&self) -> Option<u64> {
+        self.sources.iter().map(|source| source.total_bytes()).sum()
+    }
+
+    fn set_rounding(&mut self, rounding: RoundingMode) {
+        for source in &mut self.sources {
+            source.set_rounding(rounding);
+        }
+    }
+}
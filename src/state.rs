@@ -0,0 +1,478 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::admin::AdminAction;
+use crate::encryption;
+use crate::otel::{self, Span};
+use crate::statsd;
+use crate::{Account, AccountTransactions, Amount, Error, MergedClients, Result, TransactionMessage, Tx, TxState};
+
+const ACCOUNTS_FILE: &str = "accounts.csv";
+const TRANSACTIONS_FILE: &str = "transactions.csv";
+const MESSAGES_FILE: &str = "messages.csv";
+const TOMBSTONES_FILE: &str = "tombstones.csv";
+const PRUNED_FILE: &str = "pruned.csv";
+const COMPACTIONS_FILE: &str = "compactions.csv";
+const MERGES_FILE: &str = "merges.csv";
+const IDEMPOTENCY_FILE: &str = "idempotency_keys.csv";
+const WITHDRAWN_FILE: &str = "withdrawn.csv";
+const FEE_USAGE_FILE: &str = "fee_usage.csv";
+const ROUNDING_FILE: &str = "rounding.csv";
+
+// Stands in for an erased client so the sum of accounts plus tombstones still ties out to the ledger.
+// Deliberately carries no client id.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Tombstone {
+    pub seq: u32,
+    pub erased_at: u64,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub transactions: u32,
+    pub messages: u32,
+}
+
+// One compaction pass, folding the message log up to through_time into the snapshot
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Compaction {
+    pub compacted_at: u64,
+    pub through_time: u32,
+    pub messages: u32,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+}
+
+// Audit record of a client merge, kept so the retired client id can be traced to its successor
+#[derive(Debug, Serialize, Deserialize)]
+struct MergeRecord {
+    merged_at: u64,
+    from: u16,
+    into: u16,
+    transactions: u32,
+    messages: u32,
+    lock_conflict: bool,
+}
+
+// Where AccountTransactions is kept between runs. Stores that write as messages are handled do so in record,
+// the rest write everything in save.
+pub trait Store: Send {
+    fn load(&mut self, account_txs: &mut AccountTransactions) -> Result<()>;
+
+    // Called after each message is handled, whether or not it was applied
+    fn record(&mut self, _account_txs: &AccountTransactions, _msg: &TransactionMessage) -> Result<()> {
+        Ok(())
+    }
+
+    // Called after an admin action changed an account or transaction outside any message
+    fn record_admin(&mut self, _account_txs: &AccountTransactions, _action: &AdminAction) -> Result<()> {
+        Ok(())
+    }
+
+    fn save(&mut self, account_txs: &AccountTransactions) -> Result<()>;
+
+    fn describe(&self) -> String;
+
+    // Confirms the backend can still be reached, for serve's /readyz
+    fn check(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    // True when save replaces the whole state in one write, so a crash keeps either all of it or none
+    fn saves_atomically(&self) -> bool {
+        false
+    }
+}
+
+// Opens the store named by --store: dir:<path> for a state directory, snapshot:<file> for a single
+// compressed file, or with the matching feature, postgres://... or sled:<path>
+pub fn open_store(spec: &str) -> Result<Box<dyn Store>> {
+    let store = open_backend(spec)?;
+    if otel::enabled() || statsd::enabled() { Ok(Box::new(InstrumentedStore(store))) } else { Ok(store) }
+}
+
+fn open_backend(spec: &str) -> Result<Box<dyn Store>> {
+    if let Some(dir) = spec.strip_prefix("dir:") {
+        return Ok(Box::new(StateStore::new(dir)));
+    }
+    if let Some(path) = spec.strip_prefix("snapshot:") {
+        return Ok(Box::new(crate::snapshot::SnapshotStore::new(path)));
+    }
+    if let Some(path) = spec.strip_prefix("sled:") {
+        #[cfg(feature = "sled")]
+        return Ok(Box::new(crate::kv::SledStore::open(path)?));
+        #[cfg(not(feature = "sled"))]
+        return Err(Error::InvalidArgument(format!("This build has no sled support for {}. Rebuild with --features sled.", path)));
+    }
+    if spec.starts_with("postgres://") || spec.starts_with("postgresql://") {
+        #[cfg(feature = "postgres")]
+        return Ok(Box::new(crate::pg::PgStore::connect(spec)?));
+        #[cfg(not(feature = "postgres"))]
+        return Err(Error::InvalidArgument(String::from("This build has no PostgreSQL support. Rebuild with --features postgres.")));
+    }
+    Err(Error::InvalidArgument(format!("Unknown store, {}. Expected dir:<path>, snapshot:<file>, sled:<path> or postgres://...", spec)))
+}
+
+// Persistent state kept as a directory of CSV files, one per AccountTransactions map
+pub struct StateStore {
+    dir: PathBuf,
+}
+
+impl StateStore {
+    pub fn new<P: AsRef<Path>>(dir: P) -> StateStore {
+        StateStore { dir: dir.as_ref().to_path_buf() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn load(&self, account_txs: &mut AccountTransactions) -> Result<()> {
+        let accounts = self.read_records(ACCOUNTS_FILE)?;
+        let txs = self.read_records(TRANSACTIONS_FILE)?;
+        let msgs = self.read_records(MESSAGES_FILE)?;
+        restore(account_txs, accounts, txs, msgs);
+
+        account_txs.pruned_ranges.extend(self.read_records(PRUNED_FILE)?);
+        account_txs.idempotency_keys.restore(self.read_records(IDEMPOTENCY_FILE)?);
+        account_txs.withdrawal_limits.restore(self.read_records(WITHDRAWN_FILE)?);
+        account_txs.fee_schedules.restore(self.read_records(FEE_USAGE_FILE)?);
+        account_txs.rounding.restore(self.read_records(ROUNDING_FILE)?)?;
+
+        for compaction in self.compactions()? {
+            account_txs.compacted_through = account_txs.compacted_through.max(compaction.through_time);
+        }
+
+        Ok(())
+    }
+
+    pub fn save(&self, account_txs: &AccountTransactions) -> Result<()> {
+        let (accounts, txs, msgs) = records(account_txs);
+        self.write_records(ACCOUNTS_FILE, accounts.iter())?;
+        self.write_records(TRANSACTIONS_FILE, txs.iter())?;
+        self.write_records(MESSAGES_FILE, msgs.iter())?;
+        self.write_records(PRUNED_FILE, account_txs.pruned_ranges.iter())?;
+        self.write_records(IDEMPOTENCY_FILE, account_txs.idempotency_keys.records().iter())?;
+        self.write_records(WITHDRAWN_FILE, account_txs.withdrawal_limits.records().iter())?;
+        self.write_records(FEE_USAGE_FILE, account_txs.fee_schedules.records().iter())?;
+        self.write_records(ROUNDING_FILE, account_txs.rounding.records().iter())?;
+
+        Ok(())
+    }
+
+    pub fn tombstones(&self) -> Result<Vec<Tombstone>> {
+        self.read_records(TOMBSTONES_FILE)
+    }
+
+    pub fn compactions(&self) -> Result<Vec<Compaction>> {
+        self.read_records(COMPACTIONS_FILE)
+    }
+
+    // The snapshot files always hold the current balances, so compaction only has to trim the message log
+    // down to the newest keep_messages and rewrite every file without leftovers from interrupted saves.
+    pub fn compact(&self, keep_messages: usize) -> Result<Compaction> {
+        let bytes_before = self.size()?;
+
+        let mut account_txs = AccountTransactions::new();
+        self.load(&mut account_txs)?;
+        let messages = account_txs.trim_messages(keep_messages);
+        for path in self.files()? {
+            if path.extension().is_some_and(|ext| ext == "tmp") {
+                fs::remove_file(&path).map_err(|e| Error::StorageError(format!("{}: {}", path.display(), e)))?;
+            }
+        }
+        self.save(&account_txs)?;
+
+        let mut compactions = self.compactions()?;
+        compactions.push(Compaction {
+            compacted_at: now_secs(),
+            through_time: account_txs.compacted_through,
+            messages,
+            bytes_before,
+            bytes_after: self.size()?,
+        });
+        self.write_records(COMPACTIONS_FILE, compactions.iter())?;
+
+        Ok(compactions.pop().expect("Compaction was just added."))
+    }
+
+    pub fn record_merge(&self, merged: &MergedClients) -> Result<()> {
+        let mut merges: Vec<MergeRecord> = self.read_records(MERGES_FILE)?;
+        merges.push(MergeRecord {
+            merged_at: now_secs(),
+            from: merged.from,
+            into: merged.into,
+            transactions: merged.transactions,
+            messages: merged.messages,
+            lock_conflict: merged.lock_conflict,
+        });
+        self.write_records(MERGES_FILE, merges.iter())
+    }
+
+    // Removes every trace of the client from the state, leaving a tombstone with the balances it held
+    pub fn erase_client(&self, client: u16) -> Result<Tombstone> {
+        let mut account_txs = AccountTransactions::new();
+        self.load(&mut account_txs)?;
+
+        let erased = account_txs.erase_client(client)
+            .ok_or_else(|| Error::NotFound(format!("Client, {}, not found in state.", client)))?;
+        // Saved first, so an interrupted erase never leaves a tombstone for a client still in the state
+        self.save(&account_txs)?;
+
+        let mut tombstones = self.tombstones()?;
+        let tombstone = Tombstone {
+            seq: tombstones.len() as u32 + 1,
+            erased_at: now_secs(),
+            available: erased.account.available,
+            held: erased.account.held,
+            total: erased.account.total,
+            transactions: erased.transactions,
+            messages: erased.messages,
+        };
+        tombstones.push(tombstone);
+        self.write_records(TOMBSTONES_FILE, tombstones.iter())?;
+
+        Ok(tombstones.pop().expect("Tombstone was just added."))
+    }
+
+    fn storage_error<N: fmt::Display, E: fmt::Display>(&self, name: N, e: E) -> Error {
+        Error::StorageError(format!("{}: {}", self.dir.join(name.to_string()).display(), e))
+    }
+
+    fn files(&self) -> Result<Vec<PathBuf>> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| self.storage_error("", e))?;
+        let mut files = Vec::new();
+        for entry in entries {
+            let entry = entry.map_err(|e| self.storage_error("", e))?;
+            if entry.file_type().map_err(|e| self.storage_error("", e))?.is_file() {
+                files.push(entry.path());
+            }
+        }
+        Ok(files)
+    }
+
+    // Total bytes used by the files in the state directory
+    fn size(&self) -> Result<u64> {
+        let mut bytes = 0;
+        for path in self.files()? {
+            bytes += fs::metadata(&path).map_err(|e| Error::StorageError(format!("{}: {}", path.display(), e)))?.len();
+        }
+        Ok(bytes)
+    }
+
+    // A file that does not exist yet reads as empty
+    fn read_records<T: DeserializeOwned>(&self, name: &str) -> Result<Vec<T>> {
+        let path = self.dir.join(name);
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let bytes = fs::read(path).map_err(|e| self.storage_error(name, e))?;
+        let bytes = encryption::open(bytes, name.as_bytes()).map_err(|e| self.storage_error(name, e))?;
+        let mut rdr = csv::Reader::from_reader(bytes.as_slice());
+        let mut records = Vec::new();
+        for result in rdr.deserialize() {
+            records.push(result.map_err(|e| self.storage_error(name, e))?);
+        }
+        Ok(records)
+    }
+
+    // Writes next to the target then renames, so an interrupted save never leaves a torn file
+    fn write_records<S: Serialize, I: Iterator<Item = S>>(&self, name: &str, records: I) -> Result<()> {
+        fs::create_dir_all(&self.dir).map_err(|e| self.storage_error("", e))?;
+        let path = self.dir.join(name);
+        let tmp_path = self.dir.join(format!("{}.tmp", name));
+        let mut wtr = csv::Writer::from_writer(Vec::new());
+        for record in records {
+            wtr.serialize(record).map_err(|e| self.storage_error(name, e))?;
+        }
+        let bytes = wtr.into_inner().map_err(|e| self.storage_error(name, e))?;
+        let bytes = encryption::seal(bytes, name.as_bytes()).map_err(|e| self.storage_error(name, e))?;
+        fs::write(&tmp_path, bytes).map_err(|e| self.storage_error(name, e))?;
+        fs::rename(tmp_path, path).map_err(|e| self.storage_error(name, e))?;
+        Ok(())
+    }
+}
+
+impl Store for StateStore {
+    fn load(&mut self, account_txs: &mut AccountTransactions) -> Result<()> {
+        StateStore::load(self, account_txs)
+    }
+
+    fn save(&mut self, account_txs: &AccountTransactions) -> Result<()> {
+        StateStore::save(self, account_txs)
+    }
+
+    fn describe(&self) -> String {
+        self.dir.display().to_string()
+    }
+
+    // The directory is created by the first save, so only its parent has to exist before then
+    fn check(&mut self) -> Result<()> {
+        check_dir(if self.dir.exists() { &self.dir } else { parent_dir(&self.dir) })
+    }
+}
+
+// The directory a file or directory path sits in, "." for a bare name
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    path.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."))
+}
+
+pub(crate) fn check_dir(dir: &Path) -> Result<()> {
+    match fs::metadata(dir) {
+        Ok(metadata) if metadata.is_dir() => Ok(()),
+        Ok(_) => Err(Error::StorageError(format!("{} is not a directory.", dir.display()))),
+        Err(e) => Err(Error::StorageError(format!("{}: {}", dir.display(), e))),
+    }
+}
+
+// Reports each store operation as a span and a StatsD timer. Per-message records are sampled like transactions
+// and not timed.
+struct InstrumentedStore(Box<dyn Store>);
+
+impl InstrumentedStore {
+    fn run<T>(&mut self, name: &'static str, mut span: Span, op: impl FnOnce(&mut dyn Store) -> Result<T>) -> Result<T> {
+        span.set("store", self.0.describe());
+        let start = Instant::now();
+        let result = op(self.0.as_mut());
+        statsd::timing(name, start.elapsed());
+        if let Err(e) = &result {
+            span.fail(e);
+        }
+        result
+    }
+}
+
+impl Store for InstrumentedStore {
+    fn load(&mut self, account_txs: &mut AccountTransactions) -> Result<()> {
+        self.run("store.load", otel::span("store.load"), |store| store.load(account_txs))
+    }
+
+    fn record(&mut self, account_txs: &AccountTransactions, msg: &TransactionMessage) -> Result<()> {
+        let mut span = otel::sampled_span("store.record", msg.tx_time);
+        if span.is_recording() {
+            span.set("store", self.0.describe());
+        }
+        let result = self.0.record(account_txs, msg);
+        if let Err(e) = &result {
+            span.fail(e);
+        }
+        result
+    }
+
+    fn record_admin(&mut self, account_txs: &AccountTransactions, action: &AdminAction) -> Result<()> {
+        self.run("store.record_admin", otel::span("store.record_admin"), |store| store.record_admin(account_txs, action))
+    }
+
+    fn save(&mut self, account_txs: &AccountTransactions) -> Result<()> {
+        self.run("store.save", otel::span("store.save"), |store| store.save(account_txs))
+    }
+
+    fn describe(&self) -> String {
+        self.0.describe()
+    }
+
+    fn check(&mut self) -> Result<()> {
+        self.0.check()
+    }
+
+    fn saves_atomically(&self) -> bool {
+        self.0.saves_atomically()
+    }
+}
+
+// The engine's maps in id order, the layout shared by the state files and snapshots. Amounts are kept as currency
+// subunits so nothing is lost across runs.
+pub(crate) fn records(account_txs: &AccountTransactions) -> (Vec<Account>, Vec<Tx>, Vec<TransactionMessage>) {
+    let mut accounts: Vec<Account> = account_txs.account_client.values().cloned().collect();
+    accounts.sort_by_key(|acct| acct.client);
+
+    let mut txs: Vec<Tx> = account_txs.txs_txid.values().cloned().collect();
+    txs.sort_by_key(|tx| tx.tx);
+
+    let mut msgs: Vec<TransactionMessage> = account_txs.tx_msgs_time.values().cloned().collect();
+    msgs.sort_by_key(|msg| msg.tx_time);
+
+    (accounts, txs, msgs)
+}
+
+pub(crate) fn restore(account_txs: &mut AccountTransactions, accounts: Vec<Account>, txs: Vec<Tx>,
+                      msgs: Vec<TransactionMessage>) {
+    for acct in accounts {
+        account_txs.account_client.insert(acct.client, acct);
+    }
+    for mut tx in txs {
+        // Read from a disputed flag, which a chargeback cleared
+        if tx.state == TxState::Posted && tx.chargeback_time != 0 {
+            tx.state = TxState::ChargedBack;
+        }
+        account_txs.txs_txid.insert(tx.tx, tx);
+    }
+    for msg in msgs {
+        account_txs.tx_msgs_time.insert(msg.tx_time, msg);
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{apply, message, temp_path, units};
+    use crate::TransactionType;
+
+    #[test]
+    fn compaction_keeps_the_state() {
+        let dir = temp_path("compact-state");
+        let store = StateStore::new(&dir);
+        let mut account_txs = AccountTransactions::new();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(5))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 3, units(4))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DISPUTE, 2, 2, 0)).unwrap();
+        store.save(&account_txs).unwrap();
+        let mut before = AccountTransactions::new();
+        store.load(&mut before).unwrap();
+
+        let compaction = store.compact(0).unwrap();
+        assert_eq!(compaction.messages, 4);
+        let mut after = AccountTransactions::new();
+        store.load(&mut after).unwrap();
+        for client in [1, 2] {
+            assert_eq!(after.account_client.get(&client), before.account_client.get(&client));
+        }
+        assert_eq!(after.txs_txid.len(), before.txs_txid.len());
+        // The disputed deposit can still be resolved
+        apply(&mut after, message(TransactionType::RESOLVE, 2, 2, 0)).unwrap();
+        assert_eq!(after.account_client[&2].available, units(5));
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn erased_client_is_gone_after_reload() {
+        let dir = temp_path("erase-state");
+        let store = StateStore::new(&dir);
+        let mut account_txs = AccountTransactions::new();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(10))).unwrap();
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 2, 2, units(5))).unwrap();
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 3, units(4))).unwrap();
+        store.save(&account_txs).unwrap();
+
+        let tombstone = store.erase_client(1).unwrap();
+        assert_eq!((tombstone.seq, tombstone.available, tombstone.transactions, tombstone.messages), (1, units(6), 2, 2));
+        let mut after = AccountTransactions::new();
+        store.load(&mut after).unwrap();
+        assert!(!after.account_client.contains_key(&1));
+        assert!(after.txs_txid.values().all(|tx| tx.client != 1));
+        assert!(after.tx_msgs_time.values().all(|msg| msg.client != 1));
+        assert_eq!(after.account_client[&2].available, units(5));
+        assert_eq!(store.tombstones().unwrap().len(), 1);
+        assert!(matches!(store.erase_client(1), Err(Error::NotFound(_))));
+        let _ = fs::remove_dir_all(dir);
+    }
+}
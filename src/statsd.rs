@@ -0,0 +1,149 @@
+use std::collections::BTreeMap;
+use std::net::UdpSocket;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::config::StatsdConfig;
+use crate::{Error, Rejection, Result, TransactionMessage};
+
+// Keeps each packet inside a typical MTU
+const MAX_PACKET: usize = 1432;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static CLIENT: Mutex<Option<Client>> = Mutex::new(None);
+
+// Metric name and tags
+type CounterKey = (String, Vec<(&'static str, String)>);
+
+struct Client {
+    socket: UdpSocket,
+    prefix: String,
+    dogstatsd: bool,
+    // Constant tags appended to every DogStatsD metric, already joined
+    tags: String,
+    // Summed between flushes so a large file sends one packet a second rather than one per row
+    counters: BTreeMap<CounterKey, i64>,
+    timings: Vec<String>,
+    flusher: Option<(Sender<()>, JoinHandle<()>)>,
+}
+
+// Starts sending metrics to the StatsD server, flushing every flush_interval_ms from a background thread
+pub fn init(config: &StatsdConfig) -> Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0")?;
+    socket.connect(&config.addr).map_err(|e| Error::ConfigError(format!("StatsD address, {}: {}", config.addr, e)))?;
+    let (stop, stopped) = mpsc::channel::<()>();
+    let interval = Duration::from_millis(config.flush_interval_ms.max(1));
+    let worker = thread::spawn(move || {
+        while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+            flush();
+        }
+    });
+    *CLIENT.lock().expect("StatsD lock poisoned.") = Some(Client {
+        socket,
+        prefix: config.prefix.clone(),
+        dogstatsd: config.dogstatsd,
+        tags: config.tags.join(","),
+        counters: BTreeMap::new(),
+        timings: Vec::new(),
+        flusher: Some((stop, worker)),
+    });
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+// Sends what is left and stops the flusher
+pub fn shutdown() {
+    ENABLED.store(false, Ordering::Relaxed);
+    let flusher = CLIENT.lock().expect("StatsD lock poisoned.").as_mut().and_then(|client| client.flusher.take());
+    if let Some((stop, worker)) = flusher {
+        drop(stop);
+        let _ = worker.join();
+    }
+    flush();
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn count(name: &str, value: i64, tags: &[(&'static str, &str)]) {
+    if !enabled() {
+        return;
+    }
+    if let Some(client) = CLIENT.lock().expect("StatsD lock poisoned.").as_mut() {
+        let tags = tags.iter().map(|(key, value)| (*key, value.to_string())).collect();
+        *client.counters.entry((name.to_string(), tags)).or_insert(0) += value;
+    }
+}
+
+pub fn timing(name: &str, elapsed: Duration) {
+    if !enabled() {
+        return;
+    }
+    if let Some(client) = CLIENT.lock().expect("StatsD lock poisoned.").as_mut() {
+        let line = client.line(name, &format!("{:.3}|ms", elapsed.as_secs_f64() * 1000.0), &[]);
+        client.timings.push(line);
+    }
+}
+
+// One handled message: transactions by type, and rejections by reason
+pub fn message(msg: &TransactionMessage, rejection: Option<Rejection>) {
+    if !enabled() {
+        return;
+    }
+    let tx_type = msg.tx_type.to_string();
+    count("transactions", 1, &[("type", &tx_type)]);
+    if let Some(reason) = rejection {
+        count("rejections", 1, &[("type", &tx_type), ("reason", reason.code())]);
+    }
+}
+
+impl Client {
+    // DogStatsD carries tags after |#. Plain StatsD has no tags, so their values become part of the name.
+    fn line(&self, name: &str, value: &str, tags: &[(&'static str, String)]) -> String {
+        if self.dogstatsd {
+            let mut all: Vec<String> = tags.iter().map(|(key, value)| format!("{}:{}", key, value)).collect();
+            if !self.tags.is_empty() {
+                all.push(self.tags.clone());
+            }
+            let tags = if all.is_empty() { String::new() } else { format!("|#{}", all.join(",")) };
+            format!("{}{}:{}{}", self.prefix, name, value, tags)
+        } else {
+            let suffix: String = tags.iter().map(|(_, value)| format!(".{}", value)).collect();
+            format!("{}{}{}:{}", self.prefix, name, suffix, value)
+        }
+    }
+}
+
+// Packs the pending metrics into as few packets as fit. Send failures are ignored, as StatsD over UDP is lossy
+// by design.
+fn flush() {
+    let mut guard = CLIENT.lock().expect("StatsD lock poisoned.");
+    let client = match guard.as_mut() {
+        Some(client) => client,
+        None => return,
+    };
+    let counters = std::mem::take(&mut client.counters);
+    let mut lines: Vec<String> = counters.iter()
+        .map(|((name, tags), value)| client.line(name, &format!("{}|c", value), tags))
+        .collect();
+    lines.append(&mut client.timings);
+
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+            let _ = client.socket.send(packet.as_bytes());
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(&line);
+    }
+    if !packet.is_empty() {
+        let _ = client.socket.send(packet.as_bytes());
+    }
+}
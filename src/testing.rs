@@ -0,0 +1,26 @@
+// Builders for the messages the unit tests feed an engine
+use crate::{AccountTransactions, Amount, Result, TransactionMessage, TransactionType};
+
+pub(crate) fn message(tx_type: TransactionType, client: u16, tx: u32, amount: Amount) -> TransactionMessage {
+    TransactionMessage { tx_time: 0, tx_type, client, tx, amount, to_client: None, counterparty: None, memo: None,
+                         category: None }
+}
+
+// Handles the message as the next one the engine sees, as a read would
+pub(crate) fn apply(account_txs: &mut AccountTransactions, mut msg: TransactionMessage) -> Result<()> {
+    msg.tx_time = account_txs.next_tx_time();
+    account_txs.handle_tx_message(&msg)
+}
+
+// Amounts in whole currency units
+pub(crate) fn units(units: Amount) -> Amount {
+    units * 10_000
+}
+
+// A path under the temporary directory unique to this process and name, removed first if a run left it behind
+#[cfg(feature = "io")]
+pub(crate) fn temp_path(name: &str) -> std::path::PathBuf {
+    let path = std::env::temp_dir().join(format!("tx_acct-test-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
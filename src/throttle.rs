@@ -0,0 +1,24 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Holds ingestion to an average rate by sleeping whenever the rows so far are ahead of schedule
+pub struct Throttle {
+    rows_per_second: Option<f64>,
+    start: Instant,
+}
+
+impl Throttle {
+    pub fn new(rows_per_second: Option<f64>) -> Throttle {
+        Throttle { rows_per_second: rows_per_second.filter(|rate| *rate > 0.0), start: Instant::now() }
+    }
+
+    pub fn wait(&self, rows: u32) {
+        if let Some(rate) = self.rows_per_second {
+            let due = Duration::from_secs_f64(f64::from(rows) / rate);
+            let elapsed = self.start.elapsed();
+            if due > elapsed {
+                thread::sleep(due - elapsed);
+            }
+        }
+    }
+}
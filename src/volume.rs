@@ -0,0 +1,37 @@
+use std::collections::BTreeMap;
+
+use crate::{AccountTransactions, Amount, TransactionMessage, TransactionType};
+
+// How many messages of one type applied for one client, and what they came to in subunits
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TypeVolume {
+    pub count: u64,
+    pub amount: Amount,
+}
+
+impl AccountTransactions {
+    // By (client, type), for the messages applied since the engine started
+    pub fn volumes(&self) -> &BTreeMap<(u16, TransactionType), TypeVolume> {
+        &self.volumes
+    }
+
+    // Summed over clients, by type
+    pub fn type_volumes(&self) -> BTreeMap<TransactionType, TypeVolume> {
+        let mut by_type: BTreeMap<TransactionType, TypeVolume> = BTreeMap::new();
+        for ((_, tx_type), volume) in &self.volumes {
+            let totals = by_type.entry(tx_type.clone()).or_default();
+            totals.count += volume.count;
+            totals.amount += volume.amount;
+        }
+        by_type
+    }
+
+    // Called for each message that applied. Messages naming an earlier transaction, disputes and approvals among
+    // them, count that transaction's amount, since their own rows carry none.
+    pub(crate) fn count_volume(&mut self, transaction_msg: &TransactionMessage) {
+        let amount = self.txs_txid.get(&transaction_msg.tx).map_or(transaction_msg.amount, |tx| tx.amount);
+        let volume = self.volumes.entry((transaction_msg.client, transaction_msg.tx_type.clone())).or_default();
+        volume.count += 1;
+        volume.amount += amount;
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::Cursor;
+
+use wasm_bindgen::prelude::*;
+
+use crate::output::{write_all, OutputFormat};
+use crate::source::{CsvSource, TransactionSource};
+use crate::{to_subunit, Account, AccountTransactions, Error, TransactionMessage, TransactionType};
+
+// The engine for JavaScript, such as a page checking a small file in the browser. Messages are applied directly
+// rather than through read_source, which times its progress with a clock the browser build lacks.
+#[wasm_bindgen(js_name = Engine)]
+pub struct WasmEngine {
+    account_txs: AccountTransactions,
+    next_time: u32,
+}
+
+// Counts for one call to processCsv, with the message for each row that was not applied
+#[wasm_bindgen]
+pub struct Report {
+    pub rows: u32,
+    pub applied: u32,
+    pub rejected: u32,
+    pub malformed: u32,
+    errors: Vec<String>,
+}
+
+#[wasm_bindgen]
+impl Report {
+    #[wasm_bindgen(getter)]
+    pub fn errors(&self) -> Vec<String> {
+        self.errors.clone()
+    }
+}
+
+impl Default for WasmEngine {
+    fn default() -> WasmEngine {
+        WasmEngine::new()
+    }
+}
+
+#[wasm_bindgen(js_class = Engine)]
+impl WasmEngine {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmEngine {
+        WasmEngine { account_txs: AccountTransactions::new(), next_time: 1 }
+    }
+
+    // Applies the rows of a type,client,tx,amount CSV, header included, as the command line reads a file
+    #[wasm_bindgen(js_name = processCsv)]
+    pub fn process_csv(&mut self, text: &str) -> Result<Report, JsError> {
+        let mut source = CsvSource::new("input", Cursor::new(text.as_bytes().to_vec()))?;
+        let mut report = Report { rows: 0, applied: 0, rejected: 0, malformed: 0, errors: Vec::new() };
+        while let Some(row) = source.next_row() {
+            report.rows += 1;
+            match row.parsed {
+                Ok(msg) => match self.apply(msg) {
+                    Ok(()) => report.applied += 1,
+                    Err(e) => {
+                        report.rejected += 1;
+                        report.errors.push(e.to_string());
+                    }
+                },
+                Err(Error::ParseError(e)) => {
+                    report.malformed += 1;
+                    report.errors.push(format!("Row {}: {}", report.rows, e));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(report)
+    }
+
+    // Applies one transaction, the amount in currency units as written in the input. A rejection is thrown.
+    pub fn submit(&mut self, tx_type: &str, client: u16, tx: u32, amount: Option<String>) -> Result<(), JsError> {
+        let tx_type: TransactionType = tx_type.parse()?;
+        let amount = amount.as_deref().map(to_subunit).transpose()?.unwrap_or(0);
+        self.apply(TransactionMessage { tx_time: 0, tx_type, client, tx, amount, to_client: None, counterparty: None,
+                                        memo: None, category: None })?;
+        Ok(())
+    }
+
+    // Accounts in the command line's CSV output format, ordered by client
+    #[wasm_bindgen(js_name = accountsCsv)]
+    pub fn accounts_csv(&self) -> Result<String, JsError> {
+        self.accounts(OutputFormat::Csv)
+    }
+
+    // Accounts as JSON Lines, ordered by client
+    #[wasm_bindgen(js_name = accountsJson)]
+    pub fn accounts_json(&self) -> Result<String, JsError> {
+        self.accounts(OutputFormat::Json)
+    }
+}
+
+impl WasmEngine {
+    fn apply(&mut self, msg: TransactionMessage) -> crate::Result<()> {
+        let msg = TransactionMessage { tx_time: self.next_time, ..msg };
+        self.next_time += 1;
+        self.account_txs.handle_tx_message(&msg)
+    }
+
+    fn accounts(&self, format: OutputFormat) -> Result<String, JsError> {
+        let mut accounts: Vec<&Account> = self.account_txs.iter_accounts().collect();
+        accounts.sort_by_key(|account| account.client);
+        let mut out = Vec::new();
+        write_all(format.sink(&mut out).as_mut(), accounts)?;
+        Ok(String::from_utf8(out)?)
+    }
+}
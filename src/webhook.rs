@@ -0,0 +1,165 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::change::{AccountChange, AccountState};
+use crate::config::WebhookConfig;
+use crate::http::{self, Url};
+use crate::logging::utc_timestamp;
+use crate::observer::EngineObserver;
+use crate::{Result, TransactionType};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WebhookEvent {
+    // An account went from unlocked to locked
+    Locked,
+    Chargeback,
+    // A dispute was opened on a transaction
+    Dispute,
+}
+
+impl WebhookEvent {
+    pub fn all() -> Vec<WebhookEvent> {
+        vec![WebhookEvent::Locked, WebhookEvent::Chargeback, WebhookEvent::Dispute]
+    }
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: WebhookEvent,
+    client: u16,
+    tx: u32,
+    account: &'a AccountState,
+    at: String,
+}
+
+struct Delivery {
+    url: Url,
+    body: Vec<u8>,
+    retries: u32,
+    backoff: Duration,
+}
+
+// Posts webhook payloads from a background thread so retries never hold up ingestion. finish waits for the
+// queued deliveries, so nothing is lost when the run ends.
+pub struct Notifier {
+    hooks: Vec<(Url, WebhookConfig)>,
+    sender: Option<Sender<Delivery>>,
+    worker: Option<JoinHandle<()>>,
+    // Deliveries sent to the worker that it has not finished, retries included
+    queued: Arc<AtomicUsize>,
+}
+
+impl Notifier {
+    pub fn new(webhooks: &[WebhookConfig]) -> Result<Notifier> {
+        let mut hooks = Vec::new();
+        for hook in webhooks {
+            hooks.push((Url::parse(&hook.url)?, hook.clone()));
+        }
+        let (sender, receiver) = mpsc::channel::<Delivery>();
+        let queued = Arc::new(AtomicUsize::new(0));
+        let worker = {
+            let queued = Arc::clone(&queued);
+            thread::spawn(move || {
+                for delivery in receiver {
+                    deliver(&delivery);
+                    queued.fetch_sub(1, Ordering::Relaxed);
+                }
+            })
+        };
+        Ok(Notifier { hooks, sender: Some(sender), worker: Some(worker), queued })
+    }
+
+    // Fires the hooks subscribed to whichever events the change amounts to. applied is whether the message
+    // that caused it was accepted.
+    pub fn notify(&self, change: &AccountChange, applied: bool) -> Result<()> {
+        let mut events = Vec::new();
+        if change.new.locked && !change.old.as_ref().is_some_and(|old| old.locked) {
+            events.push(WebhookEvent::Locked);
+        }
+        if applied && change.tx_type == TransactionType::CHARGEBACK.to_string() {
+            events.push(WebhookEvent::Chargeback);
+        }
+        if applied && change.tx_type == TransactionType::DISPUTE.to_string() {
+            events.push(WebhookEvent::Dispute);
+        }
+
+        for event in events {
+            let body = serde_json::to_vec(&Payload {
+                event,
+                client: change.client,
+                tx: change.tx,
+                account: &change.new,
+                at: utc_timestamp(),
+            })?;
+            for (url, hook) in self.hooks.iter().filter(|(_, hook)| hook.events.contains(&event)) {
+                if let Some(sender) = &self.sender {
+                    self.queued.fetch_add(1, Ordering::Relaxed);
+                    // The worker only stops once the sender is dropped, so this cannot fail
+                    let _ = sender.send(Delivery {
+                        url: url.clone(),
+                        body: body.clone(),
+                        retries: hook.retries,
+                        backoff: Duration::from_millis(hook.backoff_ms),
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn queued(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn finish(&mut self) {
+        self.sender.take();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Only a change to an account that already existed can come from an applied dispute or chargeback
+impl EngineObserver for Notifier {
+    fn name(&self) -> &str {
+        "webhooks"
+    }
+
+    fn account_created(&mut self, change: &AccountChange) -> Result<()> {
+        self.notify(change, false)
+    }
+
+    fn account_changed(&mut self, change: &AccountChange) -> Result<()> {
+        self.notify(change, true)
+    }
+}
+
+impl Drop for Notifier {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+// Tries once plus up to retries more times, doubling the wait after each failure
+fn deliver(delivery: &Delivery) {
+    let mut backoff = delivery.backoff;
+    for attempt in 0..=delivery.retries {
+        let error = match http::post(&delivery.url, "application/json", &delivery.body) {
+            Ok(status) if (200..300).contains(&status) => return,
+            Ok(status) => format!("HTTP status {}", status),
+            Err(e) => e.to_string(),
+        };
+        if attempt == delivery.retries {
+            diag!("Webhook to {}{} failed after {} attempts. {}", delivery.url.host, delivery.url.path, attempt + 1, error);
+            return;
+        }
+        thread::sleep(backoff);
+        backoff *= 2;
+    }
+}
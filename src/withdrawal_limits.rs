@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+
+#[cfg(feature = "io")]
+use serde::{Deserialize, Serialize};
+
+use crate::error::Rejection;
+use crate::{format_amount, rejected, Amount, Result, TransactionMessage};
+#[cfg(feature = "io")]
+use crate::rounding::{self, RoundingMode};
+#[cfg(feature = "io")]
+use crate::Error;
+
+// Most a client may withdraw, in currency subunits. None is no limit.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WithdrawalLimit {
+    // In one withdrawal
+    pub max_single: Option<Amount>,
+    // In all withdrawals applied while the limit was set, counted across runs with the state
+    pub max_cumulative: Option<Amount>,
+}
+
+// A withdrawal refused for going over its client's limit
+#[derive(Debug, Clone, PartialEq)]
+pub struct LimitBreach {
+    pub client: u16,
+    pub tx: u32,
+    pub amount: Amount,
+    pub reason: Rejection,
+    // The limit it went over, and for the cumulative limit what the client had withdrawn before it
+    pub limit: Amount,
+    pub withdrawn: Amount,
+}
+
+// Per-client withdrawal limits, checked by the engine on every withdrawal that has the funds. A client without
+// limits of its own has the default ones.
+#[derive(Debug, Clone, Default)]
+pub struct WithdrawalLimits {
+    limits: HashMap<u16, WithdrawalLimit>,
+    default: Option<WithdrawalLimit>,
+    // Applied withdrawals by client, only kept for clients with a cumulative limit
+    withdrawn: HashMap<u16, Amount>,
+    breaches: Vec<LimitBreach>,
+}
+
+// One row of a limits file, client,max_withdrawal,max_cumulative with amounts in currency units, an empty amount for
+// no limit, and * as the client for the default
+#[cfg(feature = "io")]
+#[derive(Deserialize)]
+struct LimitRow {
+    client: String,
+    #[serde(default)]
+    max_withdrawal: String,
+    #[serde(default)]
+    max_cumulative: String,
+}
+
+// What one client has withdrawn towards its cumulative limit, as the state files, snapshots and stores keep it
+#[cfg(feature = "io")]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct WithdrawnRecord {
+    pub(crate) client: u16,
+    pub(crate) amount: Amount,
+}
+
+impl WithdrawalLimits {
+    pub fn new() -> WithdrawalLimits {
+        WithdrawalLimits::default()
+    }
+
+    pub fn set(&mut self, client: u16, limit: WithdrawalLimit) {
+        self.limits.insert(client, limit);
+    }
+
+    pub fn set_default(&mut self, limit: WithdrawalLimit) {
+        self.default = Some(limit);
+    }
+
+    // Amounts past the fourth decimal place round by the given mode, as input rows do
+    #[cfg(feature = "io")]
+    pub fn read(path: &str, rounding: RoundingMode) -> Result<WithdrawalLimits> {
+        let invalid = |e: &dyn std::fmt::Display| Error::InvalidArgument(format!("Withdrawal limits {}: {}", path, e));
+        let amount = |text: &str| match text.trim() {
+            "" => Ok(None),
+            text => rounding::parse_subunits(text, rounding).map(Some).map_err(|e| invalid(&e)),
+        };
+        let mut rdr = csv::ReaderBuilder::new().comment(Some(b'#')).trim(csv::Trim::All).from_path(path)
+            .map_err(|e| invalid(&e))?;
+        let mut limits = WithdrawalLimits::new();
+        for row in rdr.deserialize() {
+            let row: LimitRow = row.map_err(|e| invalid(&e))?;
+            let limit = WithdrawalLimit {
+                max_single: amount(&row.max_withdrawal)?,
+                max_cumulative: amount(&row.max_cumulative)?,
+            };
+            match row.client.as_str() {
+                "*" => limits.set_default(limit),
+                client => {
+                    let client = client.parse().map_err(|_| invalid(&format!("{} is not a client id", client)))?;
+                    limits.set(client, limit);
+                }
+            }
+        }
+        Ok(limits)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.limits.is_empty() && self.default.is_none()
+    }
+
+    pub fn limit(&self, client: u16) -> Option<&WithdrawalLimit> {
+        self.limits.get(&client).or(self.default.as_ref())
+    }
+
+    // What the client has withdrawn towards its cumulative limit
+    pub fn withdrawn(&self, client: u16) -> Amount {
+        self.withdrawn.get(&client).copied().unwrap_or(0)
+    }
+
+    // Withdrawals refused so far, in the order they were made
+    pub fn breaches(&self) -> &[LimitBreach] {
+        &self.breaches
+    }
+
+    // Keeps what earlier limits counted, so limits set after the state was loaded go on from it
+    pub(crate) fn carry_over(&mut self, earlier: WithdrawalLimits) {
+        for (client, withdrawn) in earlier.withdrawn {
+            self.withdrawn.entry(client).or_insert(withdrawn);
+        }
+    }
+
+    // What client from withdrew counts towards client into's cumulative limit from here on
+    pub(crate) fn merge_client(&mut self, from: u16, into: u16) {
+        if let Some(withdrawn) = self.withdrawn.remove(&from) {
+            let total = self.withdrawn.entry(into).or_insert(0);
+            *total = total.saturating_add(withdrawn);
+        }
+    }
+
+    // By client
+    #[cfg(feature = "io")]
+    pub(crate) fn records(&self) -> Vec<WithdrawnRecord> {
+        let mut records: Vec<WithdrawnRecord> = self.withdrawn.iter()
+            .map(|(client, amount)| WithdrawnRecord { client: *client, amount: *amount })
+            .collect();
+        records.sort_by_key(|record| record.client);
+        records
+    }
+
+    #[cfg(feature = "io")]
+    pub(crate) fn restore(&mut self, records: Vec<WithdrawnRecord>) {
+        self.withdrawn.extend(records.into_iter().map(|record| (record.client, record.amount)));
+    }
+
+    // Refuses a withdrawal over its client's limits, otherwise counts it towards the cumulative one. Called once the
+    // withdrawal is known to have the funds, so only withdrawals that apply are counted.
+    pub(crate) fn admit(&mut self, msg: &TransactionMessage) -> Result<()> {
+        let limit = match self.limit(msg.client) {
+            Some(limit) => *limit,
+            None => return Ok(()),
+        };
+        let withdrawn = self.withdrawn.get(&msg.client).copied().unwrap_or(0);
+        let breach = match (limit.max_single, limit.max_cumulative) {
+            (Some(max), _) if msg.amount > max => Some((Rejection::WithdrawalLimit, max)),
+            (_, Some(max)) if withdrawn.saturating_add(msg.amount) > max => Some((Rejection::CumulativeLimit, max)),
+            _ => None,
+        };
+        if let Some((reason, max)) = breach {
+            self.breaches.push(LimitBreach { client: msg.client, tx: msg.tx, amount: msg.amount, reason, limit: max,
+                                             withdrawn });
+            let message = match reason {
+                Rejection::WithdrawalLimit => format!(
+                    "Withdrawal over the client's limit of {} per withdrawal. Ignored transaction. Client: {}, \
+                     Transaction ID: {}.", format_amount(max), msg.client, msg.tx),
+                _ => format!(
+                    "Withdrawal over the client's cumulative limit of {}, {} already withdrawn. Ignored transaction. \
+                     Client: {}, Transaction ID: {}.", format_amount(max), format_amount(withdrawn),
+                    msg.client, msg.tx),
+            };
+            return Err(rejected(reason, message));
+        }
+        if limit.max_cumulative.is_some() {
+            self.withdrawn.insert(msg.client, withdrawn.saturating_add(msg.amount));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{apply, message, units};
+    use crate::{AccountTransactions, Error, TransactionType};
+
+    fn engine(limit: WithdrawalLimit) -> AccountTransactions {
+        let mut limits = WithdrawalLimits::new();
+        limits.set(1, limit);
+        let mut account_txs = AccountTransactions::new();
+        account_txs.set_withdrawal_limits(limits);
+        apply(&mut account_txs, message(TransactionType::DEPOSIT, 1, 1, units(100))).unwrap();
+        account_txs
+    }
+
+    fn rejection(result: Result<()>) -> Option<Rejection> {
+        match result {
+            Err(Error::PolicyViolation { reason, .. }) => Some(reason),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn refuses_a_withdrawal_over_the_single_limit() {
+        let mut account_txs = engine(WithdrawalLimit { max_single: Some(units(10)), max_cumulative: None });
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(10))).unwrap();
+        let result = apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 3, units(11)));
+        assert_eq!(rejection(result), Some(Rejection::WithdrawalLimit));
+        assert_eq!(account_txs.get_account(1).map(|account| account.available), Some(units(90)));
+        assert_eq!(account_txs.withdrawal_limits().breaches().len(), 1);
+    }
+
+    #[test]
+    fn counts_only_applied_withdrawals_towards_the_cumulative_limit() {
+        let mut account_txs = engine(WithdrawalLimit { max_single: None, max_cumulative: Some(units(50)) });
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(30))).unwrap();
+        let result = apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 3, units(30)));
+        assert_eq!(rejection(result), Some(Rejection::CumulativeLimit));
+        // Refused for funds, so not counted either
+        let result = apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 4, units(500)));
+        assert_eq!(rejection(result), Some(Rejection::InsufficientFunds));
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 5, units(20))).unwrap();
+        assert_eq!(account_txs.withdrawal_limits().withdrawn(1), units(50));
+        let breach = &account_txs.withdrawal_limits().breaches()[0];
+        assert_eq!((breach.tx, breach.limit, breach.withdrawn), (3, units(50), units(30)));
+    }
+
+    #[test]
+    fn leaves_no_transaction_for_a_refused_withdrawal() {
+        let mut account_txs = engine(WithdrawalLimit { max_single: Some(units(10)), max_cumulative: None });
+        assert!(apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(20))).is_err());
+        assert!(account_txs.get_transaction(2).is_none());
+        let result = apply(&mut account_txs, message(TransactionType::DISPUTE, 1, 2, 0));
+        assert_eq!(rejection(result), Some(Rejection::UnknownTx));
+        // The id was never used, so a corrected withdrawal may take it
+        apply(&mut account_txs, message(TransactionType::WITHDRAWAL, 1, 2, units(5))).unwrap();
+        assert_eq!(account_txs.get_account(1).map(|account| account.available), Some(units(95)));
+    }
+}